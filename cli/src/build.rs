@@ -0,0 +1,817 @@
+//! Evaluating packages and running the commands they declare.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use exec::graph::{ActionGraph, ActionKey};
+use exec::key::KeyComponents;
+use exec::{Command, Config, Label, Package};
+use loader::{Executor, Loader};
+use model::{DirEntry, DirEntryKind, FileAttributes};
+use tracing::{error, info, info_span, instrument, warn, Instrument, Span};
+use zaun::instrumentation::AccessedFiles;
+use zaun::{SANDBOX_BUILD_DIR, SANDBOX_SOURCE_DIR};
+use zisch::action::ActionsDAO;
+use zisch::db::Db;
+use zisch::import::SourceFilesDAO;
+use zisch::model::{BuildConfig, BuildConfigId};
+use zisch::output::OutputFilesDAO;
+use zwischen::{Key, Zwischen};
+
+use crate::progress::{BuildProgress, Outcome, PlainProgress};
+use crate::trace::PHASE_TARGET;
+
+/// The evaluated packages of a workspace.
+pub struct Workspace {
+    root: Utf8PathBuf,
+    /// Skipped when looking for packages and globbing.
+    excluded: Utf8PathBuf,
+    loader: Loader,
+    executor: Executor,
+    config: Config,
+    packages: BTreeMap<Utf8PathBuf, Package>,
+}
+
+impl Workspace {
+    /// Finds and evaluates all packages below `root` for the build config called `config`,
+    /// skipping `excluded`.
+    pub fn load(root: &Utf8Path, excluded: &Utf8Path, config: &str) -> Result<Workspace> {
+        let loader = Loader::default();
+        let executor = Executor::default();
+        let configs = executor.evaluate_workspace(&loader, root)?;
+        let mut workspace = Workspace {
+            root: root.to_owned(),
+            excluded: excluded.to_owned(),
+            loader,
+            executor,
+            config: exec::config::select_config(&configs, config)?,
+            packages: BTreeMap::new(),
+        };
+        for package in loader::find_packages(root, excluded)? {
+            workspace.reevaluate(&package)?;
+        }
+        Ok(workspace)
+    }
+
+    /// The build config the packages were evaluated for.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Evaluates the build file of `package` again, forgetting the package if it is gone.
+    #[instrument(skip(self))]
+    pub fn reevaluate(&mut self, package: &Utf8Path) -> Result<()> {
+        if !self
+            .root
+            .join(package)
+            .join(loader::BUILD_FILE_NAME)
+            .is_file()
+        {
+            info!("package removed: //{package}");
+            self.packages.remove(package);
+            return Ok(());
+        }
+        let evaluated = self.executor.evaluate_package(
+            &self.loader,
+            &self.root,
+            &self.excluded,
+            package,
+            &self.config,
+        )?;
+        self.packages.insert(package.to_owned(), evaluated);
+        Ok(())
+    }
+
+    /// Returns the packages with a `glob()` that matches something else
+    /// after the workspace-relative `changed` paths changed.
+    pub fn glob_affected_packages(&self, changed: &BTreeSet<Utf8PathBuf>) -> BTreeSet<Utf8PathBuf> {
+        let exists: BTreeMap<&Utf8Path, bool> = changed
+            .iter()
+            .map(|path| {
+                let metadata = std::fs::symlink_metadata(self.root.join(path));
+                (path.as_path(), metadata.is_ok_and(|m| !m.is_dir()))
+            })
+            .collect();
+        self.packages
+            .iter()
+            .filter(|(_, evaluated)| {
+                evaluated.globs.iter().any(|glob| {
+                    exists
+                        .iter()
+                        .any(|(path, exists)| glob.is_affected_by(path, *exists))
+                })
+            })
+            .map(|(package, _)| package.clone())
+            .collect()
+    }
+
+    pub fn graph(&self) -> Result<ActionGraph> {
+        Ok(ActionGraph::new(
+            self.packages
+                .values()
+                .flat_map(|evaluated| &evaluated.commands)
+                .cloned()
+                .collect(),
+        )?)
+    }
+}
+
+/// Looks up the content hashes of the source files the commands at `indices` use,
+/// `None` for those that do not exist.
+pub async fn source_hashes(
+    db: &mut Db,
+    graph: &ActionGraph,
+    indices: impl IntoIterator<Item = &usize>,
+) -> Result<HashMap<Utf8PathBuf, Option<[u8; 32]>>> {
+    let mut source_hashes = HashMap::new();
+    for &index in indices {
+        for src in &graph.commands()[index].srcs {
+            if graph.producer(src).is_none() && !source_hashes.contains_key(src) {
+                let hash = db.source_file_hash(src).await?;
+                source_hashes.insert(src.clone(), hash.map(|h| *h.as_bytes()));
+            }
+        }
+    }
+    Ok(source_hashes)
+}
+
+/// Returns the key components recorded when the commands at `indices` last succeeded
+/// with `config`, by label.
+pub async fn recorded_key_components(
+    db: &mut Db,
+    config: BuildConfigId,
+    graph: &ActionGraph,
+    indices: impl IntoIterator<Item = &usize>,
+) -> Result<BTreeMap<Label, KeyComponents>> {
+    let mut recorded = BTreeMap::new();
+    for &index in indices {
+        let label = &graph.commands()[index].label;
+        if let Some(json) = db.key_components(config, &label.to_string()).await? {
+            let components: KeyComponents = serde_json::from_str(&json)
+                .with_context(|| format!("while reading the key components of {label}"))?;
+            recorded.insert(label.clone(), components);
+        }
+    }
+    Ok(recorded)
+}
+
+/// Returns the inputs that commands used according to their recorded key components,
+/// to narrow their keys the same way the build that recorded them did.
+pub fn used_srcs(
+    recorded: &BTreeMap<Label, KeyComponents>,
+) -> BTreeMap<Label, BTreeSet<Utf8PathBuf>> {
+    recorded
+        .iter()
+        .filter_map(|(label, components)| Some((label.clone(), components.used_srcs()?)))
+        .collect()
+}
+
+/// Returns the action running `command` in the sandbox, writing below `build_root`.
+pub fn sandbox_action(
+    command: &Command,
+    build_root: &Utf8Path,
+    instrument: bool,
+) -> Result<zaun::Action> {
+    let (cmd, args) = command
+        .args
+        .split_first()
+        .ok_or_else(|| anyhow!("{} has no command", command.label))?;
+    Ok(zaun::Action {
+        build: build_root.to_owned(),
+        exec_steps: vec![zaun::Exec {
+            cmd: cmd.clone(),
+            args: args.to_vec(),
+            env: command.env.clone(),
+        }],
+        instrument,
+        ..Default::default()
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildSummary {
+    pub ran: usize,
+    pub up_to_date: usize,
+    /// Commands whose outputs were restored from the blob store instead of running them.
+    pub restored: usize,
+    pub failed: Vec<Label>,
+}
+
+/// Runs commands of one build config whose action key changed since they last succeeded.
+///
+/// What the keys were derived from when commands last succeeded is recorded in the
+/// build database, so that later builds know which commands are up to date.
+/// Their outputs are recorded by key in the blob store, so that they can be restored
+/// instead of running the command again, e.g. in another build config or on another
+/// machine sharing a remote cache.
+#[derive(Debug)]
+pub struct Builder {
+    config: BuildConfig,
+    /// The workspace root, where source files are.
+    root: Utf8PathBuf,
+    /// The output directory of the build config.
+    build_root: Utf8PathBuf,
+    /// Outputs are stored and recorded by action key here, so that they survive
+    /// switching configs.
+    cas: Box<dyn Zwischen>,
+    /// Whether commands record the files they access.
+    instrument: bool,
+    /// The inputs commands used when they last ran, as recorded by instrumentation.
+    used_srcs: BTreeMap<Label, BTreeSet<Utf8PathBuf>>,
+    progress: Arc<dyn BuildProgress>,
+}
+
+impl Builder {
+    /// Creates a builder for the sources below `root`, writing the outputs of `config`
+    /// below `build_root` and storing them in `cas`.
+    pub fn new(
+        config: BuildConfig,
+        root: impl Into<Utf8PathBuf>,
+        build_root: impl Into<Utf8PathBuf>,
+        cas: Box<dyn Zwischen>,
+    ) -> Builder {
+        Builder {
+            config,
+            root: root.into(),
+            build_root: build_root.into(),
+            cas,
+            instrument: false,
+            used_srcs: BTreeMap::new(),
+            progress: Arc::new(PlainProgress::default()),
+        }
+    }
+
+    /// Reports progress and the output of commands to `progress` instead of printing them.
+    pub fn with_progress(mut self, progress: Arc<dyn BuildProgress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Records the files commands access, so that they are only run again
+    /// when one of the inputs they actually used changes.
+    pub fn with_instrumentation(mut self, instrument: bool) -> Self {
+        self.instrument = instrument;
+        self
+    }
+
+    /// Builds `targets` and everything they depend on. Builds all commands if `targets` is empty.
+    pub async fn build(
+        &mut self,
+        db: &mut Db,
+        graph: &ActionGraph,
+        targets: &[Label],
+    ) -> Result<BuildSummary> {
+        let roots = if targets.is_empty() {
+            (0..graph.commands().len()).collect()
+        } else {
+            targets
+                .iter()
+                .map(|t| graph.find(t).ok_or_else(|| anyhow!("unknown target {t}")))
+                .collect::<Result<Vec<_>>>()?
+        };
+        let order = graph.topo_order(graph.transitive_deps(roots))?;
+
+        let recorded = recorded_key_components(db, self.config.id, graph, &order).await?;
+        self.used_srcs = used_srcs(&recorded);
+        let source_hashes = source_hashes(db, graph, &order).await?;
+        let source_hash = |src: &Utf8Path| source_hashes.get(src).copied().flatten();
+        let keys: Vec<ActionKey> = graph
+            .key_components(source_hash, &self.used_srcs)
+            .iter()
+            .map(KeyComponents::key)
+            .collect();
+        // Commands are up to date if their key did not change since they last
+        // succeeded and their outputs are still there.
+        let up_to_date: BTreeSet<usize> = order
+            .iter()
+            .copied()
+            .filter(|&index| {
+                let command = &graph.commands()[index];
+                recorded
+                    .get(&command.label)
+                    .is_some_and(|recorded| recorded.key() == keys[index])
+                    && command
+                        .outs
+                        .iter()
+                        .all(|out| self.build_root.join(out).is_file())
+            })
+            .collect();
+
+        // Commands are ready once all of their dependencies finished,
+        // the time until they run is spent in the queue.
+        let mut dependents: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut waiting_for: HashMap<usize, usize> = HashMap::new();
+        for &index in &order {
+            let deps = graph.deps(index);
+            waiting_for.insert(index, deps.len());
+            for dep in deps {
+                dependents.entry(dep).or_default().push(index);
+            }
+        }
+        let mut ready: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|index| waiting_for[index] == 0)
+            .collect();
+        let mut queued: HashMap<usize, Span> = HashMap::new();
+
+        let mut summary = BuildSummary::default();
+        let mut failed = BTreeSet::new();
+        let mut succeeded = Vec::new();
+        let mut stored_outputs = HashMap::new();
+        self.progress.start(order.len());
+        for &index in &order {
+            for ready in ready.drain(..) {
+                let label = &graph.commands()[ready].label;
+                if !graph.deps(ready).iter().any(|dep| failed.contains(dep))
+                    && !up_to_date.contains(&ready)
+                {
+                    let span = info_span!(target: PHASE_TARGET, "queue", label = %label);
+                    queued.insert(ready, span);
+                    self.progress.queued(label);
+                }
+            }
+
+            let command = &graph.commands()[index];
+            if graph.deps(index).iter().any(|dep| failed.contains(dep)) {
+                failed.insert(index);
+                self.progress.finished(&command.label, Outcome::Skipped);
+            } else if up_to_date.contains(&index) {
+                summary.up_to_date += 1;
+                self.progress.finished(&command.label, Outcome::UpToDate);
+            } else if let Some(outputs) = self.restore_outputs(db, command, keys[index]).await? {
+                drop(queued.remove(&index));
+                stored_outputs.insert(index, outputs);
+                summary.restored += 1;
+                succeeded.push(index);
+                self.progress.finished(&command.label, Outcome::Restored);
+            } else {
+                summary.ran += 1;
+                drop(queued.remove(&index));
+                self.progress.started(&command.label);
+                let capture = info_span!(target: PHASE_TARGET, "capture", label = %command.label);
+                let outputs = if self.run_command(command)? {
+                    self.record_outputs(db, command).instrument(capture).await?
+                } else {
+                    None
+                };
+                if let Some(outputs) = outputs {
+                    stored_outputs.insert(index, outputs);
+                    succeeded.push(index);
+                    self.progress.finished(&command.label, Outcome::Succeeded);
+                } else {
+                    error!("{} failed", command.label);
+                    failed.insert(index);
+                    summary.failed.push(command.label.clone());
+                    self.progress.finished(&command.label, Outcome::Failed);
+                }
+            }
+
+            for &dependent in dependents.get(&index).into_iter().flatten() {
+                let waiting = waiting_for
+                    .get_mut(&dependent)
+                    .expect("dependents are in order");
+                *waiting -= 1;
+                if *waiting == 0 {
+                    ready.push(dependent);
+                }
+            }
+        }
+
+        // Key what succeeded by the inputs it just used, so that it is up to date next time,
+        // and keep what the keys were derived from, to explain the next time they change.
+        let components = graph.key_components(source_hash, &self.used_srcs);
+        // Builds that know nothing about the inputs commands used, e.g. on other machines,
+        // key them by all declared inputs.
+        let declared = graph.key_components(source_hash, &BTreeMap::new());
+        for index in succeeded {
+            let label = graph.commands()[index].label.to_string();
+            db.record_key_components(
+                self.config.id,
+                &label,
+                &serde_json::to_string(&components[index])?,
+            )
+            .await?;
+            if let Some(outputs) = stored_outputs.get(&index) {
+                // Keeps the stored outputs alive when collecting garbage.
+                db.record_action_outputs(self.config.id, &label, *outputs)
+                    .await?;
+                let keys = [components[index].key(), declared[index].key()];
+                for key in keys.iter().collect::<HashSet<_>>() {
+                    self.cas.record_action(&action_digest(*key), outputs)?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Restores the outputs recorded in the blob store for `key`, e.g. by a build on
+    /// another machine, and records them for the build config. Returns the `entry_hash`
+    /// of the tree listing them if it did.
+    ///
+    /// The blob store is a cache: if the outputs cannot be restored, the command runs.
+    async fn restore_outputs(
+        &self,
+        db: &mut Db,
+        command: &Command,
+        key: ActionKey,
+    ) -> Result<Option<Key>> {
+        let restored = (|| {
+            let Some(outputs) = self.cas.lookup_action(&action_digest(key))? else {
+                return Ok(None);
+            };
+            let files = zwischen::tree::load_files(&*self.cas, outputs)?;
+            let outs: BTreeSet<&Utf8PathBuf> = command.outs.iter().collect();
+            if !files.keys().eq(outs) {
+                return Err(anyhow!(
+                    "{outputs} lists other outputs than {:?}",
+                    command.outs
+                ));
+            }
+            zwischen::tree::restore_files(&*self.cas, &files, &self.build_root)?;
+            anyhow::Ok(Some((outputs, files)))
+        })();
+        let (outputs, files) = match restored {
+            Ok(Some(restored)) => restored,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!("cannot restore the outputs of {}: {e:?}", command.label);
+                return Ok(None);
+            }
+        };
+        info!("restored the outputs of {}", command.label);
+        let hashes = files
+            .into_iter()
+            .map(|(path, entry)| (path, entry.content_hash))
+            .collect();
+        db.record_outputs(self.config.id, &hashes).await?;
+        Ok(Some(outputs))
+    }
+
+    /// Runs `command` in the sandbox. Returns whether it succeeded.
+    #[instrument(skip_all, fields(label = %command.label, config = %self.config.name))]
+    fn run_command(&mut self, command: &Command) -> Result<bool> {
+        let action = sandbox_action(command, &self.build_root, self.instrument)?;
+        let provision =
+            info_span!(target: PHASE_TARGET, "provision", label = %command.label).entered();
+        std::fs::create_dir_all(&self.build_root)
+            .with_context(|| format!("while creating {}", self.build_root))?;
+        let exec_dir = zaun::new_exec_dir();
+        drop(provision);
+
+        info!("running {} in {exec_dir}", command.label);
+        let sandbox = info_span!(
+            target: PHASE_TARGET,
+            "sandbox",
+            label = %command.label,
+            execution_us = tracing::field::Empty
+        )
+        .entered();
+        let mut child = zaun::spawn_piped(exec_dir.as_std_path(), &action)
+            .with_context(|| format!("while running {}", command.label))?;
+        let stdout = child
+            .stdout
+            .take()
+            .map(|pipe| Box::new(pipe) as Box<dyn Read + Send>);
+        let stderr = child
+            .stderr
+            .take()
+            .map(|pipe| Box::new(pipe) as Box<dyn Read + Send>);
+        let exit_status = std::thread::scope(|scope| {
+            for pipe in stdout.into_iter().chain(stderr) {
+                scope.spawn(|| {
+                    for line in BufReader::new(pipe).split(b'\n') {
+                        match line {
+                            Ok(line) => self.progress.output(&command.label, &line),
+                            Err(e) => {
+                                warn!("while reading the output of {}: {e}", command.label);
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+            child.wait()
+        })
+        .with_context(|| format!("while waiting for {}", command.label))?;
+        if let Ok(result) = zaun::ExecResult::read(exec_dir.as_std_path()) {
+            sandbox.record("execution_us", result.execution.as_micros() as u64);
+        }
+        drop(sandbox);
+        if !exit_status.success() {
+            info!(
+                "inspect the sandbox of {} with `zaun debug {exec_dir}`",
+                command.label
+            );
+        } else if self.instrument {
+            self.record_used_srcs(command, &exec_dir);
+        } else {
+            // Nothing is known about what it used this time, so all inputs count.
+            self.used_srcs.remove(&command.label);
+        }
+        Ok(exit_status.success())
+    }
+
+    /// Remembers which inputs `command` used according to the files it accessed in `exec_dir`.
+    /// Forgets them if that is not known for sure, so that all inputs count again.
+    fn record_used_srcs(&mut self, command: &Command, exec_dir: &Utf8Path) {
+        let accessed = match AccessedFiles::read(exec_dir.as_std_path()) {
+            Ok(accessed) if accessed.complete => accessed,
+            Ok(_) => {
+                warn!("accessed files of {} are incomplete", command.label);
+                self.used_srcs.remove(&command.label);
+                return;
+            }
+            Err(e) => {
+                warn!("no accessed files of {}: {e}", command.label);
+                self.used_srcs.remove(&command.label);
+                return;
+            }
+        };
+        let accessed: BTreeSet<&Utf8Path> = accessed
+            .below(SANDBOX_SOURCE_DIR.into())
+            .chain(accessed.below(SANDBOX_BUILD_DIR.into()))
+            .collect();
+        let used = command
+            .srcs
+            .iter()
+            .filter(|src| {
+                // Accesses are recorded by resolved path and not for directories,
+                // so only regular files can turn out to be unused.
+                let is_file = |root: &Utf8Path| {
+                    std::fs::symlink_metadata(root.join(src)).is_ok_and(|m| m.is_file())
+                };
+                accessed.contains(src.as_path())
+                    || !(is_file(&self.root) || is_file(&self.build_root))
+            })
+            .cloned()
+            .collect();
+        self.used_srcs.insert(command.label.clone(), used);
+    }
+
+    /// Stores the outputs of `command`, records them for the build config and returns
+    /// the `entry_hash` of the tree listing them.
+    /// Returns `None` if `command` did not produce all of its declared outputs.
+    async fn record_outputs(&self, db: &mut Db, command: &Command) -> Result<Option<Key>> {
+        let outs: BTreeSet<Utf8PathBuf> = command.outs.iter().cloned().collect();
+        let missing: Vec<_> = outs
+            .iter()
+            .filter(|out| !self.build_root.join(out).is_file())
+            .collect();
+        if !missing.is_empty() {
+            error!("{} did not produce {missing:?}", command.label);
+            return Ok(None);
+        }
+        let files: Vec<Utf8PathBuf> = outs.iter().map(|out| self.build_root.join(out)).collect();
+        let keys = self.cas.store_many(&files)?;
+        let mut tree = BTreeMap::new();
+        for ((out, file), key) in outs.iter().zip(&files).zip(&keys) {
+            let metadata =
+                std::fs::metadata(file).with_context(|| format!("while inspecting {file}"))?;
+            let entry = DirEntry {
+                kind: DirEntryKind::File {
+                    attributes: FileAttributes::from_metadata(&metadata),
+                },
+                content_hash: *key,
+            };
+            tree.insert(out.clone(), entry);
+        }
+        let outputs = outs.into_iter().zip(keys).collect();
+        db.record_outputs(self.config.id, &outputs).await?;
+        Ok(Some(zwischen::tree::store_files(&*self.cas, &tree)?))
+    }
+}
+
+/// Outputs are recorded in the blob store by action key.
+fn action_digest(key: ActionKey) -> Key {
+    Key::from(blake3::Hash::from(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use zaun::instrumentation::ACCESSED_JSON_FILE_NAME;
+    use zisch::build_config::BuildConfigsDAO;
+    use zisch::roots::RootsDAO;
+    use zwischen::FileSystemZwischen;
+
+    use super::*;
+
+    /// Collects which commands started and their outcomes.
+    #[derive(Debug, Default)]
+    struct Outcomes {
+        started: Mutex<Vec<Label>>,
+        finished: Mutex<Vec<(Label, Outcome)>>,
+    }
+
+    impl BuildProgress for Outcomes {
+        fn started(&self, label: &Label) {
+            self.started.lock().unwrap().push(label.clone());
+        }
+
+        fn finished(&self, label: &Label, outcome: Outcome) {
+            self.finished.lock().unwrap().push((label.clone(), outcome));
+        }
+    }
+
+    fn command(name: &str, srcs: &[&str], outs: &[&str]) -> Command {
+        Command {
+            label: Label::new("pkg", name),
+            args: vec!["cc".into()],
+            env: BTreeMap::new(),
+            srcs: srcs.iter().map(Utf8PathBuf::from).collect(),
+            outs: outs.iter().map(Utf8PathBuf::from).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_that_succeeded_before_are_up_to_date() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let (root, build_root) = (dir.join("src"), dir.join("build"));
+        std::fs::create_dir_all(root.join("pkg"))?;
+        std::fs::create_dir_all(build_root.join("pkg"))?;
+        std::fs::write(root.join("pkg/main.c"), "int main() {}")?;
+        std::fs::write(build_root.join("pkg/main.o"), "object")?;
+        std::fs::write(build_root.join("pkg/main"), "binary")?;
+        let graph = ActionGraph::new(vec![
+            command("link", &["pkg/main.o"], &["pkg/main"]),
+            command("compile", &["pkg/main.c"], &["pkg/main.o"]),
+        ])?;
+
+        let mut db = Db::in_memory().await?;
+        db.import_source_tree(&root, &dir.join("zack")).await?;
+        let config = db.get_default_build_config().await?.get().clone();
+        // What an earlier build recorded when both commands succeeded.
+        let source_hashes = source_hashes(&mut db, &graph, &[0, 1]).await?;
+        let components = graph.key_components(
+            |src| source_hashes.get(src).copied().flatten(),
+            &BTreeMap::new(),
+        );
+        for components in &components {
+            db.record_key_components(
+                config.id,
+                &components.label.to_string(),
+                &serde_json::to_string(components)?,
+            )
+            .await?;
+        }
+
+        let outcomes = Arc::new(Outcomes::default());
+        let summary = Builder::new(
+            config,
+            &root,
+            &build_root,
+            Box::new(FileSystemZwischen::new(dir.join("cas"))),
+        )
+        .with_progress(outcomes.clone())
+        .build(&mut db, &graph, &[])
+        .await?;
+
+        assert_eq!(
+            summary,
+            BuildSummary {
+                ran: 0,
+                up_to_date: 2,
+                restored: 0,
+                failed: vec![],
+            }
+        );
+        assert_eq!(
+            *outcomes.finished.lock().unwrap(),
+            [
+                (Label::new("pkg", "compile"), Outcome::UpToDate),
+                (Label::new("pkg", "link"), Outcome::UpToDate),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outputs_recorded_elsewhere_are_restored() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let (root, build_root) = (dir.join("src"), dir.join("build"));
+        std::fs::create_dir_all(root.join("pkg"))?;
+        std::fs::write(root.join("pkg/main.c"), "int main() {}")?;
+        let graph = ActionGraph::new(vec![command("compile", &["pkg/main.c"], &["pkg/main.o"])])?;
+
+        let mut db = Db::in_memory().await?;
+        db.import_source_tree(&root, &dir.join("zack")).await?;
+        let config = db.get_default_build_config().await?.get().clone();
+        // What a build on another machine stored in the shared cache.
+        let cas = FileSystemZwischen::new(dir.join("cas"));
+        let object = cas.store_bytes(b"object")?;
+        let outputs = zwischen::tree::store_files(
+            &cas,
+            &BTreeMap::from([(
+                "pkg/main.o".into(),
+                DirEntry {
+                    kind: DirEntryKind::File {
+                        attributes: FileAttributes {
+                            executable: false,
+                            size: 6,
+                        },
+                    },
+                    content_hash: object,
+                },
+            )]),
+        )?;
+        let source_hashes = source_hashes(&mut db, &graph, &[0]).await?;
+        let components = graph.key_components(
+            |src| source_hashes.get(src).copied().flatten(),
+            &BTreeMap::new(),
+        );
+        cas.record_action(&action_digest(components[0].key()), &outputs)?;
+
+        let outcomes = Arc::new(Outcomes::default());
+        let mut builder =
+            Builder::new(config, &root, &build_root, Box::new(cas)).with_progress(outcomes.clone());
+        let summary = builder.build(&mut db, &graph, &[]).await?;
+        assert_eq!(summary.restored, 1);
+        assert_eq!(summary.ran, 0);
+        assert_eq!(
+            *outcomes.finished.lock().unwrap(),
+            [(Label::new("pkg", "compile"), Outcome::Restored)]
+        );
+        assert_eq!(std::fs::read(build_root.join("pkg/main.o"))?, b"object");
+        // Kept alive like outputs of commands that ran.
+        assert_eq!(db.live_output_trees().await?, BTreeSet::from([outputs]));
+
+        // Recorded like a command that ran.
+        let summary = builder.build(&mut db, &graph, &[]).await?;
+        assert_eq!(summary.up_to_date, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_changes_to_used_inputs_run_commands_again() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let (root, build_root) = (dir.join("src"), dir.join("build"));
+        std::fs::create_dir_all(root.join("pkg"))?;
+        std::fs::create_dir_all(build_root.join("pkg"))?;
+        std::fs::write(root.join("pkg/main.c"), "int main() {}")?;
+        std::fs::write(root.join("pkg/unused.h"), "")?;
+        std::fs::write(build_root.join("pkg/main.o"), "object")?;
+        let command = command("compile", &["pkg/main.c", "pkg/unused.h"], &["pkg/main.o"]);
+        let graph = ActionGraph::new(vec![command.clone()])?;
+
+        let mut db = Db::in_memory().await?;
+        db.import_source_tree(&root, &dir.join("zack")).await?;
+        let config = db.get_default_build_config().await?.get().clone();
+        let outcomes = Arc::new(Outcomes::default());
+        let mut builder = Builder::new(
+            config.clone(),
+            &root,
+            &build_root,
+            Box::new(FileSystemZwischen::new(dir.join("cas"))),
+        )
+        .with_progress(outcomes.clone())
+        .with_instrumentation(true);
+
+        // What the command accessed when it last ran and what the build then recorded.
+        let exec_dir = dir.join("exec");
+        std::fs::create_dir(&exec_dir)?;
+        std::fs::write(
+            exec_dir.join(ACCESSED_JSON_FILE_NAME),
+            r#"{"complete": true, "files": {
+                "/source/pkg/main.c": ["read"],
+                "/build/pkg/main.o": ["write"]
+            }}"#,
+        )?;
+        builder.record_used_srcs(&command, &exec_dir);
+        assert_eq!(
+            builder.used_srcs[&command.label],
+            BTreeSet::from(["pkg/main.c".into()])
+        );
+        let source_hashes = source_hashes(&mut db, &graph, &[0]).await?;
+        let components = graph.key_components(
+            |src| source_hashes.get(src).copied().flatten(),
+            &builder.used_srcs,
+        );
+        db.record_key_components(
+            config.id,
+            &command.label.to_string(),
+            &serde_json::to_string(&components[0])?,
+        )
+        .await?;
+
+        std::fs::write(root.join("pkg/unused.h"), "#define UNUSED")?;
+        db.import_source_tree(&root, &dir.join("zack")).await?;
+        let summary = builder.build(&mut db, &graph, &[]).await?;
+        assert_eq!(summary.up_to_date, 1);
+        assert_eq!(summary.ran, 0);
+
+        std::fs::write(root.join("pkg/main.c"), "int main() { return 1; }")?;
+        db.import_source_tree(&root, &dir.join("zack")).await?;
+        // Running needs zaun, only whether it starts matters here.
+        let _ = builder.build(&mut db, &graph, &[]).await;
+        assert_eq!(*outcomes.started.lock().unwrap(), [command.label]);
+        Ok(())
+    }
+}
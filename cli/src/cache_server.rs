@@ -0,0 +1,28 @@
+//! `zack cache-server`: sharing the blob store with other machines.
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use tracing::info;
+use zwischen::http::CacheServer;
+use zwischen::FileSystemZwischen;
+
+pub fn cache_server(
+    listen: &str,
+    store: Option<Utf8PathBuf>,
+    compress: Option<i32>,
+    workers: Option<usize>,
+    max_upload_bytes: u64,
+) -> Result<()> {
+    let store = store.unwrap_or_else(|| directories::cas_dir().to_owned());
+    let mut server = CacheServer::bind(
+        listen,
+        FileSystemZwischen::new(store.clone()).with_compression(compress),
+    )?
+    .with_max_upload_bytes(max_upload_bytes);
+    if let Some(workers) = workers {
+        server = server.with_workers(workers);
+    }
+    info!("serving {store} on http://{}", server.local_addr());
+    server.run();
+    Ok(())
+}
@@ -0,0 +1,17 @@
+//! Opening the blob store outputs are kept in.
+
+use zwischen::http::HttpZwischen;
+use zwischen::tiered::LocalThenRemote;
+use zwischen::{FileSystemZwischen, Zwischen};
+
+/// Opens the blob store of the workspace, backed by the cache server at `remote_cache` if given.
+pub fn open(remote_cache: Option<&str>) -> Box<dyn Zwischen> {
+    let local = FileSystemZwischen::new(directories::cas_dir().to_owned());
+    match remote_cache {
+        Some(url) => Box::new(LocalThenRemote::new(
+            local.clone(),
+            HttpZwischen::new(url, local),
+        )),
+        None => Box::new(local),
+    }
+}
@@ -0,0 +1,55 @@
+//! `zack config`: managing the build configs known to the build database.
+
+use anyhow::{anyhow, Result};
+use zisch::build_config::{BuildConfigError, BuildConfigsDAO};
+use zisch::db::Db;
+use zisch::model::BuildConfigId;
+
+/// Prints all known configs with their output directories, marking pinned ones.
+pub async fn list(db: &mut Db) -> Result<()> {
+    for config in db.list_build_configs().await? {
+        println!(
+            "{}\t{}{}",
+            config.name,
+            directories::config_build_dir(&config.name),
+            if config.pinned { "\tpinned" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+pub async fn add(db: &mut Db, name: &str) -> Result<()> {
+    if !exec::config::valid_config_name(name) {
+        return Err(anyhow!(
+            "invalid build config name {name:?}, use letters, digits, '_', '-' and '.'"
+        ));
+    }
+    db.create_build_config(name).await?;
+    Ok(())
+}
+
+/// Removes the config, the outputs recorded for it and its output directory.
+pub async fn rm(db: &mut Db, name: &str) -> Result<()> {
+    let id = find(db, name).await?;
+    db.delete_build_config(id).await?;
+    let output_dir = directories::config_build_dir(name);
+    if output_dir.exists() {
+        std::fs::remove_dir_all(&output_dir)?;
+    }
+    Ok(())
+}
+
+/// Pins or unpins the config: `zack gc --max-size` never evicts the outputs of pinned configs.
+pub async fn pin(db: &mut Db, name: &str, pinned: bool) -> Result<()> {
+    let id = find(db, name).await?;
+    db.set_build_config_pinned(id, pinned).await
+}
+
+async fn find(db: &mut Db, name: &str) -> Result<BuildConfigId> {
+    Ok(db
+        .find_build_config(name)
+        .await?
+        .ok_or_else(|| BuildConfigError::UnknownName(name.into()))?
+        .get()
+        .id)
+}
@@ -0,0 +1,192 @@
+//! `zack explain`: why a command has to run again.
+//!
+//! Compares what the action key of a command is derived from now with what it was
+//! derived from when the command last succeeded, as recorded in the build database.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use anyhow::Result;
+use exec::graph::ActionGraph;
+use exec::key::{InputHash, KeyChange, KeyComponents};
+use exec::Label;
+use zisch::build_config::{BuildConfigError, BuildConfigsDAO};
+use zisch::db::Db;
+use zisch::import::SourceFilesDAO;
+
+use crate::build::{recorded_key_components, used_srcs, Workspace};
+
+pub async fn explain(config: &str, target: &Label) -> Result<()> {
+    let root = directories::workspace_dir();
+    let excluded = directories::target_dir();
+
+    let graph = Workspace::load(root, excluded, config)?.graph()?;
+    let index = crate::query::find(&graph, target)?;
+    let deps = graph.transitive_deps([index]);
+
+    let mut db = Db::new().await?;
+    db.import_source_tree(root, excluded).await?;
+    let config_id = db
+        .find_build_config(config)
+        .await?
+        .ok_or_else(|| BuildConfigError::UnknownName(config.into()))?
+        .get()
+        .id;
+    let previous = recorded_key_components(&mut db, config_id, &graph, &deps).await?;
+
+    let source_hashes = crate::build::source_hashes(&mut db, &graph, &deps).await?;
+    let current = graph.key_components(
+        |src| source_hashes.get(src).copied().flatten(),
+        &used_srcs(&previous),
+    );
+    print!("{}", explanation(&graph, index, &current, &previous));
+    Ok(())
+}
+
+/// Describes how the key of the command at `index` changed since it last succeeded,
+/// following changed outputs of other commands down to the changes that caused them.
+pub fn explanation(
+    graph: &ActionGraph,
+    index: usize,
+    current: &[KeyComponents],
+    previous: &BTreeMap<Label, KeyComponents>,
+) -> String {
+    fn explain(
+        graph: &ActionGraph,
+        index: usize,
+        current: &[KeyComponents],
+        previous: &BTreeMap<Label, KeyComponents>,
+        depth: usize,
+        seen: &mut BTreeSet<usize>,
+        text: &mut String,
+    ) {
+        let indent = "  ".repeat(depth);
+        let current_components = &current[index];
+        let label = &current_components.label;
+        let Some(previous_components) = previous.get(label) else {
+            writeln!(
+                text,
+                "{indent}{label} has not succeeded with this build config yet"
+            )
+            .expect("writing to a string");
+            return;
+        };
+        if current_components.key() == previous_components.key() {
+            writeln!(
+                text,
+                "{indent}{label} did not change since it last succeeded"
+            )
+            .expect("writing to a string");
+            return;
+        }
+
+        writeln!(text, "{indent}{label} changed since it last succeeded:")
+            .expect("writing to a string");
+        for change in current_components.changes_since(previous_components) {
+            writeln!(text, "{indent}  {change}").expect("writing to a string");
+            if let KeyChange::Input {
+                current: Some(InputHash::Generated { producer, .. }),
+                previous: Some(InputHash::Generated { .. }),
+                ..
+            } = &change
+            {
+                if let Some(producer) = graph.find(producer) {
+                    if seen.insert(producer) {
+                        explain(graph, producer, current, previous, depth + 2, seen, text);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut text = String::new();
+    explain(
+        graph,
+        index,
+        current,
+        previous,
+        0,
+        &mut BTreeSet::new(),
+        &mut text,
+    );
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use exec::Command;
+
+    use super::*;
+
+    fn command(name: &str, srcs: &[&str], outs: &[&str]) -> Command {
+        Command {
+            label: Label::new("pkg", name),
+            args: vec!["cc".into()],
+            env: BTreeMap::new(),
+            srcs: srcs.iter().map(Utf8PathBuf::from).collect(),
+            outs: outs.iter().map(Utf8PathBuf::from).collect(),
+        }
+    }
+
+    fn graph() -> ActionGraph {
+        ActionGraph::new(vec![
+            command("link", &["pkg/main.o"], &["pkg/main"]),
+            command("compile", &["pkg/main.c", "pkg/main.h"], &["pkg/main.o"]),
+        ])
+        .unwrap()
+    }
+
+    fn components(
+        graph: &ActionGraph,
+        changed: &str,
+        used_srcs: &BTreeMap<Label, BTreeSet<Utf8PathBuf>>,
+    ) -> Vec<KeyComponents> {
+        graph.key_components(
+            |src| Some(if src == changed { [2; 32] } else { [1; 32] }),
+            used_srcs,
+        )
+    }
+
+    fn by_label(components: Vec<KeyComponents>) -> BTreeMap<Label, KeyComponents> {
+        components
+            .into_iter()
+            .map(|components| (components.label.clone(), components))
+            .collect()
+    }
+
+    #[test]
+    fn changed_sources_are_named_through_dependencies() {
+        let graph = graph();
+        let previous = by_label(components(&graph, "", &BTreeMap::new()));
+        let current = components(&graph, "pkg/main.c", &BTreeMap::new());
+
+        assert_eq!(
+            explanation(&graph, 0, &current, &previous),
+            "//pkg:link changed since it last succeeded:\n  \
+             input pkg/main.o, an output of //pkg:compile, changed\n    \
+             //pkg:compile changed since it last succeeded:\n      \
+             source file pkg/main.c changed\n"
+        );
+        assert_eq!(
+            explanation(&graph, 0, &current, &BTreeMap::new()),
+            "//pkg:link has not succeeded with this build config yet\n"
+        );
+    }
+
+    #[test]
+    fn recorded_unused_inputs_narrow_the_current_keys() {
+        let graph = graph();
+        let used = BTreeMap::from([(
+            Label::new("pkg", "compile"),
+            BTreeSet::from([Utf8PathBuf::from("pkg/main.c")]),
+        )]);
+        let previous = by_label(components(&graph, "", &used));
+        let current = components(&graph, "pkg/main.h", &used_srcs(&previous));
+
+        assert_eq!(
+            explanation(&graph, 1, &current, &previous),
+            "//pkg:compile did not change since it last succeeded\n"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+//! `zack fsck`: checking the integrity of the blob store.
+
+use anyhow::{anyhow, Result};
+use tracing::info;
+use zwischen::fsck::{FsckOptions, ProblemKind};
+use zwischen::FileSystemZwischen;
+
+pub fn fsck(quarantine: bool) -> Result<()> {
+    let store = FileSystemZwischen::new(directories::cas_dir().to_owned());
+    let report = store.fsck(&FsckOptions {
+        quarantine,
+        ..Default::default()
+    })?;
+
+    for problem in &report.problems {
+        let description = match &problem.kind {
+            ProblemKind::Mismatch { actual, .. } => format!("content hashes to {actual}"),
+            ProblemKind::Unreadable(error) => format!("unreadable: {error}"),
+            ProblemKind::Orphaned => "not a blob".to_string(),
+            ProblemKind::TempFile => "left over by an interrupted store".to_string(),
+        };
+        match &problem.quarantined {
+            Some(target) => println!("{}: {description}, moved to {target}", problem.path),
+            None => println!("{}: {description}", problem.path),
+        }
+    }
+    info!(
+        "checked {} blobs, found {} problems",
+        report.checked,
+        report.problems.len()
+    );
+
+    if !report.is_clean() && !quarantine {
+        return Err(anyhow!(
+            "found {} problems in {}, run with --quarantine to move them out of the way",
+            report.problems.len(),
+            store.base_path()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! `zack gc`: deleting blobs the build database no longer refers to,
+//! neither directly nor through the stored listings of the outputs of commands.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use tracing::info;
+use zisch::db::Db;
+use zisch::roots::RootsDAO;
+use zwischen::gc::{GcOptions, Root};
+use zwischen::FileSystemZwischen;
+
+pub async fn gc(
+    db: &mut Db,
+    grace_period: Duration,
+    size_budget: Option<u64>,
+    dry_run: bool,
+) -> Result<()> {
+    let blobs = db.live_content_hashes().await?;
+    let trees = db.live_output_trees().await?;
+    info!(
+        "{} live hashes and {} output listings in the build database",
+        blobs.len(),
+        trees.len()
+    );
+    let roots = blobs
+        .into_iter()
+        .map(Root::Blob)
+        .chain(trees.into_iter().map(Root::Dir));
+    let pinned = db
+        .pinned_content_hashes()
+        .await?
+        .into_iter()
+        .map(Root::Blob)
+        .chain(db.pinned_output_trees().await?.into_iter().map(Root::Dir));
+
+    let store = FileSystemZwischen::new(directories::cas_dir().to_owned());
+    let report = store.gc(
+        roots,
+        pinned,
+        &GcOptions {
+            grace_period,
+            size_budget,
+            dry_run,
+        },
+    )?;
+
+    let verb = if dry_run { "would free" } else { "freed" };
+    println!(
+        "{verb} {} bytes: {} unreachable and {} evicted blobs, {} reachable blobs and {} bytes remain",
+        report.freed_bytes,
+        report.deleted,
+        report.evicted,
+        report.reachable,
+        report.remaining_bytes
+    );
+    Ok(())
+}
@@ -0,0 +1,12 @@
+pub mod build;
+pub mod cache_server;
+pub mod cas;
+pub mod config;
+pub mod explain;
+pub mod fsck;
+pub mod gc;
+pub mod progress;
+pub mod query;
+pub mod trace;
+pub mod tui;
+pub mod watch;
@@ -1,37 +1,411 @@
-use anyhow::{Context, Result};
-use directories::workspace_dir;
-use loader::{Executor, Loader};
-use starlark::environment::GlobalsBuilder;
-use starlark::starlark_module;
-use std::fs::read_to_string;
-
-#[starlark_module]
-fn starlark_quadratic(builder: &mut GlobalsBuilder) {
-    fn quadratic(a: i32, b: i32, c: i32, x: i32) -> anyhow::Result<i32> {
-        Ok(a * x * x + b * x + c)
-    }
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bpaf::Bpaf;
+use camino::{Utf8Path, Utf8PathBuf};
+use cli::build::{Builder, Workspace};
+use cli::progress::{BuildProgress, PlainProgress};
+use cli::query::OutputFormat;
+use cli::trace::Trace;
+use cli::tui::Tui;
+use exec::{Label, LabelPattern};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use zisch::build_config::BuildConfigsDAO;
+use zisch::db::Db;
+use zisch::import::SourceFilesDAO;
+
+/// How many of the slowest actions are reported after a build.
+const SLOWEST_ACTIONS: usize = 10;
+
+#[derive(Debug, Clone, Bpaf)]
+#[bpaf(options, version)]
+struct Opts {
+    #[bpaf(external)]
+    action: Action,
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::FmtSubscriber::builder()
-        .with_line_number(true)
-        .with_file(true)
-        .init();
+#[derive(Debug, Clone, Bpaf)]
+enum Action {
+    /// Builds the given targets and their dependencies,
+    /// or everything if no target is given.
+    #[bpaf(command)]
+    Build {
+        /// The build config declared in ZACK_WORKSPACE.star to build with.
+        #[bpaf(long("config"), argument("NAME"), fallback(exec::config::DEFAULT_CONFIG_NAME.to_string()))]
+        config: String,
+        /// Share outputs with the cache server at URL: store them there, and restore
+        /// the outputs it has instead of running their commands.
+        #[bpaf(
+            long("remote-cache"),
+            env("ZACK_REMOTE_CACHE"),
+            argument("URL"),
+            optional
+        )]
+        remote_cache: Option<String>,
+        /// Write the phases of all actions that ran to FILE in the Chrome trace event format.
+        #[bpaf(long("trace"), argument("FILE"), optional)]
+        trace: Option<Utf8PathBuf>,
+        /// Record the files each command accesses and only rebuild it
+        /// when one of the inputs it used changes.
+        #[bpaf(long("instrument"), switch)]
+        instrument: bool,
+        #[bpaf(positional("TARGET"))]
+        targets: Vec<Label>,
+    },
+    /// Builds the given targets and rebuilds them
+    /// whenever their inputs change.
+    #[bpaf(command)]
+    Watch {
+        /// The build config declared in ZACK_WORKSPACE.star to build with.
+        #[bpaf(long("config"), argument("NAME"), fallback(exec::config::DEFAULT_CONFIG_NAME.to_string()))]
+        config: String,
+        /// Share outputs with the cache server at URL: store them there, and restore
+        /// the outputs it has instead of running their commands.
+        #[bpaf(
+            long("remote-cache"),
+            env("ZACK_REMOTE_CACHE"),
+            argument("URL"),
+            optional
+        )]
+        remote_cache: Option<String>,
+        /// Record the files each command accesses and only rebuild it
+        /// when one of the inputs it used changes.
+        #[bpaf(long("instrument"), switch)]
+        instrument: bool,
+        #[bpaf(positional("TARGET"))]
+        targets: Vec<Label>,
+    },
+    /// Checks the integrity of the blob store.
+    #[bpaf(command)]
+    Fsck {
+        /// Move corrupted and stray files to the quarantine directory.
+        #[bpaf(long("quarantine"), switch)]
+        quarantine: bool,
+    },
+    /// Deletes blobs that the build database no longer refers to.
+    #[bpaf(command)]
+    Gc {
+        /// Keep blobs younger than this many seconds, default: one hour.
+        #[bpaf(long("grace-period"), argument("SECONDS"), fallback(3600))]
+        grace_period: u64,
+        /// Also evict the least recently used blobs until the store is at most this large,
+        /// except for the outputs of pinned build configs.
+        #[bpaf(long("max-size"), argument("BYTES"), optional)]
+        max_size: Option<u64>,
+        /// Only report what would be deleted.
+        #[bpaf(long("dry-run"), switch)]
+        dry_run: bool,
+    },
+    /// Serves the blob store over HTTP, to be used with --remote-cache.
+    #[bpaf(command)]
+    CacheServer {
+        /// The address to listen on.
+        #[bpaf(long("listen"), argument("ADDR"), fallback("127.0.0.1:8642".to_string()))]
+        listen: String,
+        /// The directory to keep blobs in, default: the blob store of the workspace.
+        #[bpaf(long("store"), argument("DIR"), optional)]
+        store: Option<Utf8PathBuf>,
+        /// Store new blobs zstd-compressed with this level.
+        #[bpaf(long("compress"), argument("LEVEL"), optional)]
+        compress: Option<i32>,
+        /// Handle requests on this many threads, default: one per CPU.
+        #[bpaf(long("workers"), argument("N"), optional)]
+        workers: Option<usize>,
+        /// Reject uploads larger than this, default: 1 GiB.
+        #[bpaf(
+            long("max-upload-size"),
+            argument("BYTES"),
+            fallback(zwischen::http::CacheServer::DEFAULT_MAX_UPLOAD_BYTES)
+        )]
+        max_upload_size: u64,
+    },
+    /// Explains why a target has to run again: what its action key is derived from
+    /// that changed since it last succeeded.
+    #[bpaf(command)]
+    Explain {
+        /// The build config declared in ZACK_WORKSPACE.star to explain the target for.
+        #[bpaf(long("config"), argument("NAME"), fallback(exec::config::DEFAULT_CONFIG_NAME.to_string()))]
+        config: String,
+        #[bpaf(positional("TARGET"))]
+        target: Label,
+    },
+    /// Lists targets, shows their attributes and how they depend on each other.
+    #[bpaf(command)]
+    Query {
+        /// The build config declared in ZACK_WORKSPACE.star to evaluate packages for.
+        #[bpaf(long("config"), argument("NAME"), fallback(exec::config::DEFAULT_CONFIG_NAME.to_string()))]
+        config: String,
+        /// Print the result as text, json or dot.
+        #[bpaf(long("output"), argument("FORMAT"), fallback(OutputFormat::Text))]
+        output: OutputFormat,
+        #[bpaf(external(query_action))]
+        action: QueryAction,
+    },
+    /// Manages the build configs known to the build database.
+    #[bpaf(command)]
+    Config {
+        #[bpaf(external(config_action))]
+        action: ConfigAction,
+    },
+}
 
-    rules::copy_built_in_rules()?;
+#[derive(Debug, Clone, Bpaf)]
+enum ConfigAction {
+    /// Lists the build configs and their output directories.
+    #[bpaf(command)]
+    List,
+    /// Registers a new build config.
+    #[bpaf(command)]
+    Add {
+        #[bpaf(positional("NAME"))]
+        name: String,
+    },
+    /// Removes a build config together with its outputs.
+    #[bpaf(command)]
+    Rm {
+        #[bpaf(positional("NAME"))]
+        name: String,
+    },
+    /// Keeps the outputs of a build config when gc evicts blobs to stay within --max-size.
+    #[bpaf(command)]
+    Pin {
+        #[bpaf(positional("NAME"))]
+        name: String,
+    },
+    /// Lets gc evict the outputs of a pinned build config again.
+    #[bpaf(command)]
+    Unpin {
+        #[bpaf(positional("NAME"))]
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Bpaf)]
+enum QueryAction {
+    /// Lists the targets matching PATTERN, e.g. //pkg:all or //pkg/...,
+    /// default: all targets.
+    #[bpaf(command)]
+    Targets {
+        #[bpaf(
+            positional("PATTERN"),
+            fallback(LabelPattern::Recursive(Utf8PathBuf::new()))
+        )]
+        pattern: LabelPattern,
+    },
+    /// Shows the attributes of a target, its direct dependencies and dependents
+    /// and the sandbox action it runs as.
+    /// Its action key is derived from the source files as the last build indexed them.
+    #[bpaf(command)]
+    Show {
+        #[bpaf(positional("TARGET"))]
+        target: Label,
+    },
+    /// Lists a target and everything it depends on.
+    #[bpaf(command)]
+    Deps {
+        #[bpaf(positional("TARGET"))]
+        target: Label,
+    },
+    /// Lists a target and everything depending on it.
+    #[bpaf(command)]
+    Rdeps {
+        #[bpaf(positional("TARGET"))]
+        target: Label,
+    },
+}
+
+async fn query(config: &str, output: OutputFormat, action: QueryAction) -> Result<()> {
+    let root = directories::workspace_dir();
+    let excluded = directories::target_dir();
+    let graph = Workspace::load(root, excluded, config)?.graph()?;
+    let selected = match action {
+        QueryAction::Targets { pattern } => cli::query::targets(&graph, &pattern),
+        QueryAction::Deps { target } => cli::query::deps(&graph, &target)?,
+        QueryAction::Rdeps { target } => cli::query::rdeps(&graph, &target)?,
+        QueryAction::Show { target } => {
+            let index = cli::query::find(&graph, &target)?;
+            // Only reads the database, so the key is the one the last build saw.
+            let mut db = Db::new().await?;
+            let deps = graph.transitive_deps([index]);
+            let recorded = match db.find_build_config(config).await? {
+                Some(build_config) => {
+                    let id = build_config.get().id;
+                    cli::build::recorded_key_components(&mut db, id, &graph, &deps).await?
+                }
+                None => BTreeMap::new(),
+            };
+            let source_hashes = cli::build::source_hashes(&mut db, &graph, &deps).await?;
+            let keys = graph.action_keys(
+                |src| source_hashes.get(src).copied().flatten(),
+                &cli::build::used_srcs(&recorded),
+            );
+            let action = cli::build::sandbox_action(
+                &graph.commands()[index],
+                &directories::config_build_dir(config),
+                false,
+            )?;
+            print!(
+                "{}",
+                cli::query::format_target(&graph, index, keys[index], &action, output)
+            );
+            return Ok(());
+        }
+    };
+    print!("{}", cli::query::format_targets(&graph, &selected, output));
+    Ok(())
+}
+
+async fn config(action: ConfigAction) -> Result<()> {
+    let mut db = Db::new().await?;
+    match action {
+        ConfigAction::List => cli::config::list(&mut db).await,
+        ConfigAction::Add { name } => cli::config::add(&mut db, &name).await,
+        ConfigAction::Rm { name } => cli::config::rm(&mut db, &name).await,
+        ConfigAction::Pin { name } => cli::config::pin(&mut db, &name, true).await,
+        ConfigAction::Unpin { name } => cli::config::pin(&mut db, &name, false).await,
+    }
+}
+
+async fn build(
+    config: &str,
+    remote_cache: Option<&str>,
+    instrument: bool,
+    trace: &Trace,
+    trace_path: Option<&Utf8Path>,
+    tui: Option<Tui>,
+    targets: &[Label],
+) -> Result<()> {
+    let progress: Arc<dyn BuildProgress> = match &tui {
+        Some(tui) => Arc::new(tui.progress()),
+        None => Arc::new(PlainProgress::default()),
+    };
+    let result = async {
+        let root = directories::workspace_dir();
+        let excluded = directories::target_dir();
 
-    let loader = Loader::default();
-    let executor = Executor::default();
+        let mut db = Db::new().await?;
+        db.import_source_tree(root, excluded).await?;
 
-    let file_path = workspace_dir().join("ZACK.star");
-    executor
-        .execute(
-            &loader,
-            &file_path,
-            read_to_string(&file_path).with_context(|| format!("while reading {file_path:?}"))?,
+        let graph = Workspace::load(root, excluded, config)?.graph()?;
+        let build_config = db.ensure_build_config(config).await?;
+        let summary = Builder::new(
+            build_config,
+            root,
+            directories::config_build_dir(config),
+            cli::cas::open(remote_cache),
         )
-        .map_err(|e| e.into_anyhow())
-        .with_context(|| format!("while executing {file_path:?}"))?;
+        .with_instrumentation(instrument)
+        .with_progress(progress)
+        .build(&mut db, &graph, targets)
+        .await?;
+        anyhow::Ok((graph, summary))
+    }
+    .await;
+    if let Some(tui) = tui {
+        tui.finish()?;
+    }
+    let (graph, summary) = result?;
 
+    cli::trace::print_report(&graph, &trace.spans(), SLOWEST_ACTIONS);
+    if let Some(path) = trace_path {
+        trace.write_chrome_trace(path)?;
+    }
+    if !summary.failed.is_empty() {
+        return Err(anyhow!("failed: {:?}", summary.failed));
+    }
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = opts().fallback_to_usage().run();
+
+    rules::copy_built_in_rules()?;
+
+    // Phases are only collected for a single build, watching would collect them forever.
+    let building = matches!(options.action, Action::Build { .. });
+    let trace = building.then(Trace::default);
+    let tui = if building && std::io::stdout().is_terminal() {
+        Some(Tui::start()?)
+    } else {
+        None
+    };
+    let log_writer = match &tui {
+        Some(tui) => BoxMakeWriter::new(tui.log_writer()),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(log_writer)
+                .with_line_number(true)
+                .with_file(true)
+                .with_filter(LevelFilter::INFO),
+        )
+        .with(trace.as_ref().map(Trace::layer))
+        .init();
+
+    match options.action {
+        Action::Build {
+            config,
+            remote_cache,
+            trace: trace_path,
+            instrument,
+            targets,
+        } => {
+            let trace = trace.expect("collected for builds");
+            build(
+                &config,
+                remote_cache.as_deref(),
+                instrument,
+                &trace,
+                trace_path.as_deref(),
+                tui,
+                &targets,
+            )
+            .await
+        }
+        Action::Watch {
+            config,
+            remote_cache,
+            instrument,
+            targets,
+        } => cli::watch::watch(&config, remote_cache.as_deref(), instrument, &targets).await,
+        Action::CacheServer {
+            listen,
+            store,
+            compress,
+            workers,
+            max_upload_size,
+        } => cli::cache_server::cache_server(&listen, store, compress, workers, max_upload_size),
+        Action::Explain { config, target } => cli::explain::explain(&config, &target).await,
+        Action::Query {
+            config,
+            output,
+            action,
+        } => query(&config, output, action).await,
+        Action::Config { action } => config(action).await,
+        Action::Fsck { quarantine } => cli::fsck::fsck(quarantine),
+        Action::Gc {
+            grace_period,
+            max_size,
+            dry_run,
+        } => {
+            let mut db = Db::new().await?;
+            cli::gc::gc(
+                &mut db,
+                Duration::from_secs(grace_period),
+                max_size,
+                dry_run,
+            )
+            .await
+        }
+    }
+}
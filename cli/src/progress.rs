@@ -0,0 +1,80 @@
+//! Reporting on a running build, as plain lines or in the terminal UI of [`crate::tui`].
+
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+
+use exec::Label;
+
+/// How a command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Its action key did not change since it last succeeded, so it did not run.
+    UpToDate,
+    /// Its outputs were restored from the blob store, so it did not run.
+    Restored,
+    Succeeded,
+    Failed,
+    /// A dependency failed, so it did not run.
+    Skipped,
+}
+
+/// Receives the progress of a build.
+///
+/// Output is called from the threads reading the pipes of a command.
+pub trait BuildProgress: fmt::Debug + Send + Sync {
+    /// A build of `total` commands starts.
+    fn start(&self, _total: usize) {}
+
+    /// All dependencies of the command finished, so it waits to run.
+    fn queued(&self, _label: &Label) {}
+
+    /// The command starts running.
+    fn started(&self, _label: &Label) {}
+
+    /// The command wrote `line` to stdout or stderr, without the newline.
+    /// It may contain ANSI escape codes.
+    fn output(&self, _label: &Label, _line: &[u8]) {}
+
+    /// The command finished, or was found not to need running.
+    fn finished(&self, _label: &Label, _outcome: Outcome) {}
+}
+
+/// Ignores progress.
+impl BuildProgress for () {}
+
+/// Passes output through and prints a line for every command that starts or fails.
+#[derive(Debug, Default)]
+pub struct PlainProgress {
+    counts: Mutex<(usize, usize)>,
+}
+
+impl BuildProgress for PlainProgress {
+    fn start(&self, total: usize) {
+        *self.counts.lock().expect("progress lock poisoned") = (0, total);
+    }
+
+    fn started(&self, label: &Label) {
+        let (finished, total) = *self.counts.lock().expect("progress lock poisoned");
+        println!("[{finished}/{total}] {label}");
+    }
+
+    fn output(&self, _label: &Label, line: &[u8]) {
+        let mut stdout = std::io::stdout().lock();
+        // Output is best effort, a closed stdout must not fail the build.
+        let _ = stdout
+            .write_all(line)
+            .and_then(|()| stdout.write_all(b"\n"));
+    }
+
+    fn finished(&self, label: &Label, outcome: Outcome) {
+        let (finished, total) = {
+            let mut counts = self.counts.lock().expect("progress lock poisoned");
+            counts.0 += 1;
+            *counts
+        };
+        if outcome == Outcome::Failed {
+            println!("[{finished}/{total}] {label} FAILED");
+        }
+    }
+}
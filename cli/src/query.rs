@@ -0,0 +1,265 @@
+//! `zack query`: inspecting the targets of a workspace and how they depend on each other.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use exec::graph::{ActionGraph, ActionKey};
+use exec::{Label, LabelPattern};
+use serde::Serialize;
+
+/// How query results are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// One label per line, or the attributes of a target.
+    #[default]
+    Text,
+    Json,
+    /// A Graphviz digraph with an edge from every target to its dependencies.
+    Dot,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "dot" => Ok(OutputFormat::Dot),
+            _ => Err(anyhow!(
+                "unknown output format {s:?}, expected text, json or dot"
+            )),
+        }
+    }
+}
+
+/// Returns the commands matching `pattern`.
+pub fn targets(graph: &ActionGraph, pattern: &LabelPattern) -> BTreeSet<usize> {
+    (0..graph.commands().len())
+        .filter(|&index| pattern.matches(&graph.commands()[index].label))
+        .collect()
+}
+
+/// Returns `target` and all commands it depends on.
+pub fn deps(graph: &ActionGraph, target: &Label) -> Result<BTreeSet<usize>> {
+    Ok(graph.transitive_deps([find(graph, target)?]))
+}
+
+/// Returns `target` and all commands depending on it.
+pub fn rdeps(graph: &ActionGraph, target: &Label) -> Result<BTreeSet<usize>> {
+    Ok(graph.transitive_rdeps([find(graph, target)?]))
+}
+
+pub fn find(graph: &ActionGraph, target: &Label) -> Result<usize> {
+    graph
+        .find(target)
+        .ok_or_else(|| anyhow!("unknown target {target}"))
+}
+
+/// Formats the labels of `selected`, sorted. As DOT, only the edges between them are included.
+pub fn format_targets(
+    graph: &ActionGraph,
+    selected: &BTreeSet<usize>,
+    output: OutputFormat,
+) -> String {
+    let labels: BTreeMap<&Label, usize> = selected
+        .iter()
+        .map(|&index| (&graph.commands()[index].label, index))
+        .collect();
+    match output {
+        OutputFormat::Text => labels.keys().map(|label| format!("{label}\n")).collect(),
+        OutputFormat::Json => {
+            let labels: Vec<&Label> = labels.into_keys().collect();
+            let mut json = serde_json::to_string_pretty(&labels).expect("labels serialize");
+            json.push('\n');
+            json
+        }
+        OutputFormat::Dot => {
+            let mut dot = String::from("digraph {\n");
+            for (label, &index) in &labels {
+                writeln!(dot, "  {:?};", label.to_string()).expect("writing to a string");
+                let mut deps: Vec<&Label> = graph
+                    .deps(index)
+                    .into_iter()
+                    .filter(|dep| selected.contains(dep))
+                    .map(|dep| &graph.commands()[dep].label)
+                    .collect();
+                deps.sort();
+                for dep in deps {
+                    writeln!(dot, "  {:?} -> {:?};", label.to_string(), dep.to_string())
+                        .expect("writing to a string");
+                }
+            }
+            dot.push_str("}\n");
+            dot
+        }
+    }
+}
+
+/// The attributes of a target and the sandbox action it runs as.
+#[derive(Debug, Serialize)]
+struct TargetInfo<'a> {
+    label: &'a Label,
+    /// The action key the next build computes, before narrowing it to the used inputs.
+    key: String,
+    args: &'a [String],
+    env: &'a BTreeMap<String, String>,
+    srcs: &'a [Utf8PathBuf],
+    outs: &'a [Utf8PathBuf],
+    deps: Vec<&'a Label>,
+    rdeps: Vec<&'a Label>,
+    action: &'a zaun::Action,
+}
+
+fn strings(items: &[impl ToString]) -> Vec<String> {
+    items.iter().map(ToString::to_string).collect()
+}
+
+/// Formats the command at `index` with its direct dependencies and dependents.
+/// As DOT, it is drawn together with their edges.
+pub fn format_target(
+    graph: &ActionGraph,
+    index: usize,
+    key: ActionKey,
+    action: &zaun::Action,
+    output: OutputFormat,
+) -> String {
+    let command = &graph.commands()[index];
+    let labels = |indices: BTreeSet<usize>| {
+        let mut labels: Vec<&Label> = indices
+            .into_iter()
+            .map(|index| &graph.commands()[index].label)
+            .collect();
+        labels.sort();
+        labels
+    };
+    let info = TargetInfo {
+        label: &command.label,
+        key: key.to_string(),
+        args: &command.args,
+        env: &command.env,
+        srcs: &command.srcs,
+        outs: &command.outs,
+        deps: labels(graph.deps(index)),
+        rdeps: labels(graph.rdeps(index)),
+        action,
+    };
+    match output {
+        OutputFormat::Text => {
+            let mut text = format!("{}\n  key: {}\n", info.label, info.key);
+            let mut list = |name: &str, items: Vec<String>| {
+                writeln!(text, "  {name}:").expect("writing to a string");
+                for item in items {
+                    writeln!(text, "    {item}").expect("writing to a string");
+                }
+            };
+            list("args", strings(info.args));
+            list(
+                "env",
+                info.env.iter().map(|(k, v)| format!("{k}={v}")).collect(),
+            );
+            list("srcs", strings(info.srcs));
+            list("outs", strings(info.outs));
+            list("deps", strings(&info.deps));
+            list("rdeps", strings(&info.rdeps));
+            let action = serde_json::to_string_pretty(info.action).expect("actions serialize");
+            list("action", action.lines().map(str::to_string).collect());
+            text
+        }
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&info).expect("targets serialize");
+            json.push('\n');
+            json
+        }
+        OutputFormat::Dot => {
+            let neighbours = graph
+                .deps(index)
+                .into_iter()
+                .chain(graph.rdeps(index))
+                .chain([index])
+                .collect();
+            format_targets(graph, &neighbours, OutputFormat::Dot)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use exec::Command;
+
+    use super::*;
+
+    fn command(package: &str, name: &str, srcs: &[&str], outs: &[&str]) -> Command {
+        Command {
+            label: Label::new(package, name),
+            args: vec!["cc".into(), "-c".into()],
+            env: BTreeMap::from([("CC".into(), "cc".into())]),
+            srcs: srcs.iter().map(Utf8PathBuf::from).collect(),
+            outs: outs.iter().map(Utf8PathBuf::from).collect(),
+        }
+    }
+
+    fn graph() -> ActionGraph {
+        ActionGraph::new(vec![
+            command("app", "main", &["lib/lib.a", "app/main.c"], &["app/main"]),
+            command("lib", "lib", &["lib/lib.c"], &["lib/lib.a"]),
+            command("lib/extra", "extra", &[], &["lib/extra/extra.a"]),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn targets_are_selected_by_pattern() {
+        let graph = graph();
+        let selected = targets(&graph, &"//lib/...".parse().unwrap());
+        assert_eq!(
+            format_targets(&graph, &selected, OutputFormat::Text),
+            "//lib:lib\n//lib/extra:extra\n"
+        );
+        let selected = targets(&graph, &"//lib:all".parse().unwrap());
+        assert_eq!(
+            format_targets(&graph, &selected, OutputFormat::Json),
+            "[\n  \"//lib:lib\"\n]\n"
+        );
+    }
+
+    #[test]
+    fn traversals_are_drawn_as_dot() {
+        let graph = graph();
+        let deps = deps(&graph, &"//app:main".parse().unwrap()).unwrap();
+        assert_eq!(
+            format_targets(&graph, &deps, OutputFormat::Dot),
+            "digraph {\n  \"//app:main\";\n  \"//app:main\" -> \"//lib:lib\";\n  \"//lib:lib\";\n}\n"
+        );
+        let rdeps = rdeps(&graph, &"//lib:lib".parse().unwrap()).unwrap();
+        assert_eq!(rdeps, deps);
+        assert!(super::deps(&graph, &"//app:missing".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn target_shows_attributes_and_action() {
+        let graph = graph();
+        let key = graph.action_keys(|_| None, &BTreeMap::new())[0];
+        let action = crate::build::sandbox_action(
+            &graph.commands()[0],
+            camino::Utf8Path::new("/build"),
+            false,
+        )
+        .unwrap();
+        let text = format_target(&graph, 0, key, &action, OutputFormat::Text);
+
+        assert!(text.starts_with(&format!(
+            "//app:main\n  key: {key}\n  args:\n    cc\n    -c\n"
+        )));
+        assert!(text.contains("  env:\n    CC=cc\n"));
+        assert!(text.contains("  deps:\n    //lib:lib\n  rdeps:\n  action:\n"));
+        let json: serde_json::Value =
+            serde_json::from_str(&format_target(&graph, 0, key, &action, OutputFormat::Json))
+                .unwrap();
+        assert_eq!(json["action"]["exec_steps"][0]["cmd"], "cc");
+        assert_eq!(json["srcs"][1], "app/main.c");
+    }
+}
@@ -0,0 +1,534 @@
+//! Timing the phases of actions, and reporting where the time of a build went.
+//!
+//! Phases are `tracing` spans with the target [`PHASE_TARGET`] and a `label` field,
+//! collected by the layer of a [`Trace`].
+//!
+//! Commands run one at a time, so the time actions spend in the queue is the time
+//! other actions ran before them, and the build takes as long as all actions together.
+//! The critical path is how long it would take if independent actions ran in parallel.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use exec::graph::ActionGraph;
+use exec::Label;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context as LayerContext;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// The target of the spans timing the phases of an action.
+pub const PHASE_TARGET: &str = "zack::phase";
+
+/// A part of running an action, named like its span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Waiting to be run after its dependencies finished, while other actions run.
+    /// As commands run one at a time, this is serialization, not waiting for resources.
+    Queue,
+    /// Preparing the directories the action runs in.
+    Provision,
+    /// Setting up and tearing down the sandbox.
+    Sandbox,
+    /// Running the steps of the action.
+    Execute,
+    /// Checking and storing the outputs.
+    Capture,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Queue => "queue",
+            Phase::Provision => "provision",
+            Phase::Sandbox => "sandbox",
+            Phase::Execute => "execute",
+            Phase::Capture => "capture",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Phase> {
+        [
+            Phase::Queue,
+            Phase::Provision,
+            Phase::Sandbox,
+            Phase::Execute,
+            Phase::Capture,
+        ]
+        .into_iter()
+        .find(|phase| phase.name() == name)
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How long one phase of an action took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseSpan {
+    pub label: String,
+    pub phase: Phase,
+    /// Since the trace started.
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Collects the phase spans of a build.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    started: Instant,
+    spans: Arc<Mutex<Vec<PhaseSpan>>>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            spans: Default::default(),
+        }
+    }
+}
+
+impl Trace {
+    /// A layer adding the phase spans it sees to this trace.
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        PhaseLayer {
+            trace: self.clone(),
+        }
+    }
+
+    /// The spans collected so far, in the order they ended.
+    pub fn spans(&self) -> Vec<PhaseSpan> {
+        self.spans.lock().expect("trace lock poisoned").clone()
+    }
+
+    /// Writes the spans to `path` in the Chrome trace event format,
+    /// as read by `chrome://tracing` and Perfetto.
+    pub fn write_chrome_trace(&self, path: &Utf8Path) -> Result<()> {
+        let trace = serde_json::json!({
+            "traceEvents": self.chrome_trace_events(),
+            "displayTimeUnit": "ms",
+        });
+        let file = std::fs::File::create(path).with_context(|| format!("while creating {path}"))?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &trace)
+            .with_context(|| format!("while writing {path}"))
+    }
+
+    /// Each action gets a track of its own, named like it, as the queue of one
+    /// overlaps with the other phases of others.
+    /// Tracks are in the order the actions started.
+    fn chrome_trace_events(&self) -> Vec<serde_json::Value> {
+        let mut spans = self.spans();
+        spans.sort_by_key(|span| span.start);
+        let mut tids: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut events = Vec::new();
+        for span in &spans {
+            let next_tid = tids.len() + 1;
+            let tid = *tids.entry(&span.label).or_insert_with(|| {
+                events.push(serde_json::json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": 1,
+                    "tid": next_tid,
+                    "args": { "name": span.label },
+                }));
+                next_tid
+            });
+            events.push(serde_json::json!({
+                "name": span.label,
+                "cat": span.phase.name(),
+                "ph": "X",
+                "ts": span.start.as_micros() as u64,
+                "dur": span.duration.as_micros() as u64,
+                "pid": 1,
+                "tid": tid,
+                "args": { "phase": span.phase.name() },
+            }));
+        }
+        events
+    }
+
+    fn push(&self, span: PhaseSpan) {
+        self.spans.lock().expect("trace lock poisoned").push(span);
+    }
+}
+
+/// A phase span that has not ended yet.
+struct OpenPhase {
+    phase: Phase,
+    label: String,
+    started: Instant,
+    /// Recorded on [`Phase::Sandbox`] spans, which end with the execution.
+    execution: Option<Duration>,
+}
+
+#[derive(Default)]
+struct PhaseFields {
+    label: Option<String>,
+    execution: Option<Duration>,
+}
+
+impl Visit for PhaseFields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "execution_us" {
+            self.execution = Some(Duration::from_micros(value));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "label" {
+            self.label = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "label" {
+            self.label = Some(format!("{value:?}"));
+        }
+    }
+}
+
+struct PhaseLayer {
+    trace: Trace,
+}
+
+impl<S> Layer<S> for PhaseLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let metadata = attrs.metadata();
+        if metadata.target() != PHASE_TARGET {
+            return;
+        }
+        let Some(phase) = Phase::from_name(metadata.name()) else {
+            return;
+        };
+        let mut fields = PhaseFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(OpenPhase {
+                phase,
+                label: fields.label.unwrap_or_default(),
+                started: Instant::now(),
+                execution: fields.execution,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(open) = extensions.get_mut::<OpenPhase>() {
+            let mut fields = PhaseFields::default();
+            values.record(&mut fields);
+            open.execution = fields.execution.or(open.execution);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(open) = span.extensions_mut().remove::<OpenPhase>() else {
+            return;
+        };
+        let start = open.started.saturating_duration_since(self.trace.started);
+        let duration = open.started.elapsed();
+        match (open.phase, open.execution) {
+            // The steps ran within the sandbox, so the end of its span is theirs.
+            (Phase::Sandbox, Some(execution)) => {
+                let execution = execution.min(duration);
+                self.trace.push(PhaseSpan {
+                    label: open.label.clone(),
+                    phase: Phase::Sandbox,
+                    start,
+                    duration: duration - execution,
+                });
+                self.trace.push(PhaseSpan {
+                    label: open.label,
+                    phase: Phase::Execute,
+                    start: start + duration - execution,
+                    duration: execution,
+                });
+            }
+            (phase, _) => self.trace.push(PhaseSpan {
+                label: open.label,
+                phase,
+                start,
+                duration,
+            }),
+        }
+    }
+}
+
+/// How long each action that ran took, without waiting in the queue.
+pub fn action_durations(spans: &[PhaseSpan]) -> BTreeMap<String, Duration> {
+    let mut durations = BTreeMap::new();
+    for span in spans.iter().filter(|span| span.phase != Phase::Queue) {
+        *durations.entry(span.label.clone()).or_default() += span.duration;
+    }
+    durations
+}
+
+/// The chain of dependent actions that took longest, dependencies first.
+/// Only actions in `durations` count, the others did not run.
+pub fn critical_path(
+    graph: &ActionGraph,
+    durations: &BTreeMap<String, Duration>,
+) -> Vec<(Label, Duration)> {
+    let duration = |index: usize| {
+        durations
+            .get(&graph.commands()[index].label.to_string())
+            .copied()
+    };
+    let order = graph
+        .topo_order(0..graph.commands().len())
+        .expect("checked for cycles in new");
+    // The longest chain ending with each action, and the dependency it continues.
+    let mut longest: BTreeMap<usize, (Duration, Option<usize>)> = BTreeMap::new();
+    for index in order {
+        let before = graph
+            .deps(index)
+            .into_iter()
+            .filter_map(|dep| longest.get(&dep).map(|(total, _)| (*total, dep)))
+            .max();
+        let total =
+            before.map_or(Duration::ZERO, |(total, _)| total) + duration(index).unwrap_or_default();
+        longest.insert(index, (total, before.map(|(_, dep)| dep)));
+    }
+
+    let mut path = Vec::new();
+    let mut next = longest
+        .iter()
+        .max_by_key(|(_, (total, _))| *total)
+        .map(|(index, _)| *index);
+    while let Some(index) = next {
+        if let Some(duration) = duration(index) {
+            path.push((graph.commands()[index].label.clone(), duration));
+        }
+        next = longest[&index].1;
+    }
+    path.reverse();
+    path
+}
+
+/// The `n` actions that took longest, slowest first.
+pub fn slowest(durations: &BTreeMap<String, Duration>, n: usize) -> Vec<(&str, Duration)> {
+    let mut slowest: Vec<(&str, Duration)> = durations
+        .iter()
+        .map(|(label, duration)| (label.as_str(), *duration))
+        .collect();
+    slowest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    slowest.truncate(n);
+    slowest
+}
+
+/// Prints [`report`].
+pub fn print_report(graph: &ActionGraph, spans: &[PhaseSpan], n: usize) {
+    print!("{}", report(graph, spans, n));
+}
+
+/// Describes how long the actions that ran took one after another, the critical path
+/// of the build and its `n` slowest actions. Empty if no action ran.
+pub fn report(graph: &ActionGraph, spans: &[PhaseSpan], n: usize) -> String {
+    let durations = action_durations(spans);
+    if durations.is_empty() {
+        return String::new();
+    }
+    let mut text = String::new();
+    let sequential: Duration = durations.values().sum();
+    writeln!(
+        text,
+        "{} actions ran one at a time for {sequential:.2?}",
+        durations.len()
+    )
+    .expect("writing to a string");
+    let path = critical_path(graph, &durations);
+    let total: Duration = path.iter().map(|(_, duration)| *duration).sum();
+    writeln!(
+        text,
+        "critical path ({total:.2?}, the least time if independent actions ran in parallel):"
+    )
+    .expect("writing to a string");
+    for (label, duration) in &path {
+        writeln!(text, "  {duration:>10.2?}  {label}").expect("writing to a string");
+    }
+    writeln!(text, "slowest actions:").expect("writing to a string");
+    for (label, duration) in slowest(&durations, n) {
+        writeln!(text, "  {duration:>10.2?}  {label}").expect("writing to a string");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use camino::Utf8PathBuf;
+    use exec::Command;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    fn command(name: &str, srcs: &[&str], outs: &[&str]) -> Command {
+        Command {
+            label: Label::new("pkg", name),
+            args: vec!["true".into()],
+            env: BTreeMap::new(),
+            srcs: srcs.iter().map(Utf8PathBuf::from).collect(),
+            outs: outs.iter().map(Utf8PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn sandbox_spans_are_split_at_the_execution() {
+        let trace = Trace::default();
+        let subscriber = tracing_subscriber::registry().with(trace.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            let label = Label::new("pkg", "compile");
+            let span = tracing::info_span!(
+                target: PHASE_TARGET,
+                "sandbox",
+                label = %label,
+                execution_us = tracing::field::Empty
+            );
+            std::thread::sleep(Duration::from_millis(2));
+            span.record("execution_us", 1000_u64);
+            drop(span);
+            tracing::info_span!("unrelated", label = %label).in_scope(|| {});
+        });
+
+        let spans = trace.spans();
+        let phases: Vec<(&str, Phase)> = spans
+            .iter()
+            .map(|span| (span.label.as_str(), span.phase))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                ("//pkg:compile", Phase::Sandbox),
+                ("//pkg:compile", Phase::Execute)
+            ]
+        );
+        assert_eq!(spans[1].duration, Duration::from_millis(1));
+        assert_eq!(spans[0].start + spans[0].duration, spans[1].start);
+    }
+
+    #[test]
+    fn chrome_trace_has_a_track_per_action() {
+        let trace = Trace::default();
+        let span = |label: &str, phase, start, duration| PhaseSpan {
+            label: label.to_string(),
+            phase,
+            start: Duration::from_millis(start),
+            duration: Duration::from_millis(duration),
+        };
+        // `b` waits in the queue while `a` runs.
+        trace.push(span("//pkg:a", Phase::Execute, 0, 10));
+        trace.push(span("//pkg:b", Phase::Queue, 0, 10));
+        trace.push(span("//pkg:b", Phase::Execute, 10, 5));
+
+        let events = trace.chrome_trace_events();
+        let tracks: Vec<(&str, u64, &str)> = events
+            .iter()
+            .map(|event| {
+                let args = &event["args"];
+                let name = args.get("phase").unwrap_or(&args["name"]);
+                (
+                    event["ph"].as_str().unwrap(),
+                    event["tid"].as_u64().unwrap(),
+                    name.as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                ("M", 1, "//pkg:a"),
+                ("X", 1, "execute"),
+                ("M", 2, "//pkg:b"),
+                ("X", 2, "queue"),
+                ("X", 2, "execute"),
+            ]
+        );
+    }
+
+    #[test]
+    fn critical_path_follows_the_slowest_chain() {
+        let graph = ActionGraph::new(vec![
+            command("link", &["pkg/a.o", "pkg/b.o"], &["pkg/main"]),
+            command("a", &["pkg/a.c"], &["pkg/a.o"]),
+            command("b", &["pkg/b.c"], &["pkg/b.o"]),
+            command("other", &[], &["pkg/other"]),
+        ])
+        .unwrap();
+        let durations = BTreeMap::from([
+            ("//pkg:link".to_string(), Duration::from_secs(1)),
+            ("//pkg:a".to_string(), Duration::from_secs(2)),
+            ("//pkg:b".to_string(), Duration::from_secs(3)),
+            ("//pkg:other".to_string(), Duration::from_secs(3)),
+        ]);
+
+        assert_eq!(
+            critical_path(&graph, &durations),
+            [
+                (Label::new("pkg", "b"), Duration::from_secs(3)),
+                (Label::new("pkg", "link"), Duration::from_secs(1)),
+            ]
+        );
+        assert_eq!(
+            slowest(&durations, 2),
+            [
+                ("//pkg:b", Duration::from_secs(3)),
+                ("//pkg:other", Duration::from_secs(3))
+            ]
+        );
+    }
+
+    #[test]
+    fn report_separates_serialization_from_the_critical_path() {
+        let graph = ActionGraph::new(vec![
+            command("link", &["pkg/a.o"], &["pkg/main"]),
+            command("a", &["pkg/a.c"], &["pkg/a.o"]),
+            command("other", &[], &["pkg/other"]),
+        ])
+        .unwrap();
+        let span = |label: &str, phase, secs| PhaseSpan {
+            label: format!("//pkg:{label}"),
+            phase,
+            start: Duration::ZERO,
+            duration: Duration::from_secs(secs),
+        };
+        let spans = [
+            span("other", Phase::Execute, 4),
+            span("a", Phase::Queue, 4),
+            span("a", Phase::Execute, 2),
+            span("link", Phase::Execute, 1),
+        ];
+
+        assert_eq!(
+            report(&graph, &spans, 1),
+            "3 actions ran one at a time for 7.00s\n\
+             critical path (4.00s, the least time if independent actions ran in parallel):\n\
+             \x20      4.00s  //pkg:other\n\
+             slowest actions:\n\
+             \x20      4.00s  //pkg:other\n"
+        );
+        assert_eq!(report(&graph, &[], 1), "");
+    }
+}
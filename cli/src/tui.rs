@@ -0,0 +1,533 @@
+//! The live dashboard of `zack build` when stdout is a terminal.
+//!
+//! The build reports to a [`TuiProgress`], the terminal is drawn on a thread of its own.
+//! Log messages of zack itself go through [`Tui::log_writer`], so that they do not
+//! garble the screen.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ansi_to_tui::IntoText;
+use exec::Label;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::getpgrp;
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Gauge, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::progress::{BuildProgress, Outcome};
+
+/// How often the screen is drawn, and so how often elapsed times tick.
+const FRAME: Duration = Duration::from_millis(100);
+/// How many actions are listed at most.
+const LISTED_ACTIONS: usize = 8;
+/// How far page up and down scroll the log.
+const PAGE: usize = 10;
+
+enum Event {
+    Start(usize),
+    Queued,
+    Started(String),
+    Output(String, Vec<u8>),
+    Finished(String, Outcome),
+    Log(Vec<u8>),
+    Done,
+}
+
+/// The running dashboard.
+#[derive(Debug)]
+pub struct Tui {
+    events: Sender<Event>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl Tui {
+    /// Takes over the terminal until [`Tui::finish`].
+    pub fn start() -> io::Result<Tui> {
+        let terminal = ratatui::try_init()?;
+        let (events, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("tui".to_string())
+            .spawn(move || run(terminal, receiver))?;
+        Ok(Tui { events, thread })
+    }
+
+    /// Receives the progress of the build.
+    pub fn progress(&self) -> TuiProgress {
+        TuiProgress {
+            events: self.events.clone(),
+        }
+    }
+
+    /// Shows log messages in the log pane of the build, for `tracing_subscriber::fmt`.
+    pub fn log_writer(&self) -> TuiLog {
+        TuiLog {
+            events: self.events.clone(),
+        }
+    }
+
+    /// Restores the terminal, then prints the output of the commands that failed.
+    pub fn finish(self) -> io::Result<()> {
+        // The thread might have ended early with an error, which join reports.
+        let _ = self.events.send(Event::Done);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("the terminal UI panicked")))
+    }
+}
+
+/// Sends progress to the [`Tui`].
+#[derive(Debug, Clone)]
+pub struct TuiProgress {
+    events: Sender<Event>,
+}
+
+impl BuildProgress for TuiProgress {
+    fn start(&self, total: usize) {
+        let _ = self.events.send(Event::Start(total));
+    }
+
+    fn queued(&self, _label: &Label) {
+        let _ = self.events.send(Event::Queued);
+    }
+
+    fn started(&self, label: &Label) {
+        let _ = self.events.send(Event::Started(label.to_string()));
+    }
+
+    fn output(&self, label: &Label, line: &[u8]) {
+        let _ = self
+            .events
+            .send(Event::Output(label.to_string(), line.to_vec()));
+    }
+
+    fn finished(&self, label: &Label, outcome: Outcome) {
+        let _ = self
+            .events
+            .send(Event::Finished(label.to_string(), outcome));
+    }
+}
+
+/// Writes log messages to the [`Tui`].
+#[derive(Debug, Clone)]
+pub struct TuiLog {
+    events: Sender<Event>,
+}
+
+impl<'a> MakeWriter<'a> for TuiLog {
+    type Writer = LogMessage;
+
+    fn make_writer(&'a self) -> LogMessage {
+        LogMessage {
+            events: self.events.clone(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// One log message, sent line by line when dropped.
+/// Written to stderr instead once the [`Tui`] finished.
+pub struct LogMessage {
+    events: Sender<Event>,
+    buffer: Vec<u8>,
+}
+
+impl Write for LogMessage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogMessage {
+    fn drop(&mut self) {
+        for line in self.buffer.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            if self.events.send(Event::Log(line.to_vec())).is_err() {
+                let _ = io::stderr().write_all(&self.buffer);
+                return;
+            }
+        }
+    }
+}
+
+fn run(mut terminal: ratatui::DefaultTerminal, events: Receiver<Event>) -> io::Result<()> {
+    let mut state = State::default();
+    let result = update(&mut terminal, &events, &mut state);
+    ratatui::try_restore()?;
+    result?;
+
+    let mut stdout = io::stdout().lock();
+    for action in state.actions.iter().filter(|a| a.failed()) {
+        writeln!(stdout, "{} failed:", action.label)?;
+        for line in &action.log {
+            stdout.write_all(line)?;
+            stdout.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies events and draws the screen until the build is done.
+fn update(
+    terminal: &mut ratatui::DefaultTerminal,
+    events: &Receiver<Event>,
+    state: &mut State,
+) -> io::Result<()> {
+    loop {
+        let mut done = false;
+        match events.recv_timeout(FRAME) {
+            Ok(Event::Done) | Err(RecvTimeoutError::Disconnected) => done = true,
+            Ok(event) => state.apply(event),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        while let Ok(event) = events.try_recv() {
+            match event {
+                Event::Done => done = true,
+                event => state.apply(event),
+            }
+        }
+        while event::poll(Duration::ZERO)? {
+            if let TermEvent::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let interrupted = key.code == KeyCode::Char('q')
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL));
+                if interrupted {
+                    ratatui::restore();
+                    // Raw mode keeps the terminal from sending SIGINT, so do what it would:
+                    // interrupt the process group, the running commands along with zack.
+                    if let Err(e) = killpg(getpgrp(), Signal::SIGINT) {
+                        eprintln!("cannot interrupt the running commands: {e}");
+                    }
+                    std::process::exit(130);
+                }
+                state.key(key.code);
+            }
+        }
+        terminal.draw(|frame| state.draw(frame, Instant::now()))?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// A command that started running.
+#[derive(Debug)]
+struct Action {
+    label: String,
+    started: Instant,
+    finished: Option<(Duration, Outcome)>,
+    log: Vec<Vec<u8>>,
+}
+
+impl Action {
+    fn failed(&self) -> bool {
+        matches!(self.finished, Some((_, Outcome::Failed)))
+    }
+}
+
+/// What the dashboard shows.
+#[derive(Debug)]
+struct State {
+    total: usize,
+    queued: usize,
+    finished: usize,
+    up_to_date: usize,
+    failed: usize,
+    /// In the order they started.
+    actions: Vec<Action>,
+    by_label: HashMap<String, usize>,
+    /// Log messages of zack.
+    build_log: Vec<Vec<u8>>,
+    /// The action whose log is shown, or `None` for the build log.
+    selected: Option<usize>,
+    /// Show the log of the action that started last.
+    follow: bool,
+    /// How many lines the log is scrolled up from its end.
+    scroll: usize,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            total: 0,
+            queued: 0,
+            finished: 0,
+            up_to_date: 0,
+            failed: 0,
+            actions: Vec::new(),
+            by_label: HashMap::new(),
+            build_log: Vec::new(),
+            selected: None,
+            follow: true,
+            scroll: 0,
+        }
+    }
+}
+
+impl State {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Start(total) => self.total = total,
+            Event::Queued => self.queued += 1,
+            Event::Started(label) => {
+                self.queued = self.queued.saturating_sub(1);
+                self.by_label.insert(label.clone(), self.actions.len());
+                self.actions.push(Action {
+                    label,
+                    started: Instant::now(),
+                    finished: None,
+                    log: Vec::new(),
+                });
+                if self.follow {
+                    self.selected = Some(self.actions.len() - 1);
+                    self.scroll = 0;
+                }
+            }
+            Event::Output(label, line) => {
+                if let Some(&index) = self.by_label.get(&label) {
+                    self.actions[index].log.push(line);
+                }
+            }
+            Event::Finished(label, outcome) => {
+                self.finished += 1;
+                match outcome {
+                    Outcome::UpToDate | Outcome::Restored => self.up_to_date += 1,
+                    Outcome::Failed => self.failed += 1,
+                    Outcome::Succeeded | Outcome::Skipped => {}
+                }
+                if let Some(&index) = self.by_label.get(&label) {
+                    let action = &mut self.actions[index];
+                    action.finished = Some((action.started.elapsed(), outcome));
+                }
+            }
+            Event::Log(line) => self.build_log.push(line),
+            Event::Done => {}
+        }
+    }
+
+    fn key(&mut self, code: KeyCode) {
+        let entries = self.actions.len() + 1;
+        // The build log comes first, then the actions.
+        let entry = self.selected.map_or(0, |index| index + 1);
+        let select = |entry: usize| entry.checked_sub(1);
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => self.scroll += 1,
+            KeyCode::Down | KeyCode::Char('j') => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll += PAGE,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            KeyCode::Left | KeyCode::BackTab => {
+                self.selected = select((entry + entries - 1) % entries);
+                self.follow = false;
+                self.scroll = 0;
+            }
+            KeyCode::Right | KeyCode::Tab => {
+                self.selected = select((entry + 1) % entries);
+                self.follow = false;
+                self.scroll = 0;
+            }
+            KeyCode::End | KeyCode::Char('f') => {
+                self.follow = true;
+                self.selected = self.actions.len().checked_sub(1);
+                self.scroll = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// The share of commands that were up to date or restored, among those that did not
+    /// depend on a failure.
+    fn cache_hit_rate(&self) -> Option<f64> {
+        let ran = self.actions.iter().filter(|a| a.finished.is_some()).count();
+        let checked = ran + self.up_to_date;
+        (checked > 0).then(|| self.up_to_date as f64 / checked as f64)
+    }
+
+    fn draw(&self, frame: &mut Frame, now: Instant) {
+        let running = self.actions.iter().filter(|a| a.finished.is_none()).count();
+        let listed = self.actions.len().clamp(1, LISTED_ACTIONS) as u16;
+        let [header, list, log] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(listed + 2),
+            Constraint::Min(3),
+        ])
+        .areas(frame.area());
+
+        let hit_rate = self
+            .cache_hit_rate()
+            .map_or("-".to_string(), |rate| format!("{:.0}%", rate * 100.0));
+        let summary = format!(
+            "{}/{} finished · {running} running · {} queued · {} failed · cache hits {hit_rate}",
+            self.finished, self.total, self.queued, self.failed
+        );
+        let ratio = if self.total == 0 {
+            0.0
+        } else {
+            self.finished as f64 / self.total as f64
+        };
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(" zack build "))
+                .gauge_style(Style::new().fg(if self.failed > 0 {
+                    Color::Red
+                } else {
+                    Color::Green
+                }))
+                .ratio(ratio.min(1.0))
+                .label(summary),
+            header,
+        );
+
+        self.draw_actions(frame, list, now);
+        self.draw_log(frame, log);
+    }
+
+    /// Running actions first, then the ones that finished last.
+    fn draw_actions(&self, frame: &mut Frame, area: Rect, now: Instant) {
+        let mut listed: Vec<usize> = (0..self.actions.len())
+            .filter(|&i| self.actions[i].finished.is_none())
+            .collect();
+        listed.extend(
+            (0..self.actions.len())
+                .rev()
+                .filter(|&i| self.actions[i].finished.is_some()),
+        );
+        listed.truncate(LISTED_ACTIONS);
+
+        let items: Vec<ListItem> = listed
+            .iter()
+            .map(|&index| {
+                let action = &self.actions[index];
+                let (elapsed, status, color) = match action.finished {
+                    None => (now - action.started, "running", Color::Yellow),
+                    Some((elapsed, Outcome::Failed)) => (elapsed, "failed", Color::Red),
+                    Some((elapsed, _)) => (elapsed, "done", Color::Green),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{:>8.1?}  ", elapsed)),
+                    Span::styled(format!("{status:<8}"), Style::new().fg(color)),
+                    Span::raw(action.label.clone()),
+                ]))
+            })
+            .collect();
+        let mut list_state = ListState::default()
+            .with_selected(listed.iter().position(|&i| Some(i) == self.selected));
+        frame.render_stateful_widget(
+            List::new(items)
+                .block(Block::bordered().title(" actions (←/→ select) "))
+                .highlight_style(Style::new().reversed()),
+            area,
+            &mut list_state,
+        );
+    }
+
+    fn draw_log(&self, frame: &mut Frame, area: Rect) {
+        let (title, lines) = match self.selected.and_then(|i| self.actions.get(i)) {
+            Some(action) => (action.label.as_str(), &action.log),
+            None => ("zack", &self.build_log),
+        };
+        let height = area.height.saturating_sub(2) as usize;
+        let end = lines.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(height);
+        let text: Vec<Line> = lines[start..end].iter().flat_map(render_line).collect();
+        let follow = if self.follow { ", following" } else { "" };
+        frame.render_widget(
+            Paragraph::new(Text::from(text)).block(
+                Block::bordered().title(format!(" {title} (↑/↓ scroll, End follow{follow}) ")),
+            ),
+            area,
+        );
+    }
+}
+
+/// Renders the ANSI colors in a line of output.
+fn render_line(line: &Vec<u8>) -> Vec<Line<'static>> {
+    match line.into_text() {
+        Ok(text) => text.lines,
+        Err(_) => vec![Line::raw(String::from_utf8_lossy(line).into_owned())],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use super::*;
+
+    fn screen(state: &State, now: Instant) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| state.draw(frame, now)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let area = buffer.area;
+        (0..area.height)
+            .map(|y| {
+                (0..area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn shows_running_actions_and_their_colored_output() {
+        let mut state = State::default();
+        state.apply(Event::Start(4));
+        state.apply(Event::Finished("//pkg:a".into(), Outcome::UpToDate));
+        state.apply(Event::Queued);
+        state.apply(Event::Queued);
+        state.apply(Event::Queued);
+        state.apply(Event::Started("//pkg:b".into()));
+        state.apply(Event::Output(
+            "//pkg:b".into(),
+            b"\x1b[31merror:\x1b[0m expected ';'".to_vec(),
+        ));
+        state.apply(Event::Finished("//pkg:b".into(), Outcome::Failed));
+        state.apply(Event::Started("//pkg:c".into()));
+        state.apply(Event::Output("//pkg:c".into(), b"compiling".to_vec()));
+
+        let shown = screen(&state, Instant::now());
+        assert!(shown.contains("2/4 finished · 1 running · 1 queued · 1 failed · cache hits 50%"));
+        assert!(shown.contains("running //pkg:c"));
+        assert!(shown.contains("failed  //pkg:b"));
+        // Following the action that started last.
+        assert!(shown.contains("compiling"));
+
+        state.key(KeyCode::Left);
+        let shown = screen(&state, Instant::now());
+        assert!(shown.contains("error: expected ';'"));
+        assert!(!shown.contains("\x1b"));
+    }
+
+    #[test]
+    fn log_messages_are_sent_line_by_line() {
+        let (events, receiver) = mpsc::channel();
+        let log = TuiLog { events };
+        let mut writer = log.make_writer();
+        writer.write_all(b"first\nsecond\n").unwrap();
+        drop(writer);
+
+        let lines: Vec<Vec<u8>> = receiver
+            .try_iter()
+            .map(|event| match event {
+                Event::Log(line) => line,
+                _ => panic!("not a log line"),
+            })
+            .collect();
+        assert_eq!(lines, [b"first".to_vec(), b"second".to_vec()]);
+    }
+}
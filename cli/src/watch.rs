@@ -0,0 +1,126 @@
+//! `zack watch`: the inner dev loop.
+//!
+//! Keeps the source index up to date, re-evaluates packages whose build file or
+//! globbed files changed and rebuilds the commands whose inputs changed.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use exec::Label;
+use tracing::{error, info};
+use zisch::build_config::BuildConfigsDAO;
+use zisch::db::Db;
+use zisch::import::SourceFilesDAO;
+use zisch::watch::{Debounce, Watcher};
+
+use crate::build::{Builder, Workspace};
+
+pub async fn watch(
+    config: &str,
+    remote_cache: Option<&str>,
+    instrument: bool,
+    targets: &[Label],
+) -> Result<()> {
+    let root = directories::workspace_dir();
+    let excluded = directories::target_dir();
+
+    let mut db = Db::new().await?;
+    // Start watching before the initial scan so that no change is missed.
+    let mut watcher = Watcher::new(root, excluded)?;
+    db.import_source_tree(root, excluded).await?;
+
+    let mut workspace = Workspace::load(root, excluded, config)?;
+    let build_config = db.ensure_build_config(config).await?;
+    let mut builder = Builder::new(
+        build_config,
+        root,
+        directories::config_build_dir(config),
+        crate::cas::open(remote_cache),
+    )
+    .with_instrumentation(instrument);
+    build(&mut db, &workspace, &mut builder, targets).await;
+
+    loop {
+        // Waiting for events blocks, so it must not hold up the runtime.
+        let (returned, changes) = tokio::task::spawn_blocking(move || {
+            let changes = watcher.next_changes(Debounce::default());
+            (watcher, changes)
+        })
+        .await?;
+        watcher = returned;
+        let changes = changes?;
+        let changed = if changes.rescan {
+            db.import_source_tree(root, excluded).await?
+        } else {
+            db.update_source_files(root, &changes.paths).await?
+        };
+        if changed.is_empty() {
+            continue;
+        }
+        info!("{} files changed", changed.len());
+
+        if changed.contains(Utf8Path::new(loader::WORKSPACE_FILE_NAME)) {
+            // The settings of the config might have changed.
+            match Workspace::load(root, excluded, config) {
+                Ok(reloaded) => workspace = reloaded,
+                Err(e) => error!("{e:?}"),
+            }
+        } else {
+            let mut packages = affected_packages(&changed);
+            packages.extend(workspace.glob_affected_packages(&changed));
+            for package in packages {
+                if let Err(e) = workspace.reevaluate(&package) {
+                    error!("{e:?}");
+                }
+            }
+        }
+        build(&mut db, &workspace, &mut builder, targets).await;
+    }
+}
+
+/// Builds and reports errors without giving up on watching.
+async fn build(db: &mut Db, workspace: &Workspace, builder: &mut Builder, targets: &[Label]) {
+    let result = async {
+        let graph = workspace.graph()?;
+        builder.build(db, &graph, targets).await
+    }
+    .await;
+    match result {
+        Ok(summary) if summary.failed.is_empty() => {
+            info!(
+                "build succeeded: {} ran, {} up to date, {} restored",
+                summary.ran, summary.up_to_date, summary.restored
+            )
+        }
+        Ok(summary) => error!("build failed: {:?}", summary.failed),
+        Err(e) => error!("{e:?}"),
+    }
+}
+
+/// Returns the packages whose build file changed.
+fn affected_packages(changed: &BTreeSet<Utf8PathBuf>) -> BTreeSet<Utf8PathBuf> {
+    changed
+        .iter()
+        .filter(|path| path.file_name() == Some(loader::BUILD_FILE_NAME))
+        .map(|path| path.parent().unwrap_or(Utf8Path::new("")).to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn affected_packages_are_build_file_dirs() {
+        let changed = BTreeSet::from([
+            Utf8PathBuf::from("ZACK.star"),
+            Utf8PathBuf::from("examples/ZACK.star"),
+            Utf8PathBuf::from("examples/main.c"),
+        ]);
+        assert_eq!(
+            affected_packages(&changed),
+            BTreeSet::from([Utf8PathBuf::from(""), Utf8PathBuf::from("examples")])
+        );
+    }
+}
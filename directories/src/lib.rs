@@ -41,6 +41,14 @@ pub fn build_dir() -> &'static Utf8Path {
     paths().build.as_path()
 }
 
+/// The output directory of the build config called `name`.
+///
+/// Each build config gets its own directory so that switching configs
+/// does not overwrite the outputs of another one.
+pub fn config_build_dir(name: &str) -> Utf8PathBuf {
+    build_dir().join(name)
+}
+
 pub fn exec_directories() -> &'static Utf8Path {
     paths().exec.as_path()
 }
@@ -49,6 +57,11 @@ pub fn db() -> &'static Utf8Path {
     paths().db.as_path()
 }
 
+/// The content-addressed blob store.
+pub fn cas_dir() -> &'static Utf8Path {
+    paths().cas.as_path()
+}
+
 #[derive(Debug)]
 struct WorkspacePaths {
     workspace: Utf8PathBuf,
@@ -57,6 +70,7 @@ struct WorkspacePaths {
     build: Utf8PathBuf,
     exec: Utf8PathBuf,
     db: Utf8PathBuf,
+    cas: Utf8PathBuf,
 }
 
 fn paths() -> &'static WorkspacePaths {
@@ -80,6 +94,8 @@ fn paths() -> &'static WorkspacePaths {
         let exec = target.join("exec");
         std::fs::create_dir_all(&exec).unwrap();
         let db = target.join("db.sqlite");
+        let cas = target.join("cas");
+        std::fs::create_dir_all(&cas).unwrap();
         WorkspacePaths {
             workspace: root,
             target,
@@ -87,6 +103,7 @@ fn paths() -> &'static WorkspacePaths {
             build,
             exec,
             db,
+            cas,
         }
     })
 }
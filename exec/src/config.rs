@@ -0,0 +1,151 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use starlark::any::ProvidesStaticType;
+use starlark::environment::GlobalsBuilder;
+use starlark::eval::Evaluator;
+use starlark::starlark_module;
+use starlark::values::dict::UnpackDictEntries;
+use starlark::values::none::NoneType;
+
+/// The name of the build config that is used if no other is selected.
+pub const DEFAULT_CONFIG_NAME: &str = "default";
+
+/// A named set of settings declared with `build_config(...)` in `ZACK_WORKSPACE.star`.
+///
+/// The settings of the selected config are visible to rules via `config_setting(...)`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    pub name: String,
+    pub settings: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: DEFAULT_CONFIG_NAME.to_string(),
+            settings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Returns the config called `name`.
+///
+/// The default config exists even if the workspace does not declare it.
+pub fn select_config(configs: &[Config], name: &str) -> anyhow::Result<Config> {
+    match configs.iter().find(|c| c.name == name) {
+        Some(config) => Ok(config.clone()),
+        None if name == DEFAULT_CONFIG_NAME => Ok(Config::default()),
+        None => Err(anyhow!(
+            "unknown build config {name:?}, ZACK_WORKSPACE.star declares: {:?}",
+            configs.iter().map(|c| &c.name).collect::<Vec<_>>()
+        )),
+    }
+}
+
+/// Collects the configs declared while evaluating `ZACK_WORKSPACE.star`.
+///
+/// Passed to the starlark evaluator as `extra`.
+#[derive(Debug, Default, ProvidesStaticType)]
+pub struct WorkspaceContext {
+    configs: RefCell<Vec<Config>>,
+}
+
+impl WorkspaceContext {
+    pub fn into_configs(self) -> Vec<Config> {
+        self.configs.into_inner()
+    }
+}
+
+/// Config names are used as directory names below the build directory.
+pub fn valid_config_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+#[starlark_module]
+pub fn workspace_globals(builder: &mut GlobalsBuilder) {
+    /// Declares a build config that can be selected with `zack build --config=NAME`.
+    fn build_config<'v>(
+        #[starlark(require = pos)] name: String,
+        #[starlark(require = named, default = UnpackDictEntries::default())]
+        settings: UnpackDictEntries<String, String>,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> anyhow::Result<NoneType> {
+        let context = eval
+            .extra
+            .and_then(|extra| extra.downcast_ref::<WorkspaceContext>())
+            .ok_or_else(|| anyhow!("build_config() can only be called in ZACK_WORKSPACE.star"))?;
+
+        if !valid_config_name(&name) {
+            return Err(anyhow!(
+                "invalid build config name {name:?}, use letters, digits, '_', '-' and '.'"
+            ));
+        }
+        let mut configs = context.configs.borrow_mut();
+        if configs.iter().any(|c| c.name == name) {
+            return Err(anyhow!("build config {name:?} is declared more than once"));
+        }
+        configs.push(Config {
+            name,
+            settings: settings.entries.into_iter().collect(),
+        });
+        Ok(NoneType)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starlark::environment::Module;
+    use starlark::syntax::{AstModule, Dialect};
+
+    use super::*;
+
+    fn evaluate(code: &str) -> Result<Vec<Config>, starlark::Error> {
+        let globals = GlobalsBuilder::standard().with(workspace_globals).build();
+        let ast = AstModule::parse("ZACK_WORKSPACE.star", code.to_owned(), &Dialect::Standard)?;
+        let module = Module::new();
+        let context = WorkspaceContext::default();
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.extra = Some(&context);
+            eval.eval_module(ast, &globals)?;
+        }
+        Ok(context.into_configs())
+    }
+
+    #[test]
+    fn build_config_declares_config() {
+        let configs = evaluate(r#"build_config("release", settings = {"opt": "3"})"#).unwrap();
+        assert_eq!(
+            configs,
+            vec![Config {
+                name: "release".into(),
+                settings: BTreeMap::from([("opt".into(), "3".into())]),
+            }]
+        );
+        assert_eq!(select_config(&configs, "release").unwrap(), configs[0]);
+        assert_eq!(
+            select_config(&configs, "default").unwrap(),
+            Config::default()
+        );
+        assert!(select_config(&configs, "debug").is_err());
+    }
+
+    #[test]
+    fn build_config_rejects_invalid_names() {
+        assert!(evaluate(r#"build_config("../release")"#).is_err());
+        assert!(evaluate(r#"build_config("")"#).is_err());
+    }
+
+    #[test]
+    fn build_config_rejects_duplicates() {
+        assert!(evaluate("build_config(\"a\")\nbuild_config(\"a\")").is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::key::{InputHash, KeyComponents, KeyInput};
+use crate::{Command, Label};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GraphError {
+    #[error("{path} is an output of both {a} and {b}")]
+    DuplicateOutput {
+        path: Utf8PathBuf,
+        a: Label,
+        b: Label,
+    },
+    #[error("{0} is declared more than once")]
+    DuplicateLabel(Label),
+    #[error("{0} depends on itself")]
+    Cycle(Label),
+}
+
+/// Identifies the inputs of a command: its declaration and the content of its inputs.
+///
+/// If the key of a command did not change, it does not need to run again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ActionKey(blake3::Hash);
+
+impl fmt::Display for ActionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<blake3::Hash> for ActionKey {
+    fn from(hash: blake3::Hash) -> Self {
+        ActionKey(hash)
+    }
+}
+
+impl From<ActionKey> for blake3::Hash {
+    fn from(key: ActionKey) -> Self {
+        key.0
+    }
+}
+
+impl From<ActionKey> for String {
+    fn from(key: ActionKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for ActionKey {
+    type Error = blake3::HexError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        blake3::Hash::from_hex(value).map(ActionKey)
+    }
+}
+
+/// The commands of all evaluated packages, connected through their inputs and outputs.
+#[derive(Debug, Clone, Default)]
+pub struct ActionGraph {
+    commands: Vec<Command>,
+    by_label: BTreeMap<Label, usize>,
+    producers: BTreeMap<Utf8PathBuf, usize>,
+}
+
+impl ActionGraph {
+    pub fn new(commands: Vec<Command>) -> Result<ActionGraph, GraphError> {
+        let mut by_label = BTreeMap::new();
+        let mut producers = BTreeMap::new();
+        for (index, command) in commands.iter().enumerate() {
+            if by_label.insert(command.label.clone(), index).is_some() {
+                return Err(GraphError::DuplicateLabel(command.label.clone()));
+            }
+            for out in &command.outs {
+                if let Some(other) = producers.insert(out.clone(), index) {
+                    return Err(GraphError::DuplicateOutput {
+                        path: out.clone(),
+                        a: commands[other].label.clone(),
+                        b: command.label.clone(),
+                    });
+                }
+            }
+        }
+
+        let graph = ActionGraph {
+            commands,
+            by_label,
+            producers,
+        };
+        graph.topo_order(0..graph.commands.len())?;
+        Ok(graph)
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn find(&self, label: &Label) -> Option<usize> {
+        self.by_label.get(label).copied()
+    }
+
+    /// Returns the command producing `path`, if it is not a source file.
+    pub fn producer(&self, path: &Utf8Path) -> Option<usize> {
+        self.producers.get(path).copied()
+    }
+
+    /// Returns the commands producing the inputs of the command at `index`.
+    pub fn deps(&self, index: usize) -> BTreeSet<usize> {
+        self.commands[index]
+            .srcs
+            .iter()
+            .filter_map(|src| self.producer(src))
+            .collect()
+    }
+
+    /// Returns `roots` and all commands they depend on, directly or indirectly.
+    pub fn transitive_deps(&self, roots: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut seen = BTreeSet::new();
+        let mut todo: Vec<usize> = roots.into_iter().collect();
+        while let Some(index) = todo.pop() {
+            if seen.insert(index) {
+                todo.extend(self.deps(index));
+            }
+        }
+        seen
+    }
+
+    /// Returns the commands using outputs of the command at `index`.
+    pub fn rdeps(&self, index: usize) -> BTreeSet<usize> {
+        (0..self.commands.len())
+            .filter(|&other| self.deps(other).contains(&index))
+            .collect()
+    }
+
+    /// Returns `roots` and all commands depending on them, directly or indirectly.
+    pub fn transitive_rdeps(&self, roots: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut rdeps: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for index in 0..self.commands.len() {
+            for dep in self.deps(index) {
+                rdeps.entry(dep).or_default().push(index);
+            }
+        }
+        let mut seen = BTreeSet::new();
+        let mut todo: Vec<usize> = roots.into_iter().collect();
+        while let Some(index) = todo.pop() {
+            if seen.insert(index) {
+                todo.extend(rdeps.get(&index).into_iter().flatten());
+            }
+        }
+        seen
+    }
+
+    /// Orders `indices` so that every command comes after the commands it depends on.
+    pub fn topo_order(
+        &self,
+        indices: impl IntoIterator<Item = usize>,
+    ) -> Result<Vec<usize>, GraphError> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            Visiting,
+            Done,
+        }
+
+        fn visit(
+            graph: &ActionGraph,
+            index: usize,
+            marks: &mut BTreeMap<usize, Mark>,
+            order: &mut Vec<usize>,
+        ) -> Result<(), GraphError> {
+            match marks.get(&index) {
+                Some(Mark::Done) => return Ok(()),
+                Some(Mark::Visiting) => {
+                    return Err(GraphError::Cycle(graph.commands[index].label.clone()));
+                }
+                None => {}
+            }
+            marks.insert(index, Mark::Visiting);
+            for dep in graph.deps(index) {
+                visit(graph, dep, marks, order)?;
+            }
+            marks.insert(index, Mark::Done);
+            order.push(index);
+            Ok(())
+        }
+
+        let indices: BTreeSet<usize> = indices.into_iter().collect();
+        let mut marks = BTreeMap::new();
+        let mut order = Vec::new();
+        for &index in &indices {
+            visit(self, index, &mut marks, &mut order)?;
+        }
+        order.retain(|i| indices.contains(i));
+        Ok(order)
+    }
+
+    /// Computes the action keys of all commands.
+    ///
+    /// `source_hash` returns the content hash of a source file,
+    /// or `None` if it does not exist.
+    ///
+    /// `used_srcs` narrows the keys to the inputs commands actually used when they last ran,
+    /// e.g. as recorded by instrumentation. Declared inputs that were not used only
+    /// contribute their path, so changing their content does not change the key.
+    pub fn action_keys(
+        &self,
+        source_hash: impl FnMut(&Utf8Path) -> Option<[u8; 32]>,
+        used_srcs: &BTreeMap<Label, BTreeSet<Utf8PathBuf>>,
+    ) -> Vec<ActionKey> {
+        self.key_components(source_hash, used_srcs)
+            .iter()
+            .map(KeyComponents::key)
+            .collect()
+    }
+
+    /// Returns what the action keys of all commands are derived from,
+    /// see [`ActionGraph::action_keys`].
+    pub fn key_components(
+        &self,
+        mut source_hash: impl FnMut(&Utf8Path) -> Option<[u8; 32]>,
+        used_srcs: &BTreeMap<Label, BTreeSet<Utf8PathBuf>>,
+    ) -> Vec<KeyComponents> {
+        let order = self
+            .topo_order(0..self.commands.len())
+            .expect("checked for cycles in new");
+        let mut keyed: Vec<Option<(KeyComponents, ActionKey)>> = vec![None; self.commands.len()];
+        for index in order {
+            let command = &self.commands[index];
+            let used = used_srcs.get(&command.label);
+            let inputs = command
+                .srcs
+                .iter()
+                .map(|src| {
+                    let hash = if used.is_some_and(|used| !used.contains(src)) {
+                        InputHash::Unused
+                    } else if let Some(producer) = self.producer(src) {
+                        let (_, key) = keyed[producer]
+                            .as_ref()
+                            .expect("dependencies are keyed first");
+                        InputHash::Generated {
+                            producer: self.commands[producer].label.clone(),
+                            key: *key,
+                        }
+                    } else {
+                        match source_hash(src) {
+                            // Only for the hex encoding.
+                            Some(hash) => InputHash::Source(
+                                blake3::Hash::from_bytes(hash).to_hex().to_string(),
+                            ),
+                            None => InputHash::Missing,
+                        }
+                    };
+                    KeyInput {
+                        path: src.clone(),
+                        hash,
+                    }
+                })
+                .collect();
+            let components = KeyComponents {
+                label: command.label.clone(),
+                args: command.args.clone(),
+                env: command.env.clone(),
+                outs: command.outs.clone(),
+                inputs,
+            };
+            let key = components.key();
+            keyed[index] = Some((components, key));
+        }
+        keyed.into_iter().map(|k| k.expect("all keyed").0).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(name: &str, srcs: &[&str], outs: &[&str]) -> Command {
+        Command {
+            label: Label::new("pkg", name),
+            args: vec!["true".into()],
+            env: BTreeMap::new(),
+            srcs: srcs.iter().map(Utf8PathBuf::from).collect(),
+            outs: outs.iter().map(Utf8PathBuf::from).collect(),
+        }
+    }
+
+    fn chain() -> ActionGraph {
+        ActionGraph::new(vec![
+            command("link", &["pkg/main.o"], &["pkg/main"]),
+            command("compile", &["pkg/main.c"], &["pkg/main.o"]),
+            command("other", &["pkg/other.c"], &["pkg/other.o"]),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn topo_order_puts_dependencies_first() {
+        let graph = chain();
+        assert_eq!(graph.topo_order([0, 1]).unwrap(), vec![1, 0]);
+        assert_eq!(graph.transitive_deps([0]), BTreeSet::from([0, 1]));
+    }
+
+    #[test]
+    fn rdeps_follow_outputs_to_their_users() {
+        let graph = chain();
+        assert_eq!(graph.rdeps(1), BTreeSet::from([0]));
+        assert_eq!(graph.rdeps(0), BTreeSet::new());
+        assert_eq!(graph.transitive_rdeps([1]), BTreeSet::from([0, 1]));
+        assert_eq!(graph.transitive_rdeps([2]), BTreeSet::from([2]));
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let result = ActionGraph::new(vec![
+            command("a", &["pkg/b.out"], &["pkg/a.out"]),
+            command("b", &["pkg/a.out"], &["pkg/b.out"]),
+        ]);
+        assert!(matches!(result, Err(GraphError::Cycle(_))));
+    }
+
+    #[test]
+    fn duplicate_outputs_are_rejected() {
+        let result = ActionGraph::new(vec![
+            command("a", &[], &["pkg/out"]),
+            command("b", &[], &["pkg/out"]),
+        ]);
+        assert_eq!(
+            result.unwrap_err(),
+            GraphError::DuplicateOutput {
+                path: "pkg/out".into(),
+                a: Label::new("pkg", "a"),
+                b: Label::new("pkg", "b"),
+            }
+        );
+    }
+
+    #[test]
+    fn source_changes_propagate_to_dependents() {
+        let graph = chain();
+        let before = graph.action_keys(|_| Some([1; 32]), &BTreeMap::new());
+        let after = graph.action_keys(
+            |src| {
+                if src == "pkg/main.c" {
+                    Some([2; 32])
+                } else {
+                    Some([1; 32])
+                }
+            },
+            &BTreeMap::new(),
+        );
+
+        assert_ne!(before[0], after[0]);
+        assert_ne!(before[1], after[1]);
+        assert_eq!(before[2], after[2]);
+    }
+
+    #[test]
+    fn unused_sources_do_not_change_narrowed_keys() {
+        let graph = ActionGraph::new(vec![command(
+            "compile",
+            &["pkg/main.c", "pkg/unused.h"],
+            &["pkg/main.o"],
+        )])
+        .unwrap();
+        fn hashes(changed: &'static str) -> impl FnMut(&Utf8Path) -> Option<[u8; 32]> {
+            move |src| Some(if src == changed { [2; 32] } else { [1; 32] })
+        }
+        let used = BTreeMap::from([(
+            Label::new("pkg", "compile"),
+            BTreeSet::from([Utf8PathBuf::from("pkg/main.c")]),
+        )]);
+
+        let unchanged = graph.action_keys(hashes(""), &used);
+        assert_eq!(graph.action_keys(hashes("pkg/unused.h"), &used), unchanged);
+        assert_ne!(graph.action_keys(hashes("pkg/main.c"), &used), unchanged);
+        // Without a record, every input counts.
+        assert_ne!(
+            graph.action_keys(hashes("pkg/unused.h"), &BTreeMap::new()),
+            graph.action_keys(hashes(""), &BTreeMap::new())
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! What action keys are derived from, to explain why a command has to run again.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::Label;
+use crate::graph::ActionKey;
+
+/// What a declared input contributes to an action key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputHash {
+    /// A source file, by the hex-encoded hash of its content.
+    Source(String),
+    /// An output of another command, by the key of that command.
+    Generated { producer: Label, key: ActionKey },
+    /// A source file that does not exist.
+    Missing,
+    /// Not used when the command last ran, so only its path counts.
+    Unused,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyInput {
+    pub path: Utf8PathBuf,
+    pub hash: InputHash,
+}
+
+/// Everything the action key of a command is derived from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyComponents {
+    pub label: Label,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub outs: Vec<Utf8PathBuf>,
+    /// The declared inputs, in declaration order.
+    pub inputs: Vec<KeyInput>,
+}
+
+impl KeyComponents {
+    pub fn key(&self) -> ActionKey {
+        let mut hasher = blake3::Hasher::new();
+        serde_json::to_writer(&mut hasher, self).expect("serializing to hasher");
+        ActionKey::from(hasher.finalize())
+    }
+
+    /// Returns the inputs that were used, if the key was narrowed to them.
+    /// `None` if all declared inputs count.
+    pub fn used_srcs(&self) -> Option<BTreeSet<Utf8PathBuf>> {
+        if !self
+            .inputs
+            .iter()
+            .any(|input| input.hash == InputHash::Unused)
+        {
+            return None;
+        }
+        Some(
+            self.inputs
+                .iter()
+                .filter(|input| input.hash != InputHash::Unused)
+                .map(|input| input.path.clone())
+                .collect(),
+        )
+    }
+
+    /// Returns how the components changed since `previous`, those of an earlier run
+    /// of the same command. Empty if the key did not change.
+    pub fn changes_since(&self, previous: &KeyComponents) -> Vec<KeyChange> {
+        let mut changes = Vec::new();
+
+        for index in 0..self.args.len().max(previous.args.len()) {
+            let (previous, current) = (previous.args.get(index), self.args.get(index));
+            if previous != current {
+                changes.push(KeyChange::Arg {
+                    index,
+                    previous: previous.cloned(),
+                    current: current.cloned(),
+                });
+            }
+        }
+
+        let names: BTreeSet<&String> = self.env.keys().chain(previous.env.keys()).collect();
+        for name in names {
+            let (previous, current) = (previous.env.get(name), self.env.get(name));
+            if previous != current {
+                changes.push(KeyChange::Env {
+                    name: name.clone(),
+                    previous: previous.cloned(),
+                    current: current.cloned(),
+                });
+            }
+        }
+
+        if self.outs != previous.outs {
+            changes.push(KeyChange::Outs {
+                previous: previous.outs.clone(),
+                current: self.outs.clone(),
+            });
+        }
+
+        let inputs = |components: &KeyComponents| {
+            let mut inputs = BTreeMap::new();
+            for input in &components.inputs {
+                inputs
+                    .entry(input.path.clone())
+                    .or_insert(input.hash.clone());
+            }
+            inputs
+        };
+        let (previous_inputs, current_inputs) = (inputs(previous), inputs(self));
+        let paths: BTreeSet<&Utf8PathBuf> = current_inputs
+            .keys()
+            .chain(previous_inputs.keys())
+            .collect();
+        for path in paths {
+            let (previous, current) = (previous_inputs.get(path), current_inputs.get(path));
+            if previous != current {
+                changes.push(KeyChange::Input {
+                    path: path.clone(),
+                    previous: previous.cloned(),
+                    current: current.cloned(),
+                });
+            }
+        }
+        if previous_inputs == current_inputs && self.inputs != previous.inputs {
+            changes.push(KeyChange::InputOrder);
+        }
+
+        changes
+    }
+}
+
+/// One difference between the key components of two runs of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyChange {
+    Arg {
+        index: usize,
+        previous: Option<String>,
+        current: Option<String>,
+    },
+    Env {
+        name: String,
+        previous: Option<String>,
+        current: Option<String>,
+    },
+    Outs {
+        previous: Vec<Utf8PathBuf>,
+        current: Vec<Utf8PathBuf>,
+    },
+    Input {
+        path: Utf8PathBuf,
+        previous: Option<InputHash>,
+        current: Option<InputHash>,
+    },
+    /// The same inputs are declared in a different order.
+    InputOrder,
+}
+
+impl fmt::Display for KeyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyChange::Arg {
+                index,
+                previous,
+                current,
+            } => match (previous, current) {
+                (Some(previous), Some(current)) => {
+                    write!(
+                        f,
+                        "argument {index} changed from {previous:?} to {current:?}"
+                    )
+                }
+                (None, Some(current)) => write!(f, "argument {index} {current:?} was added"),
+                (Some(previous), None) => write!(f, "argument {index} {previous:?} was removed"),
+                (None, None) => write!(f, "argument {index} changed"),
+            },
+            KeyChange::Env {
+                name,
+                previous,
+                current,
+            } => match (previous, current) {
+                (Some(previous), Some(current)) => write!(
+                    f,
+                    "environment variable {name} changed from {previous:?} to {current:?}"
+                ),
+                (None, Some(current)) => {
+                    write!(f, "environment variable {name} was set to {current:?}")
+                }
+                (Some(previous), None) => {
+                    write!(
+                        f,
+                        "environment variable {name} was unset, it was {previous:?}"
+                    )
+                }
+                (None, None) => write!(f, "environment variable {name} changed"),
+            },
+            KeyChange::Outs { previous, current } => {
+                write!(f, "outputs changed from {previous:?} to {current:?}")
+            }
+            KeyChange::Input {
+                path,
+                previous,
+                current,
+            } => match (previous, current) {
+                (None, _) => write!(f, "input {path} was added"),
+                (_, None) => write!(f, "input {path} was removed"),
+                (_, Some(InputHash::Unused)) => write!(f, "input {path} is no longer used"),
+                (Some(InputHash::Unused), _) => write!(f, "input {path} is used again"),
+                (_, Some(InputHash::Missing)) => {
+                    write!(f, "source file {path} no longer exists")
+                }
+                (Some(InputHash::Missing), _) => write!(f, "source file {path} was created"),
+                (Some(InputHash::Source(_)), Some(InputHash::Source(_))) => {
+                    write!(f, "source file {path} changed")
+                }
+                (_, Some(InputHash::Generated { producer, .. })) => {
+                    write!(f, "input {path}, an output of {producer}, changed")
+                }
+                (Some(InputHash::Generated { producer, .. }), _) => {
+                    write!(
+                        f,
+                        "input {path} is a source file now, not an output of {producer}"
+                    )
+                }
+            },
+            KeyChange::InputOrder => write!(f, "the inputs were reordered"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components() -> KeyComponents {
+        KeyComponents {
+            label: Label::new("pkg", "main"),
+            args: vec!["gcc".into(), "-O2".into(), "main.c".into()],
+            env: BTreeMap::from([("CC".into(), "gcc".into())]),
+            outs: vec!["pkg/main".into()],
+            inputs: vec![
+                KeyInput {
+                    path: "pkg/main.c".into(),
+                    hash: InputHash::Source("aa".into()),
+                },
+                KeyInput {
+                    path: "pkg/lib.a".into(),
+                    hash: InputHash::Generated {
+                        producer: Label::new("pkg", "lib"),
+                        key: blake3::hash(b"lib").into(),
+                    },
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn unchanged_components_have_the_same_key() {
+        let previous = components();
+        let current = components();
+        assert_eq!(current.key(), previous.key());
+        assert_eq!(current.changes_since(&previous), vec![]);
+
+        let round_tripped: KeyComponents =
+            serde_json::from_str(&serde_json::to_string(&current).unwrap()).unwrap();
+        assert_eq!(round_tripped.key(), previous.key());
+    }
+
+    #[test]
+    fn changes_name_what_changed() {
+        let previous = components();
+        let mut current = components();
+        current.args[1] = "-O3".into();
+        current.env.remove("CC");
+        current.env.insert("CFLAGS".into(), "-g".into());
+        current.inputs[0].hash = InputHash::Source("bb".into());
+        current.inputs[1].hash = InputHash::Unused;
+
+        let changes: Vec<String> = current
+            .changes_since(&previous)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_ne!(current.key(), previous.key());
+        assert_eq!(previous.used_srcs(), None);
+        assert_eq!(
+            current.used_srcs(),
+            Some(BTreeSet::from(["pkg/main.c".into()]))
+        );
+        assert_eq!(
+            changes,
+            vec![
+                "argument 1 changed from \"-O2\" to \"-O3\"",
+                "environment variable CC was unset, it was \"gcc\"",
+                "environment variable CFLAGS was set to \"-g\"",
+                "input pkg/lib.a is no longer used",
+                "source file pkg/main.c changed",
+            ]
+        );
+    }
+
+    #[test]
+    fn reordered_inputs_are_reported() {
+        let previous = components();
+        let mut current = components();
+        current.inputs.reverse();
+
+        assert_ne!(current.key(), previous.key());
+        assert_eq!(
+            current.changes_since(&previous),
+            vec![KeyChange::InputOrder]
+        );
+    }
+}
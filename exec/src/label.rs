@@ -0,0 +1,209 @@
+use std::fmt;
+use std::str::FromStr;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Identifies a target as `//package/path:name`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Label {
+    /// The package directory relative to the workspace root.
+    pub package: Utf8PathBuf,
+    pub name: String,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LabelError {
+    #[error("Label must start with '//': {0:?}")]
+    NotAbsolute(String),
+    #[error("Label has no target name: {0:?}")]
+    MissingName(String),
+    #[error("Label package must not contain '.' or '..' components: {0:?}")]
+    InvalidPackage(String),
+}
+
+impl Label {
+    pub fn new(package: impl Into<Utf8PathBuf>, name: impl Into<String>) -> Self {
+        Label {
+            package: package.into(),
+            name: name.into(),
+        }
+    }
+
+    pub fn package(&self) -> &Utf8Path {
+        &self.package
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "//{}:{}", self.package, self.name)
+    }
+}
+
+impl FromStr for Label {
+    type Err = LabelError;
+
+    /// Parses `//pkg:name` or the shorthand `//pkg/name` for `//pkg/name:name`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("//")
+            .ok_or_else(|| LabelError::NotAbsolute(s.to_string()))?;
+        let (package, name) = match rest.split_once(':') {
+            Some((package, name)) => (package, name),
+            None => (rest, rest.rsplit('/').next().unwrap_or_default()),
+        };
+        if name.is_empty() {
+            return Err(LabelError::MissingName(s.to_string()));
+        }
+        let package = Utf8PathBuf::from(package.trim_end_matches('/'));
+        if package
+            .components()
+            .any(|c| !matches!(c, camino::Utf8Component::Normal(_)))
+        {
+            return Err(LabelError::InvalidPackage(s.to_string()));
+        }
+        Ok(Label::new(package, name))
+    }
+}
+
+impl From<Label> for String {
+    fn from(label: Label) -> Self {
+        label.to_string()
+    }
+}
+
+impl TryFrom<String> for Label {
+    type Error = LabelError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Selects targets: a single label, all targets of a package with `//pkg:all`,
+/// or all targets of a package and the packages below it with `//pkg/...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelPattern {
+    Label(Label),
+    Package(Utf8PathBuf),
+    Recursive(Utf8PathBuf),
+}
+
+impl LabelPattern {
+    pub fn matches(&self, label: &Label) -> bool {
+        match self {
+            LabelPattern::Label(pattern) => pattern == label,
+            LabelPattern::Package(package) => &label.package == package,
+            LabelPattern::Recursive(package) => label.package.starts_with(package),
+        }
+    }
+}
+
+impl fmt::Display for LabelPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelPattern::Label(label) => write!(f, "{label}"),
+            LabelPattern::Package(package) => write!(f, "//{package}:all"),
+            LabelPattern::Recursive(package) if package.as_str().is_empty() => write!(f, "//..."),
+            LabelPattern::Recursive(package) => write!(f, "//{package}/..."),
+        }
+    }
+}
+
+impl FromStr for LabelPattern {
+    type Err = LabelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("//")
+            .ok_or_else(|| LabelError::NotAbsolute(s.to_string()))?;
+        if rest == "..." {
+            return Ok(LabelPattern::Recursive(Utf8PathBuf::new()));
+        }
+        if let Some(package) = rest.strip_suffix("/...") {
+            // Parse a label in the package to validate it.
+            let label: Label = format!("//{package}:all")
+                .parse()
+                .map_err(|_| LabelError::InvalidPackage(s.to_string()))?;
+            return Ok(LabelPattern::Recursive(label.package));
+        }
+        let label: Label = s.parse()?;
+        if rest.ends_with(":all") {
+            Ok(LabelPattern::Package(label.package))
+        } else {
+            Ok(LabelPattern::Label(label))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_label() {
+        let label: Label = "//examples/simple_c:main".parse().unwrap();
+        assert_eq!(label, Label::new("examples/simple_c", "main"));
+        assert_eq!(label.to_string(), "//examples/simple_c:main");
+    }
+
+    #[test]
+    fn parse_shorthand_label() {
+        let label: Label = "//examples/simple_c".parse().unwrap();
+        assert_eq!(label, Label::new("examples/simple_c", "simple_c"));
+    }
+
+    #[test]
+    fn parse_root_package_label() {
+        let label: Label = "//:hello".parse().unwrap();
+        assert_eq!(label, Label::new("", "hello"));
+        assert_eq!(label.to_string(), "//:hello");
+    }
+
+    #[test]
+    fn parse_invalid_labels() {
+        assert_eq!(
+            "examples:main".parse::<Label>(),
+            Err(LabelError::NotAbsolute("examples:main".into()))
+        );
+        assert_eq!(
+            "//examples:".parse::<Label>(),
+            Err(LabelError::MissingName("//examples:".into()))
+        );
+        assert_eq!(
+            "//../x:y".parse::<Label>(),
+            Err(LabelError::InvalidPackage("//../x:y".into()))
+        );
+    }
+
+    #[test]
+    fn patterns_match_packages() {
+        let main = Label::new("examples/simple_c", "main");
+        let root = Label::new("", "hello");
+        let matching = |pattern: &str| {
+            let pattern: LabelPattern = pattern.parse().unwrap();
+            [&main, &root].map(|label| pattern.matches(label))
+        };
+
+        assert_eq!(matching("//examples/simple_c:main"), [true, false]);
+        assert_eq!(matching("//examples/simple_c:all"), [true, false]);
+        assert_eq!(matching("//examples/..."), [true, false]);
+        assert_eq!(matching("//examples/simple:all"), [false, false]);
+        assert_eq!(matching("//..."), [true, true]);
+        assert_eq!(
+            "//examples/..."
+                .parse::<LabelPattern>()
+                .unwrap()
+                .to_string(),
+            "//examples/..."
+        );
+        assert_eq!(
+            "//../...".parse::<LabelPattern>(),
+            Err(LabelError::InvalidPackage("//../...".into()))
+        );
+    }
+}
@@ -1,12 +1,395 @@
-use allocative::Allocative;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::anyhow;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
 use starlark::any::ProvidesStaticType;
+use starlark::environment::GlobalsBuilder;
+use starlark::eval::Evaluator;
+use starlark::starlark_module;
+use starlark::values::Value;
+use starlark::values::dict::UnpackDictEntries;
+use starlark::values::list::UnpackList;
+use starlark::values::none::NoneType;
+use starlark::values::tuple::UnpackTuple;
+use zopf::ArtifactSet;
+
+pub mod config;
+pub mod graph;
+pub mod key;
+mod label;
+
+pub use config::Config;
+pub use label::{Label, LabelError, LabelPattern};
 
-#[derive(Debug, ProvidesStaticType, Allocative)]
+/// The file declaring the targets of a package.
+pub const BUILD_FILE_NAME: &str = "ZACK.star";
+
+/// Collects the commands declared while evaluating the `ZACK.star` file of one package.
+///
+/// Passed to the starlark evaluator as `extra`.
+#[derive(Debug, ProvidesStaticType)]
 pub struct BuildContext {
+    /// The workspace root, which `glob()` searches below.
+    pub root: Utf8PathBuf,
+    /// The package directory relative to the workspace root.
+    pub package: Utf8PathBuf,
+    /// The build config the package is evaluated for.
+    pub config: Config,
+    /// Skipped by `glob()`, like by the source import.
+    pub excluded: Utf8PathBuf,
+    commands: RefCell<Vec<Command>>,
+    globs: RefCell<Vec<PackageGlob>>,
+}
+
+impl BuildContext {
+    pub fn new(
+        root: impl Into<Utf8PathBuf>,
+        package: impl Into<Utf8PathBuf>,
+        config: Config,
+    ) -> Self {
+        BuildContext {
+            root: root.into(),
+            package: package.into(),
+            config,
+            excluded: Utf8PathBuf::new(),
+            commands: RefCell::default(),
+            globs: RefCell::default(),
+        }
+    }
+
+    /// Makes `glob()` skip `excluded`, e.g. the target directory.
+    pub fn with_excluded(mut self, excluded: impl Into<Utf8PathBuf>) -> Self {
+        self.excluded = excluded.into();
+        self
+    }
+
+    pub fn into_commands(self) -> Vec<Command> {
+        self.commands.into_inner()
+    }
+
+    pub fn into_package(self) -> Package {
+        Package {
+            commands: self.commands.into_inner(),
+            globs: self.globs.into_inner(),
+        }
+    }
+}
+
+/// Everything evaluating the `ZACK.star` file of one package produced.
+#[derive(Debug, Clone, Default)]
+pub struct Package {
     pub commands: Vec<Command>,
+    /// The globs evaluated, to notice when the package has to be evaluated again.
+    pub globs: Vec<PackageGlob>,
 }
 
-#[derive(Debug, Allocative)]
+/// A `glob()` evaluated for a package, with the files it matched.
+#[derive(Debug, Clone)]
+pub struct PackageGlob {
+    /// The directory the patterns are relative to, relative to the workspace root.
+    pub package: Utf8PathBuf,
+    pub set: ArtifactSet,
+    /// The matched files, relative to the workspace root.
+    pub matched: BTreeSet<Utf8PathBuf>,
+}
+
+impl PackageGlob {
+    /// Whether the glob would match something else after a change to the
+    /// workspace-relative `path`, which is a file now if `exists`.
+    pub fn is_affected_by(&self, path: &Utf8Path, exists: bool) -> bool {
+        let Ok(rel_path) = path.strip_prefix(&self.package) else {
+            return false;
+        };
+        let matches = exists && self.set.matches(rel_path);
+        matches != self.matched.contains(path)
+    }
+}
+
+/// A command declared with `cmd(...)` in a `ZACK.star` file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Command {
+    pub label: Label,
     pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// Inputs relative to the workspace root.
+    /// These are either source files or outputs of other commands.
+    pub srcs: Vec<Utf8PathBuf>,
+    /// Outputs relative to the build directory.
+    pub outs: Vec<Utf8PathBuf>,
+}
+
+/// Resolves a path given relative to `package`, rejecting anything that escapes it.
+fn package_path(package: &Utf8Path, path: &str) -> anyhow::Result<Utf8PathBuf> {
+    let path = Utf8Path::new(path);
+    if !path
+        .components()
+        .all(|c| matches!(c, Utf8Component::Normal(_) | Utf8Component::CurDir))
+    {
+        return Err(anyhow!(
+            "paths must be relative to the package and must not contain '..': {path:?}"
+        ));
+    }
+    Ok(package.join(
+        path.components()
+            .filter(|c| *c != Utf8Component::CurDir)
+            .collect::<Utf8PathBuf>(),
+    ))
+}
+
+fn build_context<'a>(
+    eval: &Evaluator<'_, 'a, '_>,
+    function: &str,
+) -> anyhow::Result<&'a BuildContext> {
+    eval.extra
+        .and_then(|extra| extra.downcast_ref::<BuildContext>())
+        .ok_or_else(|| anyhow!("{function}() can only be called while evaluating a package"))
+}
+
+#[starlark_module]
+pub fn build_globals(builder: &mut GlobalsBuilder) {
+    /// Returns the name of the build config the package is evaluated for.
+    fn config_name<'v>(eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<String> {
+        Ok(build_context(eval, "config_name")?.config.name.clone())
+    }
+
+    /// Returns the value of `key` in the settings of the current build config,
+    /// or `default` if it is not set.
+    fn config_setting<'v>(
+        #[starlark(require = pos)] key: &str,
+        #[starlark(default = NoneType)] default: Value<'v>,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> anyhow::Result<Value<'v>> {
+        let context = build_context(eval, "config_setting")?;
+        Ok(match context.config.settings.get(key) {
+            Some(value) => eval.heap().alloc_str(value).to_value(),
+            None => default,
+        })
+    }
+
+    /// Returns the files below the package matching any of the `include` patterns
+    /// and none of the `exclude` patterns, sorted and relative to the package.
+    ///
+    /// `*` does not match `/`, `**` matches any number of directories.
+    /// Like the source import, it skips what ignore files exclude, and it does not
+    /// descend into nested packages.
+    /// The package is evaluated again when matching files appear or disappear.
+    fn glob<'v>(
+        #[starlark(require = pos)] include: UnpackList<String>,
+        #[starlark(require = named, default = UnpackList::default())] exclude: UnpackList<String>,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> anyhow::Result<Vec<String>> {
+        let context = build_context(eval, "glob")?;
+        let set = ArtifactSet::new(include.items, exclude.items)?;
+        let artifacts = set.expand(
+            &context.root.join(&context.package),
+            &context.excluded,
+            &[BUILD_FILE_NAME],
+        )?;
+        let paths = artifacts.iter().map(|a| a.path().to_string()).collect();
+        context.globs.borrow_mut().push(PackageGlob {
+            package: context.package.clone(),
+            set,
+            matched: artifacts
+                .iter()
+                .map(|a| context.package.join(a.path()))
+                .collect(),
+        });
+        Ok(paths)
+    }
+
+    /// Declares a command producing `outs` from `srcs`.
+    ///
+    /// Paths in `srcs` and `outs` are relative to the package.
+    fn cmd<'v>(
+        #[starlark(args)] args: UnpackTuple<String>,
+        #[starlark(require = named)] name: String,
+        #[starlark(require = named, default = UnpackList::default())] srcs: UnpackList<String>,
+        #[starlark(require = named, default = UnpackList::default())] outs: UnpackList<String>,
+        #[starlark(require = named, default = UnpackDictEntries::default())] env: UnpackDictEntries<
+            String,
+            String,
+        >,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> anyhow::Result<NoneType> {
+        let context = build_context(eval, "cmd")?;
+
+        if args.items.is_empty() {
+            return Err(anyhow!("cmd() needs at least the command to run"));
+        }
+
+        let label = Label::new(context.package.clone(), name);
+        let mut commands = context.commands.borrow_mut();
+        if commands.iter().any(|c| c.label == label) {
+            return Err(anyhow!("{label} is declared more than once"));
+        }
+
+        let resolve = |paths: Vec<String>| {
+            paths
+                .iter()
+                .map(|p| package_path(&context.package, p))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        commands.push(Command {
+            label,
+            args: args.items,
+            env: env.entries.into_iter().collect(),
+            srcs: resolve(srcs.items)?,
+            outs: resolve(outs.items)?,
+        });
+        Ok(NoneType)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starlark::environment::Module;
+    use starlark::syntax::{AstModule, Dialect};
+
+    use super::*;
+
+    fn evaluate(package: &str, code: &str) -> Result<Vec<Command>, starlark::Error> {
+        evaluate_with_config(package, Config::default(), code)
+    }
+
+    fn evaluate_with_config(
+        package: &str,
+        config: Config,
+        code: &str,
+    ) -> Result<Vec<Command>, starlark::Error> {
+        let globals = GlobalsBuilder::standard().with(build_globals).build();
+        let ast = AstModule::parse("ZACK.star", code.to_owned(), &Dialect::Standard)?;
+        let module = Module::new();
+        let context = BuildContext::new(".", package, config);
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.extra = Some(&context);
+            eval.eval_module(ast, &globals)?;
+        }
+        Ok(context.into_commands())
+    }
+
+    #[test]
+    fn cmd_declares_command() {
+        let commands = evaluate(
+            "examples/simple_c",
+            r#"cmd("gcc", "-o", "main", "main.c", name = "main", srcs = ["./main.c"], outs = ["main"], env = {"CC": "gcc"})"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            commands,
+            vec![Command {
+                label: Label::new("examples/simple_c", "main"),
+                args: vec!["gcc".into(), "-o".into(), "main".into(), "main.c".into()],
+                env: BTreeMap::from([("CC".into(), "gcc".into())]),
+                srcs: vec!["examples/simple_c/main.c".into()],
+                outs: vec!["examples/simple_c/main".into()],
+            }]
+        );
+    }
+
+    #[test]
+    fn cmd_rejects_duplicate_names() {
+        let result = evaluate(
+            "",
+            r#"
+cmd("true", name = "a")
+cmd("true", name = "a")
+"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn cmd_rejects_escaping_paths() {
+        let result = evaluate("pkg", r#"cmd("cat", name = "a", srcs = ["../other"])"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn config_setting_reads_current_config() {
+        let config = Config {
+            name: "release".into(),
+            settings: BTreeMap::from([("opt".into(), "-O2".into())]),
+        };
+        let commands = evaluate_with_config(
+            "pkg",
+            config,
+            r#"cmd("gcc", config_setting("opt"), config_setting("debug", "-g0"), name = config_name())"#,
+        )
+        .unwrap();
+
+        assert_eq!(commands[0].label, Label::new("pkg", "release"));
+        assert_eq!(commands[0].args, vec!["gcc", "-O2", "-g0"]);
+    }
+
+    #[test]
+    fn glob_matches_files_below_package() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        for path in [
+            "pkg/main.c",
+            "pkg/lib/util.c",
+            "pkg/lib/test_util.c",
+            "pkg/README",
+            "pkg/nested/ZACK.star",
+            "pkg/nested/nested.c",
+            "pkg/target/out.c",
+        ] {
+            std::fs::create_dir_all(root.join(path).parent().unwrap())?;
+            std::fs::write(root.join(path), "")?;
+        }
+
+        let globals = GlobalsBuilder::standard().with(build_globals).build();
+        let ast = AstModule::parse(
+            "ZACK.star",
+            r#"cmd("cc", name = "a", srcs = glob(["**/*.c"], exclude = ["**/test_*"]))"#.to_owned(),
+            &Dialect::Standard,
+        )
+        .map_err(|e| e.into_anyhow())?;
+        let module = Module::new();
+        let context = BuildContext::new(root, "pkg", Config::default())
+            .with_excluded(root.join("pkg/target"));
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.extra = Some(&context);
+            eval.eval_module(ast, &globals)
+                .map_err(|e| e.into_anyhow())?;
+        }
+        let package = context.into_package();
+
+        assert_eq!(
+            package.commands[0].srcs,
+            vec![
+                Utf8PathBuf::from("pkg/lib/util.c"),
+                Utf8PathBuf::from("pkg/main.c")
+            ]
+        );
+        let [glob] = package.globs.as_slice() else {
+            panic!("expected one glob, got {:?}", package.globs);
+        };
+        assert!(glob.is_affected_by("pkg/new.c".into(), true));
+        assert!(glob.is_affected_by("pkg/main.c".into(), false));
+        assert!(!glob.is_affected_by("pkg/main.c".into(), true));
+        assert!(!glob.is_affected_by("pkg/test_new.c".into(), true));
+        assert!(!glob.is_affected_by("other/new.c".into(), true));
+        Ok(())
+    }
+
+    #[test]
+    fn cmd_requires_build_context() {
+        let globals = GlobalsBuilder::standard().with(build_globals).build();
+        let ast = AstModule::parse(
+            "ZACK.star",
+            r#"cmd("true", name = "a")"#.to_owned(),
+            &Dialect::Standard,
+        )
+        .unwrap();
+        let module = Module::new();
+        let mut eval = Evaluator::new(&module);
+        assert!(eval.eval_module(ast, &globals).is_err());
+    }
 }
@@ -1,7 +1,9 @@
 use anyhow::Context;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use dupe::{Dupe, OptionDupedExt};
-use starlark::environment::{FrozenModule, Globals, LibraryExtension, Module};
+use exec::config::WorkspaceContext;
+use exec::{BuildContext, Config, Package};
+use starlark::environment::{FrozenModule, Globals, GlobalsBuilder, LibraryExtension, Module};
 use starlark::eval::{Evaluator, FileLoader};
 use starlark::syntax::{AstModule, Dialect, DialectTypes};
 use std::collections::HashMap;
@@ -16,6 +18,36 @@ pub enum LoadError {
     ModuleNotFound { module_name: String },
 }
 
+pub use exec::BUILD_FILE_NAME;
+
+/// The file marking the workspace root and declaring the build configs.
+pub const WORKSPACE_FILE_NAME: &str = "ZACK_WORKSPACE.star";
+
+/// Returns the workspace-relative directories of all packages below `root`,
+/// respecting ignore files and skipping `excluded`.
+pub fn find_packages(root: &Utf8Path, excluded: &Utf8Path) -> anyhow::Result<Vec<Utf8PathBuf>> {
+    let excluded = excluded.to_owned();
+    let mut packages = Vec::new();
+    for entry in ignore::WalkBuilder::new(root)
+        // Nested workspaces have their own packages.
+        .filter_entry(move |e| {
+            e.path() != excluded && (e.depth() == 0 || !e.path().join(WORKSPACE_FILE_NAME).exists())
+        })
+        .build()
+    {
+        let entry = entry.with_context(|| format!("while walking {root:?}"))?;
+        if entry.file_name() != BUILD_FILE_NAME || !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let path = Utf8Path::from_path(entry.path())
+            .with_context(|| format!("non UTF-8 path: {:?}", entry.path()))?;
+        let package = path.parent().expect("file has parent").strip_prefix(root)?;
+        packages.push(package.to_owned());
+    }
+    packages.sort();
+    Ok(packages)
+}
+
 #[derive(Debug, Clone)]
 pub struct Executor {
     globals: Globals,
@@ -24,7 +56,10 @@ pub struct Executor {
 impl Default for Executor {
     fn default() -> Self {
         Executor {
-            globals: Globals::extended_by(LIBRARY_EXTENSIONS),
+            globals: GlobalsBuilder::extended_by(LIBRARY_EXTENSIONS)
+                .with(exec::build_globals)
+                .with(exec::config::workspace_globals)
+                .build(),
         }
     }
 }
@@ -45,6 +80,59 @@ impl Executor {
         let frozen = module.freeze().map_err(starlark::Error::from)?;
         Ok(frozen)
     }
+
+    /// Evaluates the workspace file below `root` and returns the declared build configs.
+    pub fn evaluate_workspace(
+        &self,
+        loader: &dyn FileLoader,
+        root: &Utf8Path,
+    ) -> anyhow::Result<Vec<Config>> {
+        let file_path = root.join(WORKSPACE_FILE_NAME);
+        let content = std::fs::read_to_string(&file_path)
+            .with_context(|| format!("while reading {file_path:?}"))?;
+        let parsed =
+            AstModule::parse(file_path.as_str(), content, &DIALECT).map_err(|e| e.into_anyhow())?;
+        let module = Module::new();
+        let context = WorkspaceContext::default();
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.set_loader(loader);
+            eval.extra = Some(&context);
+            eval.eval_module(parsed, &self.globals)
+                .map_err(|e| e.into_anyhow())
+                .with_context(|| format!("while evaluating {file_path:?}"))?;
+        }
+        Ok(context.into_configs())
+    }
+
+    /// Evaluates the build file of `package` below `root` for `config`
+    /// and returns the declared commands and evaluated globs.
+    /// Globs skip `excluded`.
+    pub fn evaluate_package(
+        &self,
+        loader: &dyn FileLoader,
+        root: &Utf8Path,
+        excluded: &Utf8Path,
+        package: &Utf8Path,
+        config: &Config,
+    ) -> anyhow::Result<Package> {
+        let file_path = root.join(package).join(BUILD_FILE_NAME);
+        let content = std::fs::read_to_string(&file_path)
+            .with_context(|| format!("while reading {file_path:?}"))?;
+        let parsed =
+            AstModule::parse(file_path.as_str(), content, &DIALECT).map_err(|e| e.into_anyhow())?;
+        let module = Module::new();
+        let context = BuildContext::new(root, package, config.clone()).with_excluded(excluded);
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.set_loader(loader);
+            eval.extra = Some(&context);
+            eval.eval_module(parsed, &self.globals)
+                .map_err(|e| e.into_anyhow())
+                .with_context(|| format!("while evaluating {file_path:?}"))?;
+        }
+        Ok(context.into_package())
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -1,12 +1,101 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20261018_000002_source_files;
+mod m20261018_000003_file_constraints;
+mod m20261019_000004_actions;
+mod m20261019_000005_pinned_configs;
+mod m20261019_000006_action_outputs;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20261018_000002_source_files::Migration),
+            Box::new(m20261018_000003_file_constraints::Migration),
+            Box::new(m20261019_000004_actions::Migration),
+            Box::new(m20261019_000005_pinned_configs::Migration),
+            Box::new(m20261019_000006_action_outputs::Migration),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm_migration::sea_orm::{ConnectionTrait, Database, DatabaseConnection};
+
+    use super::*;
+
+    async fn tables(db: &DatabaseConnection) -> Result<Vec<String>, DbErr> {
+        let rows = db
+            .query_all(sea_orm_migration::sea_orm::Statement::from_string(
+                db.get_database_backend(),
+                "SELECT name FROM sqlite_master WHERE type = 'table' \
+                 AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            ))
+            .await?;
+        rows.iter().map(|row| row.try_get_by_index(0)).collect()
+    }
+
+    #[async_std::test]
+    async fn migrations_round_trip() -> Result<(), DbErr> {
+        let db = Database::connect("sqlite::memory:").await?;
+
+        Migrator::up(&db, None).await?;
+        let migrated = tables(&db).await?;
+        assert_eq!(
+            migrated,
+            vec!["action", "build_config", "file", "seaql_migrations"]
+        );
+
+        Migrator::down(&db, None).await?;
+        assert_eq!(tables(&db).await?, vec!["seaql_migrations"]);
+
+        Migrator::up(&db, None).await?;
+        assert_eq!(tables(&db).await?, migrated);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn files_are_constrained() -> Result<(), DbErr> {
+        let db = Database::connect("sqlite::memory:").await?;
+        Migrator::up(&db, None).await?;
+
+        db.execute_unprepared("INSERT INTO build_config (id, name) VALUES (1, 'default')")
+            .await?;
+        db.execute_unprepared(
+            "INSERT INTO file (build_config_id, rel_path) VALUES (1, 'out'), (NULL, 'src')",
+        )
+        .await?;
+
+        // unknown config
+        assert!(db
+            .execute_unprepared("INSERT INTO file (build_config_id, rel_path) VALUES (2, 'out')")
+            .await
+            .is_err());
+        // duplicate paths
+        assert!(db
+            .execute_unprepared("INSERT INTO file (build_config_id, rel_path) VALUES (1, 'out')")
+            .await
+            .is_err());
+        assert!(db
+            .execute_unprepared("INSERT INTO file (build_config_id, rel_path) VALUES (NULL, 'src')")
+            .await
+            .is_err());
+
+        db.execute_unprepared("DELETE FROM build_config WHERE id = 1")
+            .await?;
+        let remaining = db
+            .query_one(sea_orm_migration::sea_orm::Statement::from_string(
+                db.get_database_backend(),
+                "SELECT rel_path FROM file",
+            ))
+            .await?
+            .unwrap();
+        assert_eq!(remaining.try_get_by_index::<String>(0)?, "src");
+        Ok(())
     }
 }
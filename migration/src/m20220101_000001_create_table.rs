@@ -30,17 +30,8 @@ impl MigrationTrait for Migration {
             )
             .await?;
 
-        // not supported by sqlite
-        // manager
-        //     .create_foreign_key(
-        //         ForeignKey::create()
-        //         .name("FK_build_config")
-        //         .from(File::Table, (File::BuildConfigId, BuildConfig::Id))
-        //         .to(BuildConfig::Table, (File::BuildConfigId, BuildConfig::Id))
-        //         .on_delete(ForeignKeyAction::Cascade)
-        //         .on_update(ForeignKeyAction::Cascade)
-        //         .to_owned()
-        //     ).await?;
+        // The foreign key from file to build_config is added by
+        // m20261018_000003_file_constraints.
 
         Ok(())
     }
@@ -50,7 +41,7 @@ impl MigrationTrait for Migration {
             .drop_table(Table::drop().table(File::Table).to_owned())
             .await?;
         manager
-            .drop_table(Table::drop().table(File::Table).to_owned())
+            .drop_table(Table::drop().table(BuildConfig::Table).to_owned())
             .await?;
         Ok(())
     }
@@ -0,0 +1,73 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+/// Source files have no build config, so `file.build_config_id` becomes nullable.
+///
+/// SQLite cannot change the nullability of an existing column, so the table is
+/// recreated and the existing rows are copied over.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        recreate_file_table(manager, integer_null(File::BuildConfigId)).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .get_connection()
+            .execute_unprepared("DELETE FROM file WHERE build_config_id IS NULL")
+            .await?;
+        recreate_file_table(manager, integer(File::BuildConfigId)).await
+    }
+}
+
+async fn recreate_file_table(
+    manager: &SchemaManager<'_>,
+    mut build_config_id: ColumnDef,
+) -> Result<(), DbErr> {
+    manager
+        .create_table(
+            Table::create()
+                .table(FileNew::Table)
+                .col(pk_auto(File::Id))
+                .col(&mut build_config_id)
+                .col(string(File::RelPath))
+                .col(blob_null(File::ContentHash))
+                .to_owned(),
+        )
+        .await?;
+
+    manager
+        .get_connection()
+        .execute_unprepared(
+            "INSERT INTO file_new (id, build_config_id, rel_path, content_hash) \
+             SELECT id, build_config_id, rel_path, content_hash FROM file",
+        )
+        .await?;
+
+    manager
+        .drop_table(Table::drop().table(File::Table).to_owned())
+        .await?;
+    manager
+        .rename_table(
+            Table::rename()
+                .table(FileNew::Table, File::Table)
+                .to_owned(),
+        )
+        .await
+}
+
+#[derive(DeriveIden)]
+enum File {
+    Table,
+    Id,
+    BuildConfigId,
+    RelPath,
+    ContentHash,
+}
+
+#[derive(DeriveIden)]
+enum FileNew {
+    Table,
+}
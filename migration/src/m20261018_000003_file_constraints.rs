@@ -0,0 +1,138 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+/// Ties `file.build_config_id` to `build_config.id` and indexes the file table.
+///
+/// Files of a removed build config are removed with it. Every path exists at most
+/// once per build config, and at most once as a source file.
+///
+/// SQLite can only add foreign keys when creating a table, so the table is
+/// recreated and the existing rows are copied over.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const FILE_CONFIG_PATH_INDEX: &str = "idx_file_build_config_id_rel_path";
+const SOURCE_FILE_PATH_INDEX: &str = "idx_file_source_rel_path";
+const FILE_HASH_INDEX: &str = "idx_file_content_hash";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let connection = manager.get_connection();
+        // Rows that would violate the new constraints.
+        connection
+            .execute_unprepared(
+                "DELETE FROM file WHERE build_config_id IS NOT NULL \
+                 AND build_config_id NOT IN (SELECT id FROM build_config)",
+            )
+            .await?;
+        connection
+            .execute_unprepared(
+                "DELETE FROM file WHERE id NOT IN \
+                 (SELECT MAX(id) FROM file GROUP BY build_config_id, rel_path)",
+            )
+            .await?;
+
+        recreate_file_table(manager, true).await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name(FILE_CONFIG_PATH_INDEX)
+                    .table(File::Table)
+                    .col(File::BuildConfigId)
+                    .col(File::RelPath)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        // NULLs are distinct in unique indexes, so source files need their own.
+        manager
+            .create_index(
+                Index::create()
+                    .name(SOURCE_FILE_PATH_INDEX)
+                    .table(File::Table)
+                    .col(File::RelPath)
+                    .unique()
+                    .and_where(Expr::col(File::BuildConfigId).is_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name(FILE_HASH_INDEX)
+                    .table(File::Table)
+                    .col(File::ContentHash)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The indexes are dropped together with the old table.
+        recreate_file_table(manager, false).await
+    }
+}
+
+async fn recreate_file_table(
+    manager: &SchemaManager<'_>,
+    with_foreign_key: bool,
+) -> Result<(), DbErr> {
+    let mut table = Table::create();
+    table
+        .table(FileNew::Table)
+        .col(pk_auto(File::Id))
+        .col(integer_null(File::BuildConfigId))
+        .col(string(File::RelPath))
+        .col(blob_null(File::ContentHash));
+    if with_foreign_key {
+        table.foreign_key(
+            ForeignKey::create()
+                .name("fk_file_build_config")
+                .from(FileNew::Table, File::BuildConfigId)
+                .to(BuildConfig::Table, BuildConfig::Id)
+                .on_delete(ForeignKeyAction::Cascade)
+                .on_update(ForeignKeyAction::Cascade),
+        );
+    }
+    manager.create_table(table.to_owned()).await?;
+
+    manager
+        .get_connection()
+        .execute_unprepared(
+            "INSERT INTO file_new (id, build_config_id, rel_path, content_hash) \
+             SELECT id, build_config_id, rel_path, content_hash FROM file",
+        )
+        .await?;
+
+    manager
+        .drop_table(Table::drop().table(File::Table).to_owned())
+        .await?;
+    manager
+        .rename_table(
+            Table::rename()
+                .table(FileNew::Table, File::Table)
+                .to_owned(),
+        )
+        .await
+}
+
+#[derive(DeriveIden)]
+enum BuildConfig {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum File {
+    Table,
+    Id,
+    BuildConfigId,
+    RelPath,
+    ContentHash,
+}
+
+#[derive(DeriveIden)]
+enum FileNew {
+    Table,
+}
@@ -0,0 +1,65 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+/// Adds the action table, which keeps what the action key of every command was
+/// derived from when it last succeeded, per build config.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+const ACTION_CONFIG_LABEL_INDEX: &str = "idx_action_build_config_id_label";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Action::Table)
+                    .col(pk_auto(Action::Id))
+                    .col(integer(Action::BuildConfigId))
+                    .col(string(Action::Label))
+                    .col(text(Action::KeyComponents))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_action_build_config")
+                            .from(Action::Table, Action::BuildConfigId)
+                            .to(BuildConfig::Table, BuildConfig::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name(ACTION_CONFIG_LABEL_INDEX)
+                    .table(Action::Table)
+                    .col(Action::BuildConfigId)
+                    .col(Action::Label)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Action::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum BuildConfig {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum Action {
+    Table,
+    Id,
+    BuildConfigId,
+    Label,
+    KeyComponents,
+}
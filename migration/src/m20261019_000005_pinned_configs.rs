@@ -0,0 +1,37 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+/// Adds `build_config.pinned`: the outputs of pinned configs are never evicted
+/// to keep the blob store within a size budget.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(BuildConfig::Table)
+                    .add_column(boolean(BuildConfig::Pinned).default(false))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(BuildConfig::Table)
+                    .drop_column(BuildConfig::Pinned)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum BuildConfig {
+    Table,
+    Pinned,
+}
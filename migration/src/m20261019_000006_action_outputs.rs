@@ -0,0 +1,37 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+/// Adds `action.outputs`: the stored listing of the outputs of the last successful run,
+/// which keeps the listing and the files in it alive when collecting garbage.
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Action::Table)
+                    .add_column(blob_null(Action::Outputs))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Action::Table)
+                    .drop_column(Action::Outputs)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Action {
+    Table,
+    Outputs,
+}
@@ -0,0 +1,244 @@
+//! The BLAKE3 digest content is addressed by throughout zack.
+
+use std::{fmt, num::ParseIntError, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone)]
+#[non_exhaustive]
+pub enum ParseDigestError {
+    #[error("invalid digest: {0}")]
+    Hex(#[from] blake3::HexError),
+    #[error("invalid digest {0:?}, expected <hash>/<size>")]
+    MissingSize(String),
+    #[error("invalid size in digest: {0}")]
+    Size(#[from] ParseIntError),
+}
+
+/// The BLAKE3 hash of some content.
+///
+/// Displayed, parsed and serialized as lowercase hex. Stored as the raw
+/// 32 bytes in the build database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest(blake3::Hash);
+
+impl Digest {
+    /// Returns the digest of `bytes`.
+    pub fn of(bytes: &[u8]) -> Self {
+        Digest(blake3::hash(bytes))
+    }
+
+    pub const fn from_bytes(bytes: [u8; 32]) -> Self {
+        Digest(blake3::Hash::from_bytes(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+}
+
+impl Ord for Digest {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl PartialOrd for Digest {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<blake3::Hash> for Digest {
+    fn from(hash: blake3::Hash) -> Self {
+        Digest(hash)
+    }
+}
+
+impl From<Digest> for blake3::Hash {
+    fn from(digest: Digest) -> Self {
+        digest.0
+    }
+}
+
+impl TryFrom<&[u8]> for Digest {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        blake3::Hash::from_slice(bytes).map(Digest)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parses the hex representation, as produced by `Display`.
+impl FromStr for Digest {
+    type Err = ParseDigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Digest(blake3::Hash::from_hex(s)?))
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        hex.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A digest together with the size of the content, as remote caches address blobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SizedDigest {
+    pub digest: Digest,
+    pub size: u64,
+}
+
+impl SizedDigest {
+    /// Returns the sized digest of `bytes`.
+    pub fn of(bytes: &[u8]) -> Self {
+        SizedDigest {
+            digest: Digest::of(bytes),
+            size: bytes.len() as u64,
+        }
+    }
+}
+
+/// `<hash>/<size>`, as in bazel's logs and resource names.
+impl fmt::Display for SizedDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.digest, self.size)
+    }
+}
+
+impl FromStr for SizedDigest {
+    type Err = ParseDigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (digest, size) = s
+            .split_once('/')
+            .ok_or_else(|| ParseDigestError::MissingSize(s.to_string()))?;
+        Ok(SizedDigest {
+            digest: digest.parse()?,
+            size: size.parse()?,
+        })
+    }
+}
+
+impl Serialize for SizedDigest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SizedDigest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Digests are stored as `Blob` columns holding the raw bytes.
+#[cfg(feature = "sea-orm")]
+mod sea_orm_impls {
+    use sea_orm::sea_query::{ArrayType, ColumnType, Nullable, Value, ValueType, ValueTypeErr};
+    use sea_orm::{ColIdx, DbErr, QueryResult, TryGetError, TryGetable};
+
+    use super::Digest;
+
+    impl From<Digest> for Value {
+        fn from(digest: Digest) -> Self {
+            Value::Bytes(Some(Box::new(digest.as_bytes().to_vec())))
+        }
+    }
+
+    impl Nullable for Digest {
+        fn null() -> Value {
+            Value::Bytes(None)
+        }
+    }
+
+    impl ValueType for Digest {
+        fn try_from(v: Value) -> Result<Self, ValueTypeErr> {
+            match v {
+                Value::Bytes(Some(bytes)) => {
+                    <Digest as TryFrom<&[u8]>>::try_from(&bytes).map_err(|_| ValueTypeErr)
+                }
+                _ => Err(ValueTypeErr),
+            }
+        }
+
+        fn type_name() -> String {
+            "Digest".to_string()
+        }
+
+        fn array_type() -> ArrayType {
+            ArrayType::Bytes
+        }
+
+        fn column_type() -> ColumnType {
+            ColumnType::Blob
+        }
+    }
+
+    impl TryGetable for Digest {
+        fn try_get_by<I: ColIdx>(res: &QueryResult, index: I) -> Result<Self, TryGetError> {
+            let bytes = Vec::<u8>::try_get_by(res, index)?;
+            <Digest as TryFrom<&[u8]>>::try_from(&bytes).map_err(|_| {
+                TryGetError::DbErr(DbErr::Type(format!(
+                    "expected a 32 byte digest, got {} bytes",
+                    bytes.len()
+                )))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_round_trips_through_hex() {
+        let digest = Digest::of(b"content");
+        let hex = digest.to_string();
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex.parse::<Digest>().ok(), Some(digest));
+        assert_eq!(
+            serde_json::to_string(&digest).unwrap(),
+            format!("\"{hex}\"")
+        );
+        assert_eq!(
+            serde_json::from_str::<Digest>(&format!("\"{hex}\"")).unwrap(),
+            digest
+        );
+        assert!("abc".parse::<Digest>().is_err());
+    }
+
+    #[test]
+    fn sized_digest_carries_the_size() {
+        let sized = SizedDigest::of(b"content");
+        assert_eq!(sized.size, 7);
+        assert_eq!(sized.to_string(), format!("{}/7", sized.digest));
+        assert_eq!(sized.to_string().parse().ok(), Some(sized));
+        assert!(matches!(
+            sized.digest.to_string().parse::<SizedDigest>(),
+            Err(ParseDigestError::MissingSize(_))
+        ));
+        assert!(matches!(
+            format!("{}/-1", sized.digest).parse::<SizedDigest>(),
+            Err(ParseDigestError::Size(_))
+        ));
+    }
+}
@@ -1,45 +1,119 @@
 use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::os::unix::fs::PermissionsExt;
 
-use blake3::Hash;
 use serde::{Deserialize, Serialize};
 
 use crate::hash::Hashable;
 
+pub mod digest;
 pub mod hash;
 
+pub use digest::{Digest, SizedDigest};
+
+/// A directory listing, addressed by the hash of its entries.
+///
+/// Stored as the JSON of its entries, so the content hash of the stored blob
+/// is the `entry_hash`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-struct Dir {
+pub struct Dir {
     entries: BTreeMap<String, DirEntry>,
-    entry_hash: Hash,
+    entry_hash: Digest,
 }
 
 impl Dir {
     pub fn from_entries(entries: BTreeMap<String, DirEntry>) -> Self {
         Dir {
-            entry_hash: entries.hash(),
+            entry_hash: entries.hash().into(),
             entries,
         }
     }
+
+    pub fn entries(&self) -> &BTreeMap<String, DirEntry> {
+        &self.entries
+    }
+
+    pub fn entry_hash(&self) -> Digest {
+        self.entry_hash
+    }
+
+    /// The stored representation of this directory.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.entries).expect("serializing entries")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        Ok(Dir::from_entries(serde_json::from_slice(bytes)?))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-struct DirEntry {
-    kind: DirEntryKind,
-    content_hash: Hash,
+pub struct DirEntry {
+    pub kind: DirEntryKind,
+    /// The `entry_hash` of a directory, the content hash of a file or
+    /// the hash of a symlink's target.
+    pub content_hash: Digest,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-enum DirEntryKind {
+pub enum DirEntryKind {
     Dir,
-    File { attributes: FileAttributes },
+    File {
+        attributes: FileAttributes,
+    },
+    /// A relative link to another entry of the same tree.
+    Symlink {
+        target: String,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-struct FileAttributes {
-    executable: bool,
-    size: u64,
+pub struct FileAttributes {
+    pub executable: bool,
+    pub size: u64,
+}
+
+impl FileAttributes {
+    /// Captures the attributes of a regular file, executable if any execute bit is set.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        FileAttributes {
+            executable: metadata.permissions().mode() & 0o111 != 0,
+            size: metadata.len(),
+        }
+    }
+
+    /// The read-only mode files with these attributes are provisioned with.
+    pub fn mode(&self) -> u32 {
+        if self.executable { 0o555 } else { 0o444 }
+    }
 }
 
-trait DirStore {
+pub trait DirStore {
     fn store_dir(&self, dir: &Dir) -> Result<(), anyhow::Error>;
+    fn load_dir(&self, entry_hash: Digest) -> Result<Dir, anyhow::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_dir_hashes_to_entry_hash() {
+        let dir = Dir::from_entries(BTreeMap::from([(
+            "main.c".to_string(),
+            DirEntry {
+                kind: DirEntryKind::File {
+                    attributes: FileAttributes {
+                        executable: false,
+                        size: 3,
+                    },
+                },
+                content_hash: Digest::of(b"int"),
+            },
+        )]));
+
+        let bytes = dir.to_bytes();
+        assert_eq!(Digest::of(&bytes), dir.entry_hash());
+        assert_eq!(Dir::from_bytes(&bytes).unwrap(), dir);
+    }
 }
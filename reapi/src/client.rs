@@ -0,0 +1,297 @@
+//! The ContentAddressableStorage and ActionCache services.
+
+use tonic::client::Grpc;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+use tonic_prost::ProstCodec;
+
+use crate::proto::{
+    ActionResult, BatchReadBlobsRequest, BatchReadBlobsResponse, BatchUpdateBlobsRequest,
+    BatchUpdateBlobsResponse, Digest, FindMissingBlobsRequest, FindMissingBlobsResponse,
+    GetActionResultRequest, UpdateActionResultRequest, batch_update_blobs_request, digest_function,
+};
+use crate::{Blob, ReapiError, digest};
+
+const CAS: &str = "/build.bazel.remote.execution.v2.ContentAddressableStorage";
+const ACTION_CACHE: &str = "/build.bazel.remote.execution.v2.ActionCache";
+
+/// Servers reject requests above 4 MiB by default, leave room for the framing.
+pub const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024 - 64 * 1024;
+
+const BLAKE3: i32 = digest_function::Value::Blake3 as i32;
+
+/// A client for the caches of a remote execution service.
+#[derive(Debug, Clone)]
+pub struct ReapiClient {
+    grpc: Grpc<Channel>,
+    instance_name: String,
+}
+
+impl ReapiClient {
+    /// Connects to `endpoint`, e.g. `grpc://localhost:9092`.
+    pub async fn connect(
+        endpoint: impl Into<String>,
+        instance_name: impl Into<String>,
+    ) -> Result<Self, ReapiError> {
+        // tonic only knows about http and https.
+        let endpoint = endpoint.into().replacen("grpc://", "http://", 1);
+        let channel = Channel::from_shared(endpoint)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?
+            .connect()
+            .await?;
+        Ok(Self::new(channel, instance_name))
+    }
+
+    pub fn new(channel: Channel, instance_name: impl Into<String>) -> Self {
+        Self {
+            grpc: Grpc::new(channel),
+            instance_name: instance_name.into(),
+        }
+    }
+
+    async fn unary<Req, Resp>(
+        &mut self,
+        service: &str,
+        method: &str,
+        request: Req,
+    ) -> Result<Resp, ReapiError>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Resp: prost::Message + Default + Send + Sync + 'static,
+    {
+        self.grpc
+            .ready()
+            .await
+            .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+        let path =
+            PathAndQuery::try_from(format!("{service}/{method}")).expect("service paths are valid");
+        let response = self
+            .grpc
+            .unary(
+                tonic::Request::new(request),
+                path,
+                ProstCodec::<Req, Resp>::default(),
+            )
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// Returns the digests the cache does not have.
+    pub async fn find_missing_blobs(
+        &mut self,
+        digests: Vec<Digest>,
+    ) -> Result<Vec<Digest>, ReapiError> {
+        let request = FindMissingBlobsRequest {
+            instance_name: self.instance_name.clone(),
+            blob_digests: digests,
+            digest_function: BLAKE3,
+        };
+        let response: FindMissingBlobsResponse =
+            self.unary(CAS, "FindMissingBlobs", request).await?;
+        Ok(response.missing_blob_digests)
+    }
+
+    /// Uploads the blobs the cache does not have yet, in as few batches as possible.
+    pub async fn upload_blobs(&mut self, blobs: Vec<Blob>) -> Result<(), ReapiError> {
+        let missing = self
+            .find_missing_blobs(blobs.iter().map(|b| b.digest.clone()).collect())
+            .await?;
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        for blob in blobs.into_iter().filter(|b| missing.contains(&b.digest)) {
+            if blob.data.len() > MAX_BATCH_BYTES {
+                return Err(ReapiError::BlobTooLarge(blob.digest));
+            }
+            if batch_bytes + blob.data.len() > MAX_BATCH_BYTES {
+                self.update_batch(std::mem::take(&mut batch)).await?;
+                batch_bytes = 0;
+            }
+            batch_bytes += blob.data.len();
+            batch.push(batch_update_blobs_request::Request {
+                digest: Some(blob.digest),
+                data: blob.data,
+            });
+        }
+        if !batch.is_empty() {
+            self.update_batch(batch).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_batch(
+        &mut self,
+        requests: Vec<batch_update_blobs_request::Request>,
+    ) -> Result<(), ReapiError> {
+        let request = BatchUpdateBlobsRequest {
+            instance_name: self.instance_name.clone(),
+            requests,
+            digest_function: BLAKE3,
+        };
+        let response: BatchUpdateBlobsResponse =
+            self.unary(CAS, "BatchUpdateBlobs", request).await?;
+        for response in response.responses {
+            check_status(response.digest, response.status)?;
+        }
+        Ok(())
+    }
+
+    /// Downloads and verifies blobs, which must all fit into one batch.
+    pub async fn download_blobs(&mut self, digests: Vec<Digest>) -> Result<Vec<Blob>, ReapiError> {
+        let request = BatchReadBlobsRequest {
+            instance_name: self.instance_name.clone(),
+            digests,
+            digest_function: BLAKE3,
+        };
+        let response: BatchReadBlobsResponse = self.unary(CAS, "BatchReadBlobs", request).await?;
+        response
+            .responses
+            .into_iter()
+            .map(|response| {
+                let expected = check_status(response.digest, response.status)?;
+                let actual = digest(&response.data);
+                if actual != expected {
+                    return Err(ReapiError::Corrupted { expected, actual });
+                }
+                Ok(Blob {
+                    digest: expected,
+                    data: response.data,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the cached result of the action, or `None` on a cache miss.
+    pub async fn get_action_result(
+        &mut self,
+        action_digest: Digest,
+    ) -> Result<Option<ActionResult>, ReapiError> {
+        let request = GetActionResultRequest {
+            instance_name: self.instance_name.clone(),
+            action_digest: Some(action_digest),
+            inline_stdout: false,
+            inline_stderr: false,
+            digest_function: BLAKE3,
+        };
+        match self.unary(ACTION_CACHE, "GetActionResult", request).await {
+            Ok(result) => Ok(Some(result)),
+            Err(ReapiError::Status(status)) if status.code() == tonic::Code::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the result of the action. Outputs must be uploaded before.
+    pub async fn update_action_result(
+        &mut self,
+        action_digest: Digest,
+        result: ActionResult,
+    ) -> Result<ActionResult, ReapiError> {
+        let request = UpdateActionResultRequest {
+            instance_name: self.instance_name.clone(),
+            action_digest: Some(action_digest),
+            action_result: Some(result),
+            digest_function: BLAKE3,
+        };
+        self.unary(ACTION_CACHE, "UpdateActionResult", request)
+            .await
+    }
+}
+
+/// Turns the per-blob status of a batch response into an error.
+fn check_status(
+    digest: Option<Digest>,
+    status: Option<crate::proto::Status>,
+) -> Result<Digest, ReapiError> {
+    let digest = digest.unwrap_or_default();
+    match status {
+        Some(status) if status.code != tonic::Code::Ok as i32 => Err(ReapiError::Blob {
+            digest,
+            code: status.code,
+            message: status.message,
+        }),
+        _ => Ok(digest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeCache;
+    use crate::proto::OutputFile;
+
+    #[tokio::test]
+    async fn upload_find_and_download() -> anyhow::Result<()> {
+        let server = FakeCache::start().await?;
+        let mut client = server.client().await?;
+        let hello = Blob {
+            digest: digest(b"hello"),
+            data: b"hello".to_vec(),
+        };
+        let world = Blob {
+            digest: digest(b"world"),
+            data: b"world".to_vec(),
+        };
+
+        client.upload_blobs(vec![hello.clone()]).await?;
+        let missing = client
+            .find_missing_blobs(vec![hello.digest.clone(), world.digest.clone()])
+            .await?;
+        assert_eq!(missing, vec![world.digest.clone()]);
+
+        client
+            .upload_blobs(vec![hello.clone(), world.clone()])
+            .await?;
+        assert_eq!(server.uploads(), 2, "present blobs are not uploaded again");
+        let downloaded = client
+            .download_blobs(vec![world.digest.clone(), hello.digest.clone()])
+            .await?;
+        assert_eq!(downloaded, [world, hello]);
+
+        let err = client
+            .download_blobs(vec![digest(b"missing")])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, ReapiError::Blob { code, .. } if code == tonic::Code::NotFound as i32)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mismatched_uploads_are_rejected() -> anyhow::Result<()> {
+        let server = FakeCache::start().await?;
+        let mut client = server.client().await?;
+        let lie = Blob {
+            digest: digest(b"claimed"),
+            data: b"actual".to_vec(),
+        };
+        let err = client.upload_blobs(vec![lie]).await.unwrap_err();
+        assert!(
+            matches!(err, ReapiError::Blob { code, .. } if code == tonic::Code::InvalidArgument as i32)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn action_results_round_trip() -> anyhow::Result<()> {
+        let server = FakeCache::start().await?;
+        let mut client = server.client().await?;
+        let action = digest(b"action");
+        assert_eq!(client.get_action_result(action.clone()).await?, None);
+
+        let result = ActionResult {
+            output_files: vec![OutputFile {
+                path: "main.o".into(),
+                digest: Some(digest(b"object")),
+                is_executable: false,
+                contents: Vec::new(),
+            }],
+            exit_code: 0,
+            ..Default::default()
+        };
+        client
+            .update_action_result(action.clone(), result.clone())
+            .await?;
+        assert_eq!(client.get_action_result(action).await?, Some(result));
+        Ok(())
+    }
+}
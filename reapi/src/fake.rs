@@ -0,0 +1,254 @@
+//! An in-process cache server for the conformance tests.
+//!
+//! Behaves like bazel-remote where the tests depend on it: blobs are verified
+//! on upload, missing blobs and action results are reported as `NOT_FOUND`.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tonic::body::Body;
+use tonic::codegen::{BoxFuture, Service, http};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Code, Status};
+use tonic_prost::ProstCodec;
+
+use crate::proto::{
+    ActionResult, BatchReadBlobsRequest, BatchReadBlobsResponse, BatchUpdateBlobsRequest,
+    BatchUpdateBlobsResponse, Digest, FindMissingBlobsRequest, FindMissingBlobsResponse,
+    GetActionResultRequest, UpdateActionResultRequest, batch_read_blobs_response,
+    batch_update_blobs_response, digest_function,
+};
+use crate::{ReapiClient, digest};
+
+#[derive(Default)]
+struct State {
+    blobs: HashMap<Digest, Vec<u8>>,
+    action_results: HashMap<Digest, ActionResult>,
+    uploads: usize,
+}
+
+pub(crate) struct FakeCache {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl FakeCache {
+    pub(crate) async fn start() -> anyhow::Result<Self> {
+        let incoming = TcpIncoming::bind("127.0.0.1:0".parse()?)?;
+        let addr = incoming.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let router = Server::builder()
+            .add_service(Cas(state.clone()))
+            .add_service(ActionCache(state.clone()));
+        let server = tokio::spawn(async move {
+            router.serve_with_incoming(incoming).await.unwrap();
+        });
+        Ok(FakeCache {
+            addr,
+            state,
+            server,
+        })
+    }
+
+    pub(crate) async fn client(&self) -> anyhow::Result<ReapiClient> {
+        Ok(ReapiClient::connect(format!("grpc://{}", self.addr), "fake").await?)
+    }
+
+    /// The number of blobs uploaded so far.
+    pub(crate) fn uploads(&self) -> usize {
+        self.state.lock().unwrap().uploads
+    }
+}
+
+impl Drop for FakeCache {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+fn check_request(instance_name: &str, digest_function: i32) -> Result<(), Status> {
+    if instance_name != "fake" {
+        return Err(Status::invalid_argument("unknown instance"));
+    }
+    if digest_function != digest_function::Value::Blake3 as i32 {
+        return Err(Status::invalid_argument("only BLAKE3 is supported"));
+    }
+    Ok(())
+}
+
+fn status(code: Code, message: &str) -> Option<crate::proto::Status> {
+    Some(crate::proto::Status {
+        code: code as i32,
+        message: message.into(),
+    })
+}
+
+#[derive(Clone)]
+struct Cas(Arc<Mutex<State>>);
+
+impl NamedService for Cas {
+    const NAME: &'static str = "build.bazel.remote.execution.v2.ContentAddressableStorage";
+}
+
+impl Service<http::Request<Body>> for Cas {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let state = self.0.clone();
+        match request.uri().path().rsplit('/').next() {
+            Some("FindMissingBlobs") => unary(request, move |r: FindMissingBlobsRequest| {
+                check_request(&r.instance_name, r.digest_function)?;
+                let state = state.lock().unwrap();
+                Ok(FindMissingBlobsResponse {
+                    missing_blob_digests: r
+                        .blob_digests
+                        .into_iter()
+                        .filter(|d| !state.blobs.contains_key(d))
+                        .collect(),
+                })
+            }),
+            Some("BatchUpdateBlobs") => unary(request, move |r: BatchUpdateBlobsRequest| {
+                check_request(&r.instance_name, r.digest_function)?;
+                let mut state = state.lock().unwrap();
+                let mut responses = Vec::new();
+                for request in r.requests {
+                    let expected = request.digest.unwrap_or_default();
+                    let status = if digest(&request.data) == expected {
+                        state.uploads += 1;
+                        state.blobs.insert(expected.clone(), request.data);
+                        None
+                    } else {
+                        status(Code::InvalidArgument, "digest mismatch")
+                    };
+                    responses.push(batch_update_blobs_response::Response {
+                        digest: Some(expected),
+                        status,
+                    });
+                }
+                Ok(BatchUpdateBlobsResponse { responses })
+            }),
+            Some("BatchReadBlobs") => unary(request, move |r: BatchReadBlobsRequest| {
+                check_request(&r.instance_name, r.digest_function)?;
+                let state = state.lock().unwrap();
+                let responses = r
+                    .digests
+                    .into_iter()
+                    .map(|digest| {
+                        let (data, status) = match state.blobs.get(&digest) {
+                            Some(data) => (data.clone(), None),
+                            None => (Vec::new(), status(Code::NotFound, "no such blob")),
+                        };
+                        batch_read_blobs_response::Response {
+                            digest: Some(digest),
+                            data,
+                            status,
+                        }
+                    })
+                    .collect();
+                Ok(BatchReadBlobsResponse { responses })
+            }),
+            _ => unimplemented(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ActionCache(Arc<Mutex<State>>);
+
+impl NamedService for ActionCache {
+    const NAME: &'static str = "build.bazel.remote.execution.v2.ActionCache";
+}
+
+impl Service<http::Request<Body>> for ActionCache {
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let state = self.0.clone();
+        match request.uri().path().rsplit('/').next() {
+            Some("GetActionResult") => unary(request, move |r: GetActionResultRequest| {
+                check_request(&r.instance_name, r.digest_function)?;
+                state
+                    .lock()
+                    .unwrap()
+                    .action_results
+                    .get(&r.action_digest.unwrap_or_default())
+                    .cloned()
+                    .ok_or_else(|| Status::not_found("no such action"))
+            }),
+            Some("UpdateActionResult") => unary(request, move |r: UpdateActionResultRequest| {
+                check_request(&r.instance_name, r.digest_function)?;
+                let result = r.action_result.unwrap_or_default();
+                state
+                    .lock()
+                    .unwrap()
+                    .action_results
+                    .insert(r.action_digest.unwrap_or_default(), result.clone());
+                Ok(result)
+            }),
+            _ => unimplemented(),
+        }
+    }
+}
+
+/// Decodes the request, calls `handler` and encodes its response.
+fn unary<Req, Resp, F>(
+    request: http::Request<Body>,
+    handler: F,
+) -> BoxFuture<http::Response<Body>, Infallible>
+where
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnOnce(Req) -> Result<Resp, Status> + Send + 'static,
+{
+    struct Handler<Req, F>(Option<F>, PhantomData<fn(Req)>);
+
+    impl<Req, Resp, F> UnaryService<Req> for Handler<Req, F>
+    where
+        Resp: Send + 'static,
+        F: FnOnce(Req) -> Result<Resp, Status>,
+    {
+        type Response = Resp;
+        type Future = BoxFuture<tonic::Response<Resp>, Status>;
+
+        fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+            let handler = self.0.take().expect("called once");
+            let response = handler(request.into_inner()).map(tonic::Response::new);
+            Box::pin(async move { response })
+        }
+    }
+
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+        Ok(grpc
+            .unary(Handler(Some(handler), PhantomData), request)
+            .await)
+    })
+}
+
+fn unimplemented() -> BoxFuture<http::Response<Body>, Infallible> {
+    Box::pin(async { Ok(Status::unimplemented("not part of the fake").into_http()) })
+}
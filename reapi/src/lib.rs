@@ -0,0 +1,360 @@
+//! Reading from and writing to caches speaking the
+//! [Bazel Remote Execution API](https://github.com/bazelbuild/remote-apis),
+//! e.g. bazel-remote or BuildBuddy.
+//!
+//! Blobs are addressed by their BLAKE3 hash, like in the rest of zack,
+//! so the cache has to support the BLAKE3 digest function.
+
+use std::collections::HashMap;
+
+use model::{Dir, DirEntryKind, DirStore, SizedDigest};
+use prost::Message;
+use thiserror::Error;
+
+pub mod client;
+#[cfg(test)]
+mod fake;
+pub mod proto;
+
+pub use client::ReapiClient;
+use proto::{
+    Digest, Directory, DirectoryNode, FileNode, SymlinkNode, command::EnvironmentVariable,
+};
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ReapiError {
+    #[error("cannot connect to the cache: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[error("cache request failed: {0}")]
+    Status(#[from] tonic::Status),
+    #[error("cache failed to handle blob {digest}: {message} (code {code})")]
+    Blob {
+        digest: Digest,
+        code: i32,
+        message: String,
+    },
+    #[error("blob {0} is larger than a batch request may be")]
+    BlobTooLarge(Digest),
+    #[error("the cache returned blob {expected} with content hashing to {actual}")]
+    Corrupted { expected: Digest, actual: Digest },
+    #[error("actions with {0} exec steps cannot be mapped to a single command")]
+    ExecSteps(usize),
+}
+
+/// Returns the digest of `bytes`.
+pub fn digest(bytes: &[u8]) -> Digest {
+    SizedDigest::of(bytes).into()
+}
+
+/// A message ready to be uploaded to the CAS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    pub digest: Digest,
+    pub data: Vec<u8>,
+}
+
+impl Blob {
+    pub fn encode(message: &impl Message) -> Self {
+        let data = message.encode_to_vec();
+        Blob {
+            digest: digest(&data),
+            data,
+        }
+    }
+}
+
+/// A [`Dir`] tree converted to REAPI [`Directory`] messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryTree {
+    /// The digest of the root [`Directory`], e.g. to be used as an input root.
+    pub root: Digest,
+    /// Every distinct directory of the tree, children before their parents.
+    pub directories: Vec<Blob>,
+}
+
+/// Converts the directory with `entry_hash` and everything below it.
+///
+/// REAPI addresses directories by the digest of their encoded message, so each
+/// child is converted before the directory referencing it.
+pub fn directory_tree(
+    store: &impl DirStore,
+    entry_hash: model::Digest,
+) -> anyhow::Result<DirectoryTree> {
+    let mut converted = HashMap::new();
+    let mut directories = Vec::new();
+    let root = convert_dir(store, entry_hash, &mut converted, &mut directories)?;
+    Ok(DirectoryTree { root, directories })
+}
+
+fn convert_dir(
+    store: &impl DirStore,
+    entry_hash: model::Digest,
+    converted: &mut HashMap<model::Digest, Digest>,
+    directories: &mut Vec<Blob>,
+) -> anyhow::Result<Digest> {
+    if let Some(digest) = converted.get(&entry_hash) {
+        return Ok(digest.clone());
+    }
+    let dir = store.load_dir(entry_hash)?;
+    let mut subdirectories = HashMap::new();
+    for entry in dir.entries().values() {
+        if entry.kind == DirEntryKind::Dir {
+            let digest = convert_dir(store, entry.content_hash, converted, directories)?;
+            subdirectories.insert(entry.content_hash, digest);
+        }
+    }
+    let blob = Blob::encode(&to_directory(&dir, &subdirectories));
+    let digest = blob.digest.clone();
+    directories.push(blob);
+    converted.insert(entry_hash, digest.clone());
+    Ok(digest)
+}
+
+/// Converts a single directory, looking up the digests of its subdirectories by entry hash.
+///
+/// # Panics
+///
+/// If a subdirectory is missing from `subdirectories`.
+pub fn to_directory(dir: &Dir, subdirectories: &HashMap<model::Digest, Digest>) -> Directory {
+    let mut directory = Directory::default();
+    // Entries are sorted by name, as REAPI requires.
+    for (name, entry) in dir.entries() {
+        match &entry.kind {
+            DirEntryKind::Dir => directory.directories.push(DirectoryNode {
+                name: name.clone(),
+                digest: Some(subdirectories[&entry.content_hash].clone()),
+            }),
+            DirEntryKind::File { attributes } => directory.files.push(FileNode {
+                name: name.clone(),
+                digest: Some(
+                    SizedDigest {
+                        digest: entry.content_hash,
+                        size: attributes.size,
+                    }
+                    .into(),
+                ),
+                is_executable: attributes.executable,
+            }),
+            DirEntryKind::Symlink { target } => directory.symlinks.push(SymlinkNode {
+                name: name.clone(),
+                target: target.clone(),
+            }),
+        }
+    }
+    directory
+}
+
+/// The messages describing an action to the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionMessages {
+    pub action: proto::Action,
+    pub command: proto::Command,
+    /// The key of the action in the action cache.
+    pub action_digest: Digest,
+    /// The encoded command and action, which belong into the CAS.
+    pub blobs: Vec<Blob>,
+}
+
+/// Maps an action with a single exec step to REAPI messages.
+///
+/// `output_paths` are relative to the build directory, which is the working directory
+/// of the command. The sandbox paths of `action` are local and not part of the key.
+pub fn action_messages(
+    action: &zaun::Action,
+    output_paths: impl IntoIterator<Item = String>,
+    input_root: Digest,
+) -> Result<ActionMessages, ReapiError> {
+    let [exec] = action.exec_steps.as_slice() else {
+        return Err(ReapiError::ExecSteps(action.exec_steps.len()));
+    };
+    let mut output_paths: Vec<String> = output_paths.into_iter().collect();
+    output_paths.sort();
+    output_paths.dedup();
+    let command = proto::Command {
+        arguments: std::iter::once(exec.cmd.clone())
+            .chain(exec.args.iter().cloned())
+            .collect(),
+        // Sorted by name, as the environment is a BTreeMap.
+        environment_variables: exec
+            .env
+            .iter()
+            .map(|(name, value)| EnvironmentVariable {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect(),
+        working_directory: String::new(),
+        output_paths,
+    };
+    let command_blob = Blob::encode(&command);
+    let action = proto::Action {
+        command_digest: Some(command_blob.digest.clone()),
+        input_root_digest: Some(input_root),
+        ..Default::default()
+    };
+    let action_blob = Blob::encode(&action);
+    Ok(ActionMessages {
+        action_digest: action_blob.digest.clone(),
+        action,
+        command,
+        blobs: vec![command_blob, action_blob],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    use model::{DirEntry, FileAttributes};
+
+    use super::*;
+
+    #[derive(Default)]
+    pub(crate) struct MemoryDirStore(Mutex<HashMap<model::Digest, Dir>>);
+
+    impl DirStore for MemoryDirStore {
+        fn store_dir(&self, dir: &Dir) -> anyhow::Result<()> {
+            self.0.lock().unwrap().insert(dir.entry_hash(), dir.clone());
+            Ok(())
+        }
+
+        fn load_dir(&self, entry_hash: model::Digest) -> anyhow::Result<Dir> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(&entry_hash)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no directory {entry_hash}"))
+        }
+    }
+
+    pub(crate) fn file(content: &[u8], executable: bool) -> DirEntry {
+        DirEntry {
+            kind: DirEntryKind::File {
+                attributes: FileAttributes {
+                    executable,
+                    size: content.len() as u64,
+                },
+            },
+            content_hash: model::Digest::of(content),
+        }
+    }
+
+    #[test]
+    fn directory_tree_references_children_by_digest() -> anyhow::Result<()> {
+        let store = MemoryDirStore::default();
+        let lib = Dir::from_entries(BTreeMap::from([("lib.c".into(), file(b"lib", false))]));
+        store.store_dir(&lib)?;
+        let dir_entry = DirEntry {
+            kind: DirEntryKind::Dir,
+            content_hash: lib.entry_hash(),
+        };
+        let root = Dir::from_entries(BTreeMap::from([
+            ("build.sh".into(), file(b"#!/bin/sh", true)),
+            (
+                "configure".into(),
+                DirEntry {
+                    kind: DirEntryKind::Symlink {
+                        target: "build.sh".into(),
+                    },
+                    content_hash: model::Digest::of(b"build.sh"),
+                },
+            ),
+            ("lib".into(), dir_entry.clone()),
+            ("vendor".into(), dir_entry),
+        ]));
+        store.store_dir(&root)?;
+
+        let tree = directory_tree(&store, root.entry_hash())?;
+        // The shared subdirectory is converted once.
+        assert_eq!(tree.directories.len(), 2);
+        let root_blob = tree.directories.last().unwrap();
+        assert_eq!(root_blob.digest, tree.root);
+        assert_eq!(tree.root, digest(&root_blob.data));
+
+        let decoded = Directory::decode(&root_blob.data[..])?;
+        assert_eq!(decoded.files.len(), 1);
+        assert_eq!(decoded.files[0].name, "build.sh");
+        assert!(decoded.files[0].is_executable);
+        assert_eq!(decoded.files[0].digest, Some(digest(b"#!/bin/sh")));
+        assert_eq!(decoded.symlinks.len(), 1);
+        assert_eq!(decoded.symlinks[0].name, "configure");
+        assert_eq!(decoded.symlinks[0].target, "build.sh");
+        let names: Vec<&str> = decoded
+            .directories
+            .iter()
+            .map(|d| d.name.as_str())
+            .collect();
+        assert_eq!(names, ["lib", "vendor"]);
+        assert_eq!(
+            decoded.directories[0].digest.as_ref(),
+            Some(&tree.directories[0].digest)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn digests_convert_to_sized_digests() {
+        let sized = SizedDigest::of(b"content");
+        let digest = Digest::from(sized);
+        assert_eq!(digest, super::digest(b"content"));
+        assert_eq!(SizedDigest::try_from(&digest).ok(), Some(sized));
+        let negative = Digest {
+            size_bytes: -1,
+            ..digest
+        };
+        assert!(SizedDigest::try_from(&negative).is_err());
+    }
+
+    #[test]
+    fn action_maps_to_command() -> anyhow::Result<()> {
+        let action = zaun::Action {
+            source: "/src".into(),
+            build: "/build".into(),
+            exec_steps: vec![zaun::Exec {
+                cmd: "cc".into(),
+                args: vec!["-c".into(), "main.c".into()],
+                env: BTreeMap::from([("PATH".into(), "/bin".into()), ("CC".into(), "cc".into())]),
+            }],
+            instrument: false,
+        };
+        let input_root = digest(b"root");
+        let messages = action_messages(&action, ["main.o".into()], input_root.clone())?;
+        assert_eq!(messages.command.arguments, ["cc", "-c", "main.c"]);
+        let env: Vec<&str> = messages
+            .command
+            .environment_variables
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(env, ["CC", "PATH"]);
+        assert_eq!(messages.command.output_paths, ["main.o"]);
+        assert_eq!(messages.action.input_root_digest, Some(input_root));
+        assert_eq!(
+            messages.action.command_digest.as_ref(),
+            Some(&messages.blobs[0].digest)
+        );
+        assert_eq!(messages.action_digest, messages.blobs[1].digest);
+
+        // Local sandbox paths do not change the key.
+        let elsewhere = zaun::Action {
+            source: "/other/src".into(),
+            build: "/other/build".into(),
+            ..action.clone()
+        };
+        let moved = action_messages(&elsewhere, ["main.o".into()], digest(b"root"))?;
+        assert_eq!(moved.action_digest, messages.action_digest);
+
+        let two_steps = zaun::Action {
+            exec_steps: vec![zaun::Exec::default(), zaun::Exec::default()],
+            ..action
+        };
+        assert!(matches!(
+            action_messages(&two_steps, [], digest(b"root")),
+            Err(ReapiError::ExecSteps(2))
+        ));
+        Ok(())
+    }
+}
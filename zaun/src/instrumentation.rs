@@ -0,0 +1,390 @@
+//! Recording which files an action accessed, using fanotify.
+//!
+//! Like Buck2 dep files, the build uses this to only key an action
+//! on the inputs it actually used.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CString;
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Child, ExitStatus};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use nix::errno::Errno;
+use nix::libc;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::fanotify::{EventFFlags, Fanotify, InitFlags, MarkFlags, MaskFlags};
+use nix::sys::statfs::statfs;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::{SANDBOX_BUILD_DIR, SANDBOX_SOURCE_DIR};
+
+/// Written by `zaun exec` for instrumented actions.
+pub const ACCESSED_JSON_FILE_NAME: &str = "accessed.json";
+
+/// Directories of the sandbox whose files are recorded, with all directories below them.
+const RECORDED_DIRS: [&str; 2] = [SANDBOX_SOURCE_DIR, SANDBOX_BUILD_DIR];
+
+/// Size of `fanotify_event_metadata`, the start of each event.
+const EVENT_METADATA_LEN: usize = 24;
+
+/// How long to wait for events before checking whether the step exited.
+const POLL_TIMEOUT_MS: u16 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    Write,
+    Exec,
+}
+
+/// The files accessed by the steps of an action, by their path within the sandbox.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessedFiles {
+    /// False if events were lost or could not be recorded at all,
+    /// so that files may be missing.
+    pub complete: bool,
+    pub files: BTreeMap<Utf8PathBuf, BTreeSet<Access>>,
+}
+
+impl AccessedFiles {
+    /// Reads the `accessed.json` of `exec_dir`.
+    pub fn read(exec_dir: &Path) -> Result<AccessedFiles, InstrumentError> {
+        let bytes =
+            std::fs::read(exec_dir.join(ACCESSED_JSON_FILE_NAME)).map_err(InstrumentError::Read)?;
+        serde_json::from_slice(&bytes).map_err(InstrumentError::Parse)
+    }
+
+    /// The accessed files below `dir`, relative to it.
+    pub fn below<'a>(&'a self, dir: &'a Utf8Path) -> impl Iterator<Item = &'a Utf8Path> + 'a {
+        self.files
+            .keys()
+            .filter_map(move |path| path.strip_prefix(dir).ok())
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum InstrumentError {
+    #[error("While creating the fanotify group: {0}")]
+    Init(#[source] Errno),
+    #[error("While marking directory {0}: {1}")]
+    Mark(Utf8PathBuf, #[source] Errno),
+    #[error("While listing directory {0}: {1}")]
+    ListDir(Utf8PathBuf, #[source] std::io::Error),
+    #[error("While waiting for fanotify events: {0}")]
+    Poll(#[source] Errno),
+    #[error("While reading fanotify events: {0}")]
+    ReadEvents(#[source] Errno),
+    #[error("While waiting for the step: {0}")]
+    Wait(#[source] std::io::Error),
+    #[error("While reading accessed files: {0}")]
+    Read(#[source] std::io::Error),
+    #[error("While parsing accessed files: {0}")]
+    Parse(#[source] serde_json::Error),
+}
+
+/// Records the files opened by any process within the sandbox.
+///
+/// Must be created after entering the sandbox, so that paths are the ones steps see.
+/// Only marks directories and reports events by directory and name, which unprivileged
+/// users may do, so it works in the user namespace of the sandbox (Linux 5.13 or later).
+/// Directories created after the recorder are not marked, so files in them are not recorded.
+pub struct Recorder {
+    fanotify: Fanotify,
+    /// The marked directories by the handle events identify them with.
+    dirs: HashMap<DirHandle, Utf8PathBuf>,
+    accessed: AccessedFiles,
+}
+
+/// Identifies a directory like fanotify events do: its file system and file handle.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct DirHandle {
+    fsid: [u8; 8],
+    handle_type: i32,
+    handle: Vec<u8>,
+}
+
+impl Recorder {
+    /// Records the files below the source and build directory of the sandbox.
+    pub fn new() -> Result<Recorder, InstrumentError> {
+        Recorder::for_dirs(&RECORDED_DIRS.map(Utf8Path::new))
+    }
+
+    /// Records the files below `roots`.
+    pub fn for_dirs(roots: &[&Utf8Path]) -> Result<Recorder, InstrumentError> {
+        let fanotify = Fanotify::init(
+            InitFlags::FAN_CLASS_NOTIF
+                | InitFlags::FAN_CLOEXEC
+                | InitFlags::FAN_NONBLOCK
+                | InitFlags::from_bits_retain(libc::FAN_REPORT_DFID_NAME),
+            EventFFlags::O_RDONLY | EventFFlags::O_LARGEFILE | EventFFlags::O_CLOEXEC,
+        )
+        .map_err(InstrumentError::Init)?;
+        let mut recorder = Recorder {
+            fanotify,
+            dirs: HashMap::new(),
+            accessed: AccessedFiles {
+                complete: true,
+                files: BTreeMap::new(),
+            },
+        };
+        let mut pending: Vec<Utf8PathBuf> = roots.iter().map(|root| root.to_path_buf()).collect();
+        while let Some(dir) = pending.pop() {
+            recorder.mark(&dir)?;
+            let entries =
+                std::fs::read_dir(&dir).map_err(|e| InstrumentError::ListDir(dir.clone(), e))?;
+            for entry in entries {
+                let entry = entry.map_err(|e| InstrumentError::ListDir(dir.clone(), e))?;
+                // Symlinked directories are marked where they are, if at all.
+                let is_dir = entry
+                    .file_type()
+                    .map_err(|e| InstrumentError::ListDir(dir.clone(), e))?
+                    .is_dir();
+                if is_dir {
+                    match Utf8PathBuf::try_from(entry.path()) {
+                        Ok(path) => pending.push(path),
+                        Err(e) => {
+                            debug!("not recording accesses in {:?}", e.as_path());
+                            recorder.accessed.complete = false;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(recorder)
+    }
+
+    fn mark(&mut self, dir: &Utf8Path) -> Result<(), InstrumentError> {
+        let mask = MaskFlags::FAN_OPEN_EXEC
+            | MaskFlags::FAN_CLOSE_WRITE
+            | MaskFlags::FAN_CLOSE_NOWRITE
+            | MaskFlags::FAN_EVENT_ON_CHILD;
+        self.fanotify
+            .mark(MarkFlags::FAN_MARK_ADD, mask, None, Some(dir.as_std_path()))
+            .map_err(|e| InstrumentError::Mark(dir.to_path_buf(), e))?;
+        let handle = dir_handle(dir).map_err(|e| InstrumentError::Mark(dir.to_path_buf(), e))?;
+        self.dirs.insert(handle, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Records accesses until `child` exits and returns its exit status.
+    pub fn wait(&mut self, child: &mut Child) -> Result<ExitStatus, InstrumentError> {
+        loop {
+            let mut fds = [PollFd::new(self.fanotify.as_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, PollTimeout::from(POLL_TIMEOUT_MS)) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(e) => return Err(InstrumentError::Poll(e)),
+            }
+            self.drain()?;
+            if let Some(status) = child.try_wait().map_err(InstrumentError::Wait)? {
+                // Events of the last moments may still be queued.
+                self.drain()?;
+                return Ok(status);
+            }
+        }
+    }
+
+    /// The files accessed so far.
+    pub fn finish(self) -> AccessedFiles {
+        self.accessed
+    }
+
+    fn drain(&mut self) -> Result<(), InstrumentError> {
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = match nix::unistd::read(self.fanotify.as_fd().as_raw_fd(), &mut buffer) {
+                Ok(read) => read,
+                Err(Errno::EAGAIN) => return Ok(()),
+                Err(e) => return Err(InstrumentError::ReadEvents(e)),
+            };
+            let mut events = &buffer[..read];
+            while events.len() >= EVENT_METADATA_LEN {
+                let event_len =
+                    (u32_at(events, 0) as usize).clamp(EVENT_METADATA_LEN, events.len());
+                self.record(&events[..event_len]);
+                events = &events[event_len..];
+            }
+        }
+    }
+
+    /// Records one event: `fanotify_event_metadata` followed by info records.
+    fn record(&mut self, event: &[u8]) {
+        let metadata_len = usize::from(u16::from_ne_bytes([event[6], event[7]]));
+        let mask = MaskFlags::from_bits_retain(u64::from_ne_bytes(
+            event[8..16].try_into().expect("8 bytes"),
+        ));
+        let pid = u32_at(event, 20) as i32;
+        if mask.contains(MaskFlags::FAN_Q_OVERFLOW) {
+            warn!("fanotify queue overflowed, accessed files are incomplete");
+            self.accessed.complete = false;
+            return;
+        }
+        // Unprivileged groups only learn their own pid, which is never a step.
+        if pid == std::process::id() as i32 {
+            return;
+        }
+        let mut infos = event.get(metadata_len..).unwrap_or_default();
+        while infos.len() >= 4 {
+            let info_len =
+                usize::from(u16::from_ne_bytes([infos[2], infos[3]])).clamp(4, infos.len());
+            let (info, rest) = infos.split_at(info_len);
+            infos = rest;
+            if info[0] != libc::FAN_EVENT_INFO_TYPE_DFID_NAME {
+                continue;
+            }
+            let Some(path) = self.resolve(info) else {
+                self.accessed.complete = false;
+                continue;
+            };
+            let Some(path) = path else {
+                continue;
+            };
+            let accesses = self.accessed.files.entry(path).or_default();
+            if mask.contains(MaskFlags::FAN_OPEN_EXEC) {
+                accesses.insert(Access::Exec);
+            }
+            if mask.contains(MaskFlags::FAN_CLOSE_WRITE) {
+                accesses.insert(Access::Write);
+            }
+            if mask.contains(MaskFlags::FAN_CLOSE_NOWRITE) {
+                accesses.insert(Access::Read);
+            }
+        }
+    }
+
+    /// The path of a `fanotify_event_info_fid` record naming a file by its directory,
+    /// `Some(None)` for events on the directory itself and `None` if it is unknown.
+    fn resolve(&self, info: &[u8]) -> Option<Option<Utf8PathBuf>> {
+        let fsid: [u8; 8] = info.get(4..12)?.try_into().ok()?;
+        let handle_len = u32_at(info.get(12..16)?, 0) as usize;
+        let handle_type = u32_at(info.get(16..20)?, 0) as i32;
+        let handle = info.get(20..20 + handle_len)?;
+        let name = info.get(20 + handle_len..)?;
+        let name = &name[..name.iter().position(|&b| b == 0)?];
+        let dir = self.dirs.get(&DirHandle {
+            fsid,
+            handle_type,
+            handle: handle.to_vec(),
+        });
+        let Some(dir) = dir else {
+            debug!("access in an unmarked directory");
+            return None;
+        };
+        match std::str::from_utf8(name).ok()? {
+            "." => Some(None),
+            name => Some(Some(dir.join(name))),
+        }
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// The handle fanotify events identify `dir` with.
+fn dir_handle(dir: &Utf8Path) -> Result<DirHandle, Errno> {
+    let stat = statfs(dir.as_std_path())?;
+    // SAFETY: `fsid_t` is two `int`s, as in the event records.
+    let fsid: [u8; 8] = unsafe { std::mem::transmute(stat.filesystem_id()) };
+    let path = CString::new(dir.as_std_path().as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+
+    // `struct file_handle` followed by room for the largest handle.
+    let mut buffer = [0u32; 2 + libc::MAX_HANDLE_SZ as usize / 4];
+    let mut mount_id = 0;
+    let mut encode = |flags| {
+        buffer[0] = libc::MAX_HANDLE_SZ as u32;
+        // SAFETY: `buffer` is aligned for and large enough for a `file_handle`
+        // with `handle_bytes` bytes.
+        let res = unsafe {
+            libc::name_to_handle_at(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                buffer.as_mut_ptr().cast(),
+                &mut mount_id,
+                flags,
+            )
+        };
+        Errno::result(res)
+    };
+    // Handles that only identify files are what fanotify reports, but older kernels
+    // do not know the flag and encode the same handles without it.
+    match encode(libc::AT_HANDLE_FID) {
+        Err(Errno::EINVAL) => encode(0)?,
+        res => res?,
+    };
+    let handle_len = (buffer[0] as usize).min(libc::MAX_HANDLE_SZ as usize);
+    let bytes: Vec<u8> = buffer[2..]
+        .iter()
+        .flat_map(|word| word.to_ne_bytes())
+        .collect();
+    Ok(DirHandle {
+        fsid,
+        handle_type: buffer[1] as i32,
+        handle: bytes[..handle_len].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessed_files_round_trip() {
+        let accessed = AccessedFiles {
+            complete: true,
+            files: BTreeMap::from([
+                (
+                    "/bin/cc".into(),
+                    BTreeSet::from([Access::Read, Access::Exec]),
+                ),
+                ("/build/pkg/main.o".into(), BTreeSet::from([Access::Write])),
+                ("/source/pkg/main.c".into(), BTreeSet::from([Access::Read])),
+            ]),
+        };
+        let json = serde_json::to_string(&accessed).unwrap();
+        assert!(json.contains(r#""/bin/cc":["read","exec"]"#));
+        assert_eq!(
+            serde_json::from_str::<AccessedFiles>(&json).unwrap(),
+            accessed
+        );
+
+        let sources: Vec<&Utf8Path> = accessed.below(SANDBOX_SOURCE_DIR.into()).collect();
+        assert_eq!(sources, [Utf8Path::new("pkg/main.c")]);
+    }
+
+    #[test]
+    fn records_accesses_below_marked_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::create_dir(root.join("pkg")).unwrap();
+        std::fs::write(root.join("pkg/used.c"), "used").unwrap();
+        std::fs::write(root.join("pkg/unused.c"), "unused").unwrap();
+
+        let mut recorder = Recorder::for_dirs(&[root]).unwrap();
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("cat pkg/used.c > pkg/out.o")
+            .current_dir(root)
+            .spawn()
+            .unwrap();
+        assert!(recorder.wait(&mut child).unwrap().success());
+        let accessed = recorder.finish();
+
+        assert!(accessed.complete);
+        let files: Vec<&Utf8Path> = accessed.below(root).collect();
+        assert_eq!(files, [Utf8Path::new("pkg/out.o"), "pkg/used.c".into()]);
+        assert_eq!(
+            accessed.files[&root.join("pkg/used.c")],
+            BTreeSet::from([Access::Read])
+        );
+        assert_eq!(
+            accessed.files[&root.join("pkg/out.o")],
+            BTreeSet::from([Access::Write])
+        );
+    }
+}
@@ -1,10 +1,12 @@
 use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs::{File, create_dir_all};
 use std::io::Read;
 use std::os::fd::{BorrowedFd, RawFd};
 use std::os::unix::process::CommandExt;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
 
 use anyhow::anyhow;
 use camino::Utf8PathBuf;
@@ -14,14 +16,15 @@ use nix::libc::{setresgid, setresuid};
 use nix::sched::CloneFlags;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::debug;
 use tracing::instrument;
-use tracing::{debug, error};
 use tracing_log::log::info;
 use uuid::Uuid;
 
 mod subid;
 
 pub mod identity;
+pub mod instrumentation;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Action {
@@ -30,6 +33,9 @@ pub struct Action {
     /// build directory, process is allowed to write here but only in declared paths. (not enforced at first)
     pub build: Utf8PathBuf,
     pub exec_steps: Vec<Exec>,
+    /// Record the files accessed by the steps in [`instrumentation::ACCESSED_JSON_FILE_NAME`].
+    #[serde(default)]
+    pub instrument: bool,
 }
 
 impl Default for Action {
@@ -38,6 +44,7 @@ impl Default for Action {
             source: directories::workspace_dir().to_owned(),
             build: directories::build_dir().to_owned(),
             exec_steps: vec![Exec::default()],
+            instrument: false,
         }
     }
 }
@@ -74,6 +81,9 @@ pub enum SpawnError {
     #[error("Writing exec JSON: {0}")]
     WriteExecJson(#[source] serde_json::Error),
 
+    #[error("No action to debug in {0:?}")]
+    MissingAction(PathBuf),
+
     #[error("Failed to wait for process: {0}")]
     ProcessWait(#[from] std::io::Error),
 }
@@ -93,7 +103,6 @@ fn zaun_exe() -> String {
 
     #[cfg(any(test, feature = "testing"))]
     {
-        use std::path::PathBuf;
         let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
         debug!(
             "finding zaun executable in test mode. manifest_dir: {manifest_dir} current_dir: {:?}",
@@ -113,29 +122,117 @@ fn zaun_exe() -> String {
     }
 }
 
+/// Where [`Action::source`] is mounted within the sandbox.
+pub const SANDBOX_SOURCE_DIR: &str = "/source";
+/// Where [`Action::build`] is mounted within the sandbox.
+pub const SANDBOX_BUILD_DIR: &str = "/build";
+
 pub const ACTION_JSON_FILE_NAME: &str = "action.json";
+/// Written by `zaun exec` once the action finished or could not run.
+pub const RESULT_JSON_FILE_NAME: &str = "result.json";
+
+/// How the action in an exec directory ended.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecResult {
+    /// The index of the step that failed, if any.
+    pub failed_step: Option<usize>,
+    /// The exit code of the last step that ran, unless it was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// How long the steps ran, without setting up the sandbox.
+    #[serde(default)]
+    pub execution: Duration,
+    /// Why the steps could not run to the end, e.g. the sandbox could not be set up
+    /// or the failed step could not be spawned.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ExecResult {
+    /// Reads the `result.json` of `exec_dir`.
+    pub fn read(exec_dir: &Path) -> std::io::Result<ExecResult> {
+        let bytes = std::fs::read(exec_dir.join(RESULT_JSON_FILE_NAME))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
 
 /// Implementation of `zaun spawn`.
-/// Spans a `zaun exec` command in a new user namespace.
+/// Spans a `zaun exec` command in a new user namespace
+/// and returns its exit status.
 #[instrument]
-pub fn spawn(exec_dir: &Path, action: &Action) -> Result<(), SpawnError> {
-    let user_ns_fd = create_user_namespace().map_err(SpawnError::CreateUserNamespace)?;
+pub fn spawn(exec_dir: &Path, action: &Action) -> Result<ExitStatus, SpawnError> {
+    write_action(exec_dir, action)?;
+    run_in_user_namespace([OsStr::new("exec"), OsStr::new("--"), exec_dir.as_os_str()])
+}
 
-    debug!("user_ns_fd: {user_ns_fd}");
+/// Like [spawn], but returns the running `zaun exec` process without waiting for it.
+/// Its stdout and stderr, which include the output of the steps, are piped, stdin is null.
+#[instrument]
+pub fn spawn_piped(exec_dir: &Path, action: &Action) -> Result<Child, SpawnError> {
+    write_action(exec_dir, action)?;
+    start_in_user_namespace(
+        [OsStr::new("exec"), OsStr::new("--"), exec_dir.as_os_str()],
+        [Stdio::null(), Stdio::piped(), Stdio::piped()],
+    )
+}
 
+fn write_action(exec_dir: &Path, action: &Action) -> Result<(), SpawnError> {
     create_dir_all(exec_dir).map_err(SpawnError::CreateExecJson)?;
     let exe_json_path = exec_dir.join(ACTION_JSON_FILE_NAME);
 
     let exe_json_file = File::create_new(&exe_json_path).map_err(SpawnError::CreateExecJson)?;
-    serde_json::to_writer_pretty(exe_json_file, &action).map_err(SpawnError::WriteExecJson)?;
+    serde_json::to_writer_pretty(exe_json_file, &action).map_err(SpawnError::WriteExecJson)
+}
+
+/// Implementation of `zaun debug`.
+/// Spawns a `zaun exec-debug` command in a new user namespace, which re-enters
+/// the sandbox of the action in `exec_dir` and runs `command` or an interactive shell.
+/// Returns its exit status.
+#[instrument]
+pub fn debug(exec_dir: &Path, command: &[String]) -> Result<ExitStatus, SpawnError> {
+    if !exec_dir.join(ACTION_JSON_FILE_NAME).is_file() {
+        return Err(SpawnError::MissingAction(exec_dir.to_owned()));
+    }
+    let args = [
+        OsStr::new("exec-debug"),
+        OsStr::new("--"),
+        exec_dir.as_os_str(),
+    ]
+    .into_iter()
+    .chain(command.iter().map(OsStr::new));
+    run_in_user_namespace(args)
+}
+
+/// Runs zaun with `args` in a new user namespace, with a minimal environment.
+fn run_in_user_namespace<I>(args: I) -> Result<ExitStatus, SpawnError>
+where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+{
+    let mut child =
+        start_in_user_namespace(args, [Stdio::inherit(), Stdio::inherit(), Stdio::inherit()])?;
+
+    let exit_status = child.wait()?;
+
+    Ok(exit_status)
+}
+
+/// Starts zaun with `args` in a new user namespace, with a minimal environment
+/// and `[stdin, stdout, stderr]`.
+fn start_in_user_namespace<I>(args: I, stdio: [Stdio; 3]) -> Result<Child, SpawnError>
+where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+{
+    let [stdin, stdout, stderr] = stdio;
+    let user_ns_fd = create_user_namespace().map_err(SpawnError::CreateUserNamespace)?;
+
+    debug!("user_ns_fd: {user_ns_fd}");
 
     let zaun_exe = zaun_exe();
     info!("zaun_exe: {zaun_exe}");
     let mut command = Command::new(zaun_exe);
     let command = command
-        .arg("exec")
-        .arg("--")
-        .arg(exec_dir)
+        .args(args)
         .env_clear()
         .env("USER", "root")
         .env("TERM", "xterm-256color")
@@ -143,9 +240,9 @@ pub fn spawn(exec_dir: &Path, action: &Action) -> Result<(), SpawnError> {
         .env("PATH", "/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin")
         // FIXME:  Control by verbosity mode or similar?
         .env("RUST_LOG", "debug")
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr);
 
     unsafe {
         command.pre_exec(move || {
@@ -173,11 +270,7 @@ pub fn spawn(exec_dir: &Path, action: &Action) -> Result<(), SpawnError> {
         });
     }
 
-    let mut child = command.spawn().map_err(SpawnError::ProcessSpawn)?;
-
-    child.wait()?;
-
-    Ok(())
+    command.spawn().map_err(SpawnError::ProcessSpawn)
 }
 
 #[derive(Debug, Error)]
@@ -253,7 +346,7 @@ mod tests {
     #[test]
     fn test_spawn() {
         let exec_dir = tempfile::tempdir().unwrap();
-        spawn(
+        let exit_status = spawn(
             exec_dir.as_ref(),
             &Action {
                 // FIXME: no overlap
@@ -266,5 +359,6 @@ mod tests {
             },
         )
         .unwrap();
+        assert!(exit_status.success());
     }
 }
@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::process::{Command, ExitStatus, Stdio};
+use std::time::Instant;
 
 use anyhow::{Context, anyhow};
 use bpaf::Bpaf;
@@ -13,13 +14,14 @@ use nix::unistd::{gethostname, pivot_root};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use sys_mount::{Mount, MountFlags};
 use thiserror::Error;
 use tracing::{debug, instrument};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use zaun::identity::{Groups, NameAndId};
-use zaun::{ACTION_JSON_FILE_NAME, new_exec_dir};
+use zaun::instrumentation::{ACCESSED_JSON_FILE_NAME, InstrumentError, Recorder};
+use zaun::{ACTION_JSON_FILE_NAME, ExecResult, RESULT_JSON_FILE_NAME, new_exec_dir};
 
 #[derive(Debug, Clone, Bpaf)]
 #[bpaf(options, version)]
@@ -45,6 +47,25 @@ enum Action {
         #[bpaf(positional("EXEC_DIR"))]
         exec_dir: Utf8PathBuf,
     },
+    /// Re-enters the sandbox of an action that ran before, e.g. a failed one,
+    /// with the same mounts, namespaces and environment. Runs an interactive
+    /// shell, or the given command, where the failed step ran.
+    #[bpaf(command)]
+    Debug {
+        #[bpaf(positional("EXEC_DIR"))]
+        exec_dir: Utf8PathBuf,
+        #[bpaf(positional("CMD"))]
+        command: Vec<String>,
+    },
+    /// Sets up the sandbox environment of an action that ran before
+    /// within the user namespace and runs the given command, or a shell.
+    #[bpaf(command)]
+    ExecDebug {
+        #[bpaf(positional("EXEC_DIR"))]
+        exec_dir: Utf8PathBuf,
+        #[bpaf(positional("CMD"))]
+        command: Vec<String>,
+    },
     /// Sets up a new user namespace with subid ranges.
     #[bpaf(command)]
     SetupUserNs {},
@@ -105,6 +126,14 @@ pub enum ExecError {
     ReadConfig(#[source] std::io::Error),
     #[error("Wwhile reading config from stdin: {0}")]
     ParseConfig(#[source] serde_json::Error),
+    #[error("While writing the result: {0}")]
+    WriteResult(#[source] std::io::Error),
+    #[error("While reading the result of the previous run: {0}")]
+    ReadResult(#[source] std::io::Error),
+    #[error("While writing the accessed files: {0}")]
+    WriteAccessed(#[source] std::io::Error),
+    #[error("While recording accessed files: {0}")]
+    Record(#[source] InstrumentError),
     #[error("While creating temporary directory for root: {0}")]
     CreateTempRootDir(#[source] std::io::Error),
     #[error("While setting host name: {0:?}")]
@@ -133,8 +162,12 @@ fn valid_overlayfs_path(path: &Utf8Path) -> Result<(), ExecError> {
         .ok_or_else(|| ExecError::InvalidOverlayfsLayerPath(path.to_owned()))
 }
 
-#[instrument]
-fn exec_command(exec_dir: &Utf8Path) -> Result<ExitStatus, ExecError> {
+/// Steps run here, within the sandbox.
+const STEP_WORKING_DIR: &str = zaun::SANDBOX_SOURCE_DIR;
+/// Started by `zaun debug` unless a command is given.
+const DEBUG_SHELL: &str = "/bin/sh";
+
+fn read_action(exec_dir: &Utf8Path) -> Result<zaun::Action, ExecError> {
     let exec_json = exec_dir.join(ACTION_JSON_FILE_NAME);
 
     let mut buffer = String::new();
@@ -142,8 +175,189 @@ fn exec_command(exec_dir: &Utf8Path) -> Result<ExitStatus, ExecError> {
     file.read_to_string(&mut buffer)
         .map_err(ExecError::ReadConfig)?;
 
-    let action: zaun::Action = serde_json::from_str(&buffer).map_err(ExecError::ParseConfig)?;
+    serde_json::from_str(&buffer).map_err(ExecError::ParseConfig)
+}
+
+fn create_dir(dir: impl AsRef<Utf8Path>) -> anyhow::Result<Utf8PathBuf> {
+    let dir = dir.as_ref();
+    std::fs::create_dir(dir).with_context(|| format!("while creating {dir:?}"))?;
+    Ok(dir.to_path_buf())
+}
+
+/// The command running `exec` within the sandbox.
+fn step_command(exec: &zaun::Exec) -> Command {
+    let mut command = Command::new(&exec.cmd);
+    command
+        .current_dir(STEP_WORKING_DIR)
+        .args(&exec.args)
+        .envs(&exec.env)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    command
+}
+
+#[instrument]
+fn exec_command(exec_dir: &Utf8Path) -> Result<ExitStatus, ExecError> {
+    let action = read_action(exec_dir)?;
+    // The exec dir is out of reach once the sandbox is entered.
+    let mut result_file = std::fs::File::create(exec_dir.join(RESULT_JSON_FILE_NAME))
+        .map_err(ExecError::WriteResult)?;
+    let accessed_file = action
+        .instrument
+        .then(|| std::fs::File::create(exec_dir.join(ACCESSED_JSON_FILE_NAME)))
+        .transpose()
+        .map_err(ExecError::WriteAccessed)?;
+
+    let mut result = ExecResult::default();
+    let ran = enter_sandbox(exec_dir, &action, false).and_then(|()| {
+        let mut recorder = match accessed_file {
+            Some(_) => Recorder::new()
+                .inspect_err(|e| warn!("not recording accessed files: {e}"))
+                .ok(),
+            None => None,
+        };
+        let exit_status = run_steps(&action.exec_steps, recorder.as_mut(), &mut result)?;
+        Ok((exit_status, recorder))
+    });
+    // Also written if the steps could not run, since those are the runs to debug.
+    if let Err(e) = &ran {
+        result.error = Some(e.to_string());
+    }
+    serde_json::to_writer_pretty(&mut result_file, &result)
+        .map_err(|e| ExecError::WriteResult(e.into()))?;
+    let (exit_status, recorder) = ran?;
+    if let Some(accessed_file) = accessed_file {
+        let accessed = recorder.map(Recorder::finish).unwrap_or_default();
+        serde_json::to_writer_pretty(accessed_file, &accessed)
+            .map_err(|e| ExecError::WriteAccessed(e.into()))?;
+    }
+    Ok(exit_status)
+}
+
+/// Runs `steps` until one fails, noting in `result` which one and how.
+/// A step that cannot be spawned or waited for counts as failed, too.
+fn run_steps(
+    steps: &[zaun::Exec],
+    mut recorder: Option<&mut Recorder>,
+    result: &mut ExecResult,
+) -> Result<ExitStatus, ExecError> {
+    let started = Instant::now();
+    let mut exit_status = ExitStatus::default();
+    result.exit_code = Some(0);
+    for (index, exec) in steps.iter().enumerate() {
+        let waited = step_command(exec)
+            .spawn()
+            .map_err(ExecError::Spawn)
+            .and_then(|mut child| match &mut recorder {
+                Some(recorder) => recorder.wait(&mut child).map_err(ExecError::Record),
+                None => child.wait().map_err(ExecError::Wait),
+            });
+        result.execution = started.elapsed();
+        exit_status = waited.inspect_err(|_| {
+            result.failed_step = Some(index);
+            result.exit_code = None;
+        })?;
+        result.exit_code = exit_status.code();
+
+        if !exit_status.success() {
+            result.failed_step = Some(index);
+            break;
+        }
+    }
+    Ok(exit_status)
+}
+
+/// The result of the previous run in `exec_dir`.
+fn previous_result(exec_dir: &Utf8Path) -> Result<ExecResult, ExecError> {
+    match ExecResult::read(exec_dir.as_std_path()) {
+        Ok(result) => Ok(result),
+        // The previous run did not finish, e.g. it was killed, so the last step is as good as any.
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::UnexpectedEof) => {
+            Ok(ExecResult::default())
+        }
+        Err(e) => Err(ExecError::ReadResult(e)),
+    }
+}
+
+/// Implementation of `zaun exec-debug`, called from [zaun::debug].
+#[instrument]
+fn exec_debug_command(exec_dir: &Utf8Path, command: &[String]) -> Result<ExitStatus, ExecError> {
+    let action = read_action(exec_dir)?;
+    let result = previous_result(exec_dir)?;
+    if let Some(error) = &result.error {
+        info!("the previous run failed: {error}");
+    }
+    let index = result
+        .failed_step
+        .or_else(|| action.exec_steps.len().checked_sub(1));
+    let step = index
+        .and_then(|index| action.exec_steps.get(index))
+        .cloned()
+        .unwrap_or_default();
+    if let Some(index) = index {
+        info!(
+            "step {index} ran {:?} with {:?}, exit code {:?}",
+            step.cmd, step.args, result.exit_code
+        );
+    }
 
+    enter_sandbox(exec_dir, &action, true)?;
+
+    let (cmd, args) = match command.split_first() {
+        Some((cmd, args)) => (cmd.clone(), args.to_vec()),
+        None => (DEBUG_SHELL.to_string(), Vec::new()),
+    };
+    step_command(&zaun::Exec { cmd, args, ..step })
+        .spawn()
+        .map_err(ExecError::Spawn)?
+        .wait()
+        .map_err(ExecError::Wait)
+}
+
+/// Creates the output, overlayfs work and root directories of the sandbox below `exec_dir`.
+///
+/// With `reenter`, the ones a previous run got as far as creating are reused.
+fn sandbox_dirs(
+    exec_dir: &Utf8Path,
+    reenter: bool,
+) -> anyhow::Result<(Utf8PathBuf, Utf8PathBuf, Utf8PathBuf)> {
+    if !reenter {
+        return Ok((
+            create_dir(exec_dir.join("out"))?,
+            create_dir(exec_dir.join("work"))?,
+            create_dir(exec_dir.join("root"))?,
+        ));
+    }
+    // The work dir is scratch space of overlayfs, which refuses to reuse it
+    // after a volatile mount.
+    let work_dir = exec_dir.join("work");
+    match std::fs::remove_dir_all(&work_dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("while removing {work_dir:?}"));
+        }
+        _ => {}
+    }
+    let reuse_dir = |dir: Utf8PathBuf| {
+        std::fs::create_dir_all(&dir).with_context(|| format!("while creating {dir:?}"))?;
+        Ok::<_, anyhow::Error>(dir)
+    };
+    Ok((
+        reuse_dir(exec_dir.join("out"))?,
+        create_dir(work_dir)?,
+        reuse_dir(exec_dir.join("root"))?,
+    ))
+}
+
+/// Unshares the namespaces and sets up the mounts of the sandbox,
+/// pivoting into the root below `exec_dir`.
+///
+/// With `reenter`, the directories of a previous run are reused, so its writes are visible.
+fn enter_sandbox(
+    exec_dir: &Utf8Path,
+    action: &zaun::Action,
+    reenter: bool,
+) -> Result<(), ExecError> {
     // FIXME: Change to the correct userid, groupid and capabilities.
 
     let euid = nix::unistd::geteuid().as_raw();
@@ -162,15 +376,7 @@ fn exec_command(exec_dir: &Utf8Path) -> Result<ExitStatus, ExecError> {
 
     nix::unistd::sethostname("zack").map_err(ExecError::SetHostName)?;
 
-    fn create_dir(dir: impl AsRef<Utf8Path>) -> anyhow::Result<Utf8PathBuf> {
-        let dir = dir.as_ref();
-        std::fs::create_dir(dir).with_context(|| format!("while creating {dir:?}"))?;
-        Ok(dir.to_path_buf())
-    }
-
-    let output_dir = create_dir(exec_dir.join("out"))?;
-    let work_dir = create_dir(exec_dir.join("work"))?;
-    let new_combined_root_dir = create_dir(exec_dir.join("root"))?;
+    let (output_dir, work_dir, new_combined_root_dir) = sandbox_dirs(exec_dir, reenter)?;
 
     let tmp_root_setup = Utf8PathBuf::from("/tmp");
     let root_sub_dir = |name: &str| {
@@ -265,24 +471,7 @@ fn exec_command(exec_dir: &Utf8Path) -> Result<ExitStatus, ExecError> {
 
     // FIXME: Setup various namespaces.
 
-    for exec in &action.exec_steps {
-        let exit_status = Command::new(&exec.cmd)
-            .current_dir("/source")
-            .args(&exec.args)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn()
-            .map_err(ExecError::Spawn)?
-            .wait()
-            .map_err(ExecError::Wait)?;
-
-        if !exit_status.success() {
-            return Ok(exit_status);
-        }
-    }
-
-    Ok(ExitStatus::default())
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -395,13 +584,16 @@ fn main() -> Result<(), Error> {
     match &options.action {
         Action::Spawn { exec } => {
             let exec_dir = new_exec_dir();
-            zaun::spawn(
+            let exit_status = zaun::spawn(
                 exec_dir.as_std_path(),
                 &zaun::Action {
                     exec_steps: vec![exec.clone().into()],
                     ..Default::default()
                 },
-            )?
+            )?;
+            if !exit_status.success() {
+                std::process::exit(exit_status.code().unwrap_or(1));
+            }
         }
         Action::Exec { exec_dir } => {
             let exit_status = exec_command(exec_dir)?;
@@ -412,6 +604,14 @@ fn main() -> Result<(), Error> {
                 std::process::exit(exit_status.code().unwrap_or(1));
             }
         }
+        Action::Debug { exec_dir, command } => {
+            let exit_status = zaun::debug(exec_dir.as_std_path(), command)?;
+            std::process::exit(exit_status.code().unwrap_or(1));
+        }
+        Action::ExecDebug { exec_dir, command } => {
+            let exit_status = exec_debug_command(exec_dir, command)?;
+            std::process::exit(exit_status.code().unwrap_or(1));
+        }
         Action::SetupUserNs {} => setup_user_ns().map_err(Error::SetupUserNs)?,
 
         Action::Probe {} => print_probe().map_err(Error::Probe)?,
@@ -419,3 +619,48 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_reenters_after_a_failed_spawn() -> anyhow::Result<()> {
+        let exec_dir = tempfile::tempdir()?;
+        let exec_dir = Utf8Path::from_path(exec_dir.path()).unwrap();
+        // The failed run got as far as creating the output dir.
+        create_dir(exec_dir.join("out"))?;
+
+        let steps = [zaun::Exec {
+            cmd: "/nonexistent/cmd".into(),
+            ..Default::default()
+        }];
+        let mut result = ExecResult::default();
+        let e = run_steps(&steps, None, &mut result).unwrap_err();
+        assert!(matches!(e, ExecError::Spawn(_)), "{e}");
+        result.error = Some(e.to_string());
+        std::fs::write(
+            exec_dir.join(RESULT_JSON_FILE_NAME),
+            serde_json::to_vec(&result)?,
+        )?;
+
+        let previous = previous_result(exec_dir)?;
+        assert_eq!(previous.failed_step, Some(0));
+        assert_eq!(previous.exit_code, None);
+        assert!(previous.error.is_some());
+
+        let (out, work, root) = sandbox_dirs(exec_dir, true)?;
+        assert!(out.is_dir() && work.is_dir() && root.is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn unfinished_result_counts_as_missing() -> anyhow::Result<()> {
+        let exec_dir = tempfile::tempdir()?;
+        let exec_dir = Utf8Path::from_path(exec_dir.path()).unwrap();
+        assert_eq!(previous_result(exec_dir)?.failed_step, None);
+        std::fs::File::create(exec_dir.join(RESULT_JSON_FILE_NAME))?;
+        assert_eq!(previous_result(exec_dir)?.failed_step, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Recording what the action keys of commands were derived from, per build config.
+
+use anyhow::{Result, anyhow};
+use model::Digest;
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter};
+
+use crate::{db::Db, entity::action, model::BuildConfigId};
+
+/// Access to the key components and outputs of the last successful run of each command.
+///
+/// The components are stored as the caller serialized them, to compare them
+/// with the current ones when a command runs again unexpectedly.
+#[allow(async_fn_in_trait)]
+pub trait ActionsDAO {
+    /// Records the key components of the command `label` of `config`, replacing earlier ones.
+    async fn record_key_components(
+        &mut self,
+        config: BuildConfigId,
+        label: &str,
+        key_components: &str,
+    ) -> Result<()>;
+
+    /// Returns the key components recorded for the command `label` of `config`.
+    async fn key_components(
+        &mut self,
+        config: BuildConfigId,
+        label: &str,
+    ) -> Result<Option<String>>;
+
+    /// Records the `entry_hash` of the stored listing of the outputs of the command `label`
+    /// of `config`, whose key components must be recorded already.
+    async fn record_action_outputs(
+        &mut self,
+        config: BuildConfigId,
+        label: &str,
+        outputs: Digest,
+    ) -> Result<()>;
+}
+
+impl ActionsDAO for Db {
+    async fn record_key_components(
+        &mut self,
+        config: BuildConfigId,
+        label: &str,
+        key_components: &str,
+    ) -> Result<()> {
+        let existing = action::Entity::find()
+            .filter(action::Column::BuildConfigId.eq(config.0))
+            .filter(action::Column::Label.eq(label))
+            .one(self.connection())
+            .await?;
+        match existing {
+            Some(existing) => {
+                let mut active: action::ActiveModel = existing.into();
+                active.key_components = ActiveValue::Set(key_components.to_string());
+                active.update(self.connection()).await?;
+            }
+            None => {
+                action::ActiveModel {
+                    build_config_id: ActiveValue::Set(config.0),
+                    label: ActiveValue::Set(label.to_string()),
+                    key_components: ActiveValue::Set(key_components.to_string()),
+                    ..Default::default()
+                }
+                .insert(self.connection())
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn key_components(
+        &mut self,
+        config: BuildConfigId,
+        label: &str,
+    ) -> Result<Option<String>> {
+        let action = action::Entity::find()
+            .filter(action::Column::BuildConfigId.eq(config.0))
+            .filter(action::Column::Label.eq(label))
+            .one(self.connection())
+            .await?;
+        Ok(action.map(|m| m.key_components))
+    }
+
+    async fn record_action_outputs(
+        &mut self,
+        config: BuildConfigId,
+        label: &str,
+        outputs: Digest,
+    ) -> Result<()> {
+        let existing = action::Entity::find()
+            .filter(action::Column::BuildConfigId.eq(config.0))
+            .filter(action::Column::Label.eq(label))
+            .one(self.connection())
+            .await?
+            .ok_or_else(|| anyhow!("no key components recorded for {label}"))?;
+        let mut active: action::ActiveModel = existing.into();
+        active.outputs = ActiveValue::Set(Some(outputs));
+        active.update(self.connection()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_config::BuildConfigsDAO;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn key_components_are_per_config() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let default = db.get_default_build_config().await?.get().id;
+        let release = db.create_build_config("release").await?.id;
+        db.record_key_components(release, "//pkg:main", "first")
+            .await?;
+        db.record_key_components(release, "//pkg:main", "second")
+            .await?;
+
+        assert_eq!(
+            db.key_components(release, "//pkg:main").await?,
+            Some("second".to_string())
+        );
+        assert_eq!(db.key_components(default, "//pkg:main").await?, None);
+
+        db.delete_build_config(release).await?;
+        assert_eq!(db.key_components(release, "//pkg:main").await?, None);
+        Ok(())
+    }
+}
@@ -1,18 +1,39 @@
 use std::sync::Arc;
 
 use ahash::AHashMap;
-use sea_orm::{ActiveModelTrait, EntityTrait};
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter};
+use thiserror::Error;
 
 use crate::{
     db::{Db, DbHelper},
-    entity::build_config,
+    entity::{action, build_config, file},
     model::{BuildConfig, BuildConfigId},
 };
 use anyhow::Result;
 
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildConfigError {
+    #[error("no build config with id {0:?}")]
+    UnknownId(BuildConfigId),
+    #[error("no build config called {0:?}")]
+    UnknownName(String),
+    #[error("a build config called {0:?} already exists")]
+    DuplicateName(String),
+    #[error(
+        "the {:?} build config cannot be renamed or removed",
+        BuildConfig::DEFAULT_NAME
+    )]
+    DefaultConfig,
+    #[error("the build config {0:?} has outputs in its directory, remove it instead of renaming")]
+    HasOutputs(String),
+}
+
+/// All build configs, loaded once and dropped by [`Db::invalidate_helper`] on changes.
 #[derive(Default)]
 struct BuildConfigCache {
     configs: AHashMap<BuildConfigId, BuildConfig>,
+    by_name: AHashMap<String, BuildConfigId>,
 }
 
 impl DbHelper for BuildConfigCache {
@@ -21,62 +42,287 @@ impl DbHelper for BuildConfigCache {
 
         let conn = db.connection();
 
-        let configs = BuildConfig::find().all(conn).await?;
+        let mut configs = BuildConfig::find().all(conn).await?;
 
-        if configs.is_empty() {
+        if !configs
+            .iter()
+            .any(|m| m.name == crate::model::BuildConfig::DEFAULT_NAME)
+        {
             let default_config = build_config::ActiveModel {
-                name: sea_orm::ActiveValue::Set("default".into()),
+                name: ActiveValue::Set(crate::model::BuildConfig::DEFAULT_NAME.into()),
                 ..Default::default()
             };
-            let result = default_config.insert(conn).await?;
-            let id = BuildConfigId(result.id);
-            let build_config = crate::model::BuildConfig {
-                id: id.clone(),
-                name: result.name,
-            };
-            self.configs.insert(id, build_config);
-        } else {
-            self.configs = configs
-                .into_iter()
-                .map(|m| {
-                    (
-                        BuildConfigId(m.id),
-                        crate::model::BuildConfig {
-                            id: BuildConfigId(m.id),
-                            name: m.name,
-                        },
-                    )
-                })
-                .collect();
+            configs.push(default_config.insert(conn).await?);
+        }
+
+        for m in configs {
+            let id = BuildConfigId(m.id);
+            self.by_name.insert(m.name.clone(), id);
+            self.configs.insert(
+                id,
+                crate::model::BuildConfig {
+                    id,
+                    name: m.name,
+                    pinned: m.pinned,
+                },
+            );
         }
 
         Ok(())
     }
 }
 
+/// A build config in the cache, known to exist when the cache was loaded.
 pub struct CacheAccess {
     cache: Arc<BuildConfigCache>,
     id: BuildConfigId,
 }
 
 impl CacheAccess {
-    pub fn get(&self) -> Option<&BuildConfig> {
-        self.cache.configs.get(&self.id)
+    pub fn get(&self) -> &BuildConfig {
+        self.cache
+            .configs
+            .get(&self.id)
+            .expect("checked when creating CacheAccess")
     }
 }
 
+#[allow(async_fn_in_trait)]
 pub trait BuildConfigsDAO {
     async fn get_default_build_config(&mut self) -> Result<CacheAccess> {
-        self.get_build_config(BuildConfigId::DEFAULT_CONFIG_ID)
-            .await
+        self.find_build_config(BuildConfig::DEFAULT_NAME)
+            .await?
+            .ok_or_else(|| BuildConfigError::UnknownName(BuildConfig::DEFAULT_NAME.into()).into())
     }
 
+    /// Returns the config with `id`, failing with [`BuildConfigError::UnknownId`] if there is none.
     async fn get_build_config(&mut self, id: BuildConfigId) -> Result<CacheAccess>;
+
+    async fn find_build_config(&mut self, name: &str) -> Result<Option<CacheAccess>>;
+
+    /// Returns all configs ordered by name.
+    async fn list_build_configs(&mut self) -> Result<Vec<BuildConfig>>;
+
+    /// Registers a new config, failing with [`BuildConfigError::DuplicateName`] if it exists.
+    async fn create_build_config(&mut self, name: &str) -> Result<BuildConfig>;
+
+    /// Returns the config called `name`, registering it if it is not known yet.
+    async fn ensure_build_config(&mut self, name: &str) -> Result<BuildConfig> {
+        match self.find_build_config(name).await? {
+            Some(existing) => Ok(existing.get().clone()),
+            None => self.create_build_config(name).await,
+        }
+    }
+
+    /// Renames the config, failing with [`BuildConfigError::HasOutputs`] once anything was
+    /// built for it, as its output directory is named after it.
+    async fn rename_build_config(&mut self, id: BuildConfigId, new_name: &str) -> Result<()>;
+
+    /// Pins or unpins the config, see [`BuildConfig::pinned`].
+    async fn set_build_config_pinned(&mut self, id: BuildConfigId, pinned: bool) -> Result<()>;
+
+    /// Removes the config and all files recorded for it.
+    async fn delete_build_config(&mut self, id: BuildConfigId) -> Result<()>;
 }
 
 impl BuildConfigsDAO for Db {
     async fn get_build_config(&mut self, id: BuildConfigId) -> Result<CacheAccess> {
         let cache: Arc<BuildConfigCache> = self.helper().await?;
+        if !cache.configs.contains_key(&id) {
+            return Err(BuildConfigError::UnknownId(id).into());
+        }
         Ok(CacheAccess { cache, id })
     }
+
+    async fn find_build_config(&mut self, name: &str) -> Result<Option<CacheAccess>> {
+        let cache: Arc<BuildConfigCache> = self.helper().await?;
+        Ok(cache
+            .by_name
+            .get(name)
+            .copied()
+            .map(|id| CacheAccess { cache, id }))
+    }
+
+    async fn list_build_configs(&mut self) -> Result<Vec<BuildConfig>> {
+        let cache: Arc<BuildConfigCache> = self.helper().await?;
+        let mut configs: Vec<BuildConfig> = cache.configs.values().cloned().collect();
+        configs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(configs)
+    }
+
+    async fn create_build_config(&mut self, name: &str) -> Result<BuildConfig> {
+        if self.find_build_config(name).await?.is_some() {
+            return Err(BuildConfigError::DuplicateName(name.into()).into());
+        }
+        let model = build_config::ActiveModel {
+            name: ActiveValue::Set(name.to_string()),
+            ..Default::default()
+        }
+        .insert(self.connection())
+        .await?;
+        self.invalidate_helper::<BuildConfigCache>();
+        Ok(BuildConfig {
+            id: BuildConfigId(model.id),
+            name: model.name,
+            pinned: model.pinned,
+        })
+    }
+
+    async fn rename_build_config(&mut self, id: BuildConfigId, new_name: &str) -> Result<()> {
+        let config = self.get_build_config(id).await?.get().clone();
+        if config.name == BuildConfig::DEFAULT_NAME {
+            return Err(BuildConfigError::DefaultConfig.into());
+        }
+        if self.find_build_config(new_name).await?.is_some() {
+            return Err(BuildConfigError::DuplicateName(new_name.into()).into());
+        }
+        let has_files = file::Entity::find()
+            .filter(file::Column::BuildConfigId.eq(id.0))
+            .one(self.connection())
+            .await?
+            .is_some();
+        let has_actions = action::Entity::find()
+            .filter(action::Column::BuildConfigId.eq(id.0))
+            .one(self.connection())
+            .await?
+            .is_some();
+        if has_files || has_actions {
+            return Err(BuildConfigError::HasOutputs(config.name).into());
+        }
+        build_config::ActiveModel {
+            id: ActiveValue::Unchanged(id.0),
+            name: ActiveValue::Set(new_name.to_string()),
+            ..Default::default()
+        }
+        .update(self.connection())
+        .await?;
+        self.invalidate_helper::<BuildConfigCache>();
+        Ok(())
+    }
+
+    async fn set_build_config_pinned(&mut self, id: BuildConfigId, pinned: bool) -> Result<()> {
+        self.get_build_config(id).await?;
+        build_config::ActiveModel {
+            id: ActiveValue::Unchanged(id.0),
+            pinned: ActiveValue::Set(pinned),
+            ..Default::default()
+        }
+        .update(self.connection())
+        .await?;
+        self.invalidate_helper::<BuildConfigCache>();
+        Ok(())
+    }
+
+    async fn delete_build_config(&mut self, id: BuildConfigId) -> Result<()> {
+        let config = self.get_build_config(id).await?.get().clone();
+        if config.name == BuildConfig::DEFAULT_NAME {
+            return Err(BuildConfigError::DefaultConfig.into());
+        }
+        // The files of the config are deleted by the foreign key cascade.
+        build_config::Entity::delete_by_id(id.0)
+            .exec(self.connection())
+            .await?;
+        self.invalidate_helper::<BuildConfigCache>();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn default_config_is_created() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let default = db.get_default_build_config().await?;
+        assert_eq!(default.get().name, BuildConfig::DEFAULT_NAME);
+        let id = default.get().id;
+        assert_eq!(db.get_build_config(id).await?.get().id, id);
+        assert_eq!(db.list_build_configs().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_id_is_an_error() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let err = db.get_build_config(BuildConfigId(42)).await.err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<BuildConfigError>(),
+            Some(&BuildConfigError::UnknownId(BuildConfigId(42)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_rename_and_delete() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let release = db.create_build_config("release").await?;
+        assert!(db.create_build_config("release").await.is_err());
+        assert_eq!(db.ensure_build_config("release").await?, release);
+
+        db.rename_build_config(release.id, "opt").await?;
+        let names: Vec<String> = db
+            .list_build_configs()
+            .await?
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(names, vec!["default", "opt"]);
+
+        db.delete_build_config(release.id).await?;
+        assert!(db.find_build_config("opt").await?.is_none());
+        assert!(db.get_build_config(release.id).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn configs_can_be_pinned() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let release = db.create_build_config("release").await?;
+        assert!(!release.pinned);
+
+        db.set_build_config_pinned(release.id, true).await?;
+        assert!(db.get_build_config(release.id).await?.get().pinned);
+        db.rename_build_config(release.id, "opt").await?;
+        assert!(db.get_build_config(release.id).await?.get().pinned);
+        db.set_build_config_pinned(release.id, false).await?;
+        assert!(!db.get_build_config(release.id).await?.get().pinned);
+        assert!(
+            db.set_build_config_pinned(BuildConfigId(42), true)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn configs_with_outputs_are_not_renamed() -> Result<()> {
+        use crate::output::OutputFilesDAO;
+
+        let mut db = Db::in_memory().await?;
+        let release = db.create_build_config("release").await?;
+        let outputs = [("bin/app".into(), model::Digest::of(b"app"))].into();
+        db.record_outputs(release.id, &outputs).await?;
+
+        let err = db
+            .rename_build_config(release.id, "opt")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<BuildConfigError>(),
+            Some(&BuildConfigError::HasOutputs("release".into()))
+        );
+        assert!(db.find_build_config("release").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn default_config_is_protected() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let id = db.get_default_build_config().await?.get().id;
+        assert!(db.rename_build_config(id, "other").await.is_err());
+        assert!(db.delete_build_config(id).await.is_err());
+        Ok(())
+    }
 }
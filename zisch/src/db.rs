@@ -1,11 +1,30 @@
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow};
 use anymap2::AnyMap;
+use camino::Utf8Path;
 use migration::MigratorTrait;
-use sea_orm::{Database, DatabaseConnection};
+use sea_orm::{ConnectOptions, Database, DatabaseConnection};
+use sqlx::sqlite::SqliteJournalMode;
+use thiserror::Error;
+use tracing::info;
 use url::Url;
 
+/// How long to wait for other `zack` processes to release a lock on the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DbError {
+    #[error(
+        "the database was migrated by a newer version of zack, unknown migrations: {unknown:?}"
+    )]
+    SchemaTooNew { unknown: Vec<String> },
+}
+
+#[allow(async_fn_in_trait)]
 pub trait DbHelper {
     async fn initialize(&mut self, db: &mut Db) -> anyhow::Result<()>;
 }
@@ -16,17 +35,37 @@ pub struct Db {
 }
 
 impl Db {
+    /// Opens the database of the current workspace.
     pub async fn new() -> Result<Db> {
-        let db_file = directories::db();
-        let db_file_url = Url::from_file_path(db_file)
-            .map_err(|_| anyhow!("Could not create URL from {db_file}"))?;
-        let db_file_path = db_file_url.path();
-        let database = Database::connect(format!("sqlite:{db_file_path}"))
+        Self::open_file(directories::db()).await
+    }
+
+    /// Opens or creates the database file at `path`.
+    pub async fn open_file(path: &Utf8Path) -> Result<Db> {
+        let url =
+            Url::from_file_path(path).map_err(|_| anyhow!("Could not create URL from {path}"))?;
+        Self::open(&format!("sqlite:{}?mode=rwc", url.path()))
             .await
-            .with_context(|| format!("while opening {db_file}"))?;
+            .with_context(|| format!("while opening {path}"))
+    }
+
+    /// Opens a fresh database that only lives as long as the returned `Db`.
+    pub async fn in_memory() -> Result<Db> {
+        Self::open("sqlite::memory:").await
+    }
 
-        // FIXME: just once per startup
-        migration::Migrator::up(&database, None).await?;
+    /// Opens the SQLite database at `url` and migrates it to the current schema.
+    ///
+    /// File databases use WAL mode so that several `zack` processes can share them.
+    pub async fn open(url: &str) -> Result<Db> {
+        let mut options = ConnectOptions::new(url);
+        options.sqlx_logging(false).map_sqlx_sqlite_opts(|opts| {
+            opts.journal_mode(SqliteJournalMode::Wal)
+                .busy_timeout(BUSY_TIMEOUT)
+        });
+        let database = Database::connect(options).await?;
+
+        migrate(&database).await?;
 
         let caches = AnyMap::new();
 
@@ -53,4 +92,100 @@ impl Db {
 
         Ok(new_dbh)
     }
+
+    /// Drops the cached helper of type `DBH` so that it is initialized again on next use.
+    ///
+    /// Must be called after changing data that the helper caches.
+    pub fn invalidate_helper<DBH: DbHelper + 'static>(&mut self) {
+        self.caches.remove::<Arc<DBH>>();
+    }
+}
+
+/// Applies pending migrations, refusing databases with migrations this binary does not know.
+async fn migrate(database: &DatabaseConnection) -> Result<()> {
+    migration::Migrator::install(database).await?;
+    let applied: HashSet<String> = migration::Migrator::get_migration_models(database)
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect();
+    let known: HashSet<String> = migration::Migrator::migrations()
+        .iter()
+        .map(|m| m.name().to_string())
+        .collect();
+
+    let mut unknown: Vec<String> = applied.difference(&known).cloned().collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(DbError::SchemaTooNew { unknown }.into());
+    }
+    let pending = known.difference(&applied).count();
+    if pending > 0 {
+        info!("applying {pending} database migrations");
+        migration::Migrator::up(database, None).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use sea_orm::{ConnectionTrait, DbBackend, Statement};
+
+    use super::*;
+
+    async fn query_string(db: &Db, sql: &str) -> Result<String> {
+        let row = db
+            .connection()
+            .query_one(Statement::from_string(DbBackend::Sqlite, sql))
+            .await?
+            .context("no row")?;
+        Ok(row.try_get_by_index(0)?)
+    }
+
+    fn temp_db_file(dir: &tempfile::TempDir) -> Utf8PathBuf {
+        Utf8PathBuf::from_path_buf(dir.path().join("db.sqlite")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn file_database_uses_wal() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Db::open_file(&temp_db_file(&dir)).await?;
+        assert_eq!(query_string(&db, "PRAGMA journal_mode").await?, "wal");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopening_keeps_schema() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = temp_db_file(&dir);
+        drop(Db::open_file(&path).await?);
+        let db = Db::open_file(&path).await?;
+        let count =
+            query_string(&db, "SELECT CAST(COUNT(*) AS TEXT) FROM seaql_migrations").await?;
+        assert_eq!(count, migration::Migrator::migrations().len().to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn newer_schema_is_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = temp_db_file(&dir);
+        let db = Db::open_file(&path).await?;
+        db.connection()
+            .execute_unprepared(
+                "INSERT INTO seaql_migrations (version, applied_at) VALUES ('m29990101_000001_future', 0)",
+            )
+            .await?;
+        drop(db);
+
+        let err = Db::open_file(&path).await.err().unwrap();
+        assert_eq!(
+            err.root_cause().downcast_ref::<DbError>(),
+            Some(&DbError::SchemaTooNew {
+                unknown: vec!["m29990101_000001_future".into()]
+            })
+        );
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.11
 
+use model::Digest;
 use sea_orm::entity::prelude::*;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
@@ -7,13 +8,28 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
-    pub build_config_id: i32,
+    pub build_config_id: Option<i32>,
     pub rel_path: String,
     #[sea_orm(column_type = "Blob", nullable)]
-    pub content_hash: Option<Vec<u8>>,
+    pub content_hash: Option<Digest>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::build_config::Entity",
+        from = "Column::BuildConfigId",
+        to = "super::build_config::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    BuildConfig,
+}
+
+impl Related<super::build_config::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BuildConfig.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
@@ -2,5 +2,6 @@
 
 pub mod prelude;
 
+pub mod action;
 pub mod build_config;
 pub mod file;
@@ -1 +1,203 @@
+//! Keeping the source file index in the build database up to date.
 
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use model::Digest;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, TransactionTrait,
+};
+use tracing::debug;
+
+use crate::{db::Db, entity::file};
+
+/// Access to the hashes of source files, i.e. files without a build config.
+#[allow(async_fn_in_trait)]
+pub trait SourceFilesDAO {
+    /// Rehashes the given workspace-relative paths below `root` and updates the index.
+    ///
+    /// Paths that no longer refer to a file are removed from the index.
+    /// Returns the paths whose content hash changed.
+    async fn update_source_files(
+        &mut self,
+        root: &Utf8Path,
+        rel_paths: &BTreeSet<Utf8PathBuf>,
+    ) -> Result<BTreeSet<Utf8PathBuf>>;
+
+    /// Walks all files below `root`, respecting ignore files and skipping `excluded`,
+    /// and updates the index with them.
+    ///
+    /// Returns the paths whose content hash changed.
+    async fn import_source_tree(
+        &mut self,
+        root: &Utf8Path,
+        excluded: &Utf8Path,
+    ) -> Result<BTreeSet<Utf8PathBuf>> {
+        let mut rel_paths = walk_source_tree(root, excluded)?;
+        rel_paths.extend(self.indexed_source_files().await?);
+        self.update_source_files(root, &rel_paths).await
+    }
+
+    /// Returns all workspace-relative paths in the index.
+    async fn indexed_source_files(&mut self) -> Result<BTreeSet<Utf8PathBuf>>;
+
+    /// Returns the indexed content hash of the given workspace-relative path.
+    async fn source_file_hash(&mut self, rel_path: &Utf8Path) -> Result<Option<Digest>>;
+}
+
+impl SourceFilesDAO for Db {
+    async fn update_source_files(
+        &mut self,
+        root: &Utf8Path,
+        rel_paths: &BTreeSet<Utf8PathBuf>,
+    ) -> Result<BTreeSet<Utf8PathBuf>> {
+        let txn = self.connection().begin().await?;
+        let mut changed = BTreeSet::new();
+
+        for rel_path in rel_paths {
+            let path = root.join(rel_path);
+            let new_hash = if path.is_file() {
+                Some(hash_file(&path)?)
+            } else {
+                None
+            };
+
+            let existing = file::Entity::find()
+                .filter(file::Column::BuildConfigId.is_null())
+                .filter(file::Column::RelPath.eq(rel_path.as_str()))
+                .one(&txn)
+                .await?;
+            let old_hash = existing.as_ref().and_then(|m| m.content_hash);
+            if old_hash == new_hash {
+                continue;
+            }
+
+            debug!("source file changed: {rel_path}");
+            changed.insert(rel_path.clone());
+
+            match (existing, new_hash) {
+                (Some(existing), None) => {
+                    file::Entity::delete_by_id(existing.id).exec(&txn).await?;
+                }
+                (Some(existing), Some(new_hash)) => {
+                    let mut active: file::ActiveModel = existing.into();
+                    active.content_hash = ActiveValue::Set(Some(new_hash));
+                    active.update(&txn).await?;
+                }
+                (None, new_hash) => {
+                    file::ActiveModel {
+                        build_config_id: ActiveValue::Set(None),
+                        rel_path: ActiveValue::Set(rel_path.to_string()),
+                        content_hash: ActiveValue::Set(new_hash),
+                        ..Default::default()
+                    }
+                    .insert(&txn)
+                    .await?;
+                }
+            }
+        }
+
+        txn.commit().await?;
+        Ok(changed)
+    }
+
+    async fn indexed_source_files(&mut self) -> Result<BTreeSet<Utf8PathBuf>> {
+        let files = file::Entity::find()
+            .filter(file::Column::BuildConfigId.is_null())
+            .all(self.connection())
+            .await?;
+        Ok(files.into_iter().map(|m| m.rel_path.into()).collect())
+    }
+
+    async fn source_file_hash(&mut self, rel_path: &Utf8Path) -> Result<Option<Digest>> {
+        let file = file::Entity::find()
+            .filter(file::Column::BuildConfigId.is_null())
+            .filter(file::Column::RelPath.eq(rel_path.as_str()))
+            .one(self.connection())
+            .await?;
+        Ok(file.and_then(|m| m.content_hash))
+    }
+}
+
+pub(crate) fn hash_file(path: &Utf8Path) -> Result<Digest> {
+    let file = std::fs::File::open(path).with_context(|| format!("while opening {path:?}"))?;
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(file)
+        .with_context(|| format!("while hashing {path:?}"))?;
+    Ok(hasher.finalize().into())
+}
+
+pub use zopf::walk::source_walker;
+
+/// Returns the workspace-relative paths of all files below `root`.
+pub fn walk_source_tree(root: &Utf8Path, excluded: &Utf8Path) -> Result<BTreeSet<Utf8PathBuf>> {
+    let mut rel_paths = BTreeSet::new();
+    for entry in source_walker(root, excluded).build() {
+        let entry = entry.with_context(|| format!("while walking {root:?}"))?;
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let path = Utf8Path::from_path(entry.path())
+            .with_context(|| format!("non UTF-8 path: {:?}", entry.path()))?;
+        rel_paths.insert(path.strip_prefix(root)?.to_owned());
+    }
+    Ok(rel_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn update_reports_changed_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        fs::write(root.join("a.c"), "a")?;
+        fs::write(root.join("b.c"), "b")?;
+        let mut db = Db::in_memory().await?;
+
+        let changed = db.import_source_tree(&root, &root.join("zack")).await?;
+        assert_eq!(changed, BTreeSet::from(["a.c".into(), "b.c".into()]));
+        assert_eq!(
+            db.source_file_hash("a.c".into()).await?,
+            Some(Digest::of(b"a"))
+        );
+
+        fs::write(root.join("a.c"), "changed")?;
+        fs::remove_file(root.join("b.c"))?;
+        let changed = db.import_source_tree(&root, &root.join("zack")).await?;
+        assert_eq!(changed, BTreeSet::from(["a.c".into(), "b.c".into()]));
+        assert_eq!(db.source_file_hash("b.c".into()).await?, None);
+        assert_eq!(
+            db.indexed_source_files().await?,
+            BTreeSet::from(["a.c".into()])
+        );
+
+        let changed = db.import_source_tree(&root, &root.join("zack")).await?;
+        assert!(changed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn walk_includes_hidden_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join(".git"))?;
+        fs::create_dir_all(root.join("zack"))?;
+        fs::write(root.join(".git/HEAD"), "")?;
+        fs::write(root.join(".gitignore"), "*.o\n")?;
+        fs::write(root.join("main.c"), "")?;
+        fs::write(root.join("main.o"), "")?;
+        fs::write(root.join("zack/db.sqlite"), "")?;
+
+        assert_eq!(
+            walk_source_tree(&root, &root.join("zack"))?,
+            BTreeSet::from([".gitignore".into(), "main.c".into()])
+        );
+        Ok(())
+    }
+}
@@ -1,6 +1,10 @@
+pub mod action;
+pub mod build_config;
+pub mod db;
 pub mod import;
 pub mod model;
+pub mod output;
+pub mod roots;
+pub mod watch;
 
-mod build_config;
-mod db;
 mod entity;
@@ -1,18 +1,20 @@
-use std::fmt::Display;
-
-use camino::Utf8PathBuf;
+use ::model::Digest;
+use camino::{Utf8Path, Utf8PathBuf};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BuildConfigId(pub i32);
 
-impl BuildConfigId {
-    pub const DEFAULT_CONFIG_ID: BuildConfigId = BuildConfigId(1);
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BuildConfig {
     pub id: BuildConfigId,
     pub name: String,
+    /// Whether its outputs are kept when gc evicts blobs to stay within a size budget.
+    pub pinned: bool,
+}
+
+impl BuildConfig {
+    /// The config that always exists. Its id is whatever the database assigned to it.
+    pub const DEFAULT_NAME: &str = "default";
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -29,30 +31,18 @@ pub struct File {
     pub id: FileId,
     build_config_id: Option<BuildConfigId>,
     pub rel_path: DbPathBuf,
-    pub content_hash: Hash,
+    pub content_hash: Digest,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DbPathBuf(Utf8PathBuf);
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct Hash {
-    internal: blake3::Hash,
-}
-
-impl Display for Hash {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.internal)
+impl DbPathBuf {
+    pub fn new(path: impl Into<Utf8PathBuf>) -> Self {
+        DbPathBuf(path.into())
     }
-}
 
-impl Ord for Hash {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.internal.as_bytes().cmp(other.internal.as_bytes())
-    }
-}
-impl PartialOrd for Hash {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    pub fn as_path(&self) -> &Utf8Path {
+        &self.0
     }
 }
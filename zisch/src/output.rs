@@ -0,0 +1,113 @@
+//! Recording the outputs of commands per build config.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use model::Digest;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, TransactionTrait,
+};
+
+use crate::{db::Db, entity::file, entity::prelude::*, model::BuildConfigId};
+
+/// Access to the hashes of built files.
+///
+/// Built files are tracked per build config, so the same path can have
+/// different content in different configs.
+#[allow(async_fn_in_trait)]
+pub trait OutputFilesDAO {
+    /// Records the content hashes of outputs of `config`, by their paths
+    /// relative to its output directory.
+    async fn record_outputs(
+        &mut self,
+        config: BuildConfigId,
+        outputs: &BTreeMap<Utf8PathBuf, Digest>,
+    ) -> Result<()>;
+
+    /// Returns the recorded content hash of an output of `config`.
+    async fn output_hash(
+        &mut self,
+        config: BuildConfigId,
+        rel_path: &Utf8Path,
+    ) -> Result<Option<Digest>>;
+}
+
+impl OutputFilesDAO for Db {
+    async fn record_outputs(
+        &mut self,
+        config: BuildConfigId,
+        outputs: &BTreeMap<Utf8PathBuf, Digest>,
+    ) -> Result<()> {
+        let txn = self.connection().begin().await?;
+        for (rel_path, &hash) in outputs {
+            let hash = ActiveValue::Set(Some(hash));
+
+            let existing = File::find()
+                .filter(file::Column::BuildConfigId.eq(config.0))
+                .filter(file::Column::RelPath.eq(rel_path.as_str()))
+                .one(&txn)
+                .await?;
+            match existing {
+                Some(existing) => {
+                    let mut active: file::ActiveModel = existing.into();
+                    active.content_hash = hash;
+                    active.update(&txn).await?;
+                }
+                None => {
+                    file::ActiveModel {
+                        build_config_id: ActiveValue::Set(Some(config.0)),
+                        rel_path: ActiveValue::Set(rel_path.to_string()),
+                        content_hash: hash,
+                        ..Default::default()
+                    }
+                    .insert(&txn)
+                    .await?;
+                }
+            }
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    async fn output_hash(
+        &mut self,
+        config: BuildConfigId,
+        rel_path: &Utf8Path,
+    ) -> Result<Option<Digest>> {
+        let file = File::find()
+            .filter(file::Column::BuildConfigId.eq(config.0))
+            .filter(file::Column::RelPath.eq(rel_path.as_str()))
+            .one(self.connection())
+            .await?;
+        Ok(file.and_then(|m| m.content_hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_config::BuildConfigsDAO;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn outputs_are_per_config() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let default = db.get_default_build_config().await?.get().id;
+        let release = db.create_build_config("release").await?.id;
+        let outs = |content: &[u8]| BTreeMap::from([("out".into(), Digest::of(content))]);
+        db.record_outputs(release, &outs(b"debug")).await?;
+        db.record_outputs(release, &outs(b"release")).await?;
+
+        let path = Utf8Path::new("out");
+        assert_eq!(
+            db.output_hash(release, path).await?,
+            Some(Digest::of(b"release"))
+        );
+        assert_eq!(db.output_hash(default, path).await?, None);
+
+        db.delete_build_config(release).await?;
+        assert_eq!(db.output_hash(release, path).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! The blobs and stored directory listings the build database still refers to.
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use model::Digest;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QuerySelect};
+
+use crate::{
+    db::Db,
+    entity::{action, build_config, file},
+};
+
+/// Access to the content hashes that garbage collection of the blob store must keep.
+#[allow(async_fn_in_trait)]
+pub trait RootsDAO {
+    /// Returns the hashes of all source files and of the outputs of all build configs.
+    ///
+    /// Outputs stay alive as long as their build config exists,
+    /// removing a config with `zack config rm` releases them.
+    async fn live_content_hashes(&mut self) -> Result<BTreeSet<Digest>>;
+
+    /// Returns the hashes of the outputs of pinned build configs,
+    /// which must be kept even to stay within a size budget.
+    async fn pinned_content_hashes(&mut self) -> Result<BTreeSet<Digest>>;
+
+    /// Returns the `entry_hash` of the stored listings of the outputs of all commands,
+    /// which keep the listings and everything in them alive.
+    async fn live_output_trees(&mut self) -> Result<BTreeSet<Digest>>;
+
+    /// Returns the `entry_hash` of the stored listings of the outputs of the commands
+    /// of pinned build configs.
+    async fn pinned_output_trees(&mut self) -> Result<BTreeSet<Digest>>;
+}
+
+impl RootsDAO for Db {
+    async fn live_content_hashes(&mut self) -> Result<BTreeSet<Digest>> {
+        let hashes: Vec<Option<Digest>> = file::Entity::find()
+            .select_only()
+            .column(file::Column::ContentHash)
+            .filter(file::Column::ContentHash.is_not_null())
+            .distinct()
+            .into_tuple()
+            .all(self.connection())
+            .await?;
+        Ok(hashes.into_iter().flatten().collect())
+    }
+
+    async fn pinned_content_hashes(&mut self) -> Result<BTreeSet<Digest>> {
+        let pinned = pinned_configs(self).await?;
+        let hashes: Vec<Option<Digest>> = file::Entity::find()
+            .select_only()
+            .column(file::Column::ContentHash)
+            .filter(file::Column::BuildConfigId.is_in(pinned))
+            .filter(file::Column::ContentHash.is_not_null())
+            .distinct()
+            .into_tuple()
+            .all(self.connection())
+            .await?;
+        Ok(hashes.into_iter().flatten().collect())
+    }
+
+    async fn live_output_trees(&mut self) -> Result<BTreeSet<Digest>> {
+        let trees: Vec<Option<Digest>> = action::Entity::find()
+            .select_only()
+            .column(action::Column::Outputs)
+            .filter(action::Column::Outputs.is_not_null())
+            .distinct()
+            .into_tuple()
+            .all(self.connection())
+            .await?;
+        Ok(trees.into_iter().flatten().collect())
+    }
+
+    async fn pinned_output_trees(&mut self) -> Result<BTreeSet<Digest>> {
+        let pinned = pinned_configs(self).await?;
+        let trees: Vec<Option<Digest>> = action::Entity::find()
+            .select_only()
+            .column(action::Column::Outputs)
+            .filter(action::Column::BuildConfigId.is_in(pinned))
+            .filter(action::Column::Outputs.is_not_null())
+            .distinct()
+            .into_tuple()
+            .all(self.connection())
+            .await?;
+        Ok(trees.into_iter().flatten().collect())
+    }
+}
+
+async fn pinned_configs(db: &Db) -> Result<Vec<i32>> {
+    Ok(build_config::Entity::find()
+        .select_only()
+        .column(build_config::Column::Id)
+        .filter(build_config::Column::Pinned.eq(true))
+        .into_tuple()
+        .all(db.connection())
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use camino::Utf8PathBuf;
+
+    use super::*;
+    use crate::action::ActionsDAO;
+    use crate::build_config::BuildConfigsDAO;
+    use crate::import::SourceFilesDAO;
+    use crate::output::OutputFilesDAO;
+
+    #[tokio::test]
+    async fn sources_and_outputs_are_live() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        std::fs::write(root.join("src.c"), "source")?;
+
+        let mut db = Db::in_memory().await?;
+        db.update_source_files(&root, &BTreeSet::from(["src.c".into()]))
+            .await?;
+        let release = db.create_build_config("release").await?.id;
+        db.record_outputs(
+            release,
+            &BTreeMap::from([("out".into(), Digest::of(b"output"))]),
+        )
+        .await?;
+
+        assert_eq!(
+            db.live_content_hashes().await?,
+            BTreeSet::from([Digest::of(b"source"), Digest::of(b"output")])
+        );
+        assert_eq!(db.pinned_content_hashes().await?, BTreeSet::new());
+        db.set_build_config_pinned(release, true).await?;
+        assert_eq!(
+            db.pinned_content_hashes().await?,
+            BTreeSet::from([Digest::of(b"output")])
+        );
+
+        db.delete_build_config(release).await?;
+        assert_eq!(
+            db.live_content_hashes().await?,
+            BTreeSet::from([Digest::of(b"source")])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_trees_are_live_with_their_config() -> Result<()> {
+        let mut db = Db::in_memory().await?;
+        let release = db.create_build_config("release").await?.id;
+        let tree = Digest::of(b"listing");
+        db.record_key_components(release, "//pkg:main", "{}")
+            .await?;
+        db.record_action_outputs(release, "//pkg:main", tree)
+            .await?;
+        assert!(
+            db.record_action_outputs(release, "//pkg:other", tree)
+                .await
+                .is_err()
+        );
+
+        assert_eq!(db.live_output_trees().await?, BTreeSet::from([tree]));
+        assert_eq!(db.pinned_output_trees().await?, BTreeSet::new());
+        db.set_build_config_pinned(release, true).await?;
+        assert_eq!(db.pinned_output_trees().await?, BTreeSet::from([tree]));
+
+        db.delete_build_config(release).await?;
+        assert_eq!(db.live_output_trees().await?, BTreeSet::new());
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+//! Watching the workspace for source file changes with inotify.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::os::fd::AsFd;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use tracing::{debug, warn};
+
+use crate::import::source_walker;
+
+/// Controls how bursts of file system events are merged into one change set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Debounce {
+    /// A burst ends after no events arrived for this long.
+    pub quiet: Duration,
+    /// A burst ends at the latest this long after its first event.
+    pub max_delay: Duration,
+}
+
+impl Default for Debounce {
+    fn default() -> Self {
+        Debounce {
+            quiet: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Debounce {
+    /// How much longer to wait for further events of the burst that started at `first`
+    /// and last saw an event at `last`. Returns `None` if the burst is complete.
+    pub fn remaining(&self, first: Instant, last: Instant, now: Instant) -> Option<Duration> {
+        let quiet_end = last + self.quiet;
+        let max_end = first + self.max_delay;
+        let end = quiet_end.min(max_end);
+        (end > now).then(|| end - now)
+    }
+}
+
+/// Workspace-relative paths that changed during one burst of events.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub paths: BTreeSet<Utf8PathBuf>,
+    /// The kernel event queue overflowed, so the changed paths are incomplete
+    /// and the whole workspace needs to be rescanned.
+    pub rescan: bool,
+}
+
+const WATCH_FLAGS: AddWatchFlags = AddWatchFlags::IN_CLOSE_WRITE
+    .union(AddWatchFlags::IN_MODIFY)
+    .union(AddWatchFlags::IN_ATTRIB)
+    .union(AddWatchFlags::IN_CREATE)
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_MOVED_TO)
+    .union(AddWatchFlags::IN_ONLYDIR)
+    .union(AddWatchFlags::IN_DONT_FOLLOW);
+
+/// Watches all directories below a root recursively.
+///
+/// inotify watches are not recursive, so every directory gets its own watch
+/// and newly created directories are added as they appear.
+#[derive(Debug)]
+pub struct Watcher {
+    inotify: Inotify,
+    root: Utf8PathBuf,
+    excluded: Utf8PathBuf,
+    watches: HashMap<WatchDescriptor, Utf8PathBuf>,
+}
+
+impl Watcher {
+    /// Starts watching `root`, skipping what the import skips, see [`source_walker`].
+    pub fn new(root: &Utf8Path, excluded: &Utf8Path) -> Result<Watcher> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .context("while initializing inotify")?;
+        let mut watcher = Watcher {
+            inotify,
+            root: root.to_owned(),
+            excluded: excluded.to_owned(),
+            watches: HashMap::new(),
+        };
+        watcher.add_recursive(root, &mut BTreeSet::new())?;
+        debug!("watching {} directories", watcher.watches.len());
+        Ok(watcher)
+    }
+
+    /// Adds watches for `dir` and all its sub directories.
+    /// Files found below `dir` are added to `found` as workspace-relative paths.
+    fn add_recursive(&mut self, dir: &Utf8Path, found: &mut BTreeSet<Utf8PathBuf>) -> Result<()> {
+        for entry in source_walker(dir, &self.excluded).build() {
+            let entry = entry.with_context(|| format!("while walking {dir:?}"))?;
+            let Some(path) = Utf8Path::from_path(entry.path()) else {
+                warn!("skipping non UTF-8 path {:?}", entry.path());
+                continue;
+            };
+            let Some(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                match self.inotify.add_watch(path.as_std_path(), WATCH_FLAGS) {
+                    Ok(wd) => {
+                        self.watches.insert(wd, path.to_owned());
+                    }
+                    // Removed again before we got to it.
+                    Err(Errno::ENOENT) => {}
+                    Err(e) => return Err(e).with_context(|| format!("while watching {path:?}")),
+                }
+            } else if let Ok(rel_path) = path.strip_prefix(&self.root) {
+                found.insert(rel_path.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks until file system events arrive and returns them once the burst is over.
+    ///
+    /// Only paths the import would pick up are returned, and removed paths,
+    /// which the index ignores unless it knows them.
+    pub fn next_changes(&mut self, debounce: Debounce) -> Result<ChangeSet> {
+        let mut changes = ChangeSet::default();
+        self.wait(PollTimeout::NONE)?;
+        let first = Instant::now();
+        let mut last = first;
+        loop {
+            if self.read_events(&mut changes)? {
+                last = Instant::now();
+            }
+            let Some(remaining) = debounce.remaining(first, last, Instant::now()) else {
+                break;
+            };
+            let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+            self.wait(timeout)?;
+        }
+        self.retain_sources(&mut changes.paths);
+        Ok(changes)
+    }
+
+    /// Removes existing paths that the import skips, e.g. because of an ignore file.
+    fn retain_sources(&self, rel_paths: &mut BTreeSet<Utf8PathBuf>) {
+        let mut by_dir: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>> = BTreeMap::new();
+        for rel_path in rel_paths.iter() {
+            let path = self.root.join(rel_path);
+            if path.symlink_metadata().is_ok() {
+                let dir = path.parent().expect("below the root").to_owned();
+                by_dir.entry(dir).or_default().push(rel_path.clone());
+            }
+        }
+        for (dir, candidates) in by_dir {
+            let sources = self.sources_in(&dir);
+            for rel_path in candidates {
+                if !sources.contains(&self.root.join(&rel_path)) {
+                    rel_paths.remove(&rel_path);
+                }
+            }
+        }
+    }
+
+    /// Returns the paths of the entries of `dir` that the import does not skip.
+    fn sources_in(&self, dir: &Utf8Path) -> BTreeSet<Utf8PathBuf> {
+        source_walker(dir, &self.excluded)
+            .max_depth(Some(1))
+            .build()
+            .filter_map(|entry| Utf8PathBuf::from_path_buf(entry.ok()?.into_path()).ok())
+            .collect()
+    }
+
+    /// Waits for the inotify file descriptor to become readable.
+    fn wait(&self, timeout: PollTimeout) -> Result<()> {
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => Ok(()),
+            Err(e) => Err(e).context("while polling inotify"),
+        }
+    }
+
+    /// Reads all pending events into `changes`. Returns whether there were any.
+    fn read_events(&mut self, changes: &mut ChangeSet) -> Result<bool> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(false),
+            Err(e) => return Err(e).context("while reading inotify events"),
+        };
+
+        for event in &events {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                warn!("inotify queue overflow, rescanning");
+                changes.rescan = true;
+                continue;
+            }
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.watches.remove(&event.wd);
+                continue;
+            }
+            let (Some(dir), Some(name)) = (self.watches.get(&event.wd), &event.name) else {
+                continue;
+            };
+            let Some(name) = name.to_str() else {
+                warn!("skipping non UTF-8 file name {name:?} in {dir}");
+                continue;
+            };
+            let path = dir.join(name);
+            if path == self.excluded {
+                continue;
+            }
+
+            if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                if event
+                    .mask
+                    .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                {
+                    if !self.sources_in(dir).contains(&path) {
+                        continue;
+                    }
+                    // Files might have been created before the watch was in place.
+                    self.add_recursive(&path, &mut changes.paths)?;
+                } else if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                    // We don't know which files were below it anymore.
+                    changes.rescan = true;
+                }
+            } else if let Ok(rel_path) = path.strip_prefix(&self.root) {
+                changes.paths.insert(rel_path.to_owned());
+            }
+        }
+
+        Ok(!events.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn debounce_waits_for_quiet_period() {
+        let debounce = Debounce {
+            quiet: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        let first = Instant::now();
+        let last = first + Duration::from_millis(50);
+
+        assert_eq!(
+            debounce.remaining(first, last, last + Duration::from_millis(30)),
+            Some(Duration::from_millis(70))
+        );
+        assert_eq!(
+            debounce.remaining(first, last, last + Duration::from_millis(100)),
+            None
+        );
+    }
+
+    #[test]
+    fn debounce_limits_burst_length() {
+        let debounce = Debounce {
+            quiet: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+        };
+        let first = Instant::now();
+        let last = first + Duration::from_millis(450);
+
+        assert_eq!(
+            debounce.remaining(first, last, last),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            debounce.remaining(first, last, first + Duration::from_millis(500)),
+            None
+        );
+    }
+
+    #[test]
+    fn watcher_reports_changed_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let excluded = root.join("zack");
+        fs::create_dir_all(root.join("pkg"))?;
+        fs::create_dir_all(root.join(".git"))?;
+        fs::create_dir_all(&excluded)?;
+        fs::write(root.join(".gitignore"), "*.o\n")?;
+
+        let mut watcher = Watcher::new(&root, &excluded)?;
+
+        fs::write(root.join("pkg/main.c"), "int main() {}")?;
+        fs::write(root.join("pkg/main.o"), "")?;
+        fs::create_dir_all(root.join("new/nested"))?;
+        fs::write(root.join("new/nested/lib.c"), "")?;
+        fs::write(excluded.join("db.sqlite"), "")?;
+        fs::write(root.join(".git/index"), "")?;
+        fs::write(root.join(".gitignore"), "*.o\n*.a\n")?;
+
+        let changes = watcher.next_changes(Debounce {
+            quiet: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+        })?;
+
+        assert!(!changes.rescan);
+        assert_eq!(
+            changes.paths,
+            BTreeSet::from([
+                ".gitignore".into(),
+                "new/nested/lib.c".into(),
+                "pkg/main.c".into()
+            ])
+        );
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
+use camino::Utf8Component;
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
+use serde::ser::SerializeStruct;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
 use thiserror::Error;
 
@@ -12,6 +15,11 @@ pub enum Artifact {
     Directory(Utf8PathBuf),
     /// A single file.
     File(Utf8PathBuf),
+    /// A symbolic link, provisioned as a link to `target` instead of the file it points to.
+    Symlink {
+        path: Utf8PathBuf,
+        target: Utf8PathBuf,
+    },
 }
 
 impl Artifact {
@@ -19,6 +27,7 @@ impl Artifact {
         match self {
             Artifact::Directory(path) => path,
             Artifact::File(path) => path,
+            Artifact::Symlink { path, .. } => path,
         }
     }
 }
@@ -45,6 +54,18 @@ pub enum EntryError {
     /// Error when a path contains parent directory references.
     #[error("Path must not contain parent directory references: {0}")]
     ContainsParentComponents(Utf8PathBuf),
+    /// Error when a symlink points to an absolute path.
+    #[error("Symlink {path} must not point to the absolute path {target}")]
+    AbsoluteSymlinkTarget {
+        path: Utf8PathBuf,
+        target: Utf8PathBuf,
+    },
+    /// Error when a symlink points outside of the tree it is provisioned into.
+    #[error("Symlink {path} points to {target}, outside of the tree")]
+    SymlinkEscapesTree {
+        path: Utf8PathBuf,
+        target: Utf8PathBuf,
+    },
 
     /// IO error that occurred during entry operations.
     #[error("IO error on {0}: {1}")]
@@ -74,25 +95,46 @@ impl Artifact {
         Ok(Artifact::File(path))
     }
 
+    /// Creates a new symlink entry from a path and the target it points to.
+    ///
+    /// The path must be relative, the target has to stay within the tree, see [`Artifact::validate`].
+    pub fn new_symlink<P: Into<Utf8PathBuf>, T: Into<Utf8PathBuf>>(
+        path: P,
+        target: T,
+    ) -> Result<Self, EntryError> {
+        let path = path.into();
+        if path.is_absolute() {
+            return Err(EntryError::NotRelative(path));
+        }
+        let target = target.into();
+        check_symlink_target(&path, &target)?;
+        Ok(Artifact::Symlink { path, target })
+    }
+
     /// Validates an entry, ensuring paths are relative and normalized.
     ///
+    /// Symlinks must point to a relative target that stays within the tree the
+    /// entry is provisioned into, so a provisioned tree never refers to files
+    /// outside of it.
+    ///
     /// Returns a Cow containing either a reference to the original entry if it's valid
     /// or a new entry with necessary corrections.
     pub fn validate(&self) -> Result<Cow<'_, Artifact>, EntryError> {
-        let path = match self {
-            Artifact::Directory(path) => path,
-            Artifact::File(path) => path,
-        };
+        let path = self.path();
 
         if path.is_absolute() {
-            return Err(EntryError::NotRelative(path.clone()));
+            return Err(EntryError::NotRelative(path.to_owned()));
         }
 
         if path
             .components()
             .any(|c| c == camino::Utf8Component::ParentDir)
         {
-            return Err(EntryError::ContainsParentComponents(path.clone()));
+            return Err(EntryError::ContainsParentComponents(path.to_owned()));
+        }
+
+        if let Artifact::Symlink { path, target } = self {
+            check_symlink_target(path, target)?;
         }
 
         // Normalize path by removing redundant segments, etc.
@@ -106,13 +148,17 @@ impl Artifact {
             let normalized_entry = match self {
                 Artifact::Directory(_) => Artifact::Directory(normalized_path),
                 Artifact::File(_) => Artifact::File(normalized_path),
+                Artifact::Symlink { target, .. } => Artifact::Symlink {
+                    path: normalized_path,
+                    target: target.clone(),
+                },
             };
             Ok(Cow::Owned(normalized_entry))
         }
     }
 
     /// Normalize a path by cleaning up redundant separators and segments.
-    fn normalize_path(path: &Utf8PathBuf) -> Utf8PathBuf {
+    pub(crate) fn normalize_path(path: &Utf8Path) -> Utf8PathBuf {
         let mut normalized = Utf8PathBuf::new();
         normalized.extend(
             path.components()
@@ -122,6 +168,84 @@ impl Artifact {
     }
 }
 
+/// Rejects absolute targets and targets leaving the tree the symlink at `path` is in.
+///
+/// The check is lexical, so it cannot see `..` following another symlink:
+/// `s -> l/..` passes, but leaves the tree if `l -> .`. Chains like that are
+/// caught by [`resolves_within`] once all symlinks of the tree are known.
+pub(crate) fn check_symlink_target(path: &Utf8Path, target: &Utf8Path) -> Result<(), EntryError> {
+    if target.is_absolute() {
+        return Err(EntryError::AbsoluteSymlinkTarget {
+            path: path.to_owned(),
+            target: target.to_owned(),
+        });
+    }
+    let mut depth = path.parent().map_or(0, |parent| {
+        Artifact::normalize_path(parent).components().count()
+    });
+    for component in target.components() {
+        match component {
+            Utf8Component::ParentDir if depth == 0 => {
+                return Err(EntryError::SymlinkEscapesTree {
+                    path: path.to_owned(),
+                    target: target.to_owned(),
+                });
+            }
+            Utf8Component::ParentDir => depth -= 1,
+            Utf8Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// How many symlinks [`resolves_within`] follows before giving up, like `MAXSYMLINKS` of Linux.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Whether resolving the symlink at `path` stays within its tree, following
+/// the other `symlinks` of the tree, by their paths relative to its root.
+///
+/// Targets that do not resolve within [`MAX_SYMLINK_HOPS`], e.g. because of
+/// a loop, count as leaving the tree.
+pub(crate) fn resolves_within(
+    path: &Utf8Path,
+    symlinks: &BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+) -> bool {
+    let Some(target) = symlinks.get(path) else {
+        return true;
+    };
+    let mut resolved = Utf8PathBuf::new();
+    if let Some(parent) = path.parent() {
+        resolved.push(Artifact::normalize_path(parent));
+    }
+    // The components left to resolve, the next one last.
+    let mut todo: Vec<Utf8Component> = target.components().rev().collect();
+    let mut hops = 0;
+    while let Some(component) = todo.pop() {
+        match component {
+            Utf8Component::Normal(name) => {
+                resolved.push(name);
+                if let Some(target) = symlinks.get(&resolved) {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return false;
+                    }
+                    resolved.pop();
+                    todo.extend(target.components().rev());
+                }
+            }
+            Utf8Component::ParentDir => {
+                if !resolved.pop() {
+                    return false;
+                }
+            }
+            Utf8Component::CurDir => {}
+            Utf8Component::RootDir | Utf8Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
 impl Serialize for Artifact {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -136,6 +260,14 @@ impl Serialize for Artifact {
                 let path_str = ensure_no_trailing_slash(path);
                 serializer.serialize_str(&path_str)
             }
+            // Paths may contain any character, so symlinks are written as a map
+            // instead of joining path and target with a separator.
+            Artifact::Symlink { path, target } => {
+                let mut symlink = serializer.serialize_struct("Symlink", 2)?;
+                symlink.serialize_field("path", &ensure_no_trailing_slash(path))?;
+                symlink.serialize_field("target", target)?;
+                symlink.end()
+            }
         }
     }
 }
@@ -147,11 +279,11 @@ impl<'de> Deserialize<'de> for Artifact {
     {
         struct EntryVisitor;
 
-        impl serde::de::Visitor<'_> for EntryVisitor {
+        impl<'a> serde::de::Visitor<'a> for EntryVisitor {
             type Value = Artifact;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a string representing a file or directory path")
+                formatter.write_str("a file or directory path, or a symlink with path and target")
             }
 
             fn visit_str<E>(self, value: &str) -> Result<Artifact, E>
@@ -166,9 +298,36 @@ impl<'de> Deserialize<'de> for Artifact {
                     Ok(Artifact::File(path))
                 }
             }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Artifact, A::Error>
+            where
+                A: serde::de::MapAccess<'a>,
+            {
+                let mut path: Option<Utf8PathBuf> = None;
+                let mut target: Option<Utf8PathBuf> = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    let (field, name) = match key.as_str() {
+                        "path" => (&mut path, "path"),
+                        "target" => (&mut target, "target"),
+                        other => {
+                            return Err(serde::de::Error::unknown_field(
+                                other,
+                                &["path", "target"],
+                            ));
+                        }
+                    };
+                    if field.replace(map.next_value()?).is_some() {
+                        return Err(serde::de::Error::duplicate_field(name));
+                    }
+                }
+                Ok(Artifact::Symlink {
+                    path: path.ok_or_else(|| serde::de::Error::missing_field("path"))?,
+                    target: target.ok_or_else(|| serde::de::Error::missing_field("target"))?,
+                })
+            }
         }
 
-        deserializer.deserialize_str(EntryVisitor)
+        deserializer.deserialize_any(EntryVisitor)
     }
 }
 
@@ -253,6 +412,72 @@ mod tests {
         assert!(matches!(file.validate(), Err(EntryError::NotRelative(_))));
     }
 
+    #[test]
+    fn test_symlink_target_policy() {
+        assert!(Artifact::new_symlink("lib/libz.so", "libz.so.1").is_ok());
+        assert!(Artifact::new_symlink("bin/tool", "../lib/tool").is_ok());
+        assert!(Artifact::new_symlink("a/./b/link", "../../c").is_ok());
+
+        assert!(matches!(
+            Artifact::new_symlink("bin/sh", "/bin/bash"),
+            Err(EntryError::AbsoluteSymlinkTarget { .. })
+        ));
+        assert!(matches!(
+            Artifact::new_symlink("link", "../outside"),
+            Err(EntryError::SymlinkEscapesTree { .. })
+        ));
+        assert!(matches!(
+            Artifact::new_symlink("a/link", "b/../../../outside"),
+            Err(EntryError::SymlinkEscapesTree { .. })
+        ));
+
+        // Entries constructed directly are checked on validation.
+        let escaping = Artifact::Symlink {
+            path: "a/link".into(),
+            target: "../..".into(),
+        };
+        assert!(matches!(
+            escaping.validate(),
+            Err(EntryError::SymlinkEscapesTree { .. })
+        ));
+    }
+
+    #[test]
+    fn test_symlink_chains_are_resolved() {
+        let symlinks = |links: &[(&str, &str)]| -> BTreeMap<Utf8PathBuf, Utf8PathBuf> {
+            links
+                .iter()
+                .map(|(path, target)| (path.into(), target.into()))
+                .collect()
+        };
+
+        // Both pass the lexical check.
+        let escaping = symlinks(&[("l", "."), ("s", "l/..")]);
+        assert!(resolves_within("l".into(), &escaping));
+        assert!(!resolves_within("s".into(), &escaping));
+
+        let nested = symlinks(&[("a/l", "."), ("a/s", "l/..")]);
+        assert!(resolves_within("a/s".into(), &nested));
+
+        let looping = symlinks(&[("a", "b"), ("b", "a")]);
+        assert!(!resolves_within("a".into(), &looping));
+    }
+
+    #[test]
+    fn test_symlink_roundtrip_serialization() {
+        let link = Artifact::new_symlink("lib/libz.so", "libz.so.1").unwrap();
+        let json = serde_json::to_string(&link).unwrap();
+        assert_eq!(json, r#"{"path":"lib/libz.so","target":"libz.so.1"}"#);
+        assert_eq!(serde_json::from_str::<Artifact>(&json).unwrap(), link);
+
+        // The separator of `ls -l` is an ordinary part of file names.
+        let arrow = Artifact::new_symlink("a -> b", "c -> d").unwrap();
+        let json = serde_json::to_string(&arrow).unwrap();
+        assert_eq!(serde_json::from_str::<Artifact>(&json).unwrap(), arrow);
+        let file: Artifact = serde_json::from_str("\"a -> b\"").unwrap();
+        assert_eq!(file, Artifact::File("a -> b".into()));
+    }
+
     #[test]
     fn test_normalize_path() {
         let path = Utf8PathBuf::from("a/./b/./c");
@@ -376,7 +601,7 @@ mod tests {
     #[test]
     fn test_artifact_nested_path_sorting() {
         // Test sorting with nested paths
-        let mut artifacts = vec![
+        let mut artifacts = [
             Artifact::new_file("a/z.txt").unwrap(),
             Artifact::new_dir("a/b/c").unwrap(),
             Artifact::new_file("a/b/d.txt").unwrap(),
@@ -0,0 +1,273 @@
+//! Selecting artifacts with glob patterns instead of listing every path.
+
+use camino::{Utf8Component, Utf8Path};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::io;
+
+use crate::artifact::Artifact;
+use crate::walk::filtered_source_walker;
+use crate::{Error, read_link};
+
+/// The files below a root matching any include pattern and no exclude pattern.
+///
+/// Patterns are relative to the root and use `/` as separator. `*` and `?` do not
+/// match `/`, `**` matches any number of directories, e.g. `src/**/*.c`.
+/// Directories matching an exclude pattern are skipped with everything below them.
+#[derive(Debug, Clone)]
+pub struct ArtifactSet {
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl ArtifactSet {
+    pub fn new<I, E>(include: I, exclude: E) -> Result<Self, Error>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+        E: IntoIterator,
+        E::Item: Into<String>,
+    {
+        let include_patterns: Vec<String> = include.into_iter().map(Into::into).collect();
+        let exclude_patterns: Vec<String> = exclude.into_iter().map(Into::into).collect();
+        Ok(ArtifactSet {
+            include: glob_set(&include_patterns)?,
+            exclude: glob_set(&exclude_patterns)?,
+            include_patterns,
+            exclude_patterns,
+        })
+    }
+
+    pub fn include_patterns(&self) -> &[String] {
+        &self.include_patterns
+    }
+
+    pub fn exclude_patterns(&self) -> &[String] {
+        &self.exclude_patterns
+    }
+
+    /// Whether the file at `rel_path`, relative to the root, belongs to the set.
+    pub fn matches(&self, rel_path: &Utf8Path) -> bool {
+        self.include.is_match(rel_path)
+            && !rel_path
+                .ancestors()
+                .filter(|p| !p.as_str().is_empty())
+                .any(|p| self.exclude.is_match(p))
+    }
+
+    /// Walks `root` like [`crate::source_walker`] skipping `excluded`, and returns the matching
+    /// files and symlinks, relative to `root`.
+    ///
+    /// Directories below `root` containing a file named like one of `boundaries`,
+    /// e.g. nested packages, are skipped with everything below them.
+    /// The result is sorted and free of conflicts, as [`crate::provision`] requires.
+    /// Symlinks are not followed.
+    pub fn expand(
+        &self,
+        root: &Utf8Path,
+        excluded: &Utf8Path,
+        boundaries: &[&str],
+    ) -> Result<Vec<Artifact>, Error> {
+        // Globbing a directory that does not exist matches nothing.
+        if let Err(e) = fs::symlink_metadata(root) {
+            if e.kind() == io::ErrorKind::NotFound {
+                return Ok(Vec::new());
+            }
+        }
+        let exclude = self.exclude.clone();
+        let walk_root = root.to_owned();
+        let boundaries: Vec<String> = boundaries.iter().map(|b| b.to_string()).collect();
+        let walker = filtered_source_walker(root, excluded, move |entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            let excluded = Utf8Path::from_path(entry.path())
+                .and_then(|path| path.strip_prefix(&walk_root).ok())
+                .is_some_and(|rel_path| exclude.is_match(rel_path));
+            let nested = entry.file_type().is_some_and(|t| t.is_dir())
+                && boundaries.iter().any(|b| entry.path().join(b).is_file());
+            !(excluded || nested)
+        });
+
+        let mut artifacts = Vec::new();
+        for entry in walker.build() {
+            let entry = entry.map_err(|e| Error::Io {
+                context: "while walking",
+                path: root.to_owned(),
+                source: e
+                    .into_io_error()
+                    .unwrap_or_else(|| io::Error::other("walk failed")),
+            })?;
+            let Some(file_type) = entry.file_type() else {
+                continue;
+            };
+            if entry.depth() == 0 || file_type.is_dir() {
+                continue;
+            }
+            let Some(path) = Utf8Path::from_path(entry.path()) else {
+                return Err(Error::Io {
+                    context: "non UTF-8 file name",
+                    path: root.join(entry.path().to_string_lossy().as_ref()),
+                    source: io::Error::from(io::ErrorKind::InvalidData),
+                });
+            };
+            let rel_path = path
+                .strip_prefix(root)
+                .expect("walked below the root")
+                .to_owned();
+            if self.include.is_match(&rel_path) {
+                artifacts.push(if file_type.is_symlink() {
+                    Artifact::Symlink {
+                        target: read_link(path)?,
+                        path: rel_path,
+                    }
+                } else {
+                    Artifact::File(rel_path)
+                });
+            }
+        }
+        artifacts.sort();
+        Ok(artifacts)
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(glob(pattern)?);
+    }
+    builder.build().map_err(|e| Error::InvalidGlob {
+        pattern: patterns.join(", "),
+        source: e,
+    })
+}
+
+fn glob(pattern: &str) -> Result<Glob, Error> {
+    let invalid = |source| Error::InvalidGlob {
+        pattern: pattern.to_owned(),
+        source,
+    };
+    let path = Utf8Path::new(pattern);
+    if path.is_absolute()
+        || path
+            .components()
+            .any(|c| matches!(c, Utf8Component::ParentDir))
+    {
+        return Err(Error::GlobOutsideRoot(pattern.to_owned()));
+    }
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn create(root: &Utf8Path, paths: &[&str]) -> std::io::Result<()> {
+        for path in paths {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "")?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn expand_includes_minus_excludes() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        create(
+            root,
+            &[
+                "main.c",
+                "src/a.c",
+                "src/a.h",
+                "src/net/b.c",
+                "src/net/test_b.c",
+                "src/test_data/c.c",
+                "docs/d.c",
+            ],
+        )?;
+        std::os::unix::fs::symlink("a.c", root.join("src/alias.c"))?;
+
+        let set = ArtifactSet::new(["src/**/*.c", "*.c"], ["**/test_*"])?;
+        assert_eq!(
+            set.expand(root, &root.join("target"), &[])?,
+            [
+                Artifact::File("main.c".into()),
+                Artifact::File("src/a.c".into()),
+                Artifact::Symlink {
+                    path: "src/alias.c".into(),
+                    target: "a.c".into(),
+                },
+                Artifact::File("src/net/b.c".into()),
+            ]
+        );
+
+        assert!(set.matches("src/new.c".into()));
+        assert!(!set.matches("src/a.h".into()));
+        assert!(!set.matches("src/net/test_b.c".into()));
+        assert!(!set.matches("src/test_data/c.c".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn expand_skips_what_the_source_walk_skips() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        create(
+            root,
+            &[
+                ".ignore",
+                "main.c",
+                "gen.c",
+                "target/out.c",
+                ".git/hook.c",
+                "nested/ZACK.star",
+                "nested/nested.c",
+                "lib/lib.c",
+            ],
+        )?;
+        fs::write(root.join(".ignore"), "gen.c\n")?;
+
+        let set = ArtifactSet::new(["**/*.c"], Vec::<String>::new())?;
+        assert_eq!(
+            set.expand(root, &root.join("target"), &["ZACK.star"])?,
+            [
+                Artifact::File("lib/lib.c".into()),
+                Artifact::File("main.c".into()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expand_missing_root_is_empty() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let root = Utf8Path::from_path(dir.path()).unwrap().join("missing");
+        let set = ArtifactSet::new(["**"], Vec::<String>::new())?;
+        assert!(set.expand(&root, &root.join("target"), &[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn patterns_must_stay_below_root() {
+        assert!(matches!(
+            ArtifactSet::new(["../*.c"], Vec::<String>::new()),
+            Err(Error::GlobOutsideRoot(_))
+        ));
+        assert!(matches!(
+            ArtifactSet::new(["/etc/*"], Vec::<String>::new()),
+            Err(Error::GlobOutsideRoot(_))
+        ));
+        assert!(matches!(
+            ArtifactSet::new(["src/[a-"], Vec::<String>::new()),
+            Err(Error::InvalidGlob { .. })
+        ));
+    }
+}
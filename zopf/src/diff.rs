@@ -0,0 +1,397 @@
+//! Recording what was provisioned, comparing a provisioned tree with that record,
+//! and removing it again.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use model::Digest;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Instant;
+
+use crate::artifact::Artifact;
+use crate::{Error, Plan, ProvisionOptions, ProvisionStats};
+
+/// What [`provision_recorded`] laid out, relative to the tree.
+///
+/// Kept with the tree, e.g. as JSON, so that it can be verified and removed without
+/// the source, which may have changed or be gone by then.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provisioned {
+    /// Parents created for files and symlinks.
+    pub parents: BTreeSet<Utf8PathBuf>,
+    /// Directories provisioned with everything below them, parents before their children.
+    pub directories: Vec<Utf8PathBuf>,
+    pub files: BTreeMap<Utf8PathBuf, ProvisionedFile>,
+    /// Paths and targets.
+    pub symlinks: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+}
+
+/// A file as it was provisioned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvisionedFile {
+    pub content_hash: Digest,
+    pub executable: bool,
+}
+
+/// How a provisioned tree differs from its record. Paths are relative to the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Provisioned entries that are gone.
+    pub missing: Vec<Utf8PathBuf>,
+    /// Entries in provisioned directories that were not provisioned.
+    pub extra: Vec<Utf8PathBuf>,
+    /// Entries whose type, content, executable bit or symlink target changed.
+    pub modified: Vec<Utf8PathBuf>,
+}
+
+impl Diff {
+    /// Whether the tree is still exactly as provisioned.
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Like [`crate::provision_with`], but also records what was provisioned,
+/// with the content hash of every file, for [`verify`] and [`unprovision`].
+///
+/// Files are hashed as they are provisioned, which reads all of them.
+pub fn provision_recorded<'a>(
+    from: &Utf8Path,
+    to: &Utf8Path,
+    sorted_paths: impl Iterator<Item = &'a Artifact>,
+    options: &ProvisionOptions,
+) -> Result<(ProvisionStats, Provisioned), Error> {
+    let progress = options.progress.as_deref().unwrap_or(&());
+    let started = Instant::now();
+    let plan = Plan::scan(from, sorted_paths, progress)?;
+    let files = plan
+        .files
+        .par_iter()
+        .map(|(source_path, rel_path)| Ok((rel_path.clone(), hash_file(source_path)?)))
+        .collect::<Result<_, Error>>()?;
+    let stats = plan.apply(to, options, started.elapsed())?;
+    let provisioned = Provisioned {
+        parents: plan.parents,
+        directories: plan.directories,
+        files,
+        symlinks: plan.symlinks.into_iter().collect(),
+    };
+    Ok((stats, provisioned))
+}
+
+/// Compares `to` with what was `provisioned` there.
+///
+/// Files are compared by content hash, so changes through hard links to
+/// the source count as modifications, too.
+/// Only directories that were provisioned with everything below them can have extra entries,
+/// unrelated files next to provisioned ones are fine.
+pub fn verify(to: &Utf8Path, provisioned: &Provisioned) -> Result<Diff, Error> {
+    let mut diff = Diff::default();
+
+    let mut expected: BTreeSet<&Utf8Path> = BTreeSet::new();
+    expected.extend(provisioned.directories.iter().map(Utf8PathBuf::as_path));
+    expected.extend(provisioned.files.keys().map(Utf8PathBuf::as_path));
+    expected.extend(provisioned.symlinks.keys().map(Utf8PathBuf::as_path));
+
+    for dir in &provisioned.directories {
+        let path = to.join(dir);
+        match symlink_metadata(&path)? {
+            None => diff.missing.push(dir.clone()),
+            Some(metadata) if !metadata.is_dir() => diff.modified.push(dir.clone()),
+            Some(_) => {
+                for entry in fs::read_dir(&path).map_err(|e| Error::Io {
+                    context: "while reading directory contents",
+                    path: path.clone(),
+                    source: e,
+                })? {
+                    let entry = entry.map_err(|e| Error::Io {
+                        context: "resolve dir entry",
+                        path: path.clone(),
+                        source: e,
+                    })?;
+                    let rel_path = dir.join(entry.file_name().to_string_lossy().as_ref());
+                    if !expected.contains(rel_path.as_path()) {
+                        diff.extra.push(rel_path);
+                    }
+                }
+            }
+        }
+    }
+
+    let files = provisioned
+        .files
+        .par_iter()
+        .map(|(rel_path, file)| {
+            let state = compare_file(&to.join(rel_path), file)?;
+            Ok((rel_path, state))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    for (rel_path, state) in files {
+        match state {
+            State::Intact => {}
+            State::Missing => diff.missing.push(rel_path.clone()),
+            State::Modified => diff.modified.push(rel_path.clone()),
+        }
+    }
+
+    for (rel_path, target) in &provisioned.symlinks {
+        let path = to.join(rel_path);
+        match symlink_metadata(&path)? {
+            None => diff.missing.push(rel_path.clone()),
+            Some(metadata) if !metadata.is_symlink() => diff.modified.push(rel_path.clone()),
+            Some(_) => {
+                if crate::read_link(&path)? != *target {
+                    diff.modified.push(rel_path.clone());
+                }
+            }
+        }
+    }
+
+    diff.missing.sort();
+    diff.extra.sort();
+    diff.modified.sort();
+    Ok(diff)
+}
+
+/// Removes what was `provisioned` in `to`.
+///
+/// Files and symlinks are removed even if they were modified. Directories, including
+/// the parents created for files, are removed once empty, so unrelated files are kept
+/// along with the directories containing them. Entries that are gone already are skipped.
+pub fn unprovision(to: &Utf8Path, provisioned: &Provisioned) -> Result<(), Error> {
+    let paths = provisioned.files.keys().chain(provisioned.symlinks.keys());
+    for rel_path in paths {
+        let path = to.join(rel_path);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::Io {
+                    context: "while removing provisioned file",
+                    path,
+                    source: e,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // Children sort after their parents, so they are removed first.
+    let mut directories: BTreeSet<&Utf8Path> = BTreeSet::new();
+    for dir in provisioned.parents.iter().chain(&provisioned.directories) {
+        directories.extend(dir.ancestors().filter(|p| !p.as_str().is_empty()));
+    }
+    for rel_path in directories.into_iter().rev() {
+        let path = to.join(rel_path);
+        match fs::remove_dir(&path) {
+            Err(e)
+                if !matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::DirectoryNotEmpty
+                ) =>
+            {
+                return Err(Error::Io {
+                    context: "while removing provisioned directory",
+                    path,
+                    source: e,
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+enum State {
+    Intact,
+    Missing,
+    Modified,
+}
+
+fn symlink_metadata(path: &Utf8Path) -> Result<Option<fs::Metadata>, Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io {
+            context: "get metadata",
+            path: path.to_owned(),
+            source: e,
+        }),
+    }
+}
+
+fn compare_file(path: &Utf8Path, provisioned: &ProvisionedFile) -> Result<State, Error> {
+    let Some(metadata) = symlink_metadata(path)? else {
+        return Ok(State::Missing);
+    };
+    if !metadata.is_file() || (metadata.permissions().mode() & 0o111 != 0) != provisioned.executable
+    {
+        return Ok(State::Modified);
+    }
+    let file = hash_file(path)?;
+    Ok(if file == *provisioned {
+        State::Intact
+    } else {
+        State::Modified
+    })
+}
+
+fn hash_file(path: &Utf8Path) -> Result<ProvisionedFile, Error> {
+    let io_error = |e| Error::Io {
+        context: "while hashing",
+        path: path.to_owned(),
+        source: e,
+    };
+    let file = fs::File::open(path).map_err(io_error)?;
+    let executable = file.metadata().map_err(io_error)?.permissions().mode() & 0o111 != 0;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(file).map_err(io_error)?;
+    Ok(ProvisionedFile {
+        content_hash: hasher.finalize().into(),
+        executable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::LinkStrategy;
+
+    fn artifacts() -> Vec<Artifact> {
+        vec![
+            Artifact::File("bin/tool".into()),
+            Artifact::Directory("lib".into()),
+        ]
+    }
+
+    fn setup(source_path: &Utf8Path) -> io::Result<()> {
+        fs::create_dir_all(source_path.join("bin"))?;
+        fs::create_dir_all(source_path.join("lib/sub"))?;
+        fs::write(source_path.join("bin/tool"), "tool")?;
+        fs::write(source_path.join("lib/a.so"), "a")?;
+        fs::write(source_path.join("lib/sub/b.so"), "b")?;
+        std::os::unix::fs::symlink("a.so", source_path.join("lib/liba.so"))
+    }
+
+    #[test]
+    fn verify_reports_missing_extra_and_modified() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let source_path = Utf8Path::from_path(source_dir.path()).unwrap();
+        let target_path = Utf8Path::from_path(target_dir.path()).unwrap();
+        setup(source_path)?;
+
+        let (_, provisioned) = provision_recorded(
+            source_path,
+            target_path,
+            artifacts().iter(),
+            &ProvisionOptions {
+                strategies: vec![LinkStrategy::Copy],
+                ..Default::default()
+            },
+        )?;
+        assert!(verify(target_path, &provisioned)?.is_empty());
+
+        // Unrelated files next to provisioned ones do not count.
+        fs::write(target_path.join("bin/other"), "")?;
+        fs::write(target_path.join("lib/sub/extra.so"), "")?;
+        fs::remove_file(target_path.join("lib/a.so"))?;
+        let tool = target_path.join("bin/tool");
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o644))?;
+        fs::write(&tool, "toot")?;
+        fs::remove_file(target_path.join("lib/liba.so"))?;
+        std::os::unix::fs::symlink("sub/b.so", target_path.join("lib/liba.so"))?;
+
+        assert_eq!(
+            verify(target_path, &provisioned)?,
+            Diff {
+                missing: vec!["lib/a.so".into()],
+                extra: vec!["lib/sub/extra.so".into()],
+                modified: vec!["bin/tool".into(), "lib/liba.so".into()],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_notices_changes_through_hard_links() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let source_path = Utf8Path::from_path(source_dir.path()).unwrap();
+        let target_path = Utf8Path::from_path(target_dir.path()).unwrap();
+        setup(source_path)?;
+
+        let (stats, provisioned) = provision_recorded(
+            source_path,
+            target_path,
+            artifacts().iter(),
+            &ProvisionOptions::default(),
+        )?;
+        assert_eq!(stats.hard_linked, 3);
+
+        // An action writing to its input changes the source along with it.
+        fs::write(target_path.join("lib/a.so"), "mutated")?;
+        assert_eq!(
+            verify(target_path, &provisioned)?.modified,
+            [Utf8PathBuf::from("lib/a.so")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unprovision_keeps_unrelated_files() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let source_path = Utf8Path::from_path(source_dir.path()).unwrap();
+        let target_path = Utf8Path::from_path(target_dir.path()).unwrap();
+        setup(source_path)?;
+
+        let (_, provisioned) = provision_recorded(
+            source_path,
+            target_path,
+            artifacts().iter(),
+            &ProvisionOptions::default(),
+        )?;
+        fs::write(target_path.join("lib/sub/extra.so"), "")?;
+        fs::write(target_path.join("README"), "")?;
+
+        unprovision(target_path, &provisioned)?;
+        assert!(!target_path.join("bin").exists());
+        assert!(!target_path.join("lib/liba.so").exists());
+        assert!(target_path.join("lib/sub/extra.so").exists());
+        assert!(target_path.join("README").exists());
+        // The source of the hard links is untouched.
+        assert_eq!(fs::read_to_string(source_path.join("bin/tool"))?, "tool");
+
+        // Nothing left to remove.
+        unprovision(target_path, &provisioned)?;
+        Ok(())
+    }
+
+    #[test]
+    fn the_record_outlives_the_source() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let source_path = Utf8Path::from_path(source_dir.path()).unwrap();
+        let target_path = Utf8Path::from_path(target_dir.path()).unwrap();
+        setup(source_path)?;
+
+        let (_, provisioned) = provision_recorded(
+            source_path,
+            target_path,
+            artifacts().iter(),
+            &ProvisionOptions::default(),
+        )?;
+        let json = serde_json::to_string(&provisioned)?;
+        drop(source_dir);
+
+        let provisioned: Provisioned = serde_json::from_str(&json)?;
+        assert!(verify(target_path, &provisioned)?.is_empty());
+        unprovision(target_path, &provisioned)?;
+        assert_eq!(fs::read_dir(target_path)?.count(), 0);
+        Ok(())
+    }
+}
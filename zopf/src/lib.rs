@@ -1,13 +1,29 @@
 use artifact::Artifact;
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
+use progress::{Phase, Progress};
+use rayon::prelude::*;
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 pub mod artifact;
+pub mod artifact_set;
+pub mod diff;
+pub mod link;
+pub mod progress;
+pub mod tree;
+pub mod walk;
+
+pub use artifact_set::ArtifactSet;
+pub use diff::{Diff, Provisioned, ProvisionedFile, provision_recorded, unprovision, verify};
+pub use link::{LinkStrategy, ProvisionOptions, ProvisionStats, normalized_mtime};
+pub use tree::provision_tree;
+pub use walk::source_walker;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -31,132 +47,308 @@ pub enum Error {
         #[source]
         source: io::Error,
     },
+    #[error("Failed to create reflink from {source_path} to {target_path}: {source}")]
+    CreateReflink {
+        source_path: Utf8PathBuf,
+        target_path: Utf8PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to copy {source_path} to {target_path}: {source}")]
+    CopyFile {
+        source_path: Utf8PathBuf,
+        target_path: Utf8PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("No link strategy configured to provision {path}")]
+    NoLinkStrategy { path: Utf8PathBuf },
+    #[error("Failed to create symlink {path} pointing to {target}: {source}")]
+    CreateSymlink {
+        path: Utf8PathBuf,
+        target: Utf8PathBuf,
+        #[source]
+        source: io::Error,
+    },
     #[error("Entry validation error: {source}")]
     EntryValidation {
         #[source]
         source: artifact::EntryError,
     },
+    #[error("Invalid glob pattern {pattern}: {source}")]
+    InvalidGlob {
+        pattern: String,
+        #[source]
+        source: globset::Error,
+    },
+    #[error("Glob pattern must be relative and must not contain '..': {0}")]
+    GlobOutsideRoot(String),
+    #[error("Failed to load {what} from the store: {source}")]
+    Store {
+        what: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Directory {dir} has an entry named {name:?}, which is not a plain name")]
+    InvalidEntryName { dir: Utf8PathBuf, name: String },
     #[error("Expected {a:?} to be strictly before {b:?}.")]
     ArtifactOrder { a: Artifact, b: Artifact },
     #[error("{b:?} conflicts with {a:?}")]
     ArtifactConflict { a: Artifact, b: Artifact },
 }
 
+/// Make the sorted paths from `from` available in `to`, with the default [`ProvisionOptions`].
+pub fn provision<'a>(
+    from: &Utf8Path,
+    to: &Utf8Path,
+    sorted_paths: impl Iterator<Item = &'a Artifact>,
+) -> Result<ProvisionStats, Error> {
+    provision_with(from, to, sorted_paths, &ProvisionOptions::default())
+}
+
 /// Make the sorted paths from `from` available in `to`.
 /// Create missing directories automatically.
 /// Go through directories in `from` recursively.
-/// Use hard links for files and make files read-only.
-pub fn provision<'a>(
+/// Link or copy files as configured in `options`, copies are made read-only
+/// and keep the executable bit. Their mtime can be normalized, see [`ProvisionOptions::mtime`].
+/// Hard links share the inode with the source, so their permissions are left alone.
+/// Recreate symlinks, including those found in directories, instead of following them.
+///
+/// Works in phases: first all paths are validated and directories are walked,
+/// then directories are created, then files and symlinks are provisioned in parallel.
+/// Nothing is written to `to` if validation fails.
+pub fn provision_with<'a>(
     from: &Utf8Path,
     to: &Utf8Path,
     sorted_paths: impl Iterator<Item = &'a Artifact>,
-) -> Result<(), Error> {
-    let mut last_entry: Option<Cow<'a, Artifact>> = None;
-    for entry in sorted_paths {
-        let validated_entry = entry
-            .validate()
-            .map_err(|e| Error::EntryValidation { source: e })?;
-        let relative_path = validated_entry.path();
-        if let Some(last_entry) = last_entry {
-            if last_entry.as_ref().cmp(validated_entry.as_ref()) != Ordering::Less {
-                return Err(Error::ArtifactOrder {
-                    a: last_entry.into_owned().to_owned(),
-                    b: entry.to_owned(),
-                });
-            }
+    options: &ProvisionOptions,
+) -> Result<ProvisionStats, Error> {
+    let progress = options.progress.as_deref().unwrap_or(&());
+    let started = Instant::now();
+    let plan = Plan::scan(from, sorted_paths, progress)?;
+    plan.apply(to, options, started.elapsed())
+}
 
-            if relative_path.starts_with(last_entry.path()) {
-                return Err(Error::ArtifactConflict {
-                    a: last_entry.into_owned().to_owned(),
-                    b: entry.to_owned(),
+/// Everything to provision, relative to `to`.
+#[derive(Debug, Default)]
+pub(crate) struct Plan {
+    /// Parents of requested files and symlinks.
+    pub(crate) parents: BTreeSet<Utf8PathBuf>,
+    /// Requested and walked directories, parents before their children.
+    pub(crate) directories: Vec<Utf8PathBuf>,
+    /// Where files are linked from, and their paths.
+    pub(crate) files: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+    /// Paths and targets.
+    pub(crate) symlinks: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+}
+
+impl Plan {
+    /// Rejects symlinks that leave the tree when following the other symlinks,
+    /// which the lexical check of each one on its own misses.
+    fn check_symlinks(&self) -> Result<(), Error> {
+        let symlinks: BTreeMap<Utf8PathBuf, Utf8PathBuf> = self
+            .symlinks
+            .iter()
+            .map(|(path, target)| (Artifact::normalize_path(path), target.clone()))
+            .collect();
+        for (path, target) in &symlinks {
+            if !artifact::resolves_within(path, &symlinks) {
+                return Err(Error::EntryValidation {
+                    source: artifact::EntryError::SymlinkEscapesTree {
+                        path: path.clone(),
+                        target: target.clone(),
+                    },
                 });
             }
         }
+        Ok(())
+    }
 
-        last_entry = Some(validated_entry.clone());
+    /// Creates the directories, then provisions files and symlinks in parallel.
+    pub(crate) fn apply(
+        &self,
+        to: &Utf8Path,
+        options: &ProvisionOptions,
+        scan: Duration,
+    ) -> Result<ProvisionStats, Error> {
+        self.check_symlinks()?;
+        let progress = options.progress.as_deref().unwrap_or(&());
+        let mut stats = ProvisionStats::default();
+        stats.timings.scan = scan;
+        stats.directories = self.directories.len();
+
+        let started = Instant::now();
+        progress.phase(
+            Phase::Directories,
+            self.directories.len() + self.parents.len(),
+        );
+        for dir in self.parents.iter().chain(&self.directories) {
+            let path = to.join(dir);
+            fs::create_dir_all(&path).map_err(|e| Error::CreateDir { path, source: e })?;
+            progress.advance(1);
+        }
+        stats.timings.directories = started.elapsed();
+
+        let started = Instant::now();
+        progress.phase(Phase::Files, self.files.len() + self.symlinks.len());
+        let strategies = self
+            .files
+            .par_iter()
+            .map(|(source_path, rel_path)| {
+                let strategy = link::link_file(
+                    source_path,
+                    &to.join(rel_path),
+                    &options.strategies,
+                    options.mtime,
+                )?;
+                progress.advance(1);
+                Ok(strategy)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        for strategy in strategies {
+            stats.record(strategy);
+        }
+        self.symlinks
+            .par_iter()
+            .try_for_each(|(rel_path, target)| {
+                let path = to.join(rel_path);
+                std::os::unix::fs::symlink(target, &path).map_err(|e| Error::CreateSymlink {
+                    path,
+                    target: target.clone(),
+                    source: e,
+                })?;
+                progress.advance(1);
+                Ok::<_, Error>(())
+            })?;
+        stats.symlinks = self.symlinks.len();
+        stats.timings.files = started.elapsed();
 
-        let source_path = from.join(relative_path);
-        let target_path = to.join(relative_path);
+        Ok(stats)
+    }
 
-        match &*validated_entry {
-            Artifact::Directory(_) => {
-                if !target_path.exists() {
-                    fs::create_dir_all(&target_path).map_err(|e| Error::CreateDir {
-                        path: target_path.clone(),
-                        source: e,
-                    })?;
+    /// Validates the requested artifacts and walks the requested directories.
+    pub(crate) fn scan<'a>(
+        from: &Utf8Path,
+        sorted_paths: impl Iterator<Item = &'a Artifact>,
+        progress: &dyn Progress,
+    ) -> Result<Plan, Error> {
+        let sorted_paths: Vec<&Artifact> = sorted_paths.collect();
+        progress.phase(Phase::Scan, sorted_paths.len());
+
+        let mut plan = Plan::default();
+        let mut last_entry: Option<Cow<'a, Artifact>> = None;
+        for entry in sorted_paths {
+            let validated_entry = entry
+                .validate()
+                .map_err(|e| Error::EntryValidation { source: e })?;
+            let relative_path = validated_entry.path();
+            if let Some(last_entry) = last_entry {
+                if last_entry.as_ref().cmp(validated_entry.as_ref()) != Ordering::Less {
+                    return Err(Error::ArtifactOrder {
+                        a: last_entry.into_owned().to_owned(),
+                        b: entry.to_owned(),
+                    });
                 }
-                // Recursively process directory contents
-                for entry in fs::read_dir(&source_path).map_err(|e| Error::Io {
-                    context: "while reading directory contents",
-                    path: source_path.clone(),
-                    source: e,
-                })? {
-                    let entry = entry.map_err(|e| Error::Io {
-                        context: "resolve dir entry",
-                        path: source_path.clone(),
-                        source: e,
-                    })?;
-                    let entry_path: Utf8PathBuf =
-                        entry.path().try_into().map_err(|e| Error::Io {
-                            context: "utf8-conversion, FIXME, extra error",
-                            path: Utf8PathBuf::from(entry.path().to_string_lossy().to_string()),
-                            source: io::Error::new(io::ErrorKind::InvalidData, e),
-                        })?;
-                    let entry_relative = entry_path.strip_prefix(from).map_err(|e| Error::Io {
-                        context: "when stripping prefix, FIXME",
-                        path: entry_path.clone(),
-                        source: io::Error::new(io::ErrorKind::Other, e),
-                    })?;
-
-                    provision(
-                        from,
-                        to,
-                        [if entry_path.is_dir() {
-                            Artifact::Directory(entry_relative.to_owned())
-                        } else {
-                            Artifact::File(entry_relative.to_owned())
-                        }]
-                        .iter(),
-                    )?;
+
+                if relative_path.starts_with(last_entry.path()) {
+                    return Err(Error::ArtifactConflict {
+                        a: last_entry.into_owned().to_owned(),
+                        b: entry.to_owned(),
+                    });
                 }
             }
-            Artifact::File(_) => {
-                if let Some(parent) = target_path.parent() {
-                    if !parent.exists() {
-                        fs::create_dir_all(parent).map_err(|e| Error::CreateDir {
-                            path: parent.to_path_buf(),
-                            source: e,
-                        })?;
-                    }
+
+            match &*validated_entry {
+                Artifact::Directory(path) => plan.walk(from, path)?,
+                Artifact::File(path) => {
+                    plan.add_parent(path);
+                    plan.files.push((from.join(path), path.clone()));
                 }
-                fs::hard_link(&source_path, &target_path).map_err(|e| Error::CreateHardLink {
-                    source_path: source_path.clone(),
-                    target_path: target_path.clone(),
+                Artifact::Symlink { path, target } => {
+                    plan.add_parent(path);
+                    plan.symlinks.push((path.clone(), target.clone()));
+                }
+            }
+            last_entry = Some(validated_entry);
+            progress.advance(1);
+        }
+        Ok(plan)
+    }
+
+    pub(crate) fn add_parent(&mut self, path: &Utf8Path) {
+        if let Some(parent) = path.parent().filter(|p| !p.as_str().is_empty()) {
+            self.parents.insert(parent.to_owned());
+        }
+    }
+
+    /// Adds the directory at `rel_path` and everything below it.
+    ///
+    /// Uses the file types `read_dir` returns, so entries are not `stat`ed.
+    fn walk(&mut self, from: &Utf8Path, rel_path: &Utf8Path) -> Result<(), Error> {
+        let mut todo = vec![rel_path.to_owned()];
+        while let Some(dir) = todo.pop() {
+            let source_path = from.join(&dir);
+            for entry in fs::read_dir(&source_path).map_err(|e| Error::Io {
+                context: "while reading directory contents",
+                path: source_path.clone(),
+                source: e,
+            })? {
+                let entry = entry.map_err(|e| Error::Io {
+                    context: "resolve dir entry",
+                    path: source_path.clone(),
                     source: e,
                 })?;
-                let mut perms = fs::metadata(&target_path)
-                    .map_err(|e| Error::Io {
-                        context: "get permissions",
-                        path: target_path.clone(),
-                        source: e,
-                    })?
-                    .permissions();
-                perms.set_readonly(true);
-                fs::set_permissions(&target_path, perms).map_err(|e| Error::Io {
-                    context: "change permissions",
-                    path: target_path.clone(),
+                let name = entry.file_name();
+                let Some(name) = name.to_str() else {
+                    return Err(Error::Io {
+                        context: "non UTF-8 file name",
+                        path: source_path.join(name.to_string_lossy().as_ref()),
+                        source: io::Error::from(io::ErrorKind::InvalidData),
+                    });
+                };
+                let entry_relative = dir.join(name);
+                let file_type = entry.file_type().map_err(|e| Error::Io {
+                    context: "get file type",
+                    path: from.join(&entry_relative),
                     source: e,
                 })?;
+
+                if file_type.is_symlink() {
+                    let target = read_link(&from.join(&entry_relative))?;
+                    artifact::check_symlink_target(&entry_relative, &target)
+                        .map_err(|e| Error::EntryValidation { source: e })?;
+                    self.symlinks.push((entry_relative, target));
+                } else if file_type.is_dir() {
+                    todo.push(entry_relative);
+                } else {
+                    self.files
+                        .push((from.join(&entry_relative), entry_relative));
+                }
             }
+            self.directories.push(dir);
         }
+        Ok(())
     }
-    Ok(())
+}
+
+pub(crate) fn read_link(path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+    let target = fs::read_link(path).map_err(|e| Error::Io {
+        context: "read symlink",
+        path: path.to_owned(),
+        source: e,
+    })?;
+    Utf8PathBuf::try_from(target).map_err(|e| Error::Io {
+        context: "non UTF-8 symlink target",
+        path: path.to_owned(),
+        source: io::Error::new(io::ErrorKind::InvalidData, e),
+    })
 }
 #[cfg(test)]
 mod tests {
     use std::fs::File;
     use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     use super::*;
@@ -193,17 +385,24 @@ mod tests {
         let mut file = File::create(&source_file)?;
         writeln!(file, "test content")?;
 
-        provision(
+        let stats = provision_with(
             &source_path,
             &target_path,
             [Artifact::File(test_file_path.into())].iter(),
+            &ProvisionOptions::default(),
         )?;
+        assert_eq!(stats.hard_linked, 1);
 
         // Check file exists in target
         let target_file = target_path.join(test_file_path);
         assert!(target_file.exists());
         assert_eq!(fs::read_to_string(&target_file)?, "test content\n");
-        assert!(fs::metadata(&target_file)?.permissions().readonly());
+        // The inode is shared with the source, which must not change.
+        assert_eq!(
+            fs::metadata(&target_file)?.ino(),
+            fs::metadata(&source_file)?.ino()
+        );
+        assert!(!fs::metadata(&source_file)?.permissions().readonly());
 
         Ok(())
     }
@@ -239,10 +438,9 @@ mod tests {
         assert!(target_path.join(dir_path).exists());
         assert!(target_path.join(dir_path).is_dir());
 
-        // Verify file was created and is read-only
+        // Verify file was created
         let target_file = target_path.join(file_path);
         assert!(target_file.exists());
-        assert!(fs::metadata(&target_file)?.permissions().readonly());
         assert_eq!(
             fs::read_to_string(&target_file)?,
             "directory test content\n"
@@ -275,11 +473,10 @@ mod tests {
         assert!(target_path.join("a/b/c").exists());
         assert!(target_path.join("a/b/c").is_dir());
 
-        // Check file content and permissions
+        // Check file content
         let target_file = target_path.join("a/b/c/nested.txt");
         assert!(target_file.exists());
         assert_eq!(fs::read_to_string(&target_file)?, "nested content\n");
-        assert!(fs::metadata(&target_file)?.permissions().readonly());
 
         Ok(())
     }
@@ -419,6 +616,344 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_provision_symlink() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        provision(
+            &source_path,
+            &target_path,
+            [Artifact::new_symlink("bin/tool", "../lib/tool")?].iter(),
+        )?;
+
+        let link = target_path.join("bin/tool");
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(fs::read_link(&link)?, Utf8PathBuf::from("../lib/tool"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_directory_keeps_symlinks() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        // A shared library with its soname link, and a link to a directory.
+        fs::create_dir_all(source_path.join("lib/plugins"))?;
+        let mut file = File::create(source_path.join("lib/libz.so.1"))?;
+        writeln!(file, "library")?;
+        std::os::unix::fs::symlink("libz.so.1", source_path.join("lib/libz.so"))?;
+        std::os::unix::fs::symlink("plugins", source_path.join("lib/extensions"))?;
+
+        provision(
+            &source_path,
+            &target_path,
+            [Artifact::Directory("lib".into())].iter(),
+        )?;
+
+        let so_link = target_path.join("lib/libz.so");
+        assert!(fs::symlink_metadata(&so_link)?.file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&so_link)?, "library\n");
+        let dir_link = target_path.join("lib/extensions");
+        assert!(fs::symlink_metadata(&dir_link)?.file_type().is_symlink());
+        assert_eq!(fs::read_link(&dir_link)?, Utf8PathBuf::from("plugins"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_rejects_escaping_symlink() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        fs::create_dir_all(source_path.join("dir"))?;
+        std::os::unix::fs::symlink("/etc/passwd", source_path.join("dir/passwd"))?;
+
+        let result = provision(
+            &source_path,
+            &target_path,
+            [Artifact::Directory("dir".into())].iter(),
+        );
+        match result {
+            Err(Error::EntryValidation {
+                source: artifact::EntryError::AbsoluteSymlinkTarget { path, .. },
+            }) => assert_eq!(path, Utf8PathBuf::from("dir/passwd")),
+            _ => panic!("Expected AbsoluteSymlinkTarget error instead of {result:?}"),
+        }
+        assert!(!target_path.join("dir/passwd").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_rejects_symlink_chains_escaping() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        // Each passes the lexical check, but `s` resolves to the parent of the tree.
+        fs::create_dir_all(source_path.join("dir"))?;
+        std::os::unix::fs::symlink(".", source_path.join("dir/l"))?;
+        std::os::unix::fs::symlink("l/../..", source_path.join("dir/s"))?;
+        // Chains within the tree are fine.
+        std::os::unix::fs::symlink("l/l/..", source_path.join("dir/up"))?;
+
+        let result = provision(
+            &source_path,
+            &target_path,
+            [Artifact::Directory("dir".into())].iter(),
+        );
+        match result {
+            Err(Error::EntryValidation {
+                source: artifact::EntryError::SymlinkEscapesTree { path, .. },
+            }) => assert_eq!(path, Utf8PathBuf::from("dir/s")),
+            _ => panic!("Expected SymlinkEscapesTree error instead of {result:?}"),
+        }
+        assert!(!target_path.join("dir").exists());
+
+        fs::remove_file(source_path.join("dir/s"))?;
+        provision(
+            &source_path,
+            &target_path,
+            [Artifact::Directory("dir".into())].iter(),
+        )?;
+        assert_eq!(
+            fs::read_link(target_path.join("dir/up"))?,
+            Utf8PathBuf::from("l/l/..")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_normalizes_mtime_on_request() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+        fs::write(source_path.join("fresh.c"), "fresh")?;
+        fs::write(source_path.join("normalized.c"), "normalized")?;
+        File::options()
+            .write(true)
+            .open(source_path.join("normalized.c"))?
+            .set_modified(normalized_mtime())?;
+
+        let stats = provision_with(
+            &source_path,
+            &target_path,
+            [
+                Artifact::File("fresh.c".into()),
+                Artifact::File("normalized.c".into()),
+            ]
+            .iter(),
+            &ProvisionOptions {
+                mtime: Some(normalized_mtime()),
+                ..Default::default()
+            },
+        )?;
+
+        // Only the source that already has the normalized mtime can share its inode.
+        assert_eq!((stats.hard_linked, stats.files()), (1, 2));
+        for file in ["fresh.c", "normalized.c"] {
+            assert_eq!(
+                fs::metadata(target_path.join(file))?.modified()?,
+                normalized_mtime()
+            );
+        }
+        assert_ne!(
+            fs::metadata(source_path.join("fresh.c"))?.modified()?,
+            normalized_mtime()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_with_copies() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        fs::create_dir(source_path.join("dir"))?;
+        let mut file = File::create(source_path.join("dir/file.txt"))?;
+        writeln!(file, "content")?;
+        std::os::unix::fs::symlink("file.txt", source_path.join("dir/link"))?;
+
+        let stats = provision_with(
+            &source_path,
+            &target_path,
+            [Artifact::Directory("dir".into())].iter(),
+            &ProvisionOptions {
+                strategies: vec![LinkStrategy::Copy],
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            (
+                stats.copied,
+                stats.files(),
+                stats.symlinks,
+                stats.directories
+            ),
+            (1, 1, 1, 1)
+        );
+
+        let source_file = source_path.join("dir/file.txt");
+        let target_file = target_path.join("dir/file.txt");
+        assert_eq!(fs::read_to_string(&target_file)?, "content\n");
+        assert_ne!(
+            fs::metadata(&target_file)?.ino(),
+            fs::metadata(&source_file)?.ino()
+        );
+        assert!(fs::metadata(&target_file)?.permissions().readonly());
+        assert!(!fs::metadata(&source_file)?.permissions().readonly());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_falls_back_from_reflink() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        let mut file = File::create(source_path.join("file.txt"))?;
+        writeln!(file, "content")?;
+
+        // Whether reflinks work depends on the file system of the temp dir.
+        let stats = provision_with(
+            &source_path,
+            &target_path,
+            [Artifact::File("file.txt".into())].iter(),
+            &ProvisionOptions {
+                strategies: vec![LinkStrategy::Reflink, LinkStrategy::Copy],
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(stats.files(), 1);
+        assert_eq!(stats.hard_linked, 0);
+
+        let target_file = target_path.join("file.txt");
+        assert_eq!(fs::read_to_string(&target_file)?, "content\n");
+        assert!(fs::metadata(&target_file)?.permissions().readonly());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision_without_strategies() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        File::create(source_path.join("file.txt"))?;
+
+        let result = provision_with(
+            &source_path,
+            &target_path,
+            [Artifact::File("file.txt".into())].iter(),
+            &ProvisionOptions {
+                strategies: vec![],
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(Error::NoLinkStrategy { .. })));
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingProgress(std::sync::Mutex<Vec<(Phase, usize, usize)>>);
+
+    impl Progress for RecordingProgress {
+        fn phase(&self, phase: Phase, total: usize) {
+            self.0.lock().unwrap().push((phase, total, 0));
+        }
+
+        fn advance(&self, count: usize) {
+            self.0.lock().unwrap().last_mut().unwrap().2 += count;
+        }
+    }
+
+    #[test]
+    fn test_provision_tree_reports_progress() -> Result<(), Box<dyn std::error::Error>> {
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+
+        let source_path = Utf8PathBuf::from_path_buf(source_dir.path().to_path_buf()).unwrap();
+        let target_path = Utf8PathBuf::from_path_buf(target_dir.path().to_path_buf()).unwrap();
+
+        for package in ["a", "b", "c"] {
+            fs::create_dir_all(source_path.join(format!("node_modules/{package}/lib")))?;
+            for i in 0..10 {
+                fs::write(
+                    source_path.join(format!("node_modules/{package}/lib/{i}.js")),
+                    format!("{package}{i}"),
+                )?;
+            }
+        }
+        fs::create_dir_all(source_path.join("node_modules/.bin"))?;
+        std::os::unix::fs::symlink("../a/lib/0.js", source_path.join("node_modules/.bin/a"))?;
+        fs::write(source_path.join("package.json"), "{}")?;
+
+        let progress = Arc::new(RecordingProgress::default());
+        let stats = provision_with(
+            &source_path,
+            &target_path,
+            [
+                Artifact::Directory("node_modules".into()),
+                Artifact::File("package.json".into()),
+            ]
+            .iter(),
+            &ProvisionOptions {
+                progress: Some(progress.clone()),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(stats.files(), 31);
+        assert_eq!(stats.symlinks, 1);
+        // node_modules, .bin and a, b, c with their lib directories.
+        assert_eq!(stats.directories, 8);
+        assert_eq!(
+            fs::read_to_string(target_path.join("node_modules/.bin/a"))?,
+            "a0"
+        );
+        assert_eq!(
+            fs::read_to_string(target_path.join("node_modules/c/lib/9.js"))?,
+            "c9"
+        );
+        assert_eq!(
+            *progress.0.lock().unwrap(),
+            [
+                (Phase::Scan, 2, 2),
+                (Phase::Directories, 8, 8),
+                (Phase::Files, 32, 32)
+            ]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_provision_existing_target_directory() -> Result<(), Box<dyn std::error::Error>> {
         let source_dir = tempdir()?;
@@ -0,0 +1,271 @@
+//! The ways a file from the source tree is made available in the target tree.
+
+use camino::Utf8Path;
+use nix::errno::Errno;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::AddAssign;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::Error;
+use crate::progress::{PhaseTimings, Progress};
+
+/// How a file is made available in the target tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LinkStrategy {
+    /// Share the inode with the source. Only works within one file system,
+    /// and the file keeps the permissions and mtime of the source. So if an mtime
+    /// is requested, only sources that already have it are hard-linked.
+    HardLink,
+    /// Share the extents with the source via `FICLONE`, e.g. on btrfs or XFS.
+    /// The file gets its own inode, which is made read-only
+    /// and keeps the executable bit of the source.
+    Reflink,
+    /// Copy the content into a new, read-only file,
+    /// which keeps the executable bit of the source.
+    Copy,
+}
+
+/// Options for [`crate::provision_with`].
+#[derive(Clone)]
+pub struct ProvisionOptions {
+    /// Tried in order for every file, until one works between source and target.
+    ///
+    /// The next strategy is only tried if the previous one is not supported,
+    /// e.g. because source and target are on different file systems.
+    pub strategies: Vec<LinkStrategy>,
+    /// Receives progress, if set.
+    pub progress: Option<Arc<dyn Progress>>,
+    /// The mtime of every provisioned file, e.g. [`normalized_mtime`], so that tools
+    /// comparing timestamps like `make` see the same ones in every build.
+    /// Reflinked and copied files get it set, sources with another mtime are
+    /// not hard-linked, as the shared inode would keep the mtime of the source.
+    ///
+    /// `None` by default, which keeps the mtime of the sources and lets every file be
+    /// hard-linked. Blobs keep the mtime of when they were stored, so normalizing
+    /// the mtime of a stored tree copies all of its files.
+    pub mtime: Option<SystemTime>,
+}
+
+/// The mtime to request for provisioned files with [`ProvisionOptions::mtime`].
+///
+/// One second after the epoch, as some tools treat a zero mtime as missing.
+pub fn normalized_mtime() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1)
+}
+
+impl fmt::Debug for ProvisionOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProvisionOptions")
+            .field("strategies", &self.strategies)
+            .field("progress", &self.progress.is_some())
+            .field("mtime", &self.mtime)
+            .finish()
+    }
+}
+
+impl Default for ProvisionOptions {
+    fn default() -> Self {
+        ProvisionOptions {
+            strategies: vec![
+                LinkStrategy::HardLink,
+                LinkStrategy::Reflink,
+                LinkStrategy::Copy,
+            ],
+            progress: None,
+            mtime: None,
+        }
+    }
+}
+
+/// What provisioning did, e.g. to notice builds falling back to copies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProvisionStats {
+    pub hard_linked: usize,
+    pub reflinked: usize,
+    pub copied: usize,
+    pub symlinks: usize,
+    pub directories: usize,
+    pub timings: PhaseTimings,
+}
+
+impl ProvisionStats {
+    /// The number of regular files provisioned, by any strategy.
+    pub fn files(&self) -> usize {
+        self.hard_linked + self.reflinked + self.copied
+    }
+
+    pub(crate) fn record(&mut self, strategy: LinkStrategy) {
+        match strategy {
+            LinkStrategy::HardLink => self.hard_linked += 1,
+            LinkStrategy::Reflink => self.reflinked += 1,
+            LinkStrategy::Copy => self.copied += 1,
+        }
+    }
+}
+
+impl AddAssign for ProvisionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.hard_linked += other.hard_linked;
+        self.reflinked += other.reflinked;
+        self.copied += other.copied;
+        self.symlinks += other.symlinks;
+        self.directories += other.directories;
+        self.timings += other.timings;
+    }
+}
+
+/// Makes `source_path` available at `target_path` with the first supported strategy.
+pub(crate) fn link_file(
+    source_path: &Utf8Path,
+    target_path: &Utf8Path,
+    strategies: &[LinkStrategy],
+    mtime: Option<SystemTime>,
+) -> Result<LinkStrategy, Error> {
+    for (i, &strategy) in strategies.iter().enumerate() {
+        let result = match strategy {
+            LinkStrategy::HardLink => hard_link(source_path, target_path, mtime),
+            LinkStrategy::Reflink => reflink(source_path, target_path, mtime),
+            LinkStrategy::Copy => copy(source_path, target_path, mtime),
+        };
+        let e = match result {
+            Ok(()) => return Ok(strategy),
+            Err(e) if is_unsupported(&e) && i + 1 < strategies.len() => continue,
+            Err(e) => e,
+        };
+        let source_path = source_path.to_owned();
+        let target_path = target_path.to_owned();
+        return Err(match strategy {
+            LinkStrategy::HardLink => Error::CreateHardLink {
+                source_path,
+                target_path,
+                source: e,
+            },
+            LinkStrategy::Reflink => Error::CreateReflink {
+                source_path,
+                target_path,
+                source: e,
+            },
+            LinkStrategy::Copy => Error::CopyFile {
+                source_path,
+                target_path,
+                source: e,
+            },
+        });
+    }
+    Err(Error::NoLinkStrategy {
+        path: target_path.to_owned(),
+    })
+}
+
+/// Whether `e` means the strategy cannot work here, rather than that provisioning failed.
+///
+/// Permission errors are real failures and never fall through to the next strategy.
+fn is_unsupported(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    let Some(errno) = e.raw_os_error() else {
+        return false;
+    };
+    matches!(
+        Errno::from_raw(errno),
+        // Different file systems.
+        Errno::EXDEV
+        // Too many hard links to the source.
+        | Errno::EMLINK
+    )
+}
+
+fn hard_link(
+    source_path: &Utf8Path,
+    target_path: &Utf8Path,
+    mtime: Option<SystemTime>,
+) -> io::Result<()> {
+    if let Some(mtime) = mtime {
+        if fs::metadata(source_path)?.modified()? != mtime {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the source has another mtime than requested",
+            ));
+        }
+    }
+    fs::hard_link(source_path, target_path)
+}
+
+fn reflink(
+    source_path: &Utf8Path,
+    target_path: &Utf8Path,
+    mtime: Option<SystemTime>,
+) -> io::Result<()> {
+    let source = fs::File::open(source_path)?;
+    let mode = source.metadata()?.permissions().mode();
+    let target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(target_path)?;
+    if let Err(e) = zwischen::reflink(&source, &target) {
+        drop(target);
+        fs::remove_file(target_path)?;
+        // `FICLONE` fails with these if the file system cannot share extents between the files.
+        let unsupported = e.raw_os_error().is_some_and(|errno| {
+            matches!(
+                Errno::from_raw(errno),
+                Errno::EOPNOTSUPP | Errno::EXDEV | Errno::ENOTTY | Errno::EINVAL
+            )
+        });
+        return Err(if unsupported {
+            io::Error::new(io::ErrorKind::Unsupported, e)
+        } else {
+            e
+        });
+    }
+    finish(&target, mtime)
+}
+
+fn copy(
+    source_path: &Utf8Path,
+    target_path: &Utf8Path,
+    mtime: Option<SystemTime>,
+) -> io::Result<()> {
+    let mut source = fs::File::open(source_path)?;
+    let mode = source.metadata()?.permissions().mode();
+    let mut target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(target_path)?;
+    io::copy(&mut source, &mut target)?;
+    finish(&target, mtime)
+}
+
+/// Sets the mtime and makes the file read-only, keeping the executable bit.
+///
+/// Only used on inodes of their own, other links to the source must not change.
+fn finish(file: &fs::File, mtime: Option<SystemTime>) -> io::Result<()> {
+    if let Some(mtime) = mtime {
+        file.set_modified(mtime)?;
+    }
+    let mut perms = file.metadata()?.permissions();
+    perms.set_readonly(true);
+    file.set_permissions(perms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permission_errors_are_not_unsupported() {
+        let error = |errno: Errno| io::Error::from_raw_os_error(errno as i32);
+        assert!(is_unsupported(&error(Errno::EXDEV)));
+        assert!(is_unsupported(&error(Errno::EOPNOTSUPP)));
+        assert!(!is_unsupported(&error(Errno::EPERM)));
+        assert!(!is_unsupported(&error(Errno::EINVAL)));
+    }
+}
@@ -0,0 +1,54 @@
+//! Reporting on long-running provisioning, e.g. of a large `node_modules`.
+
+use std::ops::AddAssign;
+use std::time::Duration;
+
+/// The phases of provisioning, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Validating the requested artifacts and walking directories.
+    Scan,
+    /// Creating directories in the target.
+    Directories,
+    /// Linking or copying files and creating symlinks, in parallel.
+    Files,
+}
+
+/// Receives progress while provisioning.
+///
+/// Called from several threads during [`Phase::Files`].
+pub trait Progress: Send + Sync {
+    /// A phase starts, with `total` items to process.
+    ///
+    /// For [`Phase::Scan`], the items are the requested artifacts,
+    /// the number of files below directories is not known yet.
+    fn phase(&self, _phase: Phase, _total: usize) {}
+
+    /// `count` more items of the current phase are done.
+    fn advance(&self, _count: usize) {}
+}
+
+/// Ignores progress.
+impl Progress for () {}
+
+/// How long each phase took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    pub scan: Duration,
+    pub directories: Duration,
+    pub files: Duration,
+}
+
+impl PhaseTimings {
+    pub fn total(&self) -> Duration {
+        self.scan + self.directories + self.files
+    }
+}
+
+impl AddAssign for PhaseTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.scan += other.scan;
+        self.directories += other.directories;
+        self.files += other.files;
+    }
+}
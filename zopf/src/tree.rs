@@ -0,0 +1,201 @@
+//! Provisioning trees stored as [`model::Dir`] listings, e.g. outputs restored from the cache.
+
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use model::{Digest, DirEntryKind};
+use rayon::prelude::*;
+use zwischen::Zwischen;
+
+use crate::progress::Phase;
+use crate::{Error, Plan, ProvisionOptions, ProvisionStats, artifact};
+
+/// Makes the tree with `entry_hash`, as stored by [`zwischen::tree::store_tree`],
+/// available in `to`.
+///
+/// Files are read-only, and executable if they were when the tree was stored.
+/// Their blobs are retrieved while scanning, so nothing is written to `to`
+/// if a blob is missing. Otherwise like [`crate::provision_with`].
+pub fn provision_tree(
+    zwischen: &dyn Zwischen,
+    entry_hash: Digest,
+    to: &Utf8Path,
+    options: &ProvisionOptions,
+) -> Result<ProvisionStats, Error> {
+    let progress = options.progress.as_deref().unwrap_or(&());
+    let started = Instant::now();
+    progress.phase(Phase::Scan, 1);
+
+    let mut plan = Plan::default();
+    let mut files = Vec::new();
+    let mut todo = vec![(Utf8PathBuf::new(), entry_hash)];
+    while let Some((dir_path, entry_hash)) = todo.pop() {
+        let dir = zwischen::tree::load_dir(zwischen, entry_hash).map_err(|e| Error::Store {
+            what: format!("directory {dir_path:?} ({entry_hash})"),
+            source: e,
+        })?;
+        for (name, entry) in dir.entries() {
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                return Err(Error::InvalidEntryName {
+                    dir: dir_path,
+                    name: name.clone(),
+                });
+            }
+            let path = dir_path.join(name);
+            match &entry.kind {
+                DirEntryKind::Dir => todo.push((path, entry.content_hash)),
+                DirEntryKind::File { attributes } => {
+                    files.push((path, entry.content_hash, attributes.clone()));
+                }
+                DirEntryKind::Symlink { target } => {
+                    let target = Utf8PathBuf::from(target);
+                    artifact::check_symlink_target(&path, &target)
+                        .map_err(|e| Error::EntryValidation { source: e })?;
+                    plan.symlinks.push((path, target));
+                }
+            }
+        }
+        plan.directories.push(dir_path);
+    }
+
+    plan.files = files
+        .par_iter()
+        .map(|(path, key, attributes)| {
+            let source_path =
+                zwischen
+                    .retrieve_file(key, attributes)
+                    .map_err(|e| Error::Store {
+                        what: format!("file {path:?} ({key})"),
+                        source: e,
+                    })?;
+            Ok((source_path, path.clone()))
+        })
+        .collect::<Result<_, Error>>()?;
+    progress.advance(1);
+
+    plan.apply(to, options, started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::tempdir;
+    use zwischen::FileSystemZwischen;
+
+    use super::*;
+    use crate::{LinkStrategy, normalized_mtime};
+
+    fn mode(path: &Utf8Path) -> std::io::Result<u32> {
+        Ok(fs::metadata(path)?.permissions().mode() & 0o777)
+    }
+
+    #[test]
+    fn provisioned_tree_keeps_executable_bit() -> anyhow::Result<()> {
+        let cas_dir = tempdir()?;
+        let source_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let zwischen = FileSystemZwischen::new(Utf8PathBuf::try_from(cas_dir.path().to_owned())?);
+        let source_path = Utf8Path::from_path(source_dir.path()).unwrap();
+        let target_path = Utf8Path::from_path(target_dir.path()).unwrap();
+
+        fs::create_dir_all(source_path.join("bin"))?;
+        fs::write(source_path.join("bin/configure"), "#!/bin/sh\n")?;
+        fs::set_permissions(
+            source_path.join("bin/configure"),
+            Permissions::from_mode(0o755),
+        )?;
+        fs::write(source_path.join("config.h"), "#define X 1\n")?;
+        std::os::unix::fs::symlink("bin/configure", source_path.join("configure"))?;
+        let root = zwischen::tree::store_tree(&zwischen, source_path)?;
+
+        let linked = target_path.join("linked");
+        let stats = provision_tree(&zwischen, root, &linked, &ProvisionOptions::default())?;
+        assert_eq!(
+            (stats.hard_linked, stats.symlinks, stats.directories),
+            (2, 1, 2)
+        );
+        assert_eq!(mode(&linked.join("bin/configure"))?, 0o555);
+        assert_eq!(mode(&linked.join("config.h"))?, 0o444);
+        assert_eq!(
+            fs::read_link(linked.join("configure"))?,
+            Utf8PathBuf::from("bin/configure")
+        );
+
+        let copied = target_path.join("copied");
+        let stats = provision_tree(
+            &zwischen,
+            root,
+            &copied,
+            &ProvisionOptions {
+                strategies: vec![LinkStrategy::Copy],
+                mtime: Some(normalized_mtime()),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(stats.copied, 2);
+        assert_eq!(mode(&copied.join("bin/configure"))?, 0o555);
+        assert_eq!(mode(&copied.join("config.h"))?, 0o444);
+        assert_eq!(
+            fs::metadata(copied.join("config.h"))?.modified()?,
+            normalized_mtime()
+        );
+        assert_eq!(fs::read_to_string(copied.join("configure"))?, "#!/bin/sh\n");
+
+        // Blobs keep the mtime of when they were stored, so they are not hard-linked
+        // if a normalized mtime is requested.
+        let normalized = target_path.join("normalized");
+        let stats = provision_tree(
+            &zwischen,
+            root,
+            &normalized,
+            &ProvisionOptions {
+                mtime: Some(normalized_mtime()),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!((stats.hard_linked, stats.files()), (0, 2));
+        assert_eq!(mode(&normalized.join("bin/configure"))?, 0o555);
+        assert_eq!(
+            fs::metadata(normalized.join("config.h"))?.modified()?,
+            normalized_mtime()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_blob_writes_nothing() -> anyhow::Result<()> {
+        let cas_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let zwischen = FileSystemZwischen::new(Utf8PathBuf::try_from(cas_dir.path().to_owned())?);
+        let target_path = Utf8Path::from_path(target_dir.path()).unwrap().join("out");
+
+        let dir = model::Dir::from_entries(
+            [(
+                "lib.a".to_string(),
+                model::DirEntry {
+                    kind: DirEntryKind::File {
+                        attributes: model::FileAttributes {
+                            executable: false,
+                            size: 3,
+                        },
+                    },
+                    content_hash: Digest::of(b"lib"),
+                },
+            )]
+            .into(),
+        );
+        zwischen.store_bytes(&dir.to_bytes())?;
+
+        let result = provision_tree(
+            &zwischen,
+            dir.entry_hash(),
+            &target_path,
+            &ProvisionOptions::default(),
+        );
+        assert!(matches!(result, Err(Error::Store { .. })));
+        assert!(!target_path.exists());
+        Ok(())
+    }
+}
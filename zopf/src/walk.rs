@@ -0,0 +1,31 @@
+//! Walking source trees, skipping what is not part of them.
+
+use camino::Utf8Path;
+
+/// Directories of version control systems, never part of the source tree.
+const VERSION_CONTROL_DIRS: [&str; 3] = [".git", ".hg", ".jj"];
+
+/// Walks the source files below `dir`, skipping `excluded`, version control directories
+/// and whatever ignore files exclude. Other hidden files are source files like any other.
+pub fn source_walker(dir: &Utf8Path, excluded: &Utf8Path) -> ignore::WalkBuilder {
+    filtered_source_walker(dir, excluded, |_| true)
+}
+
+/// Like [`source_walker`], but also skips the entries `filter` rejects,
+/// with everything below them.
+pub fn filtered_source_walker(
+    dir: &Utf8Path,
+    excluded: &Utf8Path,
+    filter: impl Fn(&ignore::DirEntry) -> bool + Send + Sync + 'static,
+) -> ignore::WalkBuilder {
+    let excluded = excluded.to_owned();
+    let mut builder = ignore::WalkBuilder::new(dir);
+    builder.hidden(false).filter_entry(move |e| {
+        e.path() != excluded
+            && !VERSION_CONTROL_DIRS
+                .iter()
+                .any(|name| e.file_name() == *name)
+            && filter(e)
+    });
+    builder
+}
@@ -0,0 +1,232 @@
+//! Checking the integrity of a [`FileSystemZwischen`].
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{
+    ACTIONS_DIR, BlobFile, FileSystemZwischen, Key, QUARANTINE_DIR, TEMP_DIR, hash_compressed_file,
+    hash_file,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckOptions {
+    /// Move problematic files to the quarantine directory instead of only reporting them.
+    pub quarantine: bool,
+    /// Younger temp files might belong to a `store` that is still running.
+    pub temp_file_min_age: Duration,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        FsckOptions {
+            quarantine: false,
+            temp_file_min_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The content does not hash to the key the blob is stored under.
+    Mismatch {
+        key: Key,
+        actual: Key,
+    },
+    Unreadable(String),
+    /// A file that does not belong to the sharded layout.
+    Orphaned,
+    /// Left behind by an interrupted `store`.
+    TempFile,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Relative to the base path of the store.
+    pub path: Utf8PathBuf,
+    pub kind: ProblemKind,
+    /// Where the file was moved to, relative to the base path of the store.
+    pub quarantined: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// The number of blobs that were rehashed.
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl FileSystemZwischen {
+    /// Rehashes all blobs, decompressing compressed ones, and looks for files
+    /// that do not belong into the store.
+    pub fn fsck(&self, options: &FsckOptions) -> Result<FsckReport> {
+        let mut report = FsckReport::default();
+        if !self.base_path.exists() {
+            return Ok(report);
+        }
+        let mut todo = vec![Utf8PathBuf::new()];
+        while let Some(rel_dir) = todo.pop() {
+            let dir = self.base_path.join(&rel_dir);
+            let entries = match dir.read_dir_utf8() {
+                Ok(entries) => entries,
+                Err(e) => {
+                    report.problems.push(Problem {
+                        path: rel_dir,
+                        kind: ProblemKind::Unreadable(e.to_string()),
+                        quarantined: None,
+                    });
+                    continue;
+                }
+            };
+            for entry in entries {
+                let entry = entry.with_context(|| format!("while listing {dir:?}"))?;
+                let rel_path = rel_dir.join(entry.file_name());
+                let file_type = entry
+                    .file_type()
+                    .with_context(|| format!("while inspecting {:?}", entry.path()))?;
+                // Action records are not blobs, the blobs they refer to are checked.
+                if rel_path == QUARANTINE_DIR || rel_path == ACTIONS_DIR {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    todo.push(rel_path);
+                    continue;
+                }
+                let kind = if rel_path.starts_with(TEMP_DIR) {
+                    if !is_older_than(entry.path(), options.temp_file_min_age) {
+                        continue;
+                    }
+                    ProblemKind::TempFile
+                } else if let Some((key, blob_file)) =
+                    BlobFile::parse(&rel_path).filter(|_| file_type.is_file())
+                {
+                    report.checked += 1;
+                    let hash = match blob_file {
+                        BlobFile::Compressed => hash_compressed_file(entry.path()),
+                        BlobFile::Plain | BlobFile::Materialized | BlobFile::Executable => {
+                            hash_file(entry.path())
+                        }
+                    };
+                    match hash {
+                        Ok(actual) if actual == key => continue,
+                        Ok(actual) => ProblemKind::Mismatch { key, actual },
+                        Err(e) => ProblemKind::Unreadable(e.to_string()),
+                    }
+                } else {
+                    ProblemKind::Orphaned
+                };
+
+                let quarantined = if options.quarantine {
+                    Some(self.quarantine(&rel_path)?)
+                } else {
+                    None
+                };
+                report.problems.push(Problem {
+                    path: rel_path,
+                    kind,
+                    quarantined,
+                });
+            }
+        }
+        report.problems.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(report)
+    }
+
+    /// Moves the file at `rel_path` into the quarantine directory and returns its new path.
+    fn quarantine(&self, rel_path: &Utf8Path) -> Result<Utf8PathBuf> {
+        let quarantine_dir = self.base_path.join(QUARANTINE_DIR);
+        std::fs::create_dir_all(&quarantine_dir)
+            .with_context(|| format!("while creating {quarantine_dir:?}"))?;
+        let name = rel_path.as_str().replace('/', "_");
+        let mut target = Utf8Path::new(QUARANTINE_DIR).join(&name);
+        let mut counter = 0;
+        while self.base_path.join(&target).exists() {
+            counter += 1;
+            target = Utf8Path::new(QUARANTINE_DIR).join(format!("{name}.{counter}"));
+        }
+        std::fs::rename(self.base_path.join(rel_path), self.base_path.join(&target))
+            .with_context(|| format!("while quarantining {rel_path:?}"))?;
+        Ok(target)
+    }
+}
+
+fn is_older_than(path: &Utf8Path, age: Duration) -> bool {
+    let Ok(modified) = path.symlink_metadata().and_then(|m| m.modified()) else {
+        // Gone already or unreadable, let the next run deal with it.
+        return false;
+    };
+    SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|elapsed| elapsed >= age)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::Zwischen;
+    use crate::tests::{ZwischenContext, write_content};
+
+    #[test]
+    fn fsck_finds_problems() -> Result<()> {
+        let mut context = ZwischenContext::new()?;
+        let good = context.store_content(b"good")?;
+        let bad = context.store_content(b"bad")?;
+        let bad_path = context.zwischen.retrieve(&bad)?;
+        std::fs::set_permissions(&bad_path, std::fs::Permissions::from_mode(0o644))?;
+        write_content(&bad_path, b"worse")?;
+
+        let base = context.zwischen.base_path().to_owned();
+        write_content(base.join("stray"), b"")?;
+        std::fs::create_dir_all(base.join(TEMP_DIR))?;
+        write_content(base.join(TEMP_DIR).join("store-1"), b"")?;
+
+        let options = FsckOptions {
+            quarantine: false,
+            temp_file_min_age: Duration::ZERO,
+        };
+        let report = context.zwischen.fsck(&options)?;
+        assert_eq!(report.checked, 2);
+        let kinds: Vec<_> = report
+            .problems
+            .iter()
+            .map(|p| (p.path.clone(), p.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    BlobFile::Plain.rel_path(&bad),
+                    ProblemKind::Mismatch {
+                        key: bad,
+                        actual: Key::of(b"worse"),
+                    }
+                ),
+                ("stray".into(), ProblemKind::Orphaned),
+                ("tmp/store-1".into(), ProblemKind::TempFile),
+            ]
+        );
+
+        // Young temp files might still be in use.
+        let report = context.zwischen.fsck(&FsckOptions::default())?;
+        assert_eq!(report.problems.len(), 2);
+
+        let report = context.zwischen.fsck(&FsckOptions {
+            quarantine: true,
+            ..options.clone()
+        })?;
+        assert!(report.problems.iter().all(|p| p.quarantined.is_some()));
+        assert!(!bad_path.exists());
+        assert!(context.zwischen.fsck(&options)?.is_clean());
+        assert!(context.zwischen.retrieve(&good).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+//! Deleting blobs that are no longer reachable from the build database.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use model::{Dir, DirEntryKind, DirStore};
+use tracing::{debug, warn};
+
+use crate::{ACTIONS_DIR, BlobFile, FileSystemZwischen, Key, QUARANTINE_DIR, TEMP_DIR, Zwischen};
+
+/// A blob that must be kept, together with everything it references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Root {
+    Blob(Key),
+    /// A stored [`Dir`], keeping all files and directories below it alive.
+    Dir(Key),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcOptions {
+    /// Blobs modified more recently are never deleted,
+    /// they might belong to a build that has not recorded them yet.
+    pub grace_period: Duration,
+    /// If set, also evict reachable blobs that are not pinned, least recently used
+    /// first, until the store is at most this many bytes.
+    pub size_budget: Option<u64>,
+    /// Only report what would be deleted.
+    pub dry_run: bool,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions {
+            grace_period: Duration::from_secs(60 * 60),
+            size_budget: None,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Blobs reachable from the roots.
+    pub reachable: usize,
+    /// Unreachable blobs that were deleted.
+    pub deleted: usize,
+    /// Reachable blobs that were deleted to stay within the size budget.
+    pub evicted: usize,
+    pub freed_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+/// A file of a blob found on disk.
+///
+/// Compressed blobs may have a second file in the materialization cache and
+/// any blob an executable copy, all of them are deleted once the blob is unreachable.
+struct Blob {
+    key: Key,
+    path: Utf8PathBuf,
+    size: u64,
+    modified: SystemTime,
+    accessed: SystemTime,
+}
+
+impl DirStore for FileSystemZwischen {
+    fn store_dir(&self, dir: &Dir) -> Result<()> {
+        self.store_bytes(&dir.to_bytes())?;
+        Ok(())
+    }
+
+    fn load_dir(&self, entry_hash: Key) -> Result<Dir> {
+        crate::tree::load_dir(self, entry_hash)
+    }
+}
+
+impl FileSystemZwischen {
+    /// Returns the keys of `roots` and of everything reachable through stored directories.
+    pub fn mark(&self, roots: impl IntoIterator<Item = Root>) -> BTreeSet<Key> {
+        let mut reachable = BTreeSet::new();
+        let mut todo: Vec<Root> = roots.into_iter().collect();
+        while let Some(root) = todo.pop() {
+            let (Root::Blob(key) | Root::Dir(key)) = root;
+            if !reachable.insert(key) {
+                continue;
+            }
+            if let Root::Dir(key) = root {
+                match self.load_dir(key) {
+                    Ok(dir) => todo.extend(dir.entries().values().filter_map(|entry| {
+                        let key = entry.content_hash;
+                        match entry.kind {
+                            DirEntryKind::Dir => Some(Root::Dir(key)),
+                            DirEntryKind::File { .. } => Some(Root::Blob(key)),
+                            // The target is stored in the listing, not as a blob.
+                            DirEntryKind::Symlink { .. } => None,
+                        }
+                    })),
+                    Err(e) => warn!("cannot follow directory {key}: {e:?}"),
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Deletes blobs that are not reachable from `roots` and are older than the grace period.
+    ///
+    /// Blobs reachable from `pinned` are kept like those reachable from `roots`,
+    /// and are never evicted to stay within the size budget.
+    pub fn gc(
+        &self,
+        roots: impl IntoIterator<Item = Root>,
+        pinned: impl IntoIterator<Item = Root>,
+        options: &GcOptions,
+    ) -> Result<GcReport> {
+        let pinned = self.mark(pinned);
+        let mut reachable = self.mark(roots);
+        reachable.extend(pinned.iter().copied());
+        let now = SystemTime::now();
+        let in_grace_period = |time: SystemTime| {
+            now.duration_since(time)
+                .map_or(true, |age| age < options.grace_period)
+        };
+
+        let mut report = GcReport::default();
+        let mut kept = Vec::new();
+        for blob in self.blobs()? {
+            if reachable.contains(&blob.key) {
+                report.reachable += 1;
+            } else if !in_grace_period(blob.modified) {
+                self.delete(&blob, options)?;
+                report.deleted += 1;
+                report.freed_bytes += blob.size;
+                continue;
+            }
+            report.remaining_bytes += blob.size;
+            kept.push(blob);
+        }
+
+        if let Some(budget) = options.size_budget {
+            kept.sort_by_key(|blob| blob.accessed);
+            for blob in kept {
+                if report.remaining_bytes <= budget {
+                    break;
+                }
+                if in_grace_period(blob.modified) || pinned.contains(&blob.key) {
+                    continue;
+                }
+                self.delete(&blob, options)?;
+                report.evicted += 1;
+                report.freed_bytes += blob.size;
+                report.remaining_bytes -= blob.size;
+            }
+        }
+        Ok(report)
+    }
+
+    fn delete(&self, blob: &Blob, options: &GcOptions) -> Result<()> {
+        debug!("deleting {} ({} bytes)", blob.key, blob.size);
+        if !options.dry_run {
+            std::fs::remove_file(&blob.path)
+                .with_context(|| format!("while deleting {:?}", blob.path))?;
+        }
+        Ok(())
+    }
+
+    /// Lists the files of all blobs in the sharded layout, the materialization cache
+    /// and the executable copies.
+    fn blobs(&self) -> Result<Vec<Blob>> {
+        let mut blobs = Vec::new();
+        if !self.base_path.exists() {
+            return Ok(blobs);
+        }
+        let mut todo = vec![Utf8PathBuf::new()];
+        while let Some(rel_dir) = todo.pop() {
+            let dir = self.base_path.join(&rel_dir);
+            for entry in dir
+                .read_dir_utf8()
+                .with_context(|| format!("while listing {dir:?}"))?
+            {
+                let entry = entry.with_context(|| format!("while listing {dir:?}"))?;
+                let rel_path = rel_dir.join(entry.file_name());
+                if rel_path == TEMP_DIR || rel_path == QUARANTINE_DIR || rel_path == ACTIONS_DIR {
+                    continue;
+                }
+                let metadata = entry
+                    .metadata()
+                    .with_context(|| format!("while inspecting {:?}", entry.path()))?;
+                if metadata.is_dir() {
+                    todo.push(rel_path);
+                } else if let Some((key, _)) = BlobFile::parse(&rel_path) {
+                    blobs.push(Blob {
+                        key,
+                        path: entry.path().to_owned(),
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                        accessed: metadata.accessed()?,
+                    });
+                }
+            }
+        }
+        Ok(blobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::{File, FileTimes};
+
+    use model::{DirEntry, FileAttributes};
+
+    use super::*;
+    use crate::tests::ZwischenContext;
+
+    fn file_entry(content: &[u8]) -> DirEntry {
+        DirEntry {
+            kind: DirEntryKind::File {
+                attributes: FileAttributes {
+                    executable: false,
+                    size: content.len() as u64,
+                },
+            },
+            content_hash: Key::of(content),
+        }
+    }
+
+    /// Pretends the blob was stored and last read `age` ago.
+    fn age(zwischen: &FileSystemZwischen, key: &Key, age: Duration) -> Result<()> {
+        let time = SystemTime::now() - age;
+        File::open(zwischen.retrieve(key)?)?
+            .set_times(FileTimes::new().set_accessed(time).set_modified(time))?;
+        Ok(())
+    }
+
+    const OLD: GcOptions = GcOptions {
+        grace_period: Duration::ZERO,
+        size_budget: None,
+        dry_run: false,
+    };
+
+    #[test]
+    fn gc_keeps_blobs_reachable_through_dirs() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = &context.zwischen;
+        let file = zwischen.store_bytes(b"file")?;
+        let nested = zwischen.store_bytes(b"nested")?;
+        let garbage = zwischen.store_bytes(b"garbage")?;
+
+        let inner = Dir::from_entries(BTreeMap::from([("nested".into(), file_entry(b"nested"))]));
+        zwischen.store_dir(&inner)?;
+        let outer = Dir::from_entries(BTreeMap::from([
+            ("file".into(), file_entry(b"file")),
+            (
+                "inner".into(),
+                DirEntry {
+                    kind: DirEntryKind::Dir,
+                    content_hash: inner.entry_hash(),
+                },
+            ),
+        ]));
+        zwischen.store_dir(&outer)?;
+
+        let report = zwischen.gc([Root::Dir(outer.entry_hash())], [], &OLD)?;
+        assert_eq!(report.reachable, 4);
+        assert_eq!(report.deleted, 1);
+        assert!(zwischen.retrieve(&file).is_ok());
+        assert!(zwischen.retrieve(&nested).is_ok());
+        assert!(zwischen.retrieve(&garbage).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gc_respects_grace_period_and_dry_run() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = &context.zwischen;
+        let young = zwischen.store_bytes(b"young")?;
+        let old = zwischen.store_bytes(b"old")?;
+        age(zwischen, &old, Duration::from_secs(7200))?;
+
+        let dry_run = GcOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(zwischen.gc([], [], &dry_run)?.deleted, 1);
+        assert!(zwischen.retrieve(&old).is_ok());
+
+        assert_eq!(zwischen.gc([], [], &GcOptions::default())?.deleted, 1);
+        assert!(zwischen.retrieve(&young).is_ok());
+        assert!(zwischen.retrieve(&old).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn size_budget_evicts_least_recently_used() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = &context.zwischen;
+        let recent = zwischen.store_bytes(&[1; 100])?;
+        let stale = zwischen.store_bytes(&[2; 100])?;
+        age(zwischen, &recent, Duration::from_secs(10))?;
+        age(zwischen, &stale, Duration::from_secs(20))?;
+
+        let report = zwischen.gc(
+            [Root::Blob(recent), Root::Blob(stale)],
+            [],
+            &GcOptions {
+                size_budget: Some(150),
+                ..OLD
+            },
+        )?;
+        assert_eq!(report.evicted, 1);
+        assert_eq!(report.remaining_bytes, 100);
+        assert!(zwischen.retrieve(&recent).is_ok());
+        assert!(zwischen.retrieve(&stale).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn size_budget_keeps_pinned_blobs() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = &context.zwischen;
+        let recent = zwischen.store_bytes(&[1; 100])?;
+        let pinned = zwischen.store_bytes(&[2; 100])?;
+        age(zwischen, &recent, Duration::from_secs(10))?;
+        age(zwischen, &pinned, Duration::from_secs(20))?;
+
+        let report = zwischen.gc(
+            [Root::Blob(recent)],
+            [Root::Blob(pinned)],
+            &GcOptions {
+                size_budget: Some(150),
+                ..OLD
+            },
+        )?;
+        assert_eq!(report.reachable, 2);
+        assert_eq!(report.evicted, 1);
+        assert!(zwischen.retrieve(&recent).is_err());
+        assert!(zwischen.retrieve(&pinned).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn storing_again_restarts_the_grace_period() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = &context.zwischen;
+        let key = zwischen.store_bytes(b"again")?;
+        age(zwischen, &key, Duration::from_secs(7200))?;
+
+        // A build stores the blob again but has not recorded it yet.
+        zwischen.store_bytes(b"again")?;
+        assert_eq!(zwischen.gc([], [], &GcOptions::default())?.deleted, 0);
+        assert!(zwischen.retrieve(&key).is_ok());
+        Ok(())
+    }
+}
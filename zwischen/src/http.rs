@@ -0,0 +1,604 @@
+//! Sharing blobs over a simple content-addressed HTTP API.
+//!
+//! Blobs live at `/cas/<hex key>`:
+//! - `HEAD` answers 200 if the blob exists and 404 otherwise,
+//! - `GET` returns the content of the blob, or 404,
+//! - `PUT` stores the request body, which must hash to the key in the path,
+//!   and answers 413 if it is larger than the server accepts.
+//!
+//! `POST /cas/missing` takes hex keys, one per line, and answers with those of them
+//! that are not stored, one per line. It accepts up to [`MISSING_BATCH_KEYS`] keys
+//! and answers 413 for more.
+//!
+//! The outputs recorded for actions live at `/ac/<hex action key>`, as the hex digest
+//! of a stored tree:
+//! - `GET` returns the digest, or 404,
+//! - `PUT` records the digest in the request body, which must be stored already.
+
+use std::collections::HashSet;
+use std::io::{Read, Seek};
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use anyhow::{Context, Result, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use model::FileAttributes;
+use tiny_http::{Method, Response, ResponseBox};
+use tracing::{debug, warn};
+
+use crate::{FileSystemZwischen, HashingWriter, Key, Zwischen, ZwischenError, hash_file};
+
+/// The most keys a `POST /cas/missing` request may ask about.
+pub const MISSING_BATCH_KEYS: usize = 4096;
+
+/// A client for a remote store speaking the protocol described in the [module docs](self).
+///
+/// Retrieved blobs are downloaded into a local [`FileSystemZwischen`],
+/// so `retrieve` can hand out plain files like any other store.
+#[derive(Debug, Clone)]
+pub struct HttpZwischen {
+    base_url: String,
+    agent: ureq::Agent,
+    downloads: FileSystemZwischen,
+}
+
+impl HttpZwischen {
+    pub fn new(base_url: impl Into<String>, downloads: FileSystemZwischen) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .new_agent();
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            agent,
+            downloads,
+        }
+    }
+
+    fn url(&self, key: &Key) -> String {
+        format!("{}/cas/{key}", self.base_url)
+    }
+
+    fn missing_url(&self) -> String {
+        format!("{}/cas/missing", self.base_url)
+    }
+
+    fn action_url(&self, action: &Key) -> String {
+        format!("{}/ac/{action}", self.base_url)
+    }
+
+    /// Uploads `file`, whose content hashes to `key`, unless the server has it already.
+    fn upload(&self, key: Key, file: std::fs::File) -> Result<Key> {
+        if self.contains(&key)? {
+            return Ok(key);
+        }
+        let url = self.url(&key);
+        let response = self
+            .agent
+            .put(&url)
+            .send(file)
+            .with_context(|| format!("while uploading to {url}"))?;
+        match response.status().as_u16() {
+            200..300 => Ok(key),
+            status => Err(anyhow!("uploading to {url} failed with status {status}")),
+        }
+    }
+}
+
+impl Zwischen for HttpZwischen {
+    fn store(&self, file: &Utf8Path) -> Result<Key> {
+        let key = hash_file(file).with_context(|| format!("while hashing {file:?}"))?;
+        let source =
+            std::fs::File::open(file).with_context(|| format!("while opening {file:?}"))?;
+        self.upload(key, source)
+    }
+
+    fn store_reader(&self, reader: &mut dyn Read) -> Result<Key> {
+        // The key is part of the URL, so the content is hashed before uploading it.
+        let mut file = tempfile::tempfile().context("while creating a temp file")?;
+        let mut writer = HashingWriter {
+            hasher: blake3::Hasher::new(),
+            file: &mut file,
+        };
+        std::io::copy(reader, &mut writer).context("while buffering upload")?;
+        let key = Key::from(writer.hasher.finalize());
+        file.rewind()?;
+        self.upload(key, file)
+    }
+
+    fn retrieve(&self, key: &Key) -> Result<Utf8PathBuf> {
+        if !self.downloads.contains(key)? {
+            let actual = self.downloads.store_reader(&mut self.open(key)?)?;
+            if actual != *key {
+                return Err(ZwischenError::Corrupted { key: *key, actual }.into());
+            }
+        }
+        self.downloads.retrieve(key)
+    }
+
+    fn retrieve_file(&self, key: &Key, attributes: &FileAttributes) -> Result<Utf8PathBuf> {
+        self.retrieve(key)?;
+        self.downloads.retrieve_file(key, attributes)
+    }
+
+    fn contains(&self, key: &Key) -> Result<bool> {
+        let url = self.url(key);
+        let response = self
+            .agent
+            .head(&url)
+            .call()
+            .with_context(|| format!("while looking up {url}"))?;
+        match response.status().as_u16() {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => Err(anyhow!("looking up {url} failed with status {status}")),
+        }
+    }
+
+    /// Asks for the missing keys in batches, instead of looking up each key.
+    fn contains_many(&self, keys: &[Key]) -> Result<Vec<bool>> {
+        let url = self.missing_url();
+        let mut present = Vec::with_capacity(keys.len());
+        for batch in keys.chunks(MISSING_BATCH_KEYS) {
+            let body: String = batch.iter().map(|key| format!("{key}\n")).collect();
+            let response = self
+                .agent
+                .post(&url)
+                .send(body)
+                .with_context(|| format!("while looking up {} keys at {url}", batch.len()))?;
+            let missing = match response.status().as_u16() {
+                200 => response
+                    .into_body()
+                    .read_to_string()
+                    .with_context(|| format!("while reading {url}"))?,
+                status => return Err(anyhow!("looking up {url} failed with status {status}")),
+            };
+            let missing = missing
+                .lines()
+                .map(|hex| {
+                    hex.parse()
+                        .with_context(|| format!("{url} answered a non-digest: {hex:?}"))
+                })
+                .collect::<Result<HashSet<Key>>>()?;
+            present.extend(batch.iter().map(|key| !missing.contains(key)));
+        }
+        Ok(present)
+    }
+
+    fn open(&self, key: &Key) -> Result<Box<dyn Read + Send>> {
+        let url = self.url(key);
+        let response = self
+            .agent
+            .get(&url)
+            .call()
+            .with_context(|| format!("while downloading {url}"))?;
+        match response.status().as_u16() {
+            200 => Ok(Box::new(response.into_body().into_reader())),
+            404 => Err(ZwischenError::NotFound { key: *key }.into()),
+            status => Err(anyhow!("downloading {url} failed with status {status}")),
+        }
+    }
+
+    fn lookup_action(&self, action: &Key) -> Result<Option<Key>> {
+        let url = self.action_url(action);
+        let response = self
+            .agent
+            .get(&url)
+            .call()
+            .with_context(|| format!("while looking up {url}"))?;
+        match response.status().as_u16() {
+            200 => {
+                let hex = response
+                    .into_body()
+                    .read_to_string()
+                    .with_context(|| format!("while reading {url}"))?;
+                Ok(Some(hex.trim().parse().with_context(|| {
+                    format!("{url} is not a digest: {hex:?}")
+                })?))
+            }
+            404 => Ok(None),
+            status => Err(anyhow!("looking up {url} failed with status {status}")),
+        }
+    }
+
+    fn record_action(&self, action: &Key, outputs: &Key) -> Result<()> {
+        let url = self.action_url(action);
+        let response = self
+            .agent
+            .put(&url)
+            .send(outputs.to_string())
+            .with_context(|| format!("while recording {url}"))?;
+        match response.status().as_u16() {
+            200..300 => Ok(()),
+            status => Err(anyhow!("recording {url} failed with status {status}")),
+        }
+    }
+}
+
+/// Serves a [`FileSystemZwischen`] using the protocol described in the [module docs](self).
+pub struct CacheServer {
+    server: tiny_http::Server,
+    store: FileSystemZwischen,
+    workers: usize,
+    max_upload_bytes: u64,
+}
+
+impl CacheServer {
+    /// Uploads are limited to 1 GiB by default.
+    pub const DEFAULT_MAX_UPLOAD_BYTES: u64 = 1 << 30;
+
+    pub fn bind(addr: impl ToSocketAddrs, store: FileSystemZwischen) -> Result<Self> {
+        let server = tiny_http::Server::http(addr).map_err(|e| anyhow!("while binding: {e}"))?;
+        Ok(Self {
+            server,
+            store,
+            workers: std::thread::available_parallelism().map_or(4, usize::from),
+            max_upload_bytes: Self::DEFAULT_MAX_UPLOAD_BYTES,
+        })
+    }
+
+    /// Handle requests on this many threads, default: one per CPU.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Reject uploads of blobs larger than this.
+    pub fn with_max_upload_bytes(mut self, max_upload_bytes: u64) -> Self {
+        self.max_upload_bytes = max_upload_bytes;
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server
+            .server_addr()
+            .to_ip()
+            .expect("bound to an IP address")
+    }
+
+    /// Handles requests on a fixed number of threads until [`CacheServer::stop`] is called.
+    pub fn run(&self) {
+        std::thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| {
+                    for request in self.server.incoming_requests() {
+                        self.handle(request);
+                    }
+                });
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        // Every unblock stops one worker.
+        for _ in 0..self.workers {
+            self.server.unblock();
+        }
+    }
+
+    fn handle(&self, mut request: tiny_http::Request) {
+        let method = request.method().clone();
+        let url = request.url().to_string();
+        let response = match self.respond(&method, &url, &mut request) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("{method} {url}: {e:?}");
+                Response::from_string(format!("{e:#}"))
+                    .with_status_code(500)
+                    .boxed()
+            }
+        };
+        debug!("{method} {url}: {}", response.status_code().0);
+        if let Err(e) = request.respond(response) {
+            warn!("{method} {url}: while responding: {e}");
+        }
+    }
+
+    fn respond(
+        &self,
+        method: &Method,
+        url: &str,
+        request: &mut tiny_http::Request,
+    ) -> Result<ResponseBox> {
+        if let Some(hex) = url.strip_prefix("/ac/") {
+            return self.respond_action(method, hex, request);
+        }
+        if url == "/cas/missing" {
+            return match method {
+                Method::Post => self.respond_missing(request),
+                _ => Ok(Response::empty(405).boxed()),
+            };
+        }
+        let Some(key) = url
+            .strip_prefix("/cas/")
+            .and_then(|hex| hex.parse::<Key>().ok())
+        else {
+            return Ok(Response::from_string("not a blob URL")
+                .with_status_code(400)
+                .boxed());
+        };
+        match method {
+            Method::Head if self.store.contains(&key)? => Ok(Response::empty(200).boxed()),
+            Method::Get if self.store.contains(&key)? => {
+                let path = self.store.retrieve(&key)?;
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("while opening {path:?}"))?;
+                Ok(Response::from_file(file).boxed())
+            }
+            Method::Head | Method::Get => Ok(Response::empty(404).boxed()),
+            Method::Put => self.put(key, request),
+            _ => Ok(Response::empty(405).boxed()),
+        }
+    }
+
+    /// Answers which of the keys in the body of `request` are not stored.
+    fn respond_missing(&self, request: &mut tiny_http::Request) -> Result<ResponseBox> {
+        let mut body = String::new();
+        // Room for the hex keys, their line breaks and some slack.
+        let limit = (MISSING_BATCH_KEYS * (2 * blake3::OUT_LEN + 2)) as u64;
+        request
+            .as_reader()
+            .take(limit + 1)
+            .read_to_string(&mut body)
+            .context("while receiving keys")?;
+        let lines: Vec<&str> = body
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        if body.len() as u64 > limit || lines.len() > MISSING_BATCH_KEYS {
+            return Ok(Response::from_string(format!(
+                "lookups are limited to {MISSING_BATCH_KEYS} keys"
+            ))
+            .with_status_code(413)
+            .boxed());
+        }
+        let mut keys = Vec::with_capacity(lines.len());
+        for line in lines {
+            let Ok(key) = line.parse::<Key>() else {
+                return Ok(Response::from_string(format!("not a digest: {line:?}"))
+                    .with_status_code(400)
+                    .boxed());
+            };
+            keys.push(key);
+        }
+        let present = self.store.contains_many(&keys)?;
+        let missing: String = keys
+            .iter()
+            .zip(present)
+            .filter(|(_, present)| !present)
+            .map(|(key, _)| format!("{key}\n"))
+            .collect();
+        Ok(Response::from_string(missing).boxed())
+    }
+
+    /// Looks up or records the outputs of the action with the key `hex`.
+    fn respond_action(
+        &self,
+        method: &Method,
+        hex: &str,
+        request: &mut tiny_http::Request,
+    ) -> Result<ResponseBox> {
+        let bad_request =
+            |message: String| Response::from_string(message).with_status_code(400).boxed();
+        let Ok(action) = hex.parse::<Key>() else {
+            return Ok(bad_request("not an action URL".into()));
+        };
+        match method {
+            Method::Get => match self.store.lookup_action(&action)? {
+                Some(outputs) => Ok(Response::from_string(outputs.to_string()).boxed()),
+                None => Ok(Response::empty(404).boxed()),
+            },
+            Method::Put => {
+                let mut body = String::new();
+                // Room for a hex digest and some whitespace.
+                request
+                    .as_reader()
+                    .take(128)
+                    .read_to_string(&mut body)
+                    .context("while receiving action record")?;
+                let Ok(outputs) = body.trim().parse::<Key>() else {
+                    return Ok(bad_request(format!("not a digest: {body:?}")));
+                };
+                // Otherwise a record could outlive the upload of what it refers to.
+                if !self.store.contains(&outputs)? {
+                    return Ok(bad_request(format!("{outputs} is not stored")));
+                }
+                self.store.record_action(&action, &outputs)?;
+                Ok(Response::empty(204).boxed())
+            }
+            _ => Ok(Response::empty(405).boxed()),
+        }
+    }
+
+    /// Stores the body of `request` as `key`. It is hashed in a temp file first,
+    /// so that nothing is stored if it does not hash to `key` or is too large.
+    fn put(&self, key: Key, request: &mut tiny_http::Request) -> Result<ResponseBox> {
+        let too_large = || {
+            Response::from_string(format!(
+                "uploads are limited to {} bytes",
+                self.max_upload_bytes
+            ))
+            .with_status_code(413)
+            .boxed()
+        };
+        if request
+            .body_length()
+            .is_some_and(|length| length as u64 > self.max_upload_bytes)
+        {
+            return Ok(too_large());
+        }
+
+        let mut temp = self.store.new_temp_file()?;
+        let mut writer = HashingWriter {
+            hasher: blake3::Hasher::new(),
+            file: temp.as_file_mut(),
+        };
+        // The body can be chunked, so its length is only known after reading it.
+        let copied = std::io::copy(
+            &mut request.as_reader().take(self.max_upload_bytes + 1),
+            &mut writer,
+        )
+        .context("while receiving upload")?;
+        if copied > self.max_upload_bytes {
+            return Ok(too_large());
+        }
+        let actual = Key::from(writer.hasher.finalize());
+        if actual != key {
+            return Ok(Response::from_string(format!("content hashes to {actual}"))
+                .with_status_code(400)
+                .boxed());
+        }
+        self.store.commit(temp, key)?;
+        Ok(Response::empty(204).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::ZwischenContext;
+
+    /// Runs `f` against a client talking to a loopback server for `server_store`.
+    fn with_server<T>(
+        server_store: &FileSystemZwischen,
+        client_downloads: &FileSystemZwischen,
+        f: impl FnOnce(&HttpZwischen) -> T,
+    ) -> T {
+        let server = CacheServer::bind("127.0.0.1:0", server_store.clone())
+            .unwrap()
+            .with_workers(2)
+            .with_max_upload_bytes(64);
+        let client = HttpZwischen::new(
+            format!("http://{}/", server.local_addr()),
+            client_downloads.clone(),
+        );
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run());
+            let result = f(&client);
+            server.stop();
+            result
+        })
+    }
+
+    #[test]
+    fn store_and_retrieve_over_http() -> Result<()> {
+        let server = ZwischenContext::new()?;
+        let client = ZwischenContext::new()?;
+        with_server(&server.zwischen, &client.zwischen, |remote| {
+            let key = remote.store_bytes(b"shared")?;
+            assert!(server.zwischen.contains(&key)?);
+            assert!(remote.contains(&key)?);
+            assert!(!client.zwischen.contains(&key)?);
+
+            let path = remote.retrieve(&key)?;
+            assert_eq!(std::fs::read(&path)?, b"shared");
+            assert!(client.zwischen.contains(&key)?);
+
+            let mut content = Vec::new();
+            remote.open(&key)?.read_to_end(&mut content)?;
+            assert_eq!(content, b"shared");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn missing_and_mismatched_blobs() -> Result<()> {
+        let server = ZwischenContext::new()?;
+        let client = ZwischenContext::new()?;
+        with_server(&server.zwischen, &client.zwischen, |remote| {
+            let missing = Key::of(b"missing");
+            assert!(!remote.contains(&missing)?);
+            let err = remote.retrieve(&missing).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ZwischenError>(),
+                Some(&ZwischenError::NotFound { key: missing })
+            );
+
+            let response = remote
+                .agent
+                .put(remote.url(&missing))
+                .send(&b"something else"[..])?;
+            assert_eq!(response.status().as_u16(), 400);
+            assert!(!server.zwischen.contains(&Key::of(b"something else"))?);
+            let response = remote
+                .agent
+                .get(format!("{}/cas/zz", remote.base_url))
+                .call()?;
+            assert_eq!(response.status().as_u16(), 400);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn missing_blobs_are_found_in_one_request() -> Result<()> {
+        let server = ZwischenContext::new()?;
+        let client = ZwischenContext::new()?;
+        with_server(&server.zwischen, &client.zwischen, |remote| {
+            let stored = server.zwischen.store_bytes(b"stored")?;
+            let missing = Key::of(b"missing");
+            assert_eq!(
+                remote.contains_many(&[stored, missing, stored])?,
+                [true, false, true]
+            );
+            assert_eq!(remote.contains_many(&[])?, [false; 0]);
+
+            let response = remote
+                .agent
+                .post(remote.missing_url())
+                .send(format!("{stored}\nzz\n"))?;
+            assert_eq!(response.status().as_u16(), 400);
+            let too_many = format!("{missing}\n").repeat(MISSING_BATCH_KEYS + 1);
+            let response = remote.agent.post(remote.missing_url()).send(too_many)?;
+            assert_eq!(response.status().as_u16(), 413);
+            let response = remote.agent.get(remote.missing_url()).call()?;
+            assert_eq!(response.status().as_u16(), 405);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn actions_are_recorded_over_http() -> Result<()> {
+        let server = ZwischenContext::new()?;
+        let client = ZwischenContext::new()?;
+        with_server(&server.zwischen, &client.zwischen, |remote| {
+            let action = Key::of(b"action");
+            assert_eq!(remote.lookup_action(&action)?, None);
+
+            // Only outputs that are stored can be recorded.
+            let outputs = Key::of(b"outputs");
+            assert!(remote.record_action(&action, &outputs).is_err());
+            assert_eq!(remote.store_bytes(b"outputs")?, outputs);
+            remote.record_action(&action, &outputs)?;
+            assert_eq!(remote.lookup_action(&action)?, Some(outputs));
+            assert_eq!(server.zwischen.lookup_action(&action)?, Some(outputs));
+
+            let other = remote.store_bytes(b"other outputs")?;
+            remote.record_action(&action, &other)?;
+            assert_eq!(remote.lookup_action(&action)?, Some(other));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn large_uploads_are_rejected() -> Result<()> {
+        let server = ZwischenContext::new()?;
+        let client = ZwischenContext::new()?;
+        with_server(&server.zwischen, &client.zwischen, |remote| {
+            let large = [7u8; 65];
+            let key = Key::of(&large);
+            let response = remote.agent.put(remote.url(&key)).send(&large[..])?;
+            assert_eq!(response.status().as_u16(), 413);
+
+            // Without a Content-Length, the streamed bytes are counted.
+            let response = remote
+                .agent
+                .put(remote.url(&key))
+                .send(ureq::SendBody::from_reader(&mut &large[..]))?;
+            assert_eq!(response.status().as_u16(), 413);
+            assert!(!server.zwischen.contains(&key)?);
+
+            assert_eq!(remote.store_bytes(&large[..64])?, Key::of(&large[..64]));
+            Ok(())
+        })
+    }
+}
@@ -1,114 +1,696 @@
-use std::{fs::Permissions, io::Read, str::FromStr};
+use std::{
+    fmt,
+    fs::{FileTimes, Permissions},
+    io::{Read, Write},
+    os::{fd::AsRawFd, unix::fs::PermissionsExt},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{Context, Result};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use model::{Digest, FileAttributes};
+use nix::errno::Errno;
+use nix::fcntl::{AtFlags, RenameFlags, renameat2};
+use nix::sys::stat::fstatat;
+use rayon::prelude::*;
+use tempfile::NamedTempFile;
+use thiserror::Error;
+use tracing::debug;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct Key(blake3::Hash);
+pub mod fsck;
+pub mod gc;
+pub mod http;
+pub mod tiered;
+pub mod tree;
 
-impl Key {
-    pub fn rel_path(&self) -> Utf8PathBuf {
-        let hex = self.0.to_hex();
-        let mut path = Utf8PathBuf::from_str(&hex[0..2]).unwrap();
-        let mut rest = &hex[2..];
-        for _ in 1..3 {
-            path = path.join(&rest[0..2]);
-            rest = &rest[2..];
+/// Temp files of interrupted writes live here, below the base path.
+const TEMP_DIR: &str = "tmp";
+/// Blobs that failed verification are moved here, below the base path.
+const QUARANTINE_DIR: &str = "quarantine";
+/// Decompressed copies of compressed blobs live here, below the base path.
+const MATERIALIZED_DIR: &str = "materialized";
+/// Executable copies of blobs live here, below the base path.
+const EXECUTABLE_DIR: &str = "executable";
+/// The outputs recorded for action keys live here, below the base path.
+const ACTIONS_DIR: &str = "actions";
+/// Appended to the path of compressed blobs.
+const COMPRESSED_EXTENSION: &str = "zst";
+/// Blobs retrieved again within this time keep their access time,
+/// to not write metadata on every retrieval.
+const ACCESS_TIME_RESOLUTION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ZwischenError {
+    #[error("blob {key} not found")]
+    NotFound { key: Key },
+    #[error("blob {key} is corrupted, its content hashes to {actual}")]
+    Corrupted { key: Key, actual: Key },
+}
+
+/// Blobs are addressed by the digest of their content.
+pub type Key = Digest;
+
+/// Returns the path of the plain blob with `key`, relative to the base path.
+///
+/// The hex digest is split into directories, so none of them grows too large.
+fn key_rel_path(key: &Key) -> Utf8PathBuf {
+    let hex = key.to_string();
+    let mut path = Utf8PathBuf::from(&hex[0..2]);
+    let mut rest = &hex[2..];
+    for _ in 1..3 {
+        path = path.join(&rest[0..2]);
+        rest = &rest[2..];
+    }
+    path = path.join(rest);
+    path
+}
+
+/// Parses a path produced by [`key_rel_path`].
+fn key_from_rel_path(rel_path: &Utf8Path) -> Option<Key> {
+    let mut hex = String::with_capacity(64);
+    let mut lengths = Vec::with_capacity(4);
+    for component in rel_path.components() {
+        let Utf8Component::Normal(name) = component else {
+            return None;
+        };
+        lengths.push(name.len());
+        hex.push_str(name);
+    }
+    if lengths != [2, 2, 2, 58] {
+        return None;
+    }
+    hex.parse().ok()
+}
+
+/// The files a blob can be stored as, relative to the base path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlobFile {
+    Plain,
+    /// zstd-compressed, still named after the hash of the uncompressed content.
+    Compressed,
+    /// A decompressed copy of a compressed blob.
+    Materialized,
+    /// An executable copy, as a hard link cannot differ in mode from its blob.
+    Executable,
+}
+
+impl BlobFile {
+    /// Files that can be used as they are come first.
+    const PREFERENCE: [BlobFile; 3] = [
+        BlobFile::Plain,
+        BlobFile::Materialized,
+        BlobFile::Compressed,
+    ];
+
+    pub(crate) fn rel_path(self, key: &Key) -> Utf8PathBuf {
+        match self {
+            BlobFile::Plain => key_rel_path(key),
+            BlobFile::Compressed => key_rel_path(key).with_extension(COMPRESSED_EXTENSION),
+            BlobFile::Materialized => Utf8Path::new(MATERIALIZED_DIR).join(key_rel_path(key)),
+            BlobFile::Executable => Utf8Path::new(EXECUTABLE_DIR).join(key_rel_path(key)),
         }
-        path = path.join(rest);
-        path
+    }
+
+    /// Parses a path produced by [`BlobFile::rel_path`].
+    pub(crate) fn parse(rel_path: &Utf8Path) -> Option<(Key, BlobFile)> {
+        if let Ok(rest) = rel_path.strip_prefix(MATERIALIZED_DIR) {
+            return key_from_rel_path(rest).map(|key| (key, BlobFile::Materialized));
+        }
+        if let Ok(rest) = rel_path.strip_prefix(EXECUTABLE_DIR) {
+            return key_from_rel_path(rest).map(|key| (key, BlobFile::Executable));
+        }
+        if rel_path.extension() == Some(COMPRESSED_EXTENSION) {
+            return key_from_rel_path(&rel_path.with_extension(""))
+                .map(|key| (key, BlobFile::Compressed));
+        }
+        key_from_rel_path(rel_path).map(|key| (key, BlobFile::Plain))
+    }
+}
+
+/// How well a compressed blob compresses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Compressed size relative to the uncompressed size, lower is better.
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.uncompressed_bytes as f64
+        }
+    }
+}
+
+impl std::ops::AddAssign for CompressionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.uncompressed_bytes += other.uncompressed_bytes;
+        self.compressed_bytes += other.compressed_bytes;
     }
 }
 
 /// A content-addressed blob store.
-pub trait Zwischen {
+///
+/// The `_many` variants take a batch of blobs at once, so that remote stores can
+/// answer with one round trip. By default, they run the single-blob operations in parallel.
+pub trait Zwischen: fmt::Debug + Send + Sync {
+    /// Stores a copy of `file`. The file itself is left untouched.
     fn store(&self, file: &Utf8Path) -> Result<Key>;
+
+    /// Stores everything `reader` yields.
+    fn store_reader(&self, reader: &mut dyn Read) -> Result<Key>;
+
+    fn store_bytes(&self, bytes: &[u8]) -> Result<Key> {
+        self.store_reader(&mut &bytes[..])
+    }
+
+    /// Returns a plain file with the content of `key`, failing with
+    /// [`ZwischenError::NotFound`] if it is not stored.
+    ///
+    /// The file is read-only and not executable.
     fn retrieve(&self, key: &Key) -> Result<Utf8PathBuf>;
+
+    /// Like [`Zwischen::retrieve`], but the file has the mode of `attributes`,
+    /// so that it can be hard linked into a tree as it is.
+    fn retrieve_file(&self, key: &Key, attributes: &FileAttributes) -> Result<Utf8PathBuf>;
+
+    fn contains(&self, key: &Key) -> Result<bool>;
+
+    fn store_many(&self, files: &[Utf8PathBuf]) -> Result<Vec<Key>> {
+        files.par_iter().map(|file| self.store(file)).collect()
+    }
+
+    /// Like [`Zwischen::retrieve`] for each key, failing if any of them is not stored.
+    fn retrieve_many(&self, keys: &[Key]) -> Result<Vec<Utf8PathBuf>> {
+        keys.par_iter().map(|key| self.retrieve(key)).collect()
+    }
+
+    /// Like [`Zwischen::contains`] for each key, the counterpart of REAPI's `FindMissingBlobs`.
+    fn contains_many(&self, keys: &[Key]) -> Result<Vec<bool>> {
+        keys.par_iter().map(|key| self.contains(key)).collect()
+    }
+
+    /// Streams the content of `key`, for stores that can do so without a local copy.
+    fn open(&self, key: &Key) -> Result<Box<dyn Read + Send>> {
+        let path = self.retrieve(key)?;
+        let file = std::fs::File::open(&path).with_context(|| format!("while opening {path:?}"))?;
+        Ok(Box::new(file))
+    }
+
+    /// Returns the `entry_hash` of the tree of outputs recorded for the action with key
+    /// `action`, so that they can be restored instead of running the action again.
+    /// Stores that do not record outputs never find any.
+    fn lookup_action(&self, _action: &Key) -> Result<Option<Key>> {
+        Ok(None)
+    }
+
+    /// Records `outputs`, the `entry_hash` of a stored tree, as the outputs of the action
+    /// with key `action`, replacing what was recorded before.
+    fn record_action(&self, _action: &Key, _outputs: &Key) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// A FileSystem-based implementation of `Zwischen`.
+///
+/// Blobs are first written to a temp file below the base path, synced and then
+/// renamed into place without replacing existing blobs, so readers never see
+/// partial blobs and concurrent stores of the same content are harmless.
+///
+/// With compression enabled, new blobs are stored zstd-compressed but keep the
+/// key of their uncompressed content. `retrieve` decompresses them on first use
+/// into a materialization cache, so callers always get a plain file to link to.
+///
+/// Retrieving a blob refreshes its access time, at most once an hour, so that
+/// gc can evict the least recently used blobs whatever the mount options.
 #[derive(Debug, Clone)]
 pub struct FileSystemZwischen {
     base_path: Utf8PathBuf,
+    verify_on_read: bool,
+    compression_level: Option<i32>,
 }
 
 impl FileSystemZwischen {
     pub fn new(base_path: Utf8PathBuf) -> Self {
-        Self { base_path }
+        Self {
+            base_path,
+            verify_on_read: false,
+            compression_level: None,
+        }
     }
-}
 
-impl Zwischen for FileSystemZwischen {
-    fn store(&self, file: &Utf8Path) -> Result<Key> {
-        let mut hasher = blake3::Hasher::new();
-
-        let file_read = std::fs::OpenOptions::new()
-            .read(true)
-            .write(false)
-            .create(false)
-            .open(file)
-            .with_context(|| format!("while opening {file:?}"))?;
-
-        // make file read-only
-        let metadata = file_read.metadata()?;
-        let mut permissions = metadata.permissions();
-        if !permissions.readonly() {
-            permissions.set_readonly(true);
-            std::fs::set_permissions(file, permissions)
-                .with_context(|| format!("while setting permissions for {file:?}"))?;
-        }
-
-        // TODO: Make inode immutable
-        // https://docs.rs/nix/latest/nix/sys/ioctl/
-
-        let mut reader = std::io::BufReader::new(file_read);
-        let mut buffer = [0; 8192];
-        loop {
-            let bytes_read = reader.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
+    /// Store new blobs compressed with the given zstd level, 0 being zstd's default.
+    /// Blobs that are already stored are kept as they are.
+    pub fn with_compression(mut self, level: Option<i32>) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Rehash blobs in `retrieve` and fail with [`ZwischenError::Corrupted`]
+    /// if they do not match their key.
+    pub fn with_verify_on_read(mut self, verify_on_read: bool) -> Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    pub fn base_path(&self) -> &Utf8Path {
+        &self.base_path
+    }
+
+    fn new_temp_file(&self) -> Result<NamedTempFile> {
+        let temp_dir = self.base_path.join(TEMP_DIR);
+        std::fs::create_dir_all(&temp_dir)
+            .with_context(|| format!("while creating {temp_dir:?}"))?;
+        tempfile::Builder::new()
+            .prefix("store-")
+            .tempfile_in(&temp_dir)
+            .with_context(|| format!("while creating a temp file in {temp_dir:?}"))
+    }
+
+    /// Copies `reader` into `temp` and commits it.
+    fn copy_into(&self, mut temp: NamedTempFile, reader: &mut dyn Read) -> Result<Key> {
+        let mut writer = HashingWriter {
+            hasher: blake3::Hasher::new(),
+            file: temp.as_file_mut(),
+        };
+        std::io::copy(reader, &mut writer).context("while copying into the store")?;
+        let key = Key::from(writer.hasher.finalize());
+        self.commit(temp, key)
+    }
+
+    /// Makes the fully written `temp` file the blob for `key`, unless the content
+    /// is stored already, compressed or not.
+    fn commit(&self, temp: NamedTempFile, key: Key) -> Result<Key> {
+        if self.touch_existing(&key)? {
+            return Ok(key);
+        }
+        let Some(level) = self.compression_level else {
+            self.rename_into(temp, &BlobFile::Plain.rel_path(&key), 0o444)?;
+            return Ok(key);
+        };
+        let mut plain = temp.reopen().context("while reopening blob")?;
+        let uncompressed_bytes = plain.metadata()?.len();
+        let mut compressed = self.new_temp_file()?;
+        let mut encoder = zstd::Encoder::new(compressed.as_file_mut(), level)?;
+        encoder.include_contentsize(true)?;
+        encoder.set_pledged_src_size(Some(uncompressed_bytes))?;
+        std::io::copy(&mut plain, &mut encoder).context("while compressing blob")?;
+        encoder.finish().context("while compressing blob")?;
+        debug!(
+            "compressed {key} from {uncompressed_bytes} to {} bytes",
+            compressed.as_file().metadata()?.len()
+        );
+        // Compressing takes a while, another store may have added the blob meanwhile.
+        if self.touch_existing(&key)? {
+            return Ok(key);
+        }
+        self.rename_into(compressed, &BlobFile::Compressed.rel_path(&key), 0o444)?;
+        Ok(key)
+    }
+
+    /// Touches the stored blob for `key`, so gc treats it as freshly stored,
+    /// and returns whether there is one.
+    fn touch_existing(&self, key: &Key) -> Result<bool> {
+        let Some(existing) = self.find(key) else {
+            return Ok(false);
+        };
+        touch(&self.base_path.join(existing.rel_path(key)))?;
+        Ok(true)
+    }
+
+    /// Gives the fully written `temp` file the read-only `mode` and moves it to `rel_path`,
+    /// unless a file already exists there. An existing file is touched instead,
+    /// so gc treats it as freshly stored.
+    fn rename_into(&self, temp: NamedTempFile, rel_path: &Utf8Path, mode: u32) -> Result<()> {
+        temp.as_file()
+            .set_permissions(Permissions::from_mode(mode))
+            .context("while making blob read-only")?;
+        temp.as_file().sync_all().context("while syncing blob")?;
+
+        let target_path = self.base_path.join(rel_path);
+        let target_dir = target_path
+            .parent()
+            .expect("blobs are in shard directories");
+        std::fs::create_dir_all(target_dir)
+            .with_context(|| format!("while creating directories for {target_path:?}"))?;
+
+        match renameat2(
+            None,
+            temp.path(),
+            None,
+            target_path.as_std_path(),
+            RenameFlags::RENAME_NOREPLACE,
+        ) {
+            Ok(()) => {
+                // The file is at its final path now, don't let the drop remove it.
+                temp.into_temp_path().keep()?;
+                sync_dir(target_dir)?;
+            }
+            // Somebody else stored the same content first, our temp file is dropped.
+            Err(Errno::EEXIST) => touch(&target_path)?,
+            // The file system does not support `RENAME_NOREPLACE`, linking does not
+            // replace existing files either. The drop removes the temp file.
+            Err(Errno::EINVAL | Errno::ENOSYS) => {
+                match std::fs::hard_link(temp.path(), &target_path) {
+                    Ok(()) => sync_dir(target_dir)?,
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                        touch(&target_path)?;
+                    }
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("while linking {:?} to {target_path:?}", temp.path())
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("while moving {:?} to {target_path:?}", temp.path()));
             }
-            hasher.update(&buffer[..bytes_read]);
         }
-        std::mem::drop(reader);
+        Ok(())
+    }
+
+    /// Returns how `key` is stored, preferring files that can be used as they are.
+    fn find(&self, key: &Key) -> Option<BlobFile> {
+        BlobFile::PREFERENCE
+            .into_iter()
+            .find(|file| self.base_path.join(file.rel_path(key)).exists())
+    }
 
-        let key = Key(hasher.finalize());
+    /// Like [`FileSystemZwischen::find`] for many keys, in parallel and relative to
+    /// the opened base directory, so only the shard directories have to be resolved.
+    fn find_many(&self, keys: &[Key]) -> Result<Vec<Option<BlobFile>>> {
+        let base = match std::fs::File::open(&self.base_path) {
+            Ok(base) => base,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(vec![None; keys.len()]);
+            }
+            Err(e) => return Err(e).with_context(|| format!("while opening {:?}", self.base_path)),
+        };
+        keys.par_iter()
+            .map(|key| {
+                for file in BlobFile::PREFERENCE {
+                    let rel_path = file.rel_path(key);
+                    match fstatat(Some(base.as_raw_fd()), rel_path.as_str(), AtFlags::empty()) {
+                        Ok(_) => return Ok(Some(file)),
+                        Err(Errno::ENOENT | Errno::ENOTDIR) => {}
+                        Err(e) => {
+                            return Err(e)
+                                .with_context(|| format!("while looking up {rel_path:?}"));
+                        }
+                    }
+                }
+                Ok(None)
+            })
+            .collect()
+    }
 
-        let target_path = self.base_path.join(key.rel_path());
-        if !target_path.exists() {
-            std::fs::create_dir_all(target_path.parent().unwrap())
-                .with_context(|| format!("while creating directories for {target_path:?}"))?;
-            std::fs::rename(file, &target_path)
-                .with_context(|| format!("while copying {file:?} to {target_path:?}"))?;
+    /// Decompresses the blob for `key` into the materialization cache, unless it is there already.
+    fn materialize(&self, key: &Key) -> Result<Utf8PathBuf> {
+        let rel_path = BlobFile::Materialized.rel_path(key);
+        let path = self.base_path.join(&rel_path);
+        if path.exists() {
+            return Ok(path);
+        }
+        let compressed_path = self.base_path.join(BlobFile::Compressed.rel_path(key));
+        let compressed = std::fs::File::open(&compressed_path)
+            .with_context(|| format!("while opening {compressed_path:?}"))?;
+        let mut temp = self.new_temp_file()?;
+        let mut writer = HashingWriter {
+            hasher: blake3::Hasher::new(),
+            file: temp.as_file_mut(),
+        };
+        std::io::copy(&mut zstd::Decoder::new(compressed)?, &mut writer)
+            .with_context(|| format!("while decompressing {compressed_path:?}"))?;
+        // Decompressing reads everything anyway, so this is always verified.
+        let actual = Key::from(writer.hasher.finalize());
+        if actual != *key {
+            return Err(ZwischenError::Corrupted { key: *key, actual }.into());
         }
+        self.rename_into(temp, &rel_path, 0o444)?;
+        Ok(path)
+    }
 
-        Ok(key)
+    /// Copies the blob for `key` into the executable copies, unless it is there already.
+    fn make_executable(&self, key: &Key) -> Result<Utf8PathBuf> {
+        let rel_path = BlobFile::Executable.rel_path(key);
+        let path = self.base_path.join(&rel_path);
+        if path.exists() {
+            return Ok(path);
+        }
+        let plain_path = self.retrieve(key)?;
+        let mut plain = std::fs::File::open(&plain_path)
+            .with_context(|| format!("while opening {plain_path:?}"))?;
+        let mut temp = self.new_temp_file()?;
+        if reflink(&plain, temp.as_file()).is_err() {
+            std::io::copy(&mut plain, temp.as_file_mut())
+                .with_context(|| format!("while copying {plain_path:?}"))?;
+        }
+        self.rename_into(temp, &rel_path, 0o555)?;
+        Ok(path)
+    }
+
+    /// Returns the compressed and uncompressed size of the blob for `key`,
+    /// or `None` if it is not stored compressed.
+    pub fn compression_stats(&self, key: &Key) -> Result<Option<CompressionStats>> {
+        let compressed_path = self.base_path.join(BlobFile::Compressed.rel_path(key));
+        if compressed_path.exists() {
+            compression_stats(&compressed_path).map(Some)
+        } else if self.find(key).is_some() {
+            Ok(None)
+        } else {
+            Err(ZwischenError::NotFound { key: *key }.into())
+        }
+    }
+}
+
+/// `ZSTD_FRAMEHEADERSIZE_MAX` from zstd.h
+const FRAME_HEADER_SIZE_MAX: usize = 18;
+
+/// Reads the uncompressed size from the zstd frame header of the compressed blob at `path`.
+fn compression_stats(path: &Utf8Path) -> Result<CompressionStats> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("while opening {path:?}"))?;
+    let compressed_bytes = file.metadata()?.len();
+    let mut header = Vec::with_capacity(FRAME_HEADER_SIZE_MAX);
+    (&mut file)
+        .take(FRAME_HEADER_SIZE_MAX as u64)
+        .read_to_end(&mut header)?;
+    let uncompressed_bytes = zstd::zstd_safe::get_frame_content_size(&header)
+        .ok()
+        .flatten()
+        .with_context(|| format!("{path:?} has no zstd content size"))?;
+    Ok(CompressionStats {
+        uncompressed_bytes,
+        compressed_bytes,
+    })
+}
+
+/// Hashes the content of `path`.
+fn hash_file(path: &Utf8Path) -> std::io::Result<Key> {
+    let file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(file)?;
+    Ok(Key::from(hasher.finalize()))
+}
+
+/// Hashes the uncompressed content of the compressed blob at `path`.
+fn hash_compressed_file(path: &Utf8Path) -> std::io::Result<Key> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(zstd::Decoder::new(std::fs::File::open(path)?)?)?;
+    Ok(Key::from(hasher.finalize()))
+}
+
+/// Sets the access and modification time of the blob at `path` to now.
+fn touch(path: &Utf8Path) -> Result<()> {
+    let now = SystemTime::now();
+    std::fs::File::open(path)
+        .and_then(|file| file.set_times(FileTimes::new().set_accessed(now).set_modified(now)))
+        .with_context(|| format!("while touching {path:?}"))
+}
+
+/// Records that the blob at `path` was used, for the least recently used eviction of gc.
+///
+/// File systems mounted with `noatime` never update access times on reads and with
+/// `relatime` only about once a day, so they are maintained explicitly. This is best
+/// effort, a blob owned by somebody else cannot be marked.
+fn mark_used(path: &Utf8Path) {
+    let now = SystemTime::now();
+    let result = std::fs::File::open(path).and_then(|file| {
+        let accessed = file.metadata()?.accessed()?;
+        if now
+            .duration_since(accessed)
+            .is_ok_and(|age| age < ACCESS_TIME_RESOLUTION)
+        {
+            return Ok(());
+        }
+        file.set_times(FileTimes::new().set_accessed(now))
+    });
+    if let Err(e) = result {
+        debug!("cannot mark {path:?} as used: {e}");
+    }
+}
+
+/// Makes renames and links into `dir` durable.
+fn sync_dir(dir: &Utf8Path) -> Result<()> {
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("while syncing {dir:?}"))
+}
+
+mod ioctl {
+    // FICLONE from linux/fs.h
+    nix::ioctl_write_int!(ficlone, 0x94, 9);
+}
+
+/// Makes `dst` share the extents of `src` if the file system supports it.
+pub fn reflink(src: &std::fs::File, dst: &std::fs::File) -> std::io::Result<()> {
+    // SAFETY: both are valid, open file descriptors for the duration of the call.
+    unsafe { ioctl::ficlone(dst.as_raw_fd(), src.as_raw_fd() as _) }
+        .map(|_| ())
+        .map_err(std::io::Error::from)
+}
+
+/// Writes everything to `file` while hashing it.
+struct HashingWriter<'a> {
+    hasher: blake3::Hasher,
+    file: &'a mut std::fs::File,
+}
+
+impl Write for HashingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Zwischen for FileSystemZwischen {
+    fn store(&self, file: &Utf8Path) -> Result<Key> {
+        let source =
+            std::fs::File::open(file).with_context(|| format!("while opening {file:?}"))?;
+        let temp = self.new_temp_file()?;
+        if reflink(&source, temp.as_file()).is_ok() {
+            let key = hash_file(Utf8Path::from_path(temp.path()).expect("store paths are UTF-8"))
+                .with_context(|| format!("while hashing {file:?}"))?;
+            return self.commit(temp, key);
+        }
+        // Different file systems or no reflink support.
+        self.copy_into(temp, &mut std::io::BufReader::new(source))
+            .with_context(|| format!("while storing {file:?}"))
+    }
+
+    fn store_reader(&self, reader: &mut dyn Read) -> Result<Key> {
+        let temp = self.new_temp_file()?;
+        self.copy_into(temp, reader)
     }
 
     fn retrieve(&self, key: &Key) -> Result<Utf8PathBuf> {
-        let target_path = self.base_path.join(key.rel_path());
-        if !target_path.exists() {
-            return Err(anyhow::anyhow!("File not found: {}", target_path));
+        let target_path = match self.find(key) {
+            Some(BlobFile::Compressed) => self.materialize(key)?,
+            Some(file) => self.base_path.join(file.rel_path(key)),
+            None => return Err(ZwischenError::NotFound { key: *key }.into()),
+        };
+        mark_used(&target_path);
+        if self.verify_on_read {
+            let actual = hash_file(&target_path)
+                .with_context(|| format!("while verifying {target_path:?}"))?;
+            if actual != *key {
+                return Err(ZwischenError::Corrupted { key: *key, actual }.into());
+            }
         }
         Ok(target_path)
     }
+
+    fn retrieve_file(&self, key: &Key, attributes: &FileAttributes) -> Result<Utf8PathBuf> {
+        if attributes.executable {
+            let path = self.make_executable(key)?;
+            mark_used(&path);
+            Ok(path)
+        } else {
+            self.retrieve(key)
+        }
+    }
+
+    fn contains(&self, key: &Key) -> Result<bool> {
+        Ok(self.find(key).is_some())
+    }
+
+    fn retrieve_many(&self, keys: &[Key]) -> Result<Vec<Utf8PathBuf>> {
+        let found = self.find_many(keys)?;
+        keys.par_iter()
+            .zip(found)
+            .map(|(key, file)| match file {
+                Some(file @ (BlobFile::Plain | BlobFile::Materialized)) if !self.verify_on_read => {
+                    let path = self.base_path.join(file.rel_path(key));
+                    mark_used(&path);
+                    Ok(path)
+                }
+                // Needs decompressing, verifying or a proper error.
+                _ => self.retrieve(key),
+            })
+            .collect()
+    }
+
+    fn contains_many(&self, keys: &[Key]) -> Result<Vec<bool>> {
+        Ok(self
+            .find_many(keys)?
+            .into_iter()
+            .map(|file| file.is_some())
+            .collect())
+    }
+
+    /// Records are files named like blobs below [`ACTIONS_DIR`], holding the hex digest
+    /// of the outputs.
+    fn lookup_action(&self, action: &Key) -> Result<Option<Key>> {
+        let path = self.base_path.join(ACTIONS_DIR).join(key_rel_path(action));
+        match std::fs::read_to_string(&path) {
+            Ok(hex) => Ok(Some(
+                hex.trim()
+                    .parse()
+                    .with_context(|| format!("while reading {path:?}"))?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("while reading {path:?}")),
+        }
+    }
+
+    fn record_action(&self, action: &Key, outputs: &Key) -> Result<()> {
+        let mut temp = self.new_temp_file()?;
+        temp.write_all(outputs.to_string().as_bytes())
+            .context("while writing action record")?;
+        let path = self.base_path.join(ACTIONS_DIR).join(key_rel_path(action));
+        let dir = path.parent().expect("records are in shard directories");
+        std::fs::create_dir_all(dir).with_context(|| format!("while creating {dir:?}"))?;
+        // Unlike blobs, records are replaced: the same action may have produced other outputs.
+        temp.persist(&path)
+            .with_context(|| format!("while moving action record to {path:?}"))?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::{Result, anyhow};
-    use std::{fs::File, io::Write, path::Path};
-    use tempfile::{NamedTempFile, TempDir, tempfile};
+    use std::{fs::File, path::Path, str::FromStr};
+    use tempfile::{NamedTempFile, TempDir};
 
     use super::*;
 
     #[test]
-    fn key_rel_path() {
-        let key = Key(blake3::Hash::from_hex(
-            "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
-        )
-        .unwrap());
-        let rel_path = key.rel_path();
+    fn rel_path_is_sharded() {
+        let key: Key = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
+            .parse()
+            .unwrap();
+        let rel_path = key_rel_path(&key);
         assert_eq!(
             rel_path,
             Utf8PathBuf::from_str(
@@ -119,14 +701,14 @@ mod tests {
     }
 
     #[derive(Debug)]
-    struct ZwischenContext {
-        dir: TempDir,
-        zwischen: FileSystemZwischen,
+    pub(crate) struct ZwischenContext {
+        _dir: TempDir,
+        pub(crate) zwischen: FileSystemZwischen,
         temp_files: Vec<NamedTempFile>,
     }
 
     impl ZwischenContext {
-        fn new() -> Result<Self> {
+        pub(crate) fn new() -> Result<Self> {
             let dir = tempfile::tempdir()?;
             let base_path = Utf8PathBuf::from_str(
                 dir.path()
@@ -135,21 +717,28 @@ mod tests {
             )?;
             let zwischen = FileSystemZwischen::new(base_path);
             Ok(Self {
-                dir,
+                _dir: dir,
                 zwischen,
                 temp_files: Vec::new(),
             })
         }
 
-        fn add_temp_file(&mut self) -> Result<Utf8PathBuf> {
+        pub(crate) fn add_temp_file(&mut self) -> Result<Utf8PathBuf> {
             self.temp_files.push(NamedTempFile::new()?);
             let temp_file: &NamedTempFile = self.temp_files.last().unwrap();
-            Ok(Utf8PathBuf::from_path_buf(temp_file.path().to_path_buf())
-                .map_err(|e| anyhow!("unexpected non-utf8 file: {e:?}"))?)
+            Utf8PathBuf::from_path_buf(temp_file.path().to_path_buf())
+                .map_err(|e| anyhow!("unexpected non-utf8 file: {e:?}"))
+        }
+
+        /// Stores `content` and returns its key.
+        pub(crate) fn store_content(&mut self, content: &[u8]) -> Result<Key> {
+            let file = self.add_temp_file()?;
+            write_content(&file, content)?;
+            self.zwischen.store(&file)
         }
     }
 
-    fn write_content(file: impl AsRef<Path>, content: &[u8]) -> Result<()> {
+    pub(crate) fn write_content(file: impl AsRef<Path>, content: &[u8]) -> Result<()> {
         let mut file = File::create(file.as_ref())
             .with_context(|| format!("while creating file {:?}", file.as_ref()))?;
         file.write_all(content)?;
@@ -184,4 +773,234 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn rel_path_round_trips() {
+        let key = Key::of(b"content");
+        assert_eq!(key_from_rel_path(&key_rel_path(&key)), Some(key));
+        assert_eq!(key_from_rel_path(Utf8Path::new("01/23/45")), None);
+        assert_eq!(key_from_rel_path(Utf8Path::new("tmp/abc")), None);
+    }
+
+    #[test]
+    fn verify_on_read_detects_corruption() -> Result<()> {
+        let mut context = ZwischenContext::new()?;
+        let key = context.store_content(b"original")?;
+        let path = context.zwischen.retrieve(&key)?;
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+        write_content(&path, b"corrupt")?;
+
+        // Without verification, corruption goes unnoticed.
+        assert_eq!(context.zwischen.retrieve(&key)?, path);
+
+        let verifying = context.zwischen.clone().with_verify_on_read(true);
+        let err = verifying.retrieve(&key).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ZwischenError>(),
+            Some(&ZwischenError::Corrupted {
+                key,
+                actual: Key::of(b"corrupt"),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn store_leaves_source_untouched() -> Result<()> {
+        let mut context = ZwischenContext::new()?;
+        let source = context.add_temp_file()?;
+        write_content(&source, b"source")?;
+        std::fs::set_permissions(&source, Permissions::from_mode(0o640))?;
+
+        let key = context.zwischen.store(&source)?;
+
+        let mode = std::fs::metadata(&source)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        let blob = std::fs::metadata(context.zwischen.retrieve(&key)?)?;
+        assert_eq!(blob.permissions().mode() & 0o777, 0o444);
+        Ok(())
+    }
+
+    #[test]
+    fn store_bytes_and_reader_match_file() -> Result<()> {
+        let mut context = ZwischenContext::new()?;
+        let key = context.store_content(b"same")?;
+        assert_eq!(context.zwischen.store_bytes(b"same")?, key);
+        assert_eq!(context.zwischen.store_reader(&mut &b"same"[..])?, key);
+        assert_eq!(key, Key::of(b"same"));
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_stores_of_same_content() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let content = vec![42u8; 1 << 20];
+        let keys = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| context.zwischen.store_bytes(&content)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        assert!(keys.iter().all(|k| *k == keys[0]));
+        let retrieved = std::fs::read(context.zwischen.retrieve(&keys[0])?)?;
+        assert_eq!(retrieved, content);
+        let temp_dir = context.zwischen.base_path().join(TEMP_DIR);
+        assert_eq!(std::fs::read_dir(temp_dir)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn storing_again_refreshes_existing_blobs() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let compressing = context.zwischen.clone().with_compression(Some(3));
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        for (zwischen, file) in [
+            (&context.zwischen, BlobFile::Plain),
+            (&compressing, BlobFile::Compressed),
+        ] {
+            let content = format!("{file:?}");
+            let key = zwischen.store_bytes(content.as_bytes())?;
+            let path = zwischen.base_path().join(file.rel_path(&key));
+            File::open(&path)?.set_modified(old)?;
+
+            zwischen.store_bytes(content.as_bytes())?;
+            assert!(std::fs::metadata(&path)?.modified()? > old);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn retrieving_marks_blobs_used() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let key = context.zwischen.store_bytes(b"used")?;
+        let path = context.zwischen.retrieve(&key)?;
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        let recent = SystemTime::now() - Duration::from_secs(60);
+        let accessed = || -> Result<SystemTime> { Ok(std::fs::metadata(&path)?.accessed()?) };
+
+        File::open(&path)?.set_times(FileTimes::new().set_accessed(recent))?;
+        context.zwischen.retrieve(&key)?;
+        assert_eq!(accessed()?, recent);
+
+        File::open(&path)?.set_times(FileTimes::new().set_accessed(old))?;
+        context.zwischen.retrieve_many(&[key])?;
+        assert!(accessed()? > recent);
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_blobs_are_materialized_on_retrieve() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = context.zwischen.clone().with_compression(Some(3));
+        let content = vec![7u8; 1 << 16];
+        let key = zwischen.store_bytes(&content)?;
+        assert_eq!(key, Key::of(&content));
+
+        let base = zwischen.base_path();
+        assert!(!base.join(key_rel_path(&key)).exists());
+        assert!(base.join(BlobFile::Compressed.rel_path(&key)).exists());
+
+        let path = zwischen.retrieve(&key)?;
+        assert_eq!(path, base.join(BlobFile::Materialized.rel_path(&key)));
+        assert_eq!(std::fs::read(&path)?, content);
+        // Uncompressed stores read the compressed blob, too.
+        assert_eq!(context.zwischen.retrieve(&key)?, path);
+
+        let stats = zwischen.compression_stats(&key)?.unwrap();
+        assert_eq!(stats.uncompressed_bytes, content.len() as u64);
+        assert!(stats.ratio() < 0.1);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_keeps_existing_plain_blobs() -> Result<()> {
+        let mut context = ZwischenContext::new()?;
+        let key = context.store_content(b"plain")?;
+        let zwischen = context.zwischen.clone().with_compression(Some(0));
+        assert_eq!(zwischen.store_bytes(b"plain")?, key);
+        assert_eq!(zwischen.compression_stats(&key)?, None);
+        assert_eq!(
+            zwischen.retrieve(&key)?,
+            zwischen.base_path().join(key_rel_path(&key))
+        );
+
+        let missing = Key::of(b"missing");
+        let err = zwischen.compression_stats(&missing).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ZwischenError>(),
+            Some(&ZwischenError::NotFound { key: missing })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn uncompressed_stores_keep_existing_compressed_blobs() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let compressing = context.zwischen.clone().with_compression(Some(3));
+        let key = compressing.store_bytes(b"compressed")?;
+        assert_eq!(context.zwischen.store_bytes(b"compressed")?, key);
+
+        let base = context.zwischen.base_path();
+        assert!(!base.join(BlobFile::Plain.rel_path(&key)).exists());
+        assert!(context.zwischen.compression_stats(&key)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_compressed_blob_is_not_materialized() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen = context.zwischen.clone().with_compression(Some(3));
+        let key = zwischen.store_bytes(b"original")?;
+        let other = zwischen.store_bytes(b"other")?;
+        let base = zwischen.base_path();
+        let path = base.join(BlobFile::Compressed.rel_path(&key));
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644))?;
+        std::fs::copy(base.join(BlobFile::Compressed.rel_path(&other)), &path)?;
+
+        let err = zwischen.retrieve(&key).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ZwischenError>(),
+            Some(&ZwischenError::Corrupted { key, actual: other })
+        );
+        assert!(!base.join(BlobFile::Materialized.rel_path(&key)).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_operations() -> Result<()> {
+        let mut context = ZwischenContext::new()?;
+        let plain = context.store_content(b"plain")?;
+        let compressed = context
+            .zwischen
+            .clone()
+            .with_compression(Some(3))
+            .store_bytes(b"compressed")?;
+        let missing = Key::of(b"missing");
+
+        let keys = [plain, missing, compressed];
+        assert_eq!(context.zwischen.contains_many(&keys)?, [true, false, true]);
+        let err = context.zwischen.retrieve_many(&keys).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ZwischenError>(),
+            Some(&ZwischenError::NotFound { key: missing })
+        );
+
+        let paths = context.zwischen.retrieve_many(&[compressed, plain])?;
+        assert_eq!(std::fs::read(&paths[0])?, b"compressed");
+        assert_eq!(std::fs::read(&paths[1])?, b"plain");
+
+        let files = [context.add_temp_file()?, context.add_temp_file()?];
+        write_content(&files[0], b"one")?;
+        write_content(&files[1], b"two")?;
+        let stored = context.zwischen.store_many(&files)?;
+        assert_eq!(stored, [Key::of(b"one"), Key::of(b"two")]);
+
+        let empty = FileSystemZwischen::new(context.zwischen.base_path().join("nothing"));
+        assert_eq!(empty.contains_many(&keys)?, [false; 3]);
+        Ok(())
+    }
 }
@@ -0,0 +1,313 @@
+//! Combining a fast local store with a shared remote one.
+
+use std::io::Read;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use model::FileAttributes;
+use rayon::prelude::*;
+use tracing::warn;
+
+use crate::{Key, Zwischen, ZwischenError};
+
+/// Stores blobs in both stores and retrieves them from `local` first.
+///
+/// Blobs found only in `remote` are copied to `local` on retrieval.
+/// The remote store is a cache that may be unreachable: its errors are logged
+/// and treated as if it did not have the blob.
+#[derive(Debug, Clone)]
+pub struct LocalThenRemote<L, R> {
+    local: L,
+    remote: R,
+}
+
+impl<L: Zwischen, R: Zwischen> LocalThenRemote<L, R> {
+    pub fn new(local: L, remote: R) -> Self {
+        Self { local, remote }
+    }
+
+    pub fn local(&self) -> &L {
+        &self.local
+    }
+
+    pub fn remote(&self) -> &R {
+        &self.remote
+    }
+
+    /// Copies the locally stored `key` to the remote store.
+    fn push(&self, key: Key) -> Result<Key> {
+        let path = self.local.retrieve(&key)?;
+        if let Err(e) = self.remote.store(&path) {
+            warn!("cannot upload {key} to the remote store: {e:?}");
+        }
+        Ok(key)
+    }
+
+    /// Copies `key` from the remote to the local store.
+    fn fetch(&self, key: &Key) -> Result<()> {
+        let mut reader = match self.remote.open(key) {
+            Ok(reader) => reader,
+            Err(e) => {
+                if !matches!(
+                    e.downcast_ref::<ZwischenError>(),
+                    Some(ZwischenError::NotFound { .. })
+                ) {
+                    warn!("cannot download {key} from the remote store: {e:?}");
+                }
+                return Err(ZwischenError::NotFound { key: *key }.into());
+            }
+        };
+        let actual = self.local.store_reader(&mut reader)?;
+        if actual != *key {
+            return Err(ZwischenError::Corrupted { key: *key, actual }.into());
+        }
+        Ok(())
+    }
+
+    fn remote_contains(&self, key: &Key) -> bool {
+        self.remote.contains(key).unwrap_or_else(|e| {
+            warn!("cannot look up {key} in the remote store: {e:?}");
+            false
+        })
+    }
+}
+
+impl<L: Zwischen, R: Zwischen> Zwischen for LocalThenRemote<L, R> {
+    fn store(&self, file: &Utf8Path) -> Result<Key> {
+        let key = self.local.store(file)?;
+        self.push(key)
+    }
+
+    fn store_reader(&self, reader: &mut dyn Read) -> Result<Key> {
+        let key = self.local.store_reader(reader)?;
+        self.push(key)
+    }
+
+    fn retrieve(&self, key: &Key) -> Result<Utf8PathBuf> {
+        if !self.local.contains(key)? {
+            self.fetch(key)?;
+        }
+        self.local.retrieve(key)
+    }
+
+    fn retrieve_file(&self, key: &Key, attributes: &FileAttributes) -> Result<Utf8PathBuf> {
+        if !self.local.contains(key)? {
+            self.fetch(key)?;
+        }
+        self.local.retrieve_file(key, attributes)
+    }
+
+    fn contains(&self, key: &Key) -> Result<bool> {
+        Ok(self.local.contains(key)? || self.remote_contains(key))
+    }
+
+    /// Uploads only the blobs the remote store lacks.
+    fn store_many(&self, files: &[Utf8PathBuf]) -> Result<Vec<Key>> {
+        let keys = self.local.store_many(files)?;
+        let present = match self.remote.contains_many(&keys) {
+            Ok(present) => present,
+            Err(e) => {
+                warn!("cannot look up blobs in the remote store: {e:?}");
+                return Ok(keys);
+            }
+        };
+        let missing: Vec<Key> = keys
+            .iter()
+            .zip(present)
+            .filter(|(_, present)| !present)
+            .map(|(key, _)| *key)
+            .collect();
+        missing
+            .par_iter()
+            .try_for_each(|key| self.push(*key).map(|_| ()))?;
+        Ok(keys)
+    }
+
+    /// Downloads the blobs missing locally in parallel.
+    fn retrieve_many(&self, keys: &[Key]) -> Result<Vec<Utf8PathBuf>> {
+        let present = self.local.contains_many(keys)?;
+        keys.par_iter()
+            .zip(present)
+            .filter(|(_, present)| !present)
+            .try_for_each(|(key, _)| self.fetch(key))?;
+        self.local.retrieve_many(keys)
+    }
+
+    /// Asks the remote store only about the blobs missing locally.
+    fn contains_many(&self, keys: &[Key]) -> Result<Vec<bool>> {
+        let mut present = self.local.contains_many(keys)?;
+        let (indices, missing): (Vec<usize>, Vec<Key>) = present
+            .iter()
+            .enumerate()
+            .filter(|(_, present)| !**present)
+            .map(|(i, _)| (i, keys[i]))
+            .unzip();
+        if missing.is_empty() {
+            return Ok(present);
+        }
+        match self.remote.contains_many(&missing) {
+            Ok(remote) => {
+                for (i, found) in indices.into_iter().zip(remote) {
+                    present[i] = found;
+                }
+            }
+            Err(e) => warn!("cannot look up blobs in the remote store: {e:?}"),
+        }
+        Ok(present)
+    }
+
+    /// Asks the remote store only if nothing is recorded locally,
+    /// and keeps what it recorded locally, too.
+    fn lookup_action(&self, action: &Key) -> Result<Option<Key>> {
+        if let Some(outputs) = self.local.lookup_action(action)? {
+            return Ok(Some(outputs));
+        }
+        match self.remote.lookup_action(action) {
+            Ok(Some(outputs)) => {
+                self.local.record_action(action, &outputs)?;
+                Ok(Some(outputs))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                warn!("cannot look up action {action} in the remote store: {e:?}");
+                Ok(None)
+            }
+        }
+    }
+
+    fn record_action(&self, action: &Key, outputs: &Key) -> Result<()> {
+        self.local.record_action(action, outputs)?;
+        if let Err(e) = self.remote.record_action(action, outputs) {
+            warn!("cannot record action {action} in the remote store: {e:?}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::FileSystemZwischen;
+    use crate::http::HttpZwischen;
+    use crate::tests::{ZwischenContext, write_content};
+
+    #[test]
+    fn remote_blobs_are_copied_to_local() -> Result<()> {
+        let local = ZwischenContext::new()?;
+        let remote = ZwischenContext::new()?;
+        let key = remote.zwischen.store_bytes(b"from CI")?;
+
+        let tiered = LocalThenRemote::new(local.zwischen.clone(), remote.zwischen.clone());
+        assert!(tiered.contains(&key)?);
+        assert!(!local.zwischen.contains(&key)?);
+        assert_eq!(std::fs::read(tiered.retrieve(&key)?)?, b"from CI");
+        assert!(local.zwischen.contains(&key)?);
+
+        let stored = tiered.store_bytes(b"from a laptop")?;
+        assert!(local.zwischen.contains(&stored)?);
+        assert!(remote.zwischen.contains(&stored)?);
+        Ok(())
+    }
+
+    #[test]
+    fn unreachable_remote_is_a_miss() -> Result<()> {
+        let local = ZwischenContext::new()?;
+        let downloads = ZwischenContext::new()?;
+        // Nothing listens on port 9 of the loopback interface.
+        let remote = HttpZwischen::new("http://127.0.0.1:9", downloads.zwischen.clone());
+        let tiered = LocalThenRemote::new(local.zwischen.clone(), remote);
+
+        let key = tiered.store_bytes(b"local only")?;
+        assert!(tiered.contains(&key)?);
+        let missing = Key::of(b"missing");
+        assert!(!tiered.contains(&missing)?);
+        let err = tiered.retrieve(&missing).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ZwischenError>(),
+            Some(&ZwischenError::NotFound { key: missing })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn actions_recorded_remotely_are_found_locally_afterwards() -> Result<()> {
+        let local = ZwischenContext::new()?;
+        let remote = ZwischenContext::new()?;
+        let action = Key::of(b"action");
+        let outputs = remote.zwischen.store_bytes(b"outputs")?;
+        remote.zwischen.record_action(&action, &outputs)?;
+
+        let tiered = LocalThenRemote::new(local.zwischen.clone(), remote.zwischen.clone());
+        assert_eq!(local.zwischen.lookup_action(&action)?, None);
+        assert_eq!(tiered.lookup_action(&action)?, Some(outputs));
+        assert_eq!(local.zwischen.lookup_action(&action)?, Some(outputs));
+
+        let other = tiered.store_bytes(b"other outputs")?;
+        tiered.record_action(&action, &other)?;
+        assert_eq!(remote.zwischen.lookup_action(&action)?, Some(other));
+        Ok(())
+    }
+
+    /// Counts the blobs stored into it.
+    #[derive(Debug)]
+    struct Counting(FileSystemZwischen, AtomicUsize);
+
+    impl Zwischen for Counting {
+        fn store(&self, file: &Utf8Path) -> Result<Key> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.store(file)
+        }
+
+        fn store_reader(&self, reader: &mut dyn Read) -> Result<Key> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.store_reader(reader)
+        }
+
+        fn retrieve(&self, key: &Key) -> Result<Utf8PathBuf> {
+            self.0.retrieve(key)
+        }
+
+        fn retrieve_file(&self, key: &Key, attributes: &FileAttributes) -> Result<Utf8PathBuf> {
+            self.0.retrieve_file(key, attributes)
+        }
+
+        fn contains(&self, key: &Key) -> Result<bool> {
+            self.0.contains(key)
+        }
+    }
+
+    #[test]
+    fn batches_only_transfer_what_is_missing() -> Result<()> {
+        let local = ZwischenContext::new()?;
+        let mut remote = ZwischenContext::new()?;
+        let shared = remote.store_content(b"shared")?;
+        let remote_only = remote.store_content(b"remote only")?;
+        let tiered = LocalThenRemote::new(
+            local.zwischen.clone(),
+            Counting(remote.zwischen.clone(), AtomicUsize::new(0)),
+        );
+
+        let missing = Key::of(b"missing");
+        assert_eq!(
+            tiered.contains_many(&[shared, remote_only, missing])?,
+            [true, true, false]
+        );
+
+        let paths = tiered.retrieve_many(&[shared, remote_only])?;
+        assert_eq!(std::fs::read(&paths[1])?, b"remote only");
+        assert_eq!(
+            local.zwischen.contains_many(&[shared, remote_only])?,
+            [true, true]
+        );
+
+        let files = [remote.add_temp_file()?, remote.add_temp_file()?];
+        write_content(&files[0], b"shared")?;
+        write_content(&files[1], b"new")?;
+        let keys = tiered.store_many(&files)?;
+        assert_eq!(tiered.remote().1.load(Ordering::SeqCst), 1);
+        assert!(remote.zwischen.contains(&keys[1])?);
+        Ok(())
+    }
+}
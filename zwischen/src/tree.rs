@@ -0,0 +1,281 @@
+//! Storing directory trees as [`Dir`] listings next to the blobs of their files.
+
+use std::collections::BTreeMap;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::{Context, Result, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use model::{Digest, Dir, DirEntry, DirEntryKind, FileAttributes};
+
+use crate::Zwischen;
+
+/// Stores the files below `root` and a listing of every directory.
+/// Returns the `entry_hash` of the listing of `root`.
+///
+/// Files keep their executable bit, but not their mtime, which provisioning can normalize.
+/// Symlinks are stored as they are, without following them.
+pub fn store_tree(zwischen: &dyn Zwischen, root: &Utf8Path) -> Result<Digest> {
+    let dir = store_dir(zwischen, root)?;
+    zwischen.store_bytes(&dir.to_bytes())?;
+    Ok(dir.entry_hash())
+}
+
+fn store_dir(zwischen: &dyn Zwischen, path: &Utf8Path) -> Result<Dir> {
+    let mut entries = BTreeMap::new();
+    let mut files: Vec<(String, Utf8PathBuf, FileAttributes)> = Vec::new();
+    for entry in path
+        .read_dir_utf8()
+        .with_context(|| format!("while listing {path:?}"))?
+    {
+        let entry = entry.with_context(|| format!("while listing {path:?}"))?;
+        let name = entry.file_name().to_owned();
+        let metadata = entry
+            .path()
+            .symlink_metadata()
+            .with_context(|| format!("while inspecting {:?}", entry.path()))?;
+        if metadata.is_symlink() {
+            let target = entry
+                .path()
+                .read_link_utf8()
+                .with_context(|| format!("while reading symlink {:?}", entry.path()))?;
+            let entry = DirEntry {
+                content_hash: Digest::of(target.as_str().as_bytes()),
+                kind: DirEntryKind::Symlink {
+                    target: target.into_string(),
+                },
+            };
+            entries.insert(name, entry);
+        } else if metadata.is_dir() {
+            let dir = store_dir(zwischen, entry.path())?;
+            zwischen.store_bytes(&dir.to_bytes())?;
+            let entry = DirEntry {
+                kind: DirEntryKind::Dir,
+                content_hash: dir.entry_hash(),
+            };
+            entries.insert(name, entry);
+        } else if metadata.is_file() {
+            files.push((
+                name,
+                entry.path().to_owned(),
+                FileAttributes::from_metadata(&metadata),
+            ));
+        } else {
+            return Err(anyhow!(
+                "{:?} is neither file, directory nor symlink",
+                entry.path()
+            ));
+        }
+    }
+
+    let paths: Vec<Utf8PathBuf> = files.iter().map(|(_, path, _)| path.clone()).collect();
+    let keys = zwischen.store_many(&paths)?;
+    for ((name, _, attributes), key) in files.into_iter().zip(keys) {
+        let entry = DirEntry {
+            kind: DirEntryKind::File { attributes },
+            content_hash: key,
+        };
+        entries.insert(name, entry);
+    }
+    Ok(Dir::from_entries(entries))
+}
+
+/// Stores the listings of a tree made of `files`, by their path relative to its root.
+/// Their content has to be stored already. Returns the `entry_hash` of the root listing.
+pub fn store_files(
+    zwischen: &dyn Zwischen,
+    files: &BTreeMap<Utf8PathBuf, DirEntry>,
+) -> Result<Digest> {
+    let mut children: BTreeMap<String, BTreeMap<Utf8PathBuf, DirEntry>> = BTreeMap::new();
+    let mut entries = BTreeMap::new();
+    for (path, entry) in files {
+        let mut components = path.components();
+        let first = components
+            .next()
+            .ok_or_else(|| anyhow!("empty path in tree"))?
+            .as_str()
+            .to_owned();
+        let rest = components.as_path();
+        if rest.as_str().is_empty() {
+            entries.insert(first, entry.clone());
+        } else {
+            children
+                .entry(first)
+                .or_default()
+                .insert(rest.to_owned(), entry.clone());
+        }
+    }
+    for (name, files) in children {
+        let entry = DirEntry {
+            kind: DirEntryKind::Dir,
+            content_hash: store_files(zwischen, &files)?,
+        };
+        if entries.insert(name.clone(), entry).is_some() {
+            return Err(anyhow!("{name:?} is both a file and a directory"));
+        }
+    }
+    let dir = Dir::from_entries(entries);
+    zwischen.store_bytes(&dir.to_bytes())?;
+    Ok(dir.entry_hash())
+}
+
+/// Lists the files and symlinks of the tree stored as `entry_hash`,
+/// by their path relative to its root.
+pub fn load_files(
+    zwischen: &dyn Zwischen,
+    entry_hash: Digest,
+) -> Result<BTreeMap<Utf8PathBuf, DirEntry>> {
+    let mut files = BTreeMap::new();
+    let mut todo = vec![(Utf8PathBuf::new(), entry_hash)];
+    while let Some((path, entry_hash)) = todo.pop() {
+        for (name, entry) in load_dir(zwischen, entry_hash)?.entries() {
+            match entry.kind {
+                DirEntryKind::Dir => todo.push((path.join(name), entry.content_hash)),
+                DirEntryKind::File { .. } | DirEntryKind::Symlink { .. } => {
+                    files.insert(path.join(name), entry.clone());
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Copies `files`, as listed by [`load_files`], out of the store to below `dest`.
+/// Unlike provisioned trees, the copies are writable and replace existing files.
+pub fn restore_files(
+    zwischen: &dyn Zwischen,
+    files: &BTreeMap<Utf8PathBuf, DirEntry>,
+    dest: &Utf8Path,
+) -> Result<()> {
+    let keys: Vec<Digest> = files.values().map(|entry| entry.content_hash).collect();
+    // Fetches what is missing in one batch.
+    zwischen.retrieve_many(&keys)?;
+    for (path, entry) in files {
+        let target = dest.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("while creating {parent:?}"))?;
+        }
+        match std::fs::remove_file(&target) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("while removing {target:?}"));
+            }
+            _ => {}
+        }
+        match &entry.kind {
+            DirEntryKind::File { attributes } => {
+                let source = zwischen.retrieve_file(&entry.content_hash, attributes)?;
+                std::fs::copy(&source, &target)
+                    .with_context(|| format!("while copying {source:?} to {target:?}"))?;
+                std::fs::set_permissions(
+                    &target,
+                    Permissions::from_mode(attributes.mode() | 0o200),
+                )
+                .with_context(|| format!("while making {target:?} writable"))?;
+            }
+            DirEntryKind::Symlink { target: link } => std::os::unix::fs::symlink(link, &target)
+                .with_context(|| format!("while creating symlink {target:?}"))?,
+            DirEntryKind::Dir => unreachable!("load_files lists no directories"),
+        }
+    }
+    Ok(())
+}
+
+/// Loads the listing stored for `entry_hash`.
+pub fn load_dir(zwischen: &dyn Zwischen, entry_hash: Digest) -> Result<Dir> {
+    let path = zwischen.retrieve(&entry_hash)?;
+    let bytes = std::fs::read(&path).with_context(|| format!("while reading {path:?}"))?;
+    Dir::from_bytes(&bytes).with_context(|| format!("{path:?} is not a directory listing"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::tests::ZwischenContext;
+
+    #[test]
+    fn files_are_restored_writable() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let zwischen: &dyn Zwischen = &context.zwischen;
+        let file = |content: &[u8], executable| DirEntry {
+            kind: DirEntryKind::File {
+                attributes: FileAttributes {
+                    executable,
+                    size: content.len() as u64,
+                },
+            },
+            content_hash: zwischen.store_bytes(content).unwrap(),
+        };
+        let files = BTreeMap::from([
+            ("pkg/main.o".into(), file(b"object", false)),
+            ("pkg/bin/main".into(), file(b"binary", true)),
+            ("README".into(), file(b"readme", false)),
+        ]);
+        let entry_hash = store_files(zwischen, &files)?;
+        assert_eq!(load_files(zwischen, entry_hash)?, files);
+
+        let dest = tempfile::tempdir()?;
+        let dest = Utf8Path::from_path(dest.path()).unwrap();
+        fs::create_dir(dest.join("pkg"))?;
+        fs::write(dest.join("pkg/main.o"), "stale")?;
+        restore_files(zwischen, &files, dest)?;
+        assert_eq!(fs::read(dest.join("pkg/main.o"))?, b"object");
+        let mode = |path: &str| fs::metadata(dest.join(path)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("pkg/main.o"), 0o644);
+        assert_eq!(mode("pkg/bin/main"), 0o755);
+        // A copy, so writing to it leaves the blob alone.
+        fs::write(dest.join("pkg/main.o"), "changed")?;
+        assert_eq!(
+            fs::read(zwischen.retrieve(&files[Utf8Path::new("pkg/main.o")].content_hash)?)?,
+            b"object"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stored_tree_keeps_executable_bit() -> Result<()> {
+        let context = ZwischenContext::new()?;
+        let source = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(source.path()).unwrap();
+        fs::create_dir(root.join("bin"))?;
+        fs::write(root.join("bin/tool"), "#!/bin/sh\n")?;
+        fs::set_permissions(root.join("bin/tool"), Permissions::from_mode(0o750))?;
+        fs::write(root.join("README"), "#!/bin/sh\n")?;
+        std::os::unix::fs::symlink("bin/tool", root.join("tool"))?;
+
+        let zwischen: &dyn Zwischen = &context.zwischen;
+        let dir = load_dir(zwischen, store_tree(zwischen, root)?)?;
+        let names: Vec<&str> = dir.entries().keys().map(String::as_str).collect();
+        assert_eq!(names, ["README", "bin", "tool"]);
+        assert_eq!(
+            dir.entries()["tool"].kind,
+            DirEntryKind::Symlink {
+                target: "bin/tool".into()
+            }
+        );
+        let DirEntryKind::File { attributes } = &dir.entries()["README"].kind else {
+            panic!("README is not a file");
+        };
+        assert!(!attributes.executable);
+
+        let bin = load_dir(zwischen, dir.entries()["bin"].content_hash)?;
+        let tool = &bin.entries()["tool"];
+        let DirEntryKind::File { attributes } = &tool.kind else {
+            panic!("bin/tool is not a file");
+        };
+        assert!(attributes.executable);
+        // Same content, so the same blob, but in two modes.
+        assert_eq!(tool.content_hash, dir.entries()["README"].content_hash);
+        let executable = zwischen.retrieve_file(&tool.content_hash, attributes)?;
+        let plain = zwischen.retrieve(&tool.content_hash)?;
+        assert_eq!(
+            fs::metadata(&executable)?.permissions().mode() & 0o777,
+            0o555
+        );
+        assert_eq!(fs::metadata(&plain)?.permissions().mode() & 0o777, 0o444);
+        assert_eq!(fs::read(executable)?, b"#!/bin/sh\n");
+        Ok(())
+    }
+}